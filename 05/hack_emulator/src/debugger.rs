@@ -0,0 +1,583 @@
+use crate::disasm::disassemble;
+use crate::keyboard::{self, KeyEvent, KeyScript};
+use crate::machine::{Machine, MemoryWrite, KBD};
+use crate::native::NativeHook;
+use crate::program::Program;
+use crate::source_map::SourceMap;
+use crate::vm::{self, ARG, LCL, SP, TEMP, THAT, THIS};
+use std::io::{BufRead, Write};
+
+const HELP: &str = "commands:
+    step [n]                   (s) execute n instructions (default 1)
+    next                       (n) run until the following ROM address (skips loops and calls)
+    continue                   (c) run until a breakpoint, a watchpoint or halt
+    break <addr|label>         (b) set a breakpoint on a ROM address
+    watch <addr|symbol>        (w) stop when a RAM cell changes
+    delete [n]                 (d) delete a breakpoint or watchpoint (all if omitted)
+    info                       (i) list breakpoints and watchpoints
+    regs                       (r) print registers and the next instruction
+    x <addr|symbol> [n]            print n words of RAM
+    set <addr|symbol> <value>      write a value to RAM
+    key <code|name|'c'|release>    press a key (KBD) until the next key command
+    list [addr|label]          (l) print instructions around an address
+    reset                          set PC to 0
+    quit                       (q) exit
+VM commands (need a source map from hack_vm):
+    vmstep [n]                 (vs) execute n VM commands (default 1)
+    vmnext                     (vn) execute one VM command, stepping over calls
+    finish                         run until the current function returns
+    backtrace                  (bt) print the VM call stack
+    frame [n]                  (f) print argument, local and stack of frame n (default 0)
+    segments                       print pointer, temp and static segments";
+
+/// 実行が止まった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// 指定した命令数を実行した
+    Done,
+    Breakpoint(u16),
+    Watchpoint(MemoryWrite),
+    Halted,
+    /// 実行できる命令数の上限に達した
+    Limit,
+}
+
+enum Point {
+    Break(u16),
+    Watch(u16),
+}
+
+/// Hackの機械語を1命令ずつ実行するデバッガ
+pub struct Debugger {
+    pub machine: Machine,
+    pub program: Program,
+    points: Vec<Point>,
+    /// `continue`や`next`で実行する命令数の上限
+    pub max_cycles: u64,
+    /// 実行に合わせてキーボードを操作するスクリプト
+    pub keys: KeyScript,
+    /// `key`コマンドで操作したキーの記録
+    pub recorded: Vec<KeyEvent>,
+    /// OSの関数をネイティブで実行する
+    pub native: Option<NativeHook>,
+}
+
+impl Debugger {
+    pub fn new(program: Program) -> Self {
+        Debugger {
+            machine: Machine::new(program.rom.clone()),
+            program,
+            points: vec![],
+            max_cycles: 100_000_000,
+            keys: KeyScript::default(),
+            recorded: vec![],
+            native: None,
+        }
+    }
+
+    /// 1命令を実行して、ウォッチポイントに当たったかを返す
+    fn step_one(&mut self) -> Option<Stop> {
+        self.keys.apply(&mut self.machine);
+        if let Some(native) = self.native.as_mut() {
+            if native.apply(&mut self.machine) {
+                return None;
+            }
+        }
+        let write = self.machine.step()?;
+        let watched = self
+            .points
+            .iter()
+            .any(|point| matches!(point, Point::Watch(address) if *address == write.address));
+        if watched && write.old != write.new {
+            Some(Stop::Watchpoint(write))
+        } else {
+            None
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.points
+            .iter()
+            .any(|point| matches!(point, Point::Break(address) if *address == self.machine.pc))
+    }
+
+    /// `until`が真になるか、ブレークポイントなどに当たるまで実行する
+    ///
+    /// 最初の1命令はブレークポイントで止まらない
+    fn run_until(&mut self, until: impl Fn(&Machine) -> bool) -> Stop {
+        for n in 0..self.max_cycles {
+            if self.machine.is_halted() {
+                return Stop::Halted;
+            }
+            if n > 0 && self.at_breakpoint() {
+                return Stop::Breakpoint(self.machine.pc);
+            }
+            if let Some(stop) = self.step_one() {
+                return stop;
+            }
+            if until(&self.machine) {
+                return Stop::Done;
+            }
+        }
+        Stop::Limit
+    }
+
+    /// `count`命令を実行する
+    pub fn step(&mut self, count: u64) -> Stop {
+        for _ in 0..count {
+            if self.machine.is_halted() {
+                return Stop::Halted;
+            }
+            if let Some(watch) = self.step_one() {
+                return watch;
+            }
+            if self.at_breakpoint() {
+                return Stop::Breakpoint(self.machine.pc);
+            }
+        }
+        Stop::Done
+    }
+
+    /// 今の命令の次のアドレスに来るまで実行する
+    pub fn step_over(&mut self) -> Stop {
+        let target = self.machine.pc.wrapping_add(1);
+        self.run_until(|machine| machine.pc == target)
+    }
+
+    pub fn cont(&mut self) -> Stop {
+        self.run_until(|_| false)
+    }
+
+    /// クロック数が`cycles`になるまで実行する
+    pub fn run_to_cycle(&mut self, cycles: u64) -> Stop {
+        if self.machine.cycles >= cycles {
+            return Stop::Done;
+        }
+        self.run_until(|machine| machine.cycles >= cycles)
+    }
+
+    /// VMコマンドの最初の命令に来て、`until`が真になるまで実行する
+    fn run_vm_until(
+        &mut self,
+        until: impl Fn(&Machine, &SourceMap) -> bool,
+    ) -> Result<Stop, String> {
+        let map = self
+            .program
+            .source_map
+            .take()
+            .ok_or("no source map is loaded (use --map)")?;
+        let stop =
+            self.run_until(|machine| vm::at_command_start(&map, machine) && until(machine, &map));
+        self.program.source_map = Some(map);
+        Ok(stop)
+    }
+
+    /// VMコマンドを`count`個実行する
+    pub fn vm_step(&mut self, count: u64) -> Result<Stop, String> {
+        for _ in 0..count {
+            let stop = self.run_vm_until(|_, _| true)?;
+            if stop != Stop::Done {
+                return Ok(stop);
+            }
+        }
+        Ok(Stop::Done)
+    }
+
+    /// VMコマンドを1つ実行する。`call`は関数から戻るまで実行する
+    pub fn vm_step_over(&mut self) -> Result<Stop, String> {
+        let depth = self.call_depth()?;
+        self.run_vm_until(|machine, map| vm::backtrace(machine, map).len() <= depth)
+    }
+
+    /// 実行中の関数から戻るまで実行する
+    pub fn vm_finish(&mut self) -> Result<Stop, String> {
+        let depth = self.call_depth()?;
+        self.run_vm_until(|machine, map| vm::backtrace(machine, map).len() < depth)
+    }
+
+    fn call_depth(&self) -> Result<usize, String> {
+        Ok(vm::backtrace(&self.machine, self.source_map()?).len())
+    }
+
+    fn source_map(&self) -> Result<&SourceMap, String> {
+        self.program
+            .source_map
+            .as_ref()
+            .ok_or_else(|| "no source map is loaded (use --map)".to_string())
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.points.push(Point::Break(address));
+    }
+
+    pub fn add_watchpoint(&mut self, address: u16) {
+        self.points.push(Point::Watch(address));
+    }
+
+    /// コマンドを標準入力などから読んで実行する
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> std::io::Result<()> {
+        let mut last = String::new();
+        self.print_location(&mut output)?;
+        write!(output, "(hdb) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            let line = if line.trim().is_empty() {
+                last.clone()
+            } else {
+                line.trim().to_string()
+            };
+            if line == "quit" || line == "q" {
+                break;
+            }
+            if let Err(message) = self.command(&line, &mut output)? {
+                writeln!(output, "{}", message)?;
+            }
+            last = line;
+            write!(output, "(hdb) ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    /// 1行のコマンドを実行する (コマンドの誤りは`Ok(Err(message))`で返す)
+    pub fn command(
+        &mut self,
+        line: &str,
+        output: &mut impl Write,
+    ) -> std::io::Result<Result<(), String>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = |n: usize| words.get(n).copied();
+        let number = |n: usize, default: u64| match arg(n) {
+            Some(text) => text
+                .parse::<u64>()
+                .map_err(|_| format!("invalid number: {}", text)),
+            None => Ok(default),
+        };
+        match arg(0).unwrap_or("") {
+            "" => {}
+            "step" | "s" => {
+                let count = match number(1, 1) {
+                    Ok(count) => count,
+                    Err(e) => return Ok(Err(e)),
+                };
+                let stop = self.step(count);
+                self.report(&stop, output)?;
+            }
+            "next" | "n" => {
+                let stop = self.step_over();
+                self.report(&stop, output)?;
+            }
+            "continue" | "c" => {
+                let stop = self.cont();
+                self.report(&stop, output)?;
+            }
+            "break" | "b" => {
+                let Some(address) = arg(1).and_then(|text| self.program.rom_address(text)) else {
+                    return Ok(Err(format!("unknown ROM address or label: {}", line)));
+                };
+                self.add_breakpoint(address);
+                writeln!(
+                    output,
+                    "breakpoint {} at {}",
+                    self.points.len(),
+                    self.rom_name(address)
+                )?;
+            }
+            "watch" | "w" => {
+                let Some(address) = arg(1).and_then(|text| self.program.ram_address(text)) else {
+                    return Ok(Err(format!("unknown RAM address or symbol: {}", line)));
+                };
+                self.add_watchpoint(address);
+                writeln!(
+                    output,
+                    "watchpoint {} at {}",
+                    self.points.len(),
+                    self.ram_name(address)
+                )?;
+            }
+            "delete" | "d" => match arg(1) {
+                None => self.points.clear(),
+                Some(_) => match number(1, 0) {
+                    Ok(n) if n >= 1 && (n as usize) <= self.points.len() => {
+                        self.points.remove(n as usize - 1);
+                    }
+                    _ => return Ok(Err(format!("no breakpoint or watchpoint {}", words[1]))),
+                },
+            },
+            "info" | "i" => {
+                for (n, point) in self.points.iter().enumerate() {
+                    match point {
+                        Point::Break(address) => {
+                            writeln!(output, "{:>3} break {}", n + 1, self.rom_name(*address))?
+                        }
+                        Point::Watch(address) => {
+                            writeln!(output, "{:>3} watch {}", n + 1, self.ram_name(*address))?
+                        }
+                    }
+                }
+            }
+            "regs" | "r" => self.print_location(output)?,
+            "x" => {
+                let Some(start) = arg(1).and_then(|text| self.program.ram_address(text)) else {
+                    return Ok(Err(format!("unknown RAM address or symbol: {}", line)));
+                };
+                let count = match number(2, 1) {
+                    Ok(count) => count,
+                    Err(e) => return Ok(Err(e)),
+                };
+                for address in (start as u64..start as u64 + count).take_while(|a| *a < 32768) {
+                    let address = address as u16;
+                    let value = self.machine.read(address);
+                    writeln!(
+                        output,
+                        "{:>12}: {:>6} (0x{:04x})",
+                        self.ram_name(address),
+                        value as i16,
+                        value
+                    )?;
+                }
+            }
+            "set" => {
+                let address = arg(1).and_then(|text| self.program.ram_address(text));
+                let value = arg(2).and_then(parse_value);
+                match (address, value) {
+                    (Some(address), Some(value)) => self.machine.write(address, value),
+                    _ => return Ok(Err(format!("usage: set <addr|symbol> <value>: {}", line))),
+                }
+            }
+            "key" => {
+                let Some(code) = arg(1).and_then(keyboard::key_code) else {
+                    return Ok(Err(format!("unknown key: {}", line)));
+                };
+                self.machine.ram[KBD] = code;
+                self.recorded.push(KeyEvent {
+                    cycle: self.machine.cycles,
+                    code,
+                });
+            }
+            "list" | "l" => {
+                let center = match arg(1) {
+                    Some(text) => match self.program.rom_address(text) {
+                        Some(address) => address,
+                        None => return Ok(Err(format!("unknown ROM address or label: {}", text))),
+                    },
+                    None => self.machine.pc,
+                };
+                let rom_len = self.machine.rom.len();
+                if center as usize >= rom_len {
+                    return Ok(Err(format!(
+                        "ROM address {} is past the end of the program ({} instructions)",
+                        center, rom_len
+                    )));
+                }
+                // u16のまま足すと65535付近であふれるのでusizeで数える
+                let start = (center as usize).saturating_sub(5);
+                for address in start..(start + 11).min(rom_len) {
+                    self.print_instruction(address as u16, output)?;
+                }
+            }
+            "vmstep" | "vs" => {
+                let count = match number(1, 1) {
+                    Ok(count) => count,
+                    Err(e) => return Ok(Err(e)),
+                };
+                match self.vm_step(count) {
+                    Ok(stop) => self.report(&stop, output)?,
+                    Err(e) => return Ok(Err(e)),
+                }
+            }
+            "vmnext" | "vn" => match self.vm_step_over() {
+                Ok(stop) => self.report(&stop, output)?,
+                Err(e) => return Ok(Err(e)),
+            },
+            "finish" => match self.vm_finish() {
+                Ok(stop) => self.report(&stop, output)?,
+                Err(e) => return Ok(Err(e)),
+            },
+            "backtrace" | "bt" => {
+                let map = match self.source_map() {
+                    Ok(map) => map,
+                    Err(e) => return Ok(Err(e)),
+                };
+                for (n, frame) in vm::backtrace(&self.machine, map).iter().enumerate() {
+                    writeln!(output, "#{:<3} {}", n, frame.describe())?;
+                }
+            }
+            "frame" | "f" => {
+                let map = match self.source_map() {
+                    Ok(map) => map,
+                    Err(e) => return Ok(Err(e)),
+                };
+                let frames = vm::backtrace(&self.machine, map);
+                let n = match number(1, 0) {
+                    Ok(n) => n as usize,
+                    Err(e) => return Ok(Err(e)),
+                };
+                let Some(frame) = frames.get(n) else {
+                    return Ok(Err(format!("no frame {}", n)));
+                };
+                let machine = &self.machine;
+                writeln!(output, "#{:<3} {}", n, frame.describe())?;
+                writeln!(
+                    output,
+                    "  argument (ARG={}): {}",
+                    frame.arg,
+                    vm::format_words(machine, frame.arg, frame.arg.wrapping_add(frame.n_args))
+                )?;
+                writeln!(
+                    output,
+                    "  local    (LCL={}): {}",
+                    frame.lcl,
+                    vm::format_words(machine, frame.lcl, frame.lcl.wrapping_add(frame.n_locals))
+                )?;
+                writeln!(
+                    output,
+                    "  stack    ({}..{}): {}",
+                    frame.stack.0,
+                    frame.stack.1,
+                    vm::format_words(machine, frame.stack.0, frame.stack.1)
+                )?;
+            }
+            "segments" => {
+                let machine = &self.machine;
+                writeln!(
+                    output,
+                    "  SP={} LCL={} ARG={}",
+                    machine.read(SP),
+                    machine.read(LCL),
+                    machine.read(ARG)
+                )?;
+                writeln!(
+                    output,
+                    "  pointer: THIS={} THAT={}",
+                    machine.read(THIS),
+                    machine.read(THAT)
+                )?;
+                writeln!(
+                    output,
+                    "  temp:    {}",
+                    vm::format_words(machine, TEMP, TEMP + 8)
+                )?;
+                let statics: Vec<String> = vm::statics(&self.program)
+                    .iter()
+                    .map(|(name, address)| format!("{}={}", name, machine.read(*address) as i16))
+                    .collect();
+                if !statics.is_empty() {
+                    writeln!(output, "  static:  {}", statics.join(" "))?;
+                }
+            }
+            "reset" => {
+                self.machine.reset();
+                self.print_location(output)?;
+            }
+            "help" | "h" => writeln!(output, "{}", HELP)?,
+            command => return Ok(Err(format!("unknown command: {} (try help)", command))),
+        }
+        Ok(Ok(()))
+    }
+
+    fn rom_name(&self, address: u16) -> String {
+        match self.program.describe(address) {
+            Some(label) => format!("{} ({})", address, label),
+            None => address.to_string(),
+        }
+    }
+
+    fn ram_name(&self, address: u16) -> String {
+        match self.program.variable_name(address) {
+            Some(name) => format!("{}({})", name, address),
+            None => address.to_string(),
+        }
+    }
+
+    fn report(&self, stop: &Stop, output: &mut impl Write) -> std::io::Result<()> {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint(address) => {
+                writeln!(output, "breakpoint at {}", self.rom_name(*address))?
+            }
+            Stop::Watchpoint(write) => writeln!(
+                output,
+                "watchpoint {}: {} -> {}",
+                self.ram_name(write.address),
+                write.old as i16,
+                write.new as i16
+            )?,
+            Stop::Halted => writeln!(output, "halted")?,
+            Stop::Limit => writeln!(output, "stopped after {} cycles", self.max_cycles)?,
+        }
+        self.print_location(output)
+    }
+
+    /// レジスタと次に実行する命令を表示する
+    pub fn print_location(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let machine = &self.machine;
+        writeln!(
+            output,
+            "PC={} A={} D={} M={} cycles={}",
+            self.rom_name(machine.pc),
+            machine.a as i16,
+            machine.d as i16,
+            machine.read(machine.a) as i16,
+            machine.cycles
+        )?;
+        if let Some(entry) = self
+            .program
+            .source_map
+            .as_ref()
+            .and_then(|map| map.lookup(machine.pc))
+        {
+            writeln!(output, "vm: {}", entry.describe())?;
+        }
+        if let Some(trap) = vm::trap(&self.program, machine) {
+            writeln!(output, "trap: {}", trap.describe(&self.program))?;
+        }
+        self.print_instruction(machine.pc, output)
+    }
+
+    fn print_instruction(&self, address: u16, output: &mut impl Write) -> std::io::Result<()> {
+        if let Some(map) = &self.program.source_map {
+            for entry in map.starting_at(address) {
+                writeln!(output, "// {}", entry.describe())?;
+            }
+        }
+        for (label, _) in self.program.labels.iter().filter(|(_, a)| *a == address) {
+            writeln!(output, "({})", label)?;
+        }
+        let marker = if address == self.machine.pc {
+            "=>"
+        } else {
+            "  "
+        };
+        let mark = if self
+            .points
+            .iter()
+            .any(|p| matches!(p, Point::Break(a) if *a == address))
+        {
+            "*"
+        } else {
+            " "
+        };
+        writeln!(
+            output,
+            "{}{}{:>5}: {}",
+            marker,
+            mark,
+            address,
+            disassemble(self.machine.instruction(address))
+        )
+    }
+}
+
+/// 10進数 (負の数も可) または`0x`で始まる16進数
+pub fn parse_value(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text
+            .parse::<i32>()
+            .ok()
+            .filter(|v| (-32768..=65535).contains(v))
+            .map(|v| v as u16),
+    }
+}
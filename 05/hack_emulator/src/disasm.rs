@@ -0,0 +1,84 @@
+use crate::program::Program;
+use std::io::{self, Write};
+
+/// compのビット(aビットを含む7ビット)とニーモニック
+const COMP: &[(u16, &str)] = &[
+    (0b0101010, "0"),
+    (0b0111111, "1"),
+    (0b0111010, "-1"),
+    (0b0001100, "D"),
+    (0b0110000, "A"),
+    (0b0001101, "!D"),
+    (0b0110001, "!A"),
+    (0b0001111, "-D"),
+    (0b0110011, "-A"),
+    (0b0011111, "D+1"),
+    (0b0110111, "A+1"),
+    (0b0001110, "D-1"),
+    (0b0110010, "A-1"),
+    (0b0000010, "D+A"),
+    (0b0010011, "D-A"),
+    (0b0000111, "A-D"),
+    (0b0000000, "D&A"),
+    (0b0010101, "D|A"),
+    (0b1110000, "M"),
+    (0b1110001, "!M"),
+    (0b1110011, "-M"),
+    (0b1110111, "M+1"),
+    (0b1110010, "M-1"),
+    (0b1000010, "D+M"),
+    (0b1010011, "D-M"),
+    (0b1000111, "M-D"),
+    (0b1000000, "D&M"),
+    (0b1010101, "D|M"),
+];
+
+const DEST: [&str; 8] = ["", "M", "D", "MD", "A", "AM", "AD", "AMD"];
+const JUMP: [&str; 8] = ["", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+/// 機械語1命令をアセンブリに戻す
+///
+/// 表にないcompは`comp?0101010`のように表示する
+pub fn disassemble(instruction: u16) -> String {
+    if instruction & 0x8000 == 0 {
+        return format!("@{}", instruction);
+    }
+    let bits = (instruction >> 6) & 0x7f;
+    let comp = match COMP.iter().find(|(code, _)| *code == bits) {
+        Some((_, mnemonic)) => mnemonic.to_string(),
+        None => format!("comp?{:07b}", bits),
+    };
+    let dest = DEST[((instruction >> 3) & 7) as usize];
+    let jump = JUMP[(instruction & 7) as usize];
+    let mut text = String::new();
+    if !dest.is_empty() {
+        text.push_str(dest);
+        text.push('=');
+    }
+    text.push_str(&comp);
+    if !jump.is_empty() {
+        text.push(';');
+        text.push_str(jump);
+    }
+    text
+}
+
+/// プログラム全体をアセンブリに戻す
+///
+/// ラベルがあれば、そのアドレスの前に`(LOOP)`のように書く
+///
+/// * `program`: - 戻すプログラム
+/// * `out`: - 出力先
+pub fn write_program<W: Write>(program: &Program, out: &mut W) -> io::Result<()> {
+    let mut labels = program.labels.iter().peekable();
+    for (address, instruction) in program.rom.iter().enumerate() {
+        while let Some((label, _)) = labels.next_if(|(_, start)| *start as usize == address) {
+            writeln!(out, "({})", label)?;
+        }
+        writeln!(out, "{}", disassemble(*instruction))?;
+    }
+    for (label, _) in labels {
+        writeln!(out, "({})", label)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,409 @@
+use crate::debugger::{Debugger, Stop};
+use crate::machine::Machine;
+use crate::program::Program;
+use crate::vm::{self, ARG, LCL, SP};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+/// ヒープの範囲 (`Memory.init`が使う範囲)
+const HEAP_START: u16 = 2048;
+const HEAP_END: u16 = 16384;
+/// ヒープの地図の1行の文字数と、1文字が表す語数
+const MAP_COLUMNS: usize = 64;
+const WORDS_PER_CHAR: usize = 16;
+/// 記録する誤りの最大数 (解放後の使用はブロックごとに最初の1回だけ記録する)
+const MAX_EVENTS: usize = 100;
+
+/// ヒープを確保・解放する関数 (`--native-os`では`Array.new`などが`Memory.alloc`を呼ばないので、
+/// それらも見る)。確保する関数は、引数に足すと確保する語数になる数
+const ALLOCATORS: [(&str, u16); 3] = [("Memory.alloc", 0), ("Array.new", 0), ("String.new", 2)];
+const DEALLOCATORS: [&str; 3] = ["Memory.deAlloc", "Array.dispose", "String.dispose"];
+
+/// 確保したブロック
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub size: u16,
+    /// 確保した場所
+    pub site: String,
+    pub cycle: u64,
+    /// OSの中だけから確保した (`Output`の文字のビットマップなど) ので、リークには数えない
+    pub os: bool,
+}
+
+/// 解放したブロック
+#[derive(Debug, Clone)]
+struct Freed {
+    block: Block,
+    /// 解放した場所
+    site: String,
+    /// 解放後の使用を報告したか
+    reported: bool,
+}
+
+/// `Memory.alloc`か`Memory.deAlloc`から戻るのを待っている呼び出し
+struct Pending {
+    return_address: u16,
+    /// 確保なら大きさと場所とOSの中だけからの確保か、解放なら`None`
+    alloc: Option<(u16, String, bool)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Routine {
+    /// 引数に足すと確保する語数になる数
+    Alloc(u16),
+    Free,
+}
+
+/// ヒープの誤った使い方
+#[derive(Debug, Clone)]
+pub enum HeapEvent {
+    /// 解放済みのブロックをもう一度解放した
+    DoubleFree {
+        address: u16,
+        site: String,
+        freed: String,
+    },
+    /// 確保していないアドレスを解放した
+    InvalidFree { address: u16, site: String },
+    /// 解放したブロックを読み書きした
+    UseAfterFree {
+        address: u16,
+        block: u16,
+        write: bool,
+        site: String,
+        freed: String,
+    },
+}
+
+/// `Memory.alloc`と`Memory.deAlloc`の呼び出しを追って、ヒープ (2048〜16383) の使い方を調べる
+///
+/// 関数の先頭は`write_function`が出力する`(Memory.alloc)`のようなラベルから探す。
+/// `Array.new`から呼ばれた`Memory.alloc`のような、確保する関数の中の呼び出しは数えない。
+/// `Sys.halt`に来たときに解放されていないブロックをリークとして報告する
+pub struct HeapInspector {
+    /// 確保・解放する関数の先頭アドレス
+    routines: HashMap<u16, Routine>,
+    halt: Option<u16>,
+    /// 確保されているブロック (アドレス順)
+    pub live: BTreeMap<u16, Block>,
+    freed: BTreeMap<u16, Freed>,
+    pending: Vec<Pending>,
+    pub events: Vec<HeapEvent>,
+    pub allocations: u64,
+    pub frees: u64,
+    /// 一番多く確保していたときの語数
+    pub peak: u32,
+    /// `Sys.halt`に来たか
+    pub halted: bool,
+}
+
+impl HeapInspector {
+    pub fn new(program: &Program) -> Result<Self, String> {
+        let mut routines = HashMap::new();
+        for (name, extra) in ALLOCATORS {
+            if let Some(address) = program.label(name) {
+                routines.insert(address, Routine::Alloc(extra));
+            }
+        }
+        for name in DEALLOCATORS {
+            if let Some(address) = program.label(name) {
+                routines.insert(address, Routine::Free);
+            }
+        }
+        if program.label("Memory.alloc").is_none() {
+            return Err("label Memory.alloc not found (translate with hack_vm)".to_string());
+        }
+        Ok(HeapInspector {
+            routines,
+            halt: program.label("Sys.halt"),
+            live: BTreeMap::new(),
+            freed: BTreeMap::new(),
+            pending: vec![],
+            events: vec![],
+            allocations: 0,
+            frees: 0,
+            peak: 0,
+            halted: false,
+        })
+    }
+
+    /// `Sys.halt`に来るか止まるまで(最大`max_cycles`クロック)実行して調べる
+    pub fn run(&mut self, debugger: &mut Debugger, max_cycles: u64) -> Stop {
+        for _ in 0..max_cycles {
+            let machine = &debugger.machine;
+            let pc = machine.pc;
+            // `Sys.halt`の先頭は止まるループと同じアドレスなので先に調べる
+            if Some(pc) == self.halt {
+                self.halted = true;
+                return Stop::Halted;
+            }
+            if machine.is_halted() {
+                return Stop::Halted;
+            }
+            match (self.pending.is_empty(), self.routines.get(&pc)) {
+                (true, Some(Routine::Alloc(extra))) => {
+                    let size = machine.read(machine.read(ARG)).wrapping_add(*extra);
+                    let (site, os) = call_site(&debugger.program, machine);
+                    self.pending.push(Pending {
+                        return_address: return_address(machine),
+                        alloc: Some((size, site, os)),
+                    });
+                }
+                (true, Some(Routine::Free)) => {
+                    let address = machine.read(machine.read(ARG));
+                    let (site, _) = call_site(&debugger.program, machine);
+                    self.free(address, site);
+                    self.pending.push(Pending {
+                        return_address: return_address(machine),
+                        alloc: None,
+                    });
+                }
+                // OSの中はブロックのヘッダを読み書きするので調べない
+                (true, None) => self.check_access(&debugger.program, machine),
+                (false, _) => {}
+            }
+            if let Stop::Watchpoint(_) | Stop::Breakpoint(_) = debugger.step(1) {
+                return Stop::Done;
+            }
+            let machine = &debugger.machine;
+            if self
+                .pending
+                .last()
+                .is_some_and(|pending| pending.return_address == machine.pc)
+            {
+                let pending = self.pending.pop().unwrap();
+                if let Some((size, site, os)) = pending.alloc {
+                    let address = machine.read(machine.read(SP).wrapping_sub(1));
+                    let block = Block {
+                        size,
+                        site,
+                        cycle: machine.cycles,
+                        os,
+                    };
+                    self.allocate(address, block);
+                }
+            }
+        }
+        Stop::Limit
+    }
+
+    fn allocate(&mut self, address: u16, block: Block) {
+        self.allocations += 1;
+        // 解放したブロックと重なる部分は使い直された
+        let end = address.saturating_add(block.size);
+        self.freed.retain(|start, freed| {
+            *start >= end || start.saturating_add(freed.block.size) <= address
+        });
+        self.live.insert(address, block);
+        let words: u32 = self.live.values().map(|block| block.size as u32).sum();
+        self.peak = self.peak.max(words);
+    }
+
+    fn free(&mut self, address: u16, site: String) {
+        self.frees += 1;
+        if let Some(block) = self.live.remove(&address) {
+            self.freed.insert(
+                address,
+                Freed {
+                    block,
+                    site,
+                    reported: false,
+                },
+            );
+        } else if let Some(freed) = self.freed.get(&address) {
+            let event = HeapEvent::DoubleFree {
+                address,
+                site,
+                freed: freed.site.clone(),
+            };
+            self.push_event(event);
+        } else {
+            self.push_event(HeapEvent::InvalidFree { address, site });
+        }
+    }
+
+    /// 次の命令が解放したブロックを読み書きするか調べる
+    fn check_access(&mut self, program: &Program, machine: &Machine) {
+        let instruction = machine.instruction(machine.pc);
+        if instruction & 0x8000 == 0 {
+            return;
+        }
+        let reads = instruction & 0x1000 != 0;
+        let write = instruction & 0x0008 != 0;
+        let address = machine.a;
+        if !(reads || write) || !(HEAP_START..HEAP_END).contains(&address) {
+            return;
+        }
+        let Some((&block, freed)) = self.freed.range_mut(..=address).next_back() else {
+            return;
+        };
+        if address >= block.saturating_add(freed.block.size) || freed.reported {
+            return;
+        }
+        freed.reported = true;
+        let event = HeapEvent::UseAfterFree {
+            address,
+            block,
+            write,
+            site: location(program, machine.pc),
+            freed: freed.site.clone(),
+        };
+        self.push_event(event);
+    }
+
+    fn push_event(&mut self, event: HeapEvent) {
+        if self.events.len() < MAX_EVENTS {
+            self.events.push(event);
+        }
+    }
+
+    /// `Sys.halt`に来たときに解放されていない、OSの外から確保したブロック
+    pub fn leaks(&self) -> impl Iterator<Item = (&u16, &Block)> {
+        self.live
+            .iter()
+            .filter(|(_, block)| self.halted && !block.os)
+    }
+
+    /// 誤り、リーク、ヒープの地図を表示する
+    pub fn report(&self, output: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            output,
+            "heap: {} allocations, {} frees, peak {} words",
+            self.allocations, self.frees, self.peak
+        )?;
+
+        writeln!(output)?;
+        writeln!(output, "errors: {}", self.events.len())?;
+        for event in self.events.iter() {
+            match event {
+                HeapEvent::DoubleFree {
+                    address,
+                    site,
+                    freed,
+                } => writeln!(
+                    output,
+                    "  double free of {} at {} (freed at {})",
+                    address, site, freed
+                )?,
+                HeapEvent::InvalidFree { address, site } => writeln!(
+                    output,
+                    "  free of unallocated address {} at {}",
+                    address, site
+                )?,
+                HeapEvent::UseAfterFree {
+                    address,
+                    block,
+                    write,
+                    site,
+                    freed,
+                } => writeln!(
+                    output,
+                    "  use after free: {} {} (block {}) at {} (freed at {})",
+                    if *write { "write" } else { "read" },
+                    address,
+                    block,
+                    site,
+                    freed
+                )?,
+            }
+        }
+
+        writeln!(output)?;
+        let words = |blocks: &[(&u16, &Block)]| -> u32 {
+            blocks.iter().map(|(_, block)| block.size as u32).sum()
+        };
+        let (os, user): (Vec<_>, Vec<_>) = self.live.iter().partition(|(_, block)| block.os);
+        let title = if self.halted {
+            "leaks at Sys.halt"
+        } else {
+            "live blocks when stopped"
+        };
+        writeln!(
+            output,
+            "{}: {} blocks ({} words), not counting {} blocks ({} words) the OS keeps",
+            title,
+            user.len(),
+            words(&user),
+            os.len(),
+            words(&os)
+        )?;
+        for (address, block) in user.iter() {
+            writeln!(
+                output,
+                "  {:>5} {:>5} words  cycle {:>10}  {}",
+                address, block.size, block.cycle, block.site
+            )?;
+        }
+
+        writeln!(output)?;
+        writeln!(
+            output,
+            "heap map ({}-{}, 1 char = {} words, '#' live, 'x' freed, '.' unused)",
+            HEAP_START,
+            HEAP_END - 1,
+            WORDS_PER_CHAR
+        )?;
+        let mut map = vec!['.'; (HEAP_END - HEAP_START) as usize / WORDS_PER_CHAR];
+        let mut mark = |start: u16, size: u16, c: char| {
+            let end = start.saturating_add(size.max(1)).min(HEAP_END);
+            for address in start.max(HEAP_START)..end {
+                let cell = &mut map[(address - HEAP_START) as usize / WORDS_PER_CHAR];
+                if *cell != '#' {
+                    *cell = c;
+                }
+            }
+        };
+        for (address, freed) in self.freed.iter() {
+            mark(*address, freed.block.size, 'x');
+        }
+        for (address, block) in self.live.iter() {
+            mark(*address, block.size, '#');
+        }
+        for (row, chunk) in map.chunks(MAP_COLUMNS).enumerate() {
+            let start = HEAP_START as usize + row * MAP_COLUMNS * WORDS_PER_CHAR;
+            writeln!(output, "{:>5} {}", start, chunk.iter().collect::<String>())?;
+        }
+        Ok(())
+    }
+}
+
+/// 呼ばれた関数の先頭で、`call`が積んだリターンアドレス (LCL-5)
+fn return_address(machine: &Machine) -> u16 {
+    machine.read(machine.read(LCL).wrapping_sub(5))
+}
+
+/// OSの外で`Memory.alloc`などを呼んだ場所 (`String.new`から呼ばれたら`String.new`を呼んだ場所) と、
+/// OSの中だけからの呼び出しか
+fn call_site(program: &Program, machine: &Machine) -> (String, bool) {
+    let Some(map) = &program.source_map else {
+        return (
+            location(program, return_address(machine).wrapping_sub(1)),
+            false,
+        );
+    };
+    let frames = vm::backtrace(machine, map);
+    let callers: Vec<_> = frames
+        .iter()
+        .skip(1)
+        .filter_map(|frame| frame.location.as_ref())
+        .filter(|entry| !entry.function.is_empty())
+        .collect();
+    match callers.iter().find(|entry| !entry.file.starts_with("(os)")) {
+        Some(entry) => (entry.describe(), false),
+        None => match callers.first() {
+            Some(entry) => (entry.describe(), true),
+            None => ("(unknown)".to_string(), false),
+        },
+    }
+}
+
+/// ROMアドレスのVMコマンドかラベル
+fn location(program: &Program, address: u16) -> String {
+    program
+        .source_map
+        .as_ref()
+        .and_then(|map| map.lookup(address))
+        .map(|entry| entry.describe())
+        .or_else(|| program.describe(address))
+        .unwrap_or_else(|| format!("ROM[{}]", address))
+}
@@ -0,0 +1,144 @@
+use crate::machine::{Machine, KBD};
+use std::fs;
+
+/// 名前のあるキーとキーコード
+const KEYS: &[(&str, u16)] = &[
+    ("SPACE", 32),
+    ("NEWLINE", 128),
+    ("ENTER", 128),
+    ("BACKSPACE", 129),
+    ("LEFT", 130),
+    ("UP", 131),
+    ("RIGHT", 132),
+    ("DOWN", 133),
+    ("HOME", 134),
+    ("END", 135),
+    ("PAGEUP", 136),
+    ("PAGEDOWN", 137),
+    ("INSERT", 138),
+    ("DELETE", 139),
+    ("ESC", 140),
+    ("F1", 141),
+    ("F2", 142),
+    ("F3", 143),
+    ("F4", 144),
+    ("F5", 145),
+    ("F6", 146),
+    ("F7", 147),
+    ("F8", 148),
+    ("F9", 149),
+    ("F10", 150),
+    ("F11", 151),
+    ("F12", 152),
+];
+
+/// `cycle`クロック目からキーボードの値を`code`にする (0は離す)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub cycle: u64,
+    pub code: u16,
+}
+
+/// `LEFT`, `'a'`, `65`, `release`のような指定をキーコードにする
+pub fn key_code(text: &str) -> Option<u16> {
+    if text.eq_ignore_ascii_case("release") {
+        return Some(0);
+    }
+    if let Some(c) = text
+        .strip_prefix('\'')
+        .and_then(|text| text.strip_suffix('\''))
+    {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii() => Some(c as u16),
+            _ => None,
+        };
+    }
+    if let Ok(code) = text.parse::<u16>() {
+        return Some(code);
+    }
+    KEYS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(text))
+        .map(|(_, code)| *code)
+}
+
+/// キーコードをスクリプトに書く形にする
+pub fn key_name(code: u16) -> String {
+    match code {
+        0 => "release".to_string(),
+        33..=126 => format!("'{}'", code as u8 as char),
+        _ => match KEYS.iter().find(|(_, c)| *c == code) {
+            Some((name, _)) => name.to_string(),
+            None => code.to_string(),
+        },
+    }
+}
+
+/// クロック数を指定してキーボードを操作するスクリプト
+///
+/// 1行に`<cycle> <key> [<duration>]`と書く。`duration`を書くとその間だけ押し、
+/// 省略すると次の操作まで押したままにする。`#`から行末まではコメント
+#[derive(Debug, Clone, Default)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>,
+    next: usize,
+}
+
+impl KeyScript {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut events = vec![];
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid key event: {}", lineno + 1, line.trim());
+            if words.len() < 2 || words.len() > 3 {
+                return Err(invalid());
+            }
+            let cycle: u64 = words[0].parse().map_err(|_| invalid())?;
+            let code = key_code(words[1]).ok_or_else(invalid)?;
+            events.push(KeyEvent { cycle, code });
+            if let Some(duration) = words.get(2) {
+                let duration: u64 = duration.parse().map_err(|_| invalid())?;
+                events.push(KeyEvent {
+                    cycle: cycle + duration,
+                    code: 0,
+                });
+            }
+        }
+        // 同じクロックでは後に書いたものを優先する
+        events.sort_by_key(|event| event.cycle);
+        Ok(KeyScript { events, next: 0 })
+    }
+
+    /// 次にキーボードが変わるクロック数
+    pub fn next_cycle(&self) -> Option<u64> {
+        self.events.get(self.next).map(|event| event.cycle)
+    }
+
+    /// 今のクロック数までの操作をキーボードに反映する
+    pub fn apply(&mut self, machine: &mut Machine) {
+        while let Some(event) = self.events.get(self.next) {
+            if event.cycle > machine.cycles {
+                break;
+            }
+            machine.ram[KBD] = event.code;
+            self.next += 1;
+        }
+    }
+}
+
+/// 記録したキー操作をスクリプトの形にする
+pub fn format_events(events: &[KeyEvent]) -> String {
+    events
+        .iter()
+        .map(|event| format!("{} {}\n", event.cycle, key_name(event.code)))
+        .collect()
+}
@@ -0,0 +1,13 @@
+pub mod debugger;
+pub mod disasm;
+pub mod heap;
+pub mod keyboard;
+pub mod machine;
+pub mod native;
+pub mod profile;
+pub mod program;
+pub mod runner;
+pub mod screen;
+pub mod script;
+pub mod source_map;
+pub mod vm;
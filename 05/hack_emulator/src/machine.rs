@@ -0,0 +1,143 @@
+/// RAMの大きさ (Aレジスタで指定できる15ビット分)
+pub const RAM_SIZE: usize = 32768;
+/// スクリーンの先頭アドレス
+pub const SCREEN: usize = 16384;
+/// キーボードのアドレス
+pub const KBD: usize = 24576;
+
+/// 1命令の実行でRAMに書き込んだ内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// Hackコンピュータ (1クロックで1命令を実行する)
+#[derive(Debug, Clone)]
+pub struct Machine {
+    pub rom: Vec<u16>,
+    pub ram: Vec<u16>,
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    /// 実行したクロック数
+    pub cycles: u64,
+}
+
+impl Machine {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Machine {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+        }
+    }
+
+    /// resetを1にしたときと同じ (PCだけを0にする)
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.cycles = 0;
+    }
+
+    pub fn instruction(&self, address: u16) -> u16 {
+        self.rom.get(address as usize).copied().unwrap_or(0)
+    }
+
+    pub fn read(&self, address: u16) -> u16 {
+        self.ram.get(address as usize).copied().unwrap_or(0)
+    }
+
+    /// RAMに書き込む (キーボードより後ろのアドレスには書き込めない)
+    pub fn write(&mut self, address: u16, value: u16) {
+        if (address as usize) < KBD {
+            self.ram[address as usize] = value;
+        }
+    }
+
+    /// 1命令を実行する
+    pub fn step(&mut self) -> Option<MemoryWrite> {
+        let instruction = self.instruction(self.pc);
+        self.cycles += 1;
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            self.pc = self.pc.wrapping_add(1);
+            return None;
+        }
+
+        let address = self.a;
+        let y = if instruction & 0x1000 != 0 {
+            self.read(address)
+        } else {
+            self.a
+        };
+        let out = alu(self.d, y, (instruction >> 6) & 0x3f);
+
+        let mut write = None;
+        if instruction & 0x0008 != 0 {
+            let old = self.read(address);
+            self.write(address, out);
+            write = Some(MemoryWrite {
+                address,
+                old,
+                new: self.read(address),
+            });
+        }
+        if instruction & 0x0020 != 0 {
+            self.a = out;
+        }
+        if instruction & 0x0010 != 0 {
+            self.d = out;
+        }
+
+        let negative = out & 0x8000 != 0;
+        let zero = out == 0;
+        let jump = (instruction & 0b100 != 0 && negative)
+            || (instruction & 0b010 != 0 && zero)
+            || (instruction & 0b001 != 0 && !negative && !zero);
+        self.pc = if jump {
+            address
+        } else {
+            self.pc.wrapping_add(1)
+        };
+        write
+    }
+
+    /// `(END) @END 0;JMP`のような無限ループで止まっているか
+    pub fn is_halted(&self) -> bool {
+        let pc = self.pc;
+        if pc as usize >= self.rom.len() {
+            return true;
+        }
+        let at = |address: u16| self.instruction(address);
+        (at(pc) == pc && is_unconditional_jump(at(pc.wrapping_add(1))))
+            || (pc > 0 && is_unconditional_jump(at(pc)) && self.a == pc - 1 && at(pc - 1) == pc - 1)
+    }
+}
+
+/// 書き込み先がなく、必ずジャンプする命令 (`0;JMP`)
+fn is_unconditional_jump(instruction: u16) -> bool {
+    instruction & 0xe000 == 0xe000 && instruction & 0x0038 == 0 && instruction & 0x0007 == 0x0007
+}
+
+/// ALU (`control`はzx, nx, zy, ny, f, noの6ビット)
+pub fn alu(x: u16, y: u16, control: u16) -> u16 {
+    let bit = |n: u16| control & (1 << (5 - n)) != 0;
+    let mut x = if bit(0) { 0 } else { x };
+    if bit(1) {
+        x = !x;
+    }
+    let mut y = if bit(2) { 0 } else { y };
+    if bit(3) {
+        y = !y;
+    }
+    let out = if bit(4) { x.wrapping_add(y) } else { x & y };
+    if bit(5) {
+        !out
+    } else {
+        out
+    }
+}
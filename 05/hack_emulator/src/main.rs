@@ -0,0 +1,246 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+use hack_assembler::logger;
+
+use hack_emulator::debugger::{self, Debugger, Stop};
+use hack_emulator::disasm;
+use hack_emulator::heap::HeapInspector;
+use hack_emulator::keyboard::{self, KeyScript};
+use hack_emulator::native::NativeHook;
+use hack_emulator::profile::Profiler;
+use hack_emulator::program::Program;
+use hack_emulator::runner::Runner;
+use hack_emulator::screen::GifRecorder;
+use hack_emulator::script::ScriptRunner;
+use hack_emulator::source_map::SourceMap;
+use hack_emulator::vm;
+
+const USAGE: &str = "usage:
+    hack_emulator asm [-o <FILE>] <FILE.asm | ->
+    hack_emulator vm [<options>] <FILE.vm | DIRECTORY | ->...
+    hack_emulator disasm <PROGRAM.asm | PROGRAM.hack> [-o <FILE>]
+    hack_emulator run <PROGRAM.asm | PROGRAM.hack> [--map <source.map>] [--max-cycles <n>] [--set <addr>=<value>]...
+        [--keys <script>] [--screen [<cycle>:]<out.png | out.ppm>]...
+        [--gif <out.gif> [--gif-interval <cycles>]] [--native-os]
+    hack_emulator debug <PROGRAM.asm | PROGRAM.hack> [--map <source.map>] [--set <addr>=<value>]...
+        [--keys <script>] [--record <script>] [--native-os]
+    hack_emulator profile <PROGRAM.asm | PROGRAM.hack> [--map <source.map>] [--max-cycles <n>]
+        [--set <addr>=<value>]... [--keys <script>] [--top <n>] [--folded <out.folded>] [--native-os]
+    hack_emulator heap <PROGRAM.asm | PROGRAM.hack> [--map <source.map>] [--max-cycles <n>]
+        [--set <addr>=<value>]... [--keys <script>] [--native-os]
+    hack_emulator test <SCRIPT.tst> [--load <PROGRAM.asm | PROGRAM.hack>]
+    hack_emulator -h | --help | -V | --version
+
+`asm` and `vm` take the same options as hack_assembler and hack_vm
+(`hack_emulator vm --help`). Every command also takes:
+  -q, --quiet     only print warnings and errors
+  -v, --verbose   print more about what the command does (-vv for more)";
+
+fn main() {
+    let (level, args) = logger::verbosity(env::args().skip(1));
+    logger::init(level);
+    if let Err(e) = run(args) {
+        log::error!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// * `args`: - プログラム名と`-q`/`-v`を除いたコマンドライン引数
+fn run(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(command) = args.first().map(String::as_str) else {
+        return Err(USAGE.into());
+    };
+    // アセンブラとVMトランスレータは、それぞれのコマンドと同じ引数を取る
+    match command {
+        "-h" | "--help" | "help" => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        "-V" | "--version" => {
+            println!("hack_emulator {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        "asm" => return hack_assembler::cli::main(args[1..].to_vec()),
+        "vm" => return hack_vm::cli::main(args[1..].to_vec()),
+        _ => {}
+    }
+    if args[1..].iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if args.len() < 2 {
+        return Err(USAGE.into());
+    }
+    let src = args[1].to_owned();
+
+    if command == "disasm" {
+        let output = match &args[2..] {
+            [] => None,
+            [option, path] if option == "-o" || option == "--output" => Some(path.to_owned()),
+            _ => return Err(USAGE.into()),
+        };
+        let program = Program::load(&src)?;
+        let mut out: BufWriter<Box<dyn Write>> = match output.as_deref() {
+            None | Some("-") => BufWriter::new(Box::new(io::stdout().lock())),
+            Some(path) => BufWriter::new(Box::new(
+                fs::File::create(path).map_err(|e| format!("{}: {}", path, e))?,
+            )),
+        };
+        disasm::write_program(&program, &mut out)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    if command == "test" {
+        // CPUEmulator用のテストスクリプトは`--load`しか取らない
+        let program = match &args[2..] {
+            [] => None,
+            [option, path] if option == "--load" => Some(path.to_owned()),
+            _ => return Err(USAGE.into()),
+        };
+        if !src.ends_with(".tst") {
+            return Err(format!("This file is not a test script: {}", src).into());
+        }
+        let mut runner = ScriptRunner::new(program);
+        runner.run(Path::new(&src))?;
+        println!("End of script - Comparison ended successfully");
+        return Ok(());
+    }
+
+    let program = Program::load(&src)?;
+    let mut debugger = Debugger::new(program);
+    let mut screenshots: Vec<(Option<u64>, String)> = vec![];
+    let mut gif: Option<String> = None;
+    let mut gif_interval: u64 = 100_000;
+    let mut record: Option<String> = None;
+    let mut top: usize = 20;
+    let mut folded: Option<String> = None;
+    let mut native_os = false;
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().cloned().ok_or(USAGE);
+        match arg.as_str() {
+            "--max-cycles" => {
+                let text = value()?;
+                debugger.max_cycles = text
+                    .parse()
+                    .map_err(|_| format!("invalid number: {}", text))?;
+            }
+            "--set" => {
+                // `SP=256`, `RAM[0]=256`
+                let text = value()?;
+                let (address, value) = text
+                    .split_once('=')
+                    .and_then(|(address, value)| {
+                        Some((
+                            debugger.program.ram_address(address)?,
+                            debugger::parse_value(value)?,
+                        ))
+                    })
+                    .ok_or_else(|| format!("invalid --set: {}", text))?;
+                debugger.machine.write(address, value);
+            }
+            "--screen" => {
+                // `rect.png` (止まったとき), `100000:frame.png`
+                let text = value()?;
+                match text.split_once(':') {
+                    Some((cycle, path)) if cycle.chars().all(|c| c.is_ascii_digit()) => {
+                        screenshots.push((Some(cycle.parse()?), path.to_string()))
+                    }
+                    _ => screenshots.push((None, text)),
+                }
+            }
+            "--map" => debugger.program.source_map = Some(SourceMap::load(Path::new(&value()?))?),
+            "--keys" => debugger.keys = KeyScript::load(&value()?)?,
+            "--record" => record = Some(value()?),
+            "--gif" => gif = Some(value()?),
+            "--folded" => folded = Some(value()?),
+            "--native-os" => native_os = true,
+            "--top" => {
+                let text = value()?;
+                top = text
+                    .parse()
+                    .map_err(|_| format!("invalid number: {}", text))?;
+            }
+            "--gif-interval" => {
+                let text = value()?;
+                gif_interval = text
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("invalid number: {}", text))?;
+            }
+            _ => return Err(format!("unknown option: {}\n{}", arg, USAGE).into()),
+        }
+    }
+
+    // ソースマップは`--map`の後で読み込まれていればよい
+    if native_os {
+        debugger.native = Some(NativeHook::new(&debugger.program)?);
+    }
+
+    match command {
+        "run" => {
+            let mut runner = Runner::new(debugger);
+            runner.screenshots = screenshots;
+            if let Some(path) = gif {
+                runner.gif = Some((GifRecorder::new(&path, 10)?, gif_interval));
+            }
+            let stop = runner.run()?;
+            if stop == Stop::Limit {
+                log::warn!("stopped after {} cycles", runner.max_cycles);
+            }
+            runner.debugger.print_location(&mut io::stdout())?;
+            // `hack_vm --checked`のトラップで止まったら失敗にする
+            if let Some(trap) = vm::trap(&runner.debugger.program, &runner.debugger.machine) {
+                return Err(format!(
+                    "stopped by a trap: {}",
+                    trap.describe(&runner.debugger.program)
+                )
+                .into());
+            }
+        }
+        "profile" => {
+            let mut profiler = Profiler::new(&debugger.program);
+            let max_cycles = debugger.max_cycles;
+            if profiler.run(&mut debugger, max_cycles) == Stop::Limit {
+                log::warn!("stopped after {} cycles", max_cycles);
+            }
+            profiler.report(&debugger.program, top, &mut io::stdout())?;
+            if let Some(path) = folded {
+                profiler.write_folded(&path)?;
+            }
+        }
+        "heap" => {
+            let mut inspector = HeapInspector::new(&debugger.program)?;
+            let max_cycles = debugger.max_cycles;
+            if inspector.run(&mut debugger, max_cycles) == Stop::Limit {
+                log::warn!("stopped after {} cycles", max_cycles);
+            }
+            inspector.report(&mut io::stdout())?;
+            // 誤りやリークがあれば失敗にする
+            let leaks = inspector.leaks().count();
+            if !inspector.events.is_empty() || leaks > 0 {
+                return Err(format!(
+                    "{} heap error(s) and {} leaked block(s)",
+                    inspector.events.len(),
+                    leaks
+                )
+                .into());
+            }
+        }
+        "debug" => {
+            debugger.repl(io::stdin().lock(), io::stdout())?;
+            if let Some(path) = record {
+                fs::write(&path, keyboard::format_events(&debugger.recorded))
+                    .map_err(|e| format!("{}: {}", path, e))?;
+            }
+        }
+        _ => return Err(format!("unknown command: {}\n{}", command, USAGE).into()),
+    }
+    Ok(())
+}
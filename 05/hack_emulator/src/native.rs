@@ -0,0 +1,119 @@
+use crate::machine::Machine;
+use crate::program::Program;
+use crate::vm::{ARG, LCL, SP, THAT, THIS};
+use hack_vm::native::{Memory, NativeOs, Outcome};
+use std::collections::HashMap;
+
+/// `call`がスタックに積むリターンアドレスとLCL, ARG, THIS, THATの数
+const FRAME_SIZE: u16 = 5;
+
+impl Memory for Machine {
+    fn read(&self, address: u16) -> u16 {
+        Machine::read(self, address)
+    }
+
+    fn write(&mut self, address: u16, value: u16) {
+        Machine::write(self, address, value)
+    }
+}
+
+/// `hack_vm`が組み込んだJack OSの関数を、先頭に来たところでRustで実行して呼び出し元に戻す
+///
+/// 関数の先頭はソースマップの`(os)/`のファイルの`function`コマンドから探すので、
+/// 自分で書いたOSのクラスはそのまま実行する。`Memory`を自分で書いたときは、
+/// `Array.new`などネイティブのヒープを使う関数もそのまま実行する
+pub struct NativeHook {
+    os: NativeOs,
+    /// 関数の先頭アドレスと関数名
+    entries: HashMap<u16, String>,
+    /// `Sys.error`の先頭アドレス
+    sys_error: Option<u16>,
+    /// ネイティブで実行した呼び出しの数
+    pub calls: u64,
+}
+
+impl NativeHook {
+    pub fn new(program: &Program) -> Result<Self, String> {
+        let map = program
+            .source_map
+            .as_ref()
+            .ok_or("--native-os needs a source map from hack_vm (use --map)")?;
+        // `(os)/`の外で定義された`Memory`の関数があるか
+        let user_memory = map.entries.iter().any(|entry| {
+            !entry.file.starts_with("(os)") && entry.command.starts_with("function Memory.")
+        });
+        let mut entries = HashMap::new();
+        let mut sys_error = None;
+        for entry in map
+            .entries
+            .iter()
+            .filter(|entry| entry.file.starts_with("(os)"))
+        {
+            let mut words = entry.command.split_whitespace();
+            let (Some("function"), Some(name)) = (words.next(), words.next()) else {
+                continue;
+            };
+            if name == "Sys.error" {
+                sys_error = Some(entry.rom_address);
+            }
+            if NativeOs::is_builtin(name) && !(user_memory && NativeOs::uses_heap(name)) {
+                entries.insert(entry.rom_address, name.to_string());
+            }
+        }
+        Ok(NativeHook {
+            os: NativeOs::new(),
+            entries,
+            sys_error,
+            calls: 0,
+        })
+    }
+
+    /// PCがOSの関数の先頭なら、関数を実行して`return`したのと同じ状態にする (1クロックと数える)
+    ///
+    /// 誤りのときは、呼び出し元が代わりに`Sys.error`を呼んだのと同じ状態にする
+    pub fn apply(&mut self, machine: &mut Machine) -> bool {
+        let Some(name) = self.entries.get(&machine.pc) else {
+            return false;
+        };
+        let lcl = machine.read(LCL);
+        let arg = machine.read(ARG);
+        let n_args = lcl.wrapping_sub(FRAME_SIZE).wrapping_sub(arg);
+        let args: Vec<u16> = (0..n_args)
+            .map(|i| machine.read(arg.wrapping_add(i)))
+            .collect();
+        let Some(outcome) = self.os.call(name, &args, machine) else {
+            return false;
+        };
+        self.calls += 1;
+        machine.cycles += 1;
+        match outcome {
+            Outcome::Return(value) => {
+                // 引数がないときは戻り値でリターンアドレスを上書きするので先に読む
+                let return_address = machine.read(lcl.wrapping_sub(FRAME_SIZE));
+                machine.write(arg, value);
+                machine.write(SP, arg.wrapping_add(1));
+                for (register, offset) in [(THAT, 1), (THIS, 2), (ARG, 3), (LCL, 4)] {
+                    let saved = machine.read(lcl.wrapping_sub(offset));
+                    machine.write(register, saved);
+                }
+                machine.pc = return_address;
+            }
+            Outcome::Error(code) => {
+                // Sys.errorは戻らないので、元の呼び出しのフレームをそのまま使う
+                let Some(sys_error) = self.sys_error else {
+                    return false;
+                };
+                machine.write(arg, code);
+                for i in 0..FRAME_SIZE {
+                    let saved = machine.read(lcl.wrapping_sub(FRAME_SIZE).wrapping_add(i));
+                    machine.write(arg.wrapping_add(1).wrapping_add(i), saved);
+                }
+                let frame_end = arg.wrapping_add(1).wrapping_add(FRAME_SIZE);
+                machine.write(LCL, frame_end);
+                machine.write(SP, frame_end);
+                machine.pc = sys_error;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,343 @@
+use crate::debugger::{Debugger, Stop};
+use crate::program::Program;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+
+/// 関数の外で実行した命令をまとめる名前
+const TOP: &str = "(top)";
+
+/// 関数ごとの集計
+#[derive(Debug, Clone, Default)]
+pub struct FunctionStats {
+    /// 関数の中の命令を実行した回数
+    pub exclusive: u64,
+    /// 関数が呼ばれてから戻るまでのクロック数 (再帰呼び出しは一番外側だけを数える)
+    pub inclusive: u64,
+    pub calls: u64,
+}
+
+struct Call {
+    function: String,
+    return_address: u16,
+    start: u64,
+}
+
+/// 命令ごとの実行回数を数えて、ラベルとVMの関数ごとに集計する
+pub struct Profiler {
+    /// ROMアドレスごとの実行回数
+    pub counts: Vec<u64>,
+    /// 関数の先頭アドレスと関数名
+    entries: HashMap<u16, String>,
+    /// ROMアドレスを含む関数 (アドレス順の先頭アドレスと関数名)
+    ranges: Vec<(u16, String)>,
+    stack: Vec<Call>,
+    pub functions: HashMap<String, FunctionStats>,
+    /// 呼び出しの列 (`Sys.init;Main.main`) ごとのクロック数
+    pub folded: Vec<(String, u64)>,
+    folded_index: HashMap<String, usize>,
+    /// 今の呼び出しの列の`folded`での位置
+    current: usize,
+}
+
+impl Profiler {
+    /// 関数の先頭はソースマップの`function`コマンドから、なければ`(Xxx.f)`というラベルから探す
+    pub fn new(program: &Program) -> Self {
+        let mut ranges: Vec<(u16, String)> = match &program.source_map {
+            Some(map) => map
+                .entries
+                .iter()
+                .filter_map(|entry| {
+                    let mut words = entry.command.split_whitespace();
+                    match (words.next(), words.next()) {
+                        (Some("function"), Some(name)) => {
+                            Some((entry.rom_address, name.to_string()))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect(),
+            None => program
+                .labels
+                .iter()
+                .filter(|(label, _)| is_function_label(label))
+                .map(|(label, address)| (*address, label.clone()))
+                .collect(),
+        };
+        let entries: HashMap<u16, String> = ranges.iter().cloned().collect();
+        // `(bootstrap)`や`(call)`のような関数の外のルーチンも区切りにして、直前の関数の時間に
+        // 数えないようにする (呼び出しには数えない)
+        if let Some(map) = &program.source_map {
+            ranges.extend(
+                map.entries
+                    .iter()
+                    .filter(|entry| entry.function.is_empty() && entry.file.starts_with('('))
+                    .map(|entry| (entry.rom_address, entry.file.clone())),
+            );
+        }
+        ranges.sort_by_key(|(address, _)| *address);
+        ranges.dedup_by(|next, previous| next.1 == previous.1);
+        Profiler {
+            counts: vec![0; program.rom.len()],
+            entries,
+            ranges,
+            stack: vec![],
+            functions: HashMap::new(),
+            folded: vec![],
+            folded_index: HashMap::new(),
+            current: 0,
+        }
+    }
+
+    /// ROMアドレスを含む関数
+    pub fn function_at(&self, address: u16) -> &str {
+        let index = self.ranges.partition_point(|(start, _)| *start <= address);
+        match index {
+            0 => TOP,
+            _ => self.ranges[index - 1].1.as_str(),
+        }
+    }
+
+    /// 止まるまで(最大`max_cycles`クロック)実行して数える
+    pub fn run(&mut self, debugger: &mut Debugger, max_cycles: u64) -> Stop {
+        // 最初に実行している関数 (テストスクリプトのように関数の途中から始めることもある)
+        // (呼び出されたわけではないので呼び出し回数には数えない)
+        // `(top)`や`(bootstrap)`は関数ではないので積まない
+        let function = self.function_at(debugger.machine.pc).to_string();
+        if !function.starts_with('(') {
+            self.stack.push(Call {
+                function,
+                return_address: u16::MAX,
+                start: debugger.machine.cycles,
+            });
+        }
+        self.update_key();
+        let mut stop = Stop::Limit;
+        for _ in 0..max_cycles {
+            let machine = &debugger.machine;
+            if machine.is_halted() {
+                stop = Stop::Halted;
+                break;
+            }
+            let pc = machine.pc;
+            let a = machine.a;
+            let jump = machine.instruction(pc) & 0x8007 > 0x8000;
+            if let Some(count) = self.counts.get_mut(pc as usize) {
+                *count += 1;
+            }
+            self.folded[self.current].1 += 1;
+            if let Stop::Watchpoint(_) | Stop::Breakpoint(_) = debugger.step(1) {
+                stop = Stop::Done;
+                break;
+            }
+            let machine = &debugger.machine;
+            // ジャンプ先が関数の先頭なら呼び出し、呼び出し元のリターンアドレスに来たら戻り
+            // (`--native-os`で実行した関数はジャンプせずに戻る)
+            let entry = (jump && machine.pc == a)
+                .then(|| self.entries.get(&machine.pc).cloned())
+                .flatten();
+            if let Some(function) = entry {
+                // `call`はLCLをSPにしてから飛ぶので、リターンアドレスはLCL-5にある
+                let lcl = machine.read(1);
+                let return_address = machine.read(lcl.wrapping_sub(5));
+                self.push(function, return_address, machine.cycles);
+            } else if self
+                .stack
+                .last()
+                .is_some_and(|call| call.return_address == machine.pc)
+            {
+                self.pop(machine.cycles);
+            }
+        }
+        let cycles = debugger.machine.cycles;
+        while !self.stack.is_empty() {
+            self.pop(cycles);
+        }
+        for (address, count) in self.counts.iter().enumerate() {
+            if *count > 0 {
+                let function = self.function_at(address as u16).to_string();
+                self.functions.entry(function).or_default().exclusive += count;
+            }
+        }
+        stop
+    }
+
+    fn push(&mut self, function: String, return_address: u16, cycles: u64) {
+        self.functions.entry(function.clone()).or_default().calls += 1;
+        self.stack.push(Call {
+            function,
+            return_address,
+            start: cycles,
+        });
+        self.update_key();
+    }
+
+    fn pop(&mut self, cycles: u64) {
+        let Some(call) = self.stack.pop() else {
+            return;
+        };
+        if !self
+            .stack
+            .iter()
+            .any(|outer| outer.function == call.function)
+        {
+            self.functions.entry(call.function).or_default().inclusive += cycles - call.start;
+        }
+        self.update_key();
+    }
+
+    /// 呼び出しの列が変わったときに、数える場所を切り替える
+    fn update_key(&mut self) {
+        let key = if self.stack.is_empty() {
+            TOP.to_string()
+        } else {
+            self.stack
+                .iter()
+                .map(|call| call.function.as_str())
+                .collect::<Vec<_>>()
+                .join(";")
+        };
+        self.current = match self.folded_index.get(&key) {
+            Some(index) => *index,
+            None => {
+                self.folded.push((key.clone(), 0));
+                self.folded_index.insert(key, self.folded.len() - 1);
+                self.folded.len() - 1
+            }
+        };
+    }
+
+    /// 関数, ラベル, 命令ごとの集計を表示する
+    ///
+    /// * `top`: - ラベルと命令は実行回数の多いものから`top`個だけ表示する
+    pub fn report(
+        &self,
+        program: &Program,
+        top: usize,
+        output: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let total: u64 = self.counts.iter().sum();
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        writeln!(output, "cycles: {}", total)?;
+
+        writeln!(output)?;
+        writeln!(
+            output,
+            "{:>12} {:>7} {:>12} {:>7} {:>8}  function",
+            "exclusive", "%", "inclusive", "%", "calls"
+        )?;
+        let mut functions: Vec<(&String, &FunctionStats)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.exclusive.cmp(&a.1.exclusive).then(a.0.cmp(b.0)));
+        for (name, stats) in functions {
+            writeln!(
+                output,
+                "{:>12} {:>6.2}% {:>12} {:>6.2}% {:>8}  {}",
+                stats.exclusive,
+                percent(stats.exclusive),
+                stats.inclusive,
+                percent(stats.inclusive),
+                stats.calls,
+                name
+            )?;
+        }
+
+        writeln!(output)?;
+        writeln!(output, "{:>12} {:>7}  label", "count", "%")?;
+        let mut labels: HashMap<String, u64> = HashMap::new();
+        for (address, count) in self.counts.iter().enumerate() {
+            if *count > 0 {
+                let label = label_at(program, address as u16);
+                *labels.entry(label).or_default() += count;
+            }
+        }
+        let mut labels: Vec<(String, u64)> = labels.into_iter().collect();
+        labels.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (label, count) in labels.iter().take(top) {
+            writeln!(output, "{:>12} {:>6.2}%  {}", count, percent(*count), label)?;
+        }
+
+        if let Some(map) = &program.source_map {
+            writeln!(output)?;
+            writeln!(output, "{:>12} {:>7}  vm command", "count", "%")?;
+            let mut commands: HashMap<String, u64> = HashMap::new();
+            for (address, count) in self.counts.iter().enumerate() {
+                if let (true, Some(entry)) = (*count > 0, map.lookup(address as u16)) {
+                    *commands.entry(entry.describe()).or_default() += count;
+                }
+            }
+            let mut commands: Vec<(String, u64)> = commands.into_iter().collect();
+            commands.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            for (command, count) in commands.iter().take(top) {
+                writeln!(
+                    output,
+                    "{:>12} {:>6.2}%  {}",
+                    count,
+                    percent(*count),
+                    command
+                )?;
+            }
+        }
+
+        writeln!(output)?;
+        writeln!(output, "{:>12} {:>7}  address", "count", "%")?;
+        let mut addresses: Vec<(usize, u64)> = self
+            .counts
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (address, count) in addresses.iter().take(top) {
+            let address = *address as u16;
+            let mut line = address.to_string();
+            if let Some(label) = program.describe(address) {
+                line.push_str(&format!(" ({})", label));
+            }
+            if let Some(entry) = program
+                .source_map
+                .as_ref()
+                .and_then(|map| map.lookup(address))
+            {
+                line.push_str(&format!(" {}", entry.describe()));
+            }
+            writeln!(output, "{:>12} {:>6.2}%  {}", count, percent(*count), line)?;
+        }
+        Ok(())
+    }
+
+    /// flamegraph.plなどで読めるfolded stack形式 (`Sys.init;Main.main 1234`) で書き出す
+    pub fn write_folded(&self, path: &str) -> Result<(), String> {
+        let mut lines: Vec<String> = self
+            .folded
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(stack, count)| format!("{} {}", stack, count))
+            .collect();
+        lines.sort();
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        fs::write(path, contents).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+/// `write_function`が出力する`(Xxx.f)`のようなラベル
+///
+/// 関数内の`Xxx.f$LOOP`や、他の翻訳器が出力する`LOOP_Xxx.f`のようなラベルは除く
+fn is_function_label(label: &str) -> bool {
+    match label.split_once('.') {
+        Some((class, _)) => !class.is_empty() && !class.contains('_') && !label.contains('$'),
+        None => false,
+    }
+}
+
+/// ROMアドレスの直前のラベル
+fn label_at(program: &Program, address: u16) -> String {
+    let index = program
+        .labels
+        .partition_point(|(_, start)| *start <= address);
+    match index {
+        0 => TOP.to_string(),
+        _ => program.labels[index - 1].0.clone(),
+    }
+}
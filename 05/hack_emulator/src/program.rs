@@ -0,0 +1,147 @@
+use crate::source_map::SourceMap;
+use hack_assembler::symbol_table::SymbolTable;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// ROMに読み込むプログラムとシンボル
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub rom: Vec<u16>,
+    /// ラベルとROMアドレス (アドレス順)
+    pub labels: Vec<(String, u16)>,
+    /// 定義済みシンボルと変数のRAMアドレス
+    pub variables: HashMap<String, u16>,
+    /// `hack_vm`が出力したソースマップ
+    pub source_map: Option<SourceMap>,
+}
+
+impl Program {
+    /// `.asm`はアセンブルして読み込み、ラベルと変数も使えるようにする。`.hack`は機械語だけを読み込む
+    ///
+    /// 隣に`.map`ファイル(`Foo.asm`なら`Foo.map`)があればソースマップも読み込む
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut program = Program::load_rom(path)?;
+        let map = SourceMap::path_for(Path::new(path));
+        if map.exists() {
+            program.source_map = Some(SourceMap::load(&map)?);
+        }
+        Ok(program)
+    }
+
+    fn load_rom(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if path.ends_with(".asm") {
+            let assembly = hack_assembler::assemble(&path.to_string())?;
+            let mut labels: Vec<(String, u16)> = assembly
+                .labels
+                .iter()
+                .map(|(name, address)| (name.to_owned(), *address as u16))
+                .collect();
+            labels.sort_by_key(|(_, address)| *address);
+            let variables = assembly
+                .variables()
+                .into_iter()
+                .map(|(name, address)| (name, address as u16))
+                .collect();
+            Ok(Program {
+                rom: assembly.words(),
+                labels,
+                variables,
+                source_map: None,
+            })
+        } else if path.ends_with(".hack") {
+            let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            Ok(Program {
+                rom: parse_hack(&contents).map_err(|e| format!("{}: {}", path, e))?,
+                labels: vec![],
+                variables: predefined(),
+                source_map: None,
+            })
+        } else {
+            let name = Path::new(path).display();
+            Err(format!("This file is not a hack program: {}", name).into())
+        }
+    }
+
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label == name)
+            .map(|(_, address)| *address)
+    }
+
+    /// ROMアドレスを`LOOP`や`LOOP+2`のように表示する
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let (label, start) = self
+            .labels
+            .iter()
+            .rev()
+            .find(|(_, start)| *start <= address)?;
+        if *start == address {
+            Some(label.to_owned())
+        } else {
+            Some(format!("{}+{}", label, address - start))
+        }
+    }
+
+    /// RAMアドレスの名前 (`SP`, `LCL`, 変数名など)
+    pub fn variable_name(&self, address: u16) -> Option<&str> {
+        // R0〜R4は SP, LCL... と重なるので、そちらを優先する
+        let mut names: Vec<&String> = self
+            .variables
+            .iter()
+            .filter(|(_, a)| **a == address)
+            .map(|(name, _)| name)
+            .collect();
+        names.sort_by_key(|name| (name.starts_with('R'), name.to_string()));
+        names.first().map(|name| name.as_str())
+    }
+
+    /// `256`, `SP`, `RAM[256]`, `i`のような指定をRAMアドレスにする
+    pub fn ram_address(&self, text: &str) -> Option<u16> {
+        let text = text
+            .strip_prefix("RAM[")
+            .and_then(|text| text.strip_suffix(']'))
+            .unwrap_or(text);
+        match text.parse::<u16>() {
+            Ok(address) => Some(address),
+            Err(_) => self.variables.get(text).copied(),
+        }
+    }
+
+    /// `12`, `LOOP`, `ROM[12]`のような指定をROMアドレスにする
+    pub fn rom_address(&self, text: &str) -> Option<u16> {
+        let text = text
+            .strip_prefix("ROM[")
+            .and_then(|text| text.strip_suffix(']'))
+            .unwrap_or(text);
+        match text.parse::<u16>() {
+            Ok(address) => Some(address),
+            Err(_) => self.label(text),
+        }
+    }
+}
+
+/// `.hack`ファイル (1行に16文字の`0`と`1`) を読む
+pub fn parse_hack(contents: &str) -> Result<Vec<u16>, String> {
+    let mut rom = vec![];
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() != 16 || !line.chars().all(|c| c == '0' || c == '1') {
+            return Err(format!("line {}: invalid instruction {}", lineno + 1, line));
+        }
+        rom.push(u16::from_str_radix(line, 2).unwrap());
+    }
+    Ok(rom)
+}
+
+fn predefined() -> HashMap<String, u16> {
+    SymbolTable::new()
+        .symbols
+        .into_iter()
+        .map(|(name, address)| (name, address as u16))
+        .collect()
+}
@@ -0,0 +1,69 @@
+use crate::debugger::{Debugger, Stop};
+use crate::screen::{self, GifRecorder};
+
+/// 止まるまで実行しながら、決められたクロックでスクリーンを書き出す
+pub struct Runner {
+    pub debugger: Debugger,
+    /// 合計で実行する命令数の上限
+    pub max_cycles: u64,
+    /// スクリーンを書き出すクロック数とファイル名 (`None`は止まったとき)
+    pub screenshots: Vec<(Option<u64>, String)>,
+    /// アニメーションGIFと、コマを記録する間隔(クロック数)
+    pub gif: Option<(GifRecorder, u64)>,
+}
+
+impl Runner {
+    pub fn new(debugger: Debugger) -> Self {
+        Runner {
+            max_cycles: debugger.max_cycles,
+            debugger,
+            screenshots: vec![],
+            gif: None,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<Stop, Box<dyn std::error::Error>> {
+        let mut shots: Vec<(Option<u64>, String)> = self.screenshots.clone();
+        let mut next_frame = 0;
+        let stop = loop {
+            let cycles = self.debugger.machine.cycles;
+            let (due, rest): (Vec<_>, Vec<_>) = shots
+                .into_iter()
+                .partition(|(at, _)| at.is_some_and(|at| at <= cycles));
+            shots = rest;
+            for (_, path) in due {
+                screen::save(&path, &self.debugger.machine)?;
+            }
+            if let Some((gif, interval)) = self.gif.as_mut() {
+                if cycles >= next_frame {
+                    gif.add_frame(&self.debugger.machine)?;
+                    next_frame = cycles + *interval;
+                }
+            }
+
+            if cycles >= self.max_cycles {
+                break Stop::Limit;
+            }
+            let mut target = self.max_cycles;
+            for at in shots.iter().filter_map(|(at, _)| *at) {
+                target = target.min(at);
+            }
+            if self.gif.is_some() {
+                target = target.min(next_frame);
+            }
+            match self.debugger.run_to_cycle(target) {
+                Stop::Done | Stop::Limit => {}
+                stop => break stop,
+            }
+        };
+
+        // 止まったときのスクリーンは、まだ書き出していないものすべてに使う
+        for (_, path) in shots {
+            screen::save(&path, &self.debugger.machine)?;
+        }
+        if let Some((gif, _)) = self.gif.as_mut() {
+            gif.add_frame(&self.debugger.machine)?;
+        }
+        Ok(stop)
+    }
+}
@@ -0,0 +1,91 @@
+use crate::machine::{Machine, SCREEN};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+
+/// スクリーンの画素 (`true`が黒)
+///
+/// 1行は32語で、各語の最下位ビットが左端の画素になる
+pub fn pixels(machine: &Machine) -> Vec<bool> {
+    let mut pixels = Vec::with_capacity(WIDTH * HEIGHT);
+    for row in 0..HEIGHT {
+        for col in 0..WIDTH {
+            let word = machine.ram[SCREEN + row * WIDTH / 16 + col / 16];
+            pixels.push(word & (1 << (col % 16)) != 0);
+        }
+    }
+    pixels
+}
+
+/// スクリーンを画像ファイルに書き出す (拡張子で`.png`か`.ppm`を選ぶ)
+pub fn save(path: &str, machine: &Machine) -> Result<(), Box<dyn std::error::Error>> {
+    let pixels = pixels(machine);
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut stream = BufWriter::new(file);
+    if path.ends_with(".ppm") {
+        write!(stream, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
+        for &black in pixels.iter() {
+            let value = if black { 0 } else { 255 };
+            stream.write_all(&[value, value, value])?;
+        }
+    } else if path.ends_with(".png") {
+        let mut encoder = png::Encoder::new(stream, WIDTH as u32, HEIGHT as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+        let mut writer = encoder.write_header()?;
+        // 1ビットのグレースケールは1が白
+        let data: Vec<u8> = pixels
+            .chunks(8)
+            .map(|bits| {
+                bits.iter()
+                    .enumerate()
+                    .fold(0, |acc, (k, &black)| acc | ((!black as u8) << (7 - k)))
+            })
+            .collect();
+        writer.write_image_data(&data)?;
+        return Ok(());
+    } else {
+        return Err(format!("unknown image format: {} (use .png or .ppm)", path).into());
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// スクリーンの変化をアニメーションGIFに記録する
+pub struct GifRecorder {
+    encoder: gif::Encoder<BufWriter<File>>,
+    /// 1コマの表示時間 (1/100秒単位)
+    delay: u16,
+    last: Option<Vec<bool>>,
+}
+
+impl GifRecorder {
+    pub fn new(path: &str, delay: u16) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let palette = [255, 255, 255, 0, 0, 0];
+        let mut encoder =
+            gif::Encoder::new(BufWriter::new(file), WIDTH as u16, HEIGHT as u16, &palette)?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+        Ok(GifRecorder {
+            encoder,
+            delay,
+            last: None,
+        })
+    }
+
+    /// 今のスクリーンを1コマ追加する (前のコマと同じなら追加しない)
+    pub fn add_frame(&mut self, machine: &Machine) -> Result<(), Box<dyn std::error::Error>> {
+        let pixels = pixels(machine);
+        if self.last.as_ref() == Some(&pixels) {
+            return Ok(());
+        }
+        let indices: Vec<u8> = pixels.iter().map(|&black| black as u8).collect();
+        let mut frame = gif::Frame::from_indexed_pixels(WIDTH as u16, HEIGHT as u16, indices, None);
+        frame.delay = self.delay;
+        self.encoder.write_frame(&frame)?;
+        self.last = Some(pixels);
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use hack_vm::code_writer::SourceMapping;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// VMコマンドと、そこから生成された命令の先頭
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceEntry {
+    pub rom_address: u16,
+    /// `.asm`での行番号
+    pub asm_line: usize,
+    /// `.vm`ファイルのパス (ブートストラップは`(bootstrap)`)
+    pub file: String,
+    pub line: usize,
+    /// VMコマンドを含む関数 (関数の外なら空)
+    pub function: String,
+    pub command: String,
+}
+
+impl SourceEntry {
+    /// `Main.vm:12`のようにファイル名と行番号を表示する
+    pub fn location(&self) -> String {
+        let file = Path::new(&self.file)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.file.clone());
+        format!("{}:{}", file, self.line)
+    }
+
+    /// `Main.vm:12 (Main.fibonacci) push argument 0`のように表示する
+    pub fn describe(&self) -> String {
+        if self.function.is_empty() {
+            format!("{} {}", self.location(), self.command)
+        } else {
+            format!("{} ({}) {}", self.location(), self.function, self.command)
+        }
+    }
+}
+
+/// `hack_vm`が出力するソースマップ (`Foo.asm`に対する`Foo.map`)
+///
+/// 1行に`ROMアドレス, .asmの行番号, ファイル:行番号, 関数名, VMコマンド`をタブ区切りで書く。
+/// `#`で始まる行は読み飛ばし、関数の外のコマンドは関数名を`-`にする
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// ROMアドレス順
+    pub entries: Vec<SourceEntry>,
+}
+
+impl SourceMap {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        SourceMap::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut entries = vec![];
+        for (lineno, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid source map entry", lineno + 1);
+            // 行の形は`hack_vm`が書くときと同じものを使って読む
+            let mapping = SourceMapping::parse(line).ok_or_else(invalid)?;
+            entries.push(SourceEntry {
+                rom_address: mapping.rom_address.try_into().map_err(|_| invalid())?,
+                asm_line: mapping.asm_line,
+                file: mapping.file,
+                line: mapping.line,
+                function: mapping.function,
+                command: mapping.command,
+            });
+        }
+        // 命令を生成しないコマンド(label)は次のコマンドと同じアドレスになるので、順序は保つ
+        entries.sort_by_key(|entry| entry.rom_address);
+        Ok(SourceMap { entries })
+    }
+
+    /// `Xxx.asm`の隣にある`Xxx.map`
+    pub fn path_for(program: &Path) -> PathBuf {
+        program.with_extension("map")
+    }
+
+    /// ROMアドレスの命令を生成したVMコマンド
+    pub fn lookup(&self, address: u16) -> Option<&SourceEntry> {
+        let index = self
+            .entries
+            .partition_point(|entry| entry.rom_address <= address);
+        self.entries[..index].last()
+    }
+
+    /// ROMアドレスから始まるVMコマンド (命令を生成しない`label`なども含む)
+    pub fn starting_at(&self, address: u16) -> impl Iterator<Item = &SourceEntry> {
+        let start = self
+            .entries
+            .partition_point(|entry| entry.rom_address < address);
+        self.entries[start..]
+            .iter()
+            .take_while(move |entry| entry.rom_address == address)
+    }
+}
@@ -0,0 +1,194 @@
+use crate::machine::Machine;
+use crate::program::Program;
+use crate::source_map::{SourceEntry, SourceMap};
+
+pub const SP: u16 = 0;
+pub const LCL: u16 = 1;
+pub const ARG: u16 = 2;
+pub const THIS: u16 = 3;
+pub const THAT: u16 = 4;
+pub const TEMP: u16 = 5;
+/// `call`がスタックに積むリターンアドレスとLCL, ARG, THIS, THATの数
+const FRAME_SIZE: u16 = 5;
+/// 壊れたスタックで無限にたどらないようにする
+const MAX_FRAMES: usize = 1024;
+
+/// VMの関数呼び出しの1段分
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// 関数名 (関数の外なら空)
+    pub function: String,
+    /// 実行中(呼び出し元では`call`)のVMコマンド
+    pub location: Option<SourceEntry>,
+    pub lcl: u16,
+    pub arg: u16,
+    pub n_args: u16,
+    pub n_locals: u16,
+    /// 作業用スタックの範囲 (`start..end`)
+    pub stack: (u16, u16),
+}
+
+impl Frame {
+    pub fn describe(&self) -> String {
+        let function = if self.function.is_empty() {
+            "(no function)"
+        } else {
+            self.function.as_str()
+        };
+        match &self.location {
+            Some(entry) => format!("{} ({}) {}", function, entry.location(), entry.command),
+            None => function.to_string(),
+        }
+    }
+}
+
+/// `function Xxx.f n`のローカル変数の数
+pub fn locals_count(map: &SourceMap, function: &str) -> Option<u16> {
+    map.entries.iter().find_map(|entry| {
+        let mut words = entry.command.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("function"), Some(name), Some(n)) if name == function => n.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// PCがVMコマンドの最初の命令を指しているか
+pub fn at_command_start(map: &SourceMap, machine: &Machine) -> bool {
+    map.starting_at(machine.pc).next().is_some()
+}
+
+/// LCLとARGからたどった関数呼び出しの列 (実行中の関数が先頭)
+pub fn backtrace(machine: &Machine, map: &SourceMap) -> Vec<Frame> {
+    let mut frames: Vec<Frame> = vec![];
+    let mut location = map.lookup(machine.pc).cloned();
+    let mut lcl = machine.read(LCL);
+    let mut arg = machine.read(ARG);
+    let mut top = machine.read(SP);
+    while frames.len() < MAX_FRAMES {
+        let Some(entry) = location else {
+            break;
+        };
+        if entry.function.is_empty() {
+            // 関数の外 (ブートストラップや、関数を使わないプログラム)
+            if frames.is_empty() {
+                frames.push(Frame {
+                    function: String::new(),
+                    location: Some(entry),
+                    lcl,
+                    arg,
+                    n_args: 0,
+                    n_locals: 0,
+                    stack: (top.min(256), top),
+                });
+            }
+            break;
+        }
+        let n_locals = locals_count(map, &entry.function).unwrap_or(0);
+        let n_args = lcl.wrapping_sub(FRAME_SIZE).wrapping_sub(arg);
+        let n_args = if n_args <= lcl { n_args } else { 0 };
+        let start = lcl.wrapping_add(n_locals).min(top);
+        frames.push(Frame {
+            function: entry.function.clone(),
+            location: Some(entry),
+            lcl,
+            arg,
+            n_args,
+            n_locals,
+            stack: (start, top),
+        });
+        if lcl < FRAME_SIZE {
+            break;
+        }
+        // 呼び出し元の`call`の次の命令に戻る
+        let return_address = machine.read(lcl - 5);
+        if return_address == 0 || return_address as usize > machine.rom.len() {
+            break;
+        }
+        location = map.lookup(return_address.wrapping_sub(1)).cloned();
+        // 呼び出し元のスタックには渡した引数まで含める
+        top = arg.wrapping_add(n_args);
+        let caller_lcl = machine.read(lcl - 4);
+        arg = machine.read(lcl - 3);
+        lcl = caller_lcl;
+    }
+    frames
+}
+
+/// `[1, 2, 3]`のようにRAMの範囲を表示する
+pub fn format_words(machine: &Machine, start: u16, end: u16) -> String {
+    let words: Vec<String> = (start..end)
+        .take(256)
+        .map(|address| (machine.read(address) as i16).to_string())
+        .collect();
+    let more = if end.saturating_sub(start) > 256 {
+        ", ..."
+    } else {
+        ""
+    };
+    format!("[{}{}]", words.join(", "), more)
+}
+
+/// static変数 (`Xxx.i`という名前の変数) をアドレス順に並べる
+pub fn statics(program: &Program) -> Vec<(String, u16)> {
+    let mut statics: Vec<(String, u16)> = program
+        .variables
+        .iter()
+        .filter(|(name, _)| {
+            name.rsplit_once('.').is_some_and(|(_, index)| {
+                !index.is_empty() && index.chars().all(|c| c.is_ascii_digit())
+            })
+        })
+        .map(|(name, address)| (name.clone(), *address))
+        .collect();
+    statics.sort_by_key(|(_, address)| *address);
+    statics
+}
+
+/// `hack_vm --checked`のトラップがエラーコードを書く変数
+pub const TRAP_CODE: &str = "__VM_TRAP";
+/// 検査に失敗した場所のROMアドレスを書く変数
+pub const TRAP_PC: &str = "__VM_TRAP_PC";
+
+/// `hack_vm --checked`が出力したコードの検査の失敗
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub code: u16,
+    /// 検査したコードのROMアドレス
+    pub address: u16,
+}
+
+impl Trap {
+    pub fn reason(&self) -> &'static str {
+        match self.code {
+            1 => "stack overflow",
+            2 => "stack underflow",
+            3 => "segment out of range",
+            _ => "unknown trap",
+        }
+    }
+
+    /// `stack overflow at Main.vm:12 (Main.f) push constant 1`のように表示する
+    pub fn describe(&self, program: &Program) -> String {
+        let location = program
+            .source_map
+            .as_ref()
+            .and_then(|map| map.lookup(self.address))
+            .map(|entry| entry.describe())
+            .unwrap_or_else(|| format!("ROM[{}]", self.address));
+        format!("{} at {}", self.reason(), location)
+    }
+}
+
+/// トラップで止まっていればその内容
+pub fn trap(program: &Program, machine: &Machine) -> Option<Trap> {
+    let code = machine.read(*program.variables.get(TRAP_CODE)?);
+    if code == 0 {
+        return None;
+    }
+    let address = program
+        .variables
+        .get(TRAP_PC)
+        .map_or(0, |address| machine.read(*address));
+    Some(Trap { code, address })
+}
@@ -0,0 +1,410 @@
+//! ランダムなVMプログラムを`hack_vm`のインタプリタと、翻訳・アセンブルしたエミュレータの両方で実行し、
+//! 止まったときのレジスタ・セグメント・スタックが同じになることを確かめる
+//!
+//! `VM_DIFF_CASES=<n>`で試すプログラムの数を、`VM_DIFF_SEED=<seed>`で1つのプログラムだけを指定できる
+
+use std::path::Path;
+
+use hack_emulator::machine::Machine;
+use hack_vm::code_writer::CodeWriter;
+use hack_vm::command::VmFile;
+use hack_vm::interpreter::{Interpreter, Stop};
+use hack_vm::{optimizer, parser};
+
+/// 既定で試すプログラムの数
+const DEFAULT_CASES: u64 = 200;
+/// インタプリタで実行するVMコマンドの最大数
+const MAX_STEPS: u64 = 1_000_000;
+/// エミュレータで実行する命令の最大数
+const MAX_CYCLES: u64 = 50_000_000;
+/// `pointer`に入れるアドレスの範囲 (`this`/`that`は最大15まで使う)
+const HEAP: std::ops::Range<u16> = 3000..3100;
+/// 比べるRAMの範囲 (SPより上は呼び出しのフレームが残っているので別に比べる)
+const COMPARED: [std::ops::Range<u16>; 3] = [0..13, 16..256, 3000..3200];
+/// 関数ごとの`loop`の数え上げに使うstaticの先頭 (それより前は普通の読み書きに使う)
+const COUNTER_STATIC: u16 = 8;
+/// 入れ子にできるループの深さ
+const MAX_LOOP_DEPTH: u16 = 2;
+
+/// 再現できるようにシードから決まる乱数 (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+}
+
+struct Function {
+    name: String,
+    /// `Main.vm`なら0、`Helper.vm`なら1
+    file: usize,
+    n_args: u16,
+    n_locals: u16,
+}
+
+/// 生成中のコードの場所
+#[derive(Clone, Copy)]
+struct Scope {
+    /// 関数の番号 (関数の外なら`None`)。番号の大きい関数しか呼ばないので再帰しない
+    function: Option<usize>,
+    n_args: u16,
+    n_locals: u16,
+    loop_depth: u16,
+}
+
+/// スタックを釣り合わせたまま、必ず止まるVMプログラムを作る
+///
+/// 関数の外で`pointer`を設定してから文を実行し、`label END; goto END`で止まる。
+/// 関数は`Main.vm`と`Helper.vm`に分けて置く
+struct Generator {
+    rng: Rng,
+    functions: Vec<Function>,
+    labels: usize,
+    lines: Vec<String>,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let functions = (0..rng.below(5))
+            .map(|i| {
+                let file = rng.below(2) as usize;
+                Function {
+                    name: format!("{}.f{}", ["Main", "Helper"][file], i),
+                    file,
+                    n_args: rng.below(4) as u16,
+                    n_locals: rng.below(4) as u16,
+                }
+            })
+            .collect();
+        Generator {
+            rng,
+            functions,
+            labels: 0,
+            lines: vec![],
+        }
+    }
+
+    /// `Main.vm`と`Helper.vm`の中身
+    fn generate(mut self) -> [String; 2] {
+        let mut files = [String::new(), String::new()];
+        let top = Scope {
+            function: None,
+            n_args: 0,
+            n_locals: 0,
+            loop_depth: 0,
+        };
+        for pointer in 0..2 {
+            self.set_pointer(pointer);
+        }
+        for _ in 0..3 + self.rng.below(6) {
+            self.statement(top, 3);
+        }
+        self.emit("label END".to_string());
+        self.emit("goto END".to_string());
+        files[0] = self.take();
+
+        for index in 0..self.functions.len() {
+            let function = &self.functions[index];
+            let scope = Scope {
+                function: Some(index),
+                n_args: function.n_args,
+                n_locals: function.n_locals,
+                loop_depth: 0,
+            };
+            let (file, header) = (
+                function.file,
+                format!("function {} {}", function.name, function.n_locals),
+            );
+            self.emit(header);
+            for _ in 0..1 + self.rng.below(4) {
+                self.statement(scope, 2);
+            }
+            self.expression(scope, 3);
+            self.emit("return".to_string());
+            files[file].push_str(&self.take());
+        }
+        files
+    }
+
+    fn emit(&mut self, line: String) {
+        self.lines.push(line);
+    }
+
+    fn take(&mut self) -> String {
+        let text = self.lines.join("\n") + "\n";
+        self.lines.clear();
+        text
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("L{}", self.labels)
+    }
+
+    fn set_pointer(&mut self, pointer: u16) {
+        let address = HEAP.start + self.rng.below((HEAP.end - HEAP.start) as u64) as u16;
+        self.emit(format!("push constant {}", address));
+        self.emit(format!("pop pointer {}", pointer));
+    }
+
+    /// スタックを変えない文
+    fn statement(&mut self, scope: Scope, depth: u32) {
+        let roll = self.rng.below(100);
+        if depth > 0 && roll < 15 && scope.loop_depth < MAX_LOOP_DEPTH {
+            // 1〜4回まわるループ。数え上げるstaticは関数とループの深さごとに分ける
+            let counter = COUNTER_STATIC
+                + scope.function.map_or(0, |index| index as u16 + 1) * MAX_LOOP_DEPTH
+                + scope.loop_depth;
+            let label = self.label();
+            let count = 1 + self.rng.below(4);
+            self.emit(format!("push constant {}", count));
+            self.emit(format!("pop static {}", counter));
+            self.emit(format!("label {}", label));
+            let inner = Scope {
+                loop_depth: scope.loop_depth + 1,
+                ..scope
+            };
+            for _ in 0..1 + self.rng.below(3) {
+                self.statement(inner, depth - 1);
+            }
+            self.emit(format!("push static {}", counter));
+            self.emit("push constant 1".to_string());
+            self.emit("sub".to_string());
+            self.emit(format!("pop static {}", counter));
+            self.emit(format!("push static {}", counter));
+            self.emit(format!("if-goto {}", label));
+        } else if depth > 0 && roll < 30 {
+            // 条件が成り立てば(`goto`なら必ず)飛ばす
+            let label = self.label();
+            if self.rng.chance(50) {
+                self.expression(scope, 2);
+                self.emit(format!("if-goto {}", label));
+            } else {
+                self.emit(format!("goto {}", label));
+            }
+            for _ in 0..1 + self.rng.below(3) {
+                self.statement(scope, depth - 1);
+            }
+            self.emit(format!("label {}", label));
+        } else if roll < 38 {
+            let pointer = self.rng.below(2) as u16;
+            self.set_pointer(pointer);
+        } else {
+            self.expression(scope, 3);
+            let (segment, index) = self.location(scope);
+            self.emit(format!("pop {} {}", segment, index));
+        }
+    }
+
+    /// 値を1つ積む式
+    fn expression(&mut self, scope: Scope, depth: u32) {
+        let callable: Vec<usize> =
+            (scope.function.map_or(0, |index| index + 1)..self.functions.len()).collect();
+        let roll = self.rng.below(100);
+        if depth == 0 || roll < 30 {
+            if self.rng.chance(50) {
+                let value = self.constant();
+                self.emit(format!("push constant {}", value));
+            } else {
+                let (segment, index) = if self.rng.chance(10) {
+                    ("pointer", self.rng.below(2) as u16)
+                } else {
+                    self.location(scope)
+                };
+                self.emit(format!("push {} {}", segment, index));
+            }
+        } else if roll < 42 {
+            self.expression(scope, depth - 1);
+            let op = ["neg", "not"][self.rng.below(2) as usize];
+            self.emit(op.to_string());
+        } else if roll < 85 || callable.is_empty() {
+            self.expression(scope, depth - 1);
+            self.expression(scope, depth - 1);
+            let ops = ["add", "sub", "and", "or", "eq", "gt", "lt"];
+            let op = ops[self.rng.below(ops.len() as u64) as usize];
+            self.emit(op.to_string());
+        } else {
+            let index = callable[self.rng.below(callable.len() as u64) as usize];
+            let n_args = self.functions[index].n_args;
+            for _ in 0..n_args {
+                self.expression(scope, depth - 1);
+            }
+            let name = self.functions[index].name.clone();
+            self.emit(format!("call {} {}", name, n_args));
+        }
+    }
+
+    /// 符号の境目や大きな値が出やすい定数 (`push constant`は0〜32767)
+    fn constant(&mut self) -> u64 {
+        match self.rng.below(6) {
+            0 => [0, 1, 2, 16383, 16384, 32766, 32767][self.rng.below(7) as usize],
+            1 | 2 => self.rng.below(32768),
+            _ => self.rng.below(10),
+        }
+    }
+
+    /// 読み書きできるセグメントとインデックス
+    fn location(&mut self, scope: Scope) -> (&'static str, u16) {
+        loop {
+            match self.rng.below(6) {
+                0 => return ("temp", self.rng.below(8) as u16),
+                1 => return ("static", self.rng.below(COUNTER_STATIC as u64) as u16),
+                2 => return ("this", self.rng.below(16) as u16),
+                3 => return ("that", self.rng.below(16) as u16),
+                4 if scope.n_locals > 0 => {
+                    return ("local", self.rng.below(scope.n_locals as u64) as u16)
+                }
+                5 if scope.n_args > 0 => {
+                    return ("argument", self.rng.below(scope.n_args as u64) as u16)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn parse(sources: &[String; 2]) -> Vec<VmFile> {
+    ["Main.vm", "Helper.vm"]
+        .iter()
+        .zip(sources.iter())
+        .filter(|(_, source)| !source.trim().is_empty())
+        .map(|(path, source)| VmFile {
+            path: path.to_string(),
+            commands: parser::parse_str(source, Path::new(path))
+                .unwrap_or_else(|errors| panic!("{:?}\n{}", errors, source)),
+        })
+        .collect()
+}
+
+/// インタプリタで実行したときのRAM
+fn interpret(programs: &[VmFile]) -> Result<Vec<u16>, String> {
+    let mut interpreter = Interpreter::new(programs, None).map_err(|e| format!("{:?}", e))?;
+    match interpreter.run(MAX_STEPS).map_err(|e| e.to_string())? {
+        Stop::Halted => Ok(interpreter.ram),
+        Stop::Limit => Err(format!("did not halt in {} steps", MAX_STEPS)),
+    }
+}
+
+/// 翻訳・アセンブルしてエミュレータで実行したときのRAM
+///
+/// `Sys.init`がないのでブートストラップはなく、インタプリタと同じくSPだけを256にして始める
+fn emulate(programs: &[VmFile], checked: bool) -> Result<Vec<u16>, String> {
+    let mut writer = CodeWriter::with_stream(Vec::new());
+    writer.checked = checked;
+    for program in programs {
+        writer.write_fragment(&CodeWriter::translate(program, checked));
+    }
+    writer.write_routines();
+    let asm = String::from_utf8(writer.stream).unwrap();
+    let assembly = hack_assembler::assemble_source(&asm).map_err(|e| e.to_string())?;
+    let end = assembly
+        .labels
+        .iter()
+        .find(|(name, _)| name == "END")
+        .map(|(_, address)| *address as u16)
+        .ok_or("no END label")?;
+
+    let mut machine = Machine::new(assembly.words());
+    machine.write(0, 256);
+    let mut cycles = 0;
+    while !machine.is_halted() {
+        if cycles == MAX_CYCLES {
+            return Err(format!("did not halt in {} cycles", MAX_CYCLES));
+        }
+        machine.step();
+        cycles += 1;
+    }
+    if machine.pc != end {
+        return Err(format!("halted at {} instead of END ({})", machine.pc, end));
+    }
+    Ok((0..=u16::MAX >> 1)
+        .map(|address| machine.read(address))
+        .collect())
+}
+
+/// 2つのRAMの、比べる範囲とスタック (256からSPの前まで) の最初の違い
+fn difference(expected: &[u16], actual: &[u16]) -> Option<String> {
+    let sp = expected[0].max(actual[0]);
+    let stack = 256..sp.min(2048);
+    COMPARED
+        .iter()
+        .cloned()
+        .chain([stack])
+        .flatten()
+        .find(|&address| expected[address as usize] != actual[address as usize])
+        .map(|address| {
+            format!(
+                "RAM[{}]: interpreter {} ({}), emulator {} ({})",
+                address,
+                expected[address as usize],
+                expected[address as usize] as i16,
+                actual[address as usize],
+                actual[address as usize] as i16
+            )
+        })
+}
+
+/// 変え方 (`checked`, `optimize`) ごとに、シードから作ったプログラムを比べる
+fn check(checked: bool, optimize: bool) {
+    let seeds: Vec<u64> = match std::env::var("VM_DIFF_SEED") {
+        Ok(seed) => vec![seed.parse().expect("VM_DIFF_SEED must be a number")],
+        Err(_) => {
+            let cases = std::env::var("VM_DIFF_CASES")
+                .ok()
+                .and_then(|cases| cases.parse().ok())
+                .unwrap_or(DEFAULT_CASES);
+            (1..=cases).collect()
+        }
+    };
+    for seed in seeds {
+        let sources = Generator::new(seed).generate();
+        let programs = parse(&sources);
+        let report = |message: String| -> ! {
+            panic!(
+                "seed {} (checked: {}, optimize: {}): {}\n--- Main.vm\n{}--- Helper.vm\n{}",
+                seed, checked, optimize, message, sources[0], sources[1]
+            )
+        };
+        let expected = interpret(&programs).unwrap_or_else(|e| report(e));
+        let mut translated = programs.clone();
+        if optimize {
+            optimizer::optimize(&mut translated);
+        }
+        let actual = emulate(&translated, checked).unwrap_or_else(|e| report(e));
+        if let Some(message) = difference(&expected, &actual) {
+            report(message);
+        }
+    }
+}
+
+#[test]
+fn translated_programs_match_interpreter() {
+    check(false, false);
+}
+
+#[test]
+fn checked_programs_match_interpreter() {
+    check(true, false);
+}
+
+#[test]
+fn optimized_programs_match_interpreter() {
+    check(false, true);
+}
@@ -0,0 +1,262 @@
+//! 小さなVMプログラムを翻訳してエミュレータで実行し、VMトランスレータの基本的な命令を確かめる
+//!
+//! `call`のARG/LCL、`return`、`if-goto`の真偽、ファイルごとのstatic、関数ごとのラベル、
+//! ローカル変数の領域、ブートストラップ、行末のコメント、`--checked`の`this`/`that`の検査をそれぞれ1つのテストで調べる
+
+use std::path::Path;
+
+use hack_emulator::machine::Machine;
+use hack_vm::code_writer::CodeWriter;
+use hack_vm::command::VmFile;
+use hack_vm::parser;
+
+/// エミュレータで実行する命令の最大数
+const MAX_CYCLES: u64 = 1_000_000;
+
+/// 結果を書くstaticの先頭 (`Sys.0`)
+const RESULT: &str = "Sys.0";
+
+/// `(パス, 中身)`のファイルをブートストラップ付きで翻訳し、止まるまで実行する
+///
+/// * `checked`: - `--checked`と同じく実行時の検査を出力する
+fn execute(files: &[(&str, &str)], checked: bool) -> (Machine, hack_assembler::Assembly) {
+    let mut writer = CodeWriter::with_stream(Vec::new());
+    writer.checked = checked;
+    writer.write_init();
+    for (path, source) in files {
+        let program = VmFile {
+            path: path.to_string(),
+            commands: parser::parse_str(source, Path::new(path))
+                .unwrap_or_else(|errors| panic!("{:?}", errors)),
+        };
+        writer.write_fragment(&CodeWriter::translate(&program, checked));
+    }
+    writer.write_routines();
+    let asm = String::from_utf8(writer.stream).unwrap();
+    let assembly = hack_assembler::assemble_source(&asm).unwrap();
+
+    let mut machine = Machine::new(assembly.words());
+    let mut cycles = 0;
+    while !machine.is_halted() {
+        assert!(cycles < MAX_CYCLES, "did not halt in {} cycles", MAX_CYCLES);
+        machine.step();
+        cycles += 1;
+    }
+    (machine, assembly)
+}
+
+/// 検査なしで翻訳して実行する
+///
+/// `Sys.init`は最後に`label END; goto END`で止まること
+fn run(files: &[(&str, &str)]) -> (Machine, hack_assembler::Assembly) {
+    let (machine, mut assembly) = execute(files, false);
+    assert_eq!(
+        machine.pc as usize,
+        assembly.symbol_table.get_address(&"Sys.init$END".to_string()),
+        "halted outside Sys.init"
+    );
+    (machine, assembly)
+}
+
+/// static変数の値
+fn read(machine: &Machine, assembly: &mut hack_assembler::Assembly, name: &str) -> i16 {
+    let name = name.to_string();
+    assert!(assembly.symbol_table.contains(&name), "no symbol {}", name);
+    machine.read(assembly.symbol_table.get_address(&name) as u16) as i16
+}
+
+/// `Sys.init`の中身を書いて、最後に`pop static 0`で結果を残して止まる
+fn sys(body: &str) -> String {
+    format!(
+        "function Sys.init 0\n{}\npop static 0\nlabel END\ngoto END\n",
+        body
+    )
+}
+
+#[test]
+fn call_passes_arguments_and_return_restores_the_caller() {
+    // ARGとLCLがSPのアドレス(0)ではなくSPの値から決まらないと引数を読めない
+    let main = "function Main.sub 0\npush argument 0\npush argument 1\nsub\nreturn\n";
+    let (machine, mut assembly) = run(&[
+        (
+            "Sys.vm",
+            &sys("push constant 10\npush constant 3\ncall Main.sub 2"),
+        ),
+        ("Main.vm", main),
+    ]);
+    assert_eq!(read(&machine, &mut assembly, RESULT), 7);
+    // returnで呼び出し元のフレームに戻り、SPは戻り値を1つ積んだ後から結果をpopした位置になる
+    assert_eq!(machine.read(0), 261);
+    assert_eq!(machine.read(1), 261);
+}
+
+#[test]
+fn return_from_a_function_without_arguments() {
+    // 引数が0個だと戻り値がリターンアドレスの場所に書かれるので、先に取っておかないと戻れない
+    let main = "function Main.answer 0\npush constant 42\nreturn\n";
+    let (machine, mut assembly) = run(&[
+        (
+            "Sys.vm",
+            &sys("call Main.answer 0\ncall Main.answer 0\nadd"),
+        ),
+        ("Main.vm", main),
+    ]);
+    assert_eq!(read(&machine, &mut assembly, RESULT), 84);
+}
+
+#[test]
+fn if_goto_jumps_on_any_non_zero_value() {
+    // 比較の真は-1なので、正の値だけで飛ぶと`lt`の結果で分岐できない
+    let body = "push constant 0
+push constant 1
+push constant 2
+lt
+if-goto TRUE
+push constant 100
+add
+label TRUE
+push constant 1
+neg
+if-goto NEGATIVE
+push constant 200
+add
+label NEGATIVE
+push constant 0
+if-goto ZERO
+push constant 1
+add
+label ZERO";
+    let (machine, mut assembly) = run(&[("Sys.vm", &sys(body))]);
+    assert_eq!(read(&machine, &mut assembly, RESULT), 1);
+}
+
+#[test]
+fn statics_belong_to_their_file() {
+    // staticが16番地からの共通の領域だと、Class1.0とClass2.0が同じ場所になる
+    let class = |name: &str, value: u16| {
+        format!(
+            "function {0}.set 0\npush constant {1}\npop static 0\npush constant 0\nreturn\n\
+             function {0}.get 0\npush static 0\nreturn\n",
+            name, value
+        )
+    };
+    let body = "call Class1.set 0
+pop temp 0
+call Class2.set 0
+pop temp 0
+call Class1.get 0
+call Class2.get 0
+sub";
+    let (machine, mut assembly) = run(&[
+        ("Sys.vm", &sys(body)),
+        ("Class1.vm", &class("Class1", 10)),
+        ("Class2.vm", &class("Class2", 25)),
+    ]);
+    assert_eq!(read(&machine, &mut assembly, RESULT), -15);
+    assert_eq!(read(&machine, &mut assembly, "Class1.0"), 10);
+    assert_eq!(read(&machine, &mut assembly, "Class2.0"), 25);
+}
+
+#[test]
+fn labels_are_scoped_to_their_function() {
+    // 2つの関数が同じ`LOOP`を使っても、それぞれの関数の中に飛ぶ
+    let count = |name: &str| {
+        format!(
+            "function Main.{} 1
+push argument 0
+pop local 0
+label LOOP
+push local 0
+push constant 1
+sub
+pop local 0
+push local 0
+if-goto LOOP
+push argument 0
+return
+",
+            name
+        )
+    };
+    let (machine, mut assembly) = run(&[
+        (
+            "Sys.vm",
+            &sys("push constant 3\ncall Main.f 1\npush constant 4\ncall Main.g 1\nadd"),
+        ),
+        ("Main.vm", &(count("f") + &count("g"))),
+    ]);
+    assert_eq!(read(&machine, &mut assembly, RESULT), 7);
+}
+
+#[test]
+fn locals_are_zeroed_and_kept_below_the_stack() {
+    // ローカル変数の分だけSPを進めないと、pushでローカル変数が上書きされる
+    let main = "function Main.f 2
+push local 0
+push constant 5
+pop local 1
+push constant 9
+push constant 9
+add
+pop temp 0
+push local 1
+add
+return
+";
+    // 先にスタックの上を汚しておき、ローカル変数が0から始まることも確かめる
+    let body = "push constant 77
+push constant 77
+push constant 77
+push constant 77
+push constant 77
+push constant 77
+push constant 77
+push constant 77
+pop temp 1
+pop temp 1
+pop temp 1
+pop temp 1
+pop temp 1
+pop temp 1
+pop temp 1
+pop temp 1
+call Main.f 0";
+    let (machine, mut assembly) = run(&[("Sys.vm", &sys(body)), ("Main.vm", main)]);
+    assert_eq!(read(&machine, &mut assembly, RESULT), 5);
+}
+
+#[test]
+fn bootstrap_starts_sys_init_with_sp_256() {
+    // Sys.initの引数の領域(ARG)はフレームの5つ下、ローカル変数はSP=256の後ろから始まる
+    let (machine, mut assembly) = run(&[("Sys.vm", &sys("push constant 1"))]);
+    assert_eq!(read(&machine, &mut assembly, RESULT), 1);
+    assert_eq!(machine.read(0), 261);
+    assert_eq!(machine.read(2), 256);
+}
+
+#[test]
+fn comments_do_not_repeat_or_break_commands() {
+    // 行末のコメントと、最後のコメントだけの行
+    let body = "push constant 3 // three
+push constant 4// four
+add
+// end of body";
+    let (machine, mut assembly) = run(&[("Sys.vm", &sys(body))]);
+    assert_eq!(read(&machine, &mut assembly, RESULT), 7);
+    assert_eq!(machine.read(0), 261);
+}
+
+#[test]
+fn checked_this_with_a_negative_base_traps() {
+    // THIS = -1にindexを足して24577を引くと溢れて負になるので、符号付きの比較だけでは通ってしまう
+    let body = "push constant 0
+not
+pop pointer 0
+push constant 5
+pop this 3
+push constant 0";
+    let (machine, mut assembly) = execute(&[("Sys.vm", &sys(body))], true);
+    assert_eq!(read(&machine, &mut assembly, "__VM_TRAP"), 3);
+    // pop this 3が書く2番地 (ARG) はそのまま
+    assert_eq!(machine.read(2), 256);
+}
@@ -0,0 +1,81 @@
+//! `07`と`08`のVMプログラムを`hack_vm`で翻訳し、テストスクリプトで実行して`.cmp`のRAMと比べる
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hack_emulator::script::ScriptRunner;
+
+/// `Foo/Foo.tst`のあるディレクトリ (`Foo/Foo.vm`があればそのファイルだけを翻訳する)
+fn samples() -> Vec<(String, PathBuf)> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("../..");
+    let mut samples = vec![];
+    for project in ["07", "08"] {
+        for group in fs::read_dir(root.join(project)).unwrap() {
+            let group = group.unwrap().path();
+            if !group.is_dir() {
+                continue;
+            }
+            for dir in fs::read_dir(&group).unwrap() {
+                let dir = dir.unwrap().path();
+                let name = dir.file_name().unwrap().to_string_lossy().to_string();
+                if dir.join(format!("{}.tst", name)).exists() {
+                    samples.push((name, dir));
+                }
+            }
+        }
+    }
+    samples.sort();
+    samples
+}
+
+/// すべてのサンプルを`flags`を付けて翻訳し、テストスクリプトで確かめる
+///
+/// * `flags`: - `hack_vm`に渡すオプション
+fn check_samples(flags: &[&str]) {
+    let samples = samples();
+    assert_eq!(samples.len(), 11, "samples: {:?}", samples);
+    let tag = flags.concat().replace('-', "");
+    for (name, dir) in samples {
+        let single = dir.join(format!("{}.vm", name));
+        let src = if single.exists() { single } else { dir.clone() };
+        let output = std::env::temp_dir().join(format!(
+            "hack_emulator_{}_{}{}.asm",
+            std::process::id(),
+            name,
+            tag
+        ));
+        let mut args: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+        args.push("-o".to_string());
+        args.push(output.to_string_lossy().to_string());
+        args.push(src.to_string_lossy().to_string());
+        hack_vm::cli::main(args).unwrap_or_else(|e| panic!("{} {:?}: {}", name, flags, e));
+
+        let mut runner = ScriptRunner::new(Some(output.to_string_lossy().to_string()));
+        let result = runner.run(&dir.join(format!("{}.tst", name)));
+        let _ = fs::remove_file(&output);
+        let _ = fs::remove_file(output.with_extension("map"));
+        if let Err(e) = result {
+            panic!("{} {:?}: {}", name, flags, e);
+        }
+    }
+}
+
+#[test]
+fn samples_pass_their_scripts() {
+    check_samples(&[]);
+}
+
+#[test]
+fn checked_samples_pass_their_scripts() {
+    check_samples(&["--checked"]);
+}
+
+#[test]
+fn optimized_samples_pass_their_scripts() {
+    check_samples(&["-O"]);
+}
+
+#[test]
+fn samples_without_os_pass_their_scripts() {
+    check_samples(&["--no-os", "-j", "1"]);
+}
@@ -0,0 +1,455 @@
+/// HDLファイルがないときに使われる組み込みチップ
+#[derive(Debug)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub inputs: &'static [(&'static str, usize)],
+    pub outputs: &'static [(&'static str, usize)],
+    /// クロックで内部状態が更新されるか
+    pub clocked: bool,
+    /// 出力に組み合わせ回路として影響する入力
+    /// (クロック付きのチップでは`address`のみ、組み合わせ回路ではすべての入力)
+    pub comb_inputs: &'static [&'static str],
+}
+
+const AB: &[(&str, usize)] = &[("a", 1), ("b", 1)];
+const OUT: &[(&str, usize)] = &[("out", 1)];
+const OUT16: &[(&str, usize)] = &[("out", 16)];
+const AB16: &[(&str, usize)] = &[("a", 16), ("b", 16)];
+
+pub static BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "Nand",
+        inputs: AB,
+        outputs: OUT,
+        clocked: false,
+        comb_inputs: &["a", "b"],
+    },
+    Builtin {
+        name: "Not",
+        inputs: &[("in", 1)],
+        outputs: OUT,
+        clocked: false,
+        comb_inputs: &["in"],
+    },
+    Builtin {
+        name: "And",
+        inputs: AB,
+        outputs: OUT,
+        clocked: false,
+        comb_inputs: &["a", "b"],
+    },
+    Builtin {
+        name: "Or",
+        inputs: AB,
+        outputs: OUT,
+        clocked: false,
+        comb_inputs: &["a", "b"],
+    },
+    Builtin {
+        name: "Xor",
+        inputs: AB,
+        outputs: OUT,
+        clocked: false,
+        comb_inputs: &["a", "b"],
+    },
+    Builtin {
+        name: "Mux",
+        inputs: &[("a", 1), ("b", 1), ("sel", 1)],
+        outputs: OUT,
+        clocked: false,
+        comb_inputs: &["a", "b", "sel"],
+    },
+    Builtin {
+        name: "DMux",
+        inputs: &[("in", 1), ("sel", 1)],
+        outputs: &[("a", 1), ("b", 1)],
+        clocked: false,
+        comb_inputs: &["in", "sel"],
+    },
+    Builtin {
+        name: "Not16",
+        inputs: &[("in", 16)],
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["in"],
+    },
+    Builtin {
+        name: "And16",
+        inputs: AB16,
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["a", "b"],
+    },
+    Builtin {
+        name: "Or16",
+        inputs: AB16,
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["a", "b"],
+    },
+    Builtin {
+        name: "Mux16",
+        inputs: &[("a", 16), ("b", 16), ("sel", 1)],
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["a", "b", "sel"],
+    },
+    Builtin {
+        name: "Or8Way",
+        inputs: &[("in", 8)],
+        outputs: OUT,
+        clocked: false,
+        comb_inputs: &["in"],
+    },
+    Builtin {
+        name: "Mux4Way16",
+        inputs: &[("a", 16), ("b", 16), ("c", 16), ("d", 16), ("sel", 2)],
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["a", "b", "c", "d", "sel"],
+    },
+    Builtin {
+        name: "Mux8Way16",
+        inputs: &[
+            ("a", 16),
+            ("b", 16),
+            ("c", 16),
+            ("d", 16),
+            ("e", 16),
+            ("f", 16),
+            ("g", 16),
+            ("h", 16),
+            ("sel", 3),
+        ],
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["a", "b", "c", "d", "e", "f", "g", "h", "sel"],
+    },
+    Builtin {
+        name: "DMux4Way",
+        inputs: &[("in", 1), ("sel", 2)],
+        outputs: &[("a", 1), ("b", 1), ("c", 1), ("d", 1)],
+        clocked: false,
+        comb_inputs: &["in", "sel"],
+    },
+    Builtin {
+        name: "DMux8Way",
+        inputs: &[("in", 1), ("sel", 3)],
+        outputs: &[
+            ("a", 1),
+            ("b", 1),
+            ("c", 1),
+            ("d", 1),
+            ("e", 1),
+            ("f", 1),
+            ("g", 1),
+            ("h", 1),
+        ],
+        clocked: false,
+        comb_inputs: &["in", "sel"],
+    },
+    Builtin {
+        name: "HalfAdder",
+        inputs: AB,
+        outputs: &[("sum", 1), ("carry", 1)],
+        clocked: false,
+        comb_inputs: &["a", "b"],
+    },
+    Builtin {
+        name: "FullAdder",
+        inputs: &[("a", 1), ("b", 1), ("c", 1)],
+        outputs: &[("sum", 1), ("carry", 1)],
+        clocked: false,
+        comb_inputs: &["a", "b", "c"],
+    },
+    Builtin {
+        name: "Add16",
+        inputs: AB16,
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["a", "b"],
+    },
+    Builtin {
+        name: "Inc16",
+        inputs: &[("in", 16)],
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["in"],
+    },
+    Builtin {
+        name: "ALU",
+        inputs: &[
+            ("x", 16),
+            ("y", 16),
+            ("zx", 1),
+            ("nx", 1),
+            ("zy", 1),
+            ("ny", 1),
+            ("f", 1),
+            ("no", 1),
+        ],
+        outputs: &[("out", 16), ("zr", 1), ("ng", 1)],
+        clocked: false,
+        comb_inputs: &["x", "y", "zx", "nx", "zy", "ny", "f", "no"],
+    },
+    Builtin {
+        name: "DFF",
+        inputs: &[("in", 1)],
+        outputs: OUT,
+        clocked: true,
+        comb_inputs: &[],
+    },
+    Builtin {
+        name: "Bit",
+        inputs: &[("in", 1), ("load", 1)],
+        outputs: OUT,
+        clocked: true,
+        comb_inputs: &[],
+    },
+    Builtin {
+        name: "Register",
+        inputs: &[("in", 16), ("load", 1)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &[],
+    },
+    Builtin {
+        name: "ARegister",
+        inputs: &[("in", 16), ("load", 1)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &[],
+    },
+    Builtin {
+        name: "DRegister",
+        inputs: &[("in", 16), ("load", 1)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &[],
+    },
+    Builtin {
+        name: "PC",
+        inputs: &[("in", 16), ("load", 1), ("inc", 1), ("reset", 1)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &[],
+    },
+    Builtin {
+        name: "RAM8",
+        inputs: &[("in", 16), ("load", 1), ("address", 3)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &["address"],
+    },
+    Builtin {
+        name: "RAM64",
+        inputs: &[("in", 16), ("load", 1), ("address", 6)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &["address"],
+    },
+    Builtin {
+        name: "RAM512",
+        inputs: &[("in", 16), ("load", 1), ("address", 9)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &["address"],
+    },
+    Builtin {
+        name: "RAM4K",
+        inputs: &[("in", 16), ("load", 1), ("address", 12)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &["address"],
+    },
+    Builtin {
+        name: "RAM16K",
+        inputs: &[("in", 16), ("load", 1), ("address", 14)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &["address"],
+    },
+    Builtin {
+        name: "ROM32K",
+        inputs: &[("address", 15)],
+        outputs: OUT16,
+        clocked: false,
+        comb_inputs: &["address"],
+    },
+    Builtin {
+        name: "Screen",
+        inputs: &[("in", 16), ("load", 1), ("address", 13)],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &["address"],
+    },
+    Builtin {
+        name: "Keyboard",
+        inputs: &[],
+        outputs: OUT16,
+        clocked: true,
+        comb_inputs: &[],
+    },
+];
+
+/// 名前から組み込みチップを探す
+pub fn find(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+impl Builtin {
+    /// NandとDFFはHDLで書けない最小単位のチップ
+    pub fn is_primitive(&self) -> bool {
+        self.name == "Nand" || self.name == "DFF"
+    }
+}
+
+/// クロック付きの組み込みチップの内部状態
+#[derive(Debug, Clone)]
+pub enum State {
+    None,
+    Word(u16),
+    Memory(Vec<u16>),
+}
+
+impl Builtin {
+    /// 内部状態の初期値
+    pub fn initial_state(&self) -> State {
+        match self.name {
+            "DFF" | "Bit" | "Register" | "ARegister" | "DRegister" | "PC" | "Keyboard" => {
+                State::Word(0)
+            }
+            "RAM8" => State::Memory(vec![0; 8]),
+            "RAM64" => State::Memory(vec![0; 64]),
+            "RAM512" => State::Memory(vec![0; 512]),
+            "RAM4K" => State::Memory(vec![0; 4096]),
+            "RAM16K" => State::Memory(vec![0; 16384]),
+            "Screen" => State::Memory(vec![0; 8192]),
+            "ROM32K" => State::Memory(vec![0; 32768]),
+            _ => State::None,
+        }
+    }
+
+    /// 入力と内部状態から出力を計算する
+    ///
+    /// * `inputs`: - `self.inputs`の順に並んだ入力の値
+    pub fn eval(&self, inputs: &[u16], state: &State) -> Vec<u16> {
+        let bit = |b: bool| b as u16;
+        let i = |n: usize| inputs[n];
+        match (self.name, state) {
+            ("Nand", _) => vec![bit(i(0) & i(1) == 0)],
+            ("Not", _) | ("Not16", _) => vec![!i(0) & mask(self.outputs[0].1)],
+            ("And", _) | ("And16", _) => vec![i(0) & i(1)],
+            ("Or", _) | ("Or16", _) => vec![i(0) | i(1)],
+            ("Xor", _) => vec![i(0) ^ i(1)],
+            ("Mux", _) | ("Mux16", _) => vec![if i(2) == 1 { i(1) } else { i(0) }],
+            ("DMux", _) => vec![i(0) & bit(i(1) == 0), i(0) & bit(i(1) == 1)],
+            ("Or8Way", _) => vec![bit(i(0) & 0xff != 0)],
+            ("Mux4Way16", _) => vec![i(i(4) as usize & 3)],
+            ("Mux8Way16", _) => vec![i(i(8) as usize & 7)],
+            ("DMux4Way", _) => (0..4).map(|n| i(0) & bit(i(1) == n)).collect(),
+            ("DMux8Way", _) => (0..8).map(|n| i(0) & bit(i(1) == n)).collect(),
+            ("HalfAdder", _) => vec![i(0) ^ i(1), i(0) & i(1)],
+            ("FullAdder", _) => {
+                let sum = i(0) + i(1) + i(2);
+                vec![sum & 1, sum >> 1]
+            }
+            ("Add16", _) => vec![i(0).wrapping_add(i(1))],
+            ("Inc16", _) => vec![i(0).wrapping_add(1)],
+            ("ALU", _) => {
+                let mut x = if i(2) == 1 { 0 } else { i(0) };
+                if i(3) == 1 {
+                    x = !x;
+                }
+                let mut y = if i(4) == 1 { 0 } else { i(1) };
+                if i(5) == 1 {
+                    y = !y;
+                }
+                let mut out = if i(6) == 1 { x.wrapping_add(y) } else { x & y };
+                if i(7) == 1 {
+                    out = !out;
+                }
+                vec![out, bit(out == 0), out >> 15]
+            }
+            ("ROM32K", State::Memory(words)) => vec![words[i(0) as usize & 0x7fff]],
+            (_, State::Word(word)) => vec![*word],
+            (_, State::Memory(words)) => {
+                let address = i(self.input_index("address").unwrap()) as usize;
+                vec![words.get(address).copied().unwrap_or(0)]
+            }
+            _ => unreachable!("no behavior for builtin chip {}", self.name),
+        }
+    }
+
+    /// クロックの立ち上がりで、次の状態に書き込む(アドレス, 値)を計算する
+    pub fn clock(&self, inputs: &[u16], state: &State) -> Option<(usize, u16)> {
+        let i = |n: usize| inputs[n];
+        match (self.name, state) {
+            ("DFF", _) => Some((0, i(0))),
+            ("Bit", _) | ("Register", _) | ("ARegister", _) | ("DRegister", _) => {
+                if i(1) == 1 {
+                    Some((0, i(0)))
+                } else {
+                    None
+                }
+            }
+            ("PC", State::Word(word)) => {
+                let next = if i(3) == 1 {
+                    0
+                } else if i(1) == 1 {
+                    i(0)
+                } else if i(2) == 1 {
+                    word.wrapping_add(1)
+                } else {
+                    *word
+                };
+                Some((0, next))
+            }
+            ("ROM32K", _) | ("Keyboard", _) => None,
+            (_, State::Memory(_)) => {
+                if i(1) == 1 {
+                    Some((i(2) as usize, i(0)))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn input_index(&self, name: &str) -> Option<usize> {
+        self.inputs.iter().position(|(pin, _)| *pin == name)
+    }
+}
+
+impl State {
+    /// `(アドレス, 値)`を書き込む (レジスタはアドレス0のみ)
+    pub fn write(&mut self, address: usize, value: u16) {
+        match self {
+            State::Word(word) => *word = value,
+            State::Memory(words) => {
+                if let Some(word) = words.get_mut(address) {
+                    *word = value;
+                }
+            }
+            State::None => {}
+        }
+    }
+
+    pub fn read(&self, address: usize) -> Option<u16> {
+        match self {
+            State::Word(word) => Some(*word),
+            State::Memory(words) => words.get(address).copied(),
+            State::None => None,
+        }
+    }
+}
+
+/// `width`ビットのマスク
+pub fn mask(width: usize) -> u16 {
+    if width >= 16 {
+        0xffff
+    } else {
+        (1 << width) - 1
+    }
+}
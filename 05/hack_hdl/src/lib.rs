@@ -0,0 +1,9 @@
+pub mod builtin;
+pub mod library;
+pub mod lint;
+pub mod netlist;
+pub mod parser;
+pub mod script;
+pub mod simulator;
+pub mod stats;
+pub mod vcd;
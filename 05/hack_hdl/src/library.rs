@@ -0,0 +1,166 @@
+use crate::builtin::{self, Builtin};
+use crate::parser::{self, ChipDef, HdlError, PinDecl};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// HDLで定義されたチップか組み込みチップ
+#[derive(Debug)]
+pub enum Chip {
+    Hdl(ChipDef),
+    Builtin(&'static Builtin),
+}
+
+impl Chip {
+    pub fn name(&self) -> &str {
+        match self {
+            Chip::Hdl(def) => &def.name,
+            Chip::Builtin(builtin) => builtin.name,
+        }
+    }
+
+    pub fn inputs(&self) -> Vec<PinDecl> {
+        match self {
+            Chip::Hdl(def) => def.inputs.clone(),
+            Chip::Builtin(builtin) => pin_decls(builtin.inputs),
+        }
+    }
+
+    pub fn outputs(&self) -> Vec<PinDecl> {
+        match self {
+            Chip::Hdl(def) => def.outputs.clone(),
+            Chip::Builtin(builtin) => pin_decls(builtin.outputs),
+        }
+    }
+
+    pub fn input_width(&self, name: &str) -> Option<usize> {
+        self.inputs()
+            .iter()
+            .find(|pin| pin.name == name)
+            .map(|pin| pin.width)
+    }
+
+    pub fn output_width(&self, name: &str) -> Option<usize> {
+        self.outputs()
+            .iter()
+            .find(|pin| pin.name == name)
+            .map(|pin| pin.width)
+    }
+}
+
+fn pin_decls(pins: &[(&str, usize)]) -> Vec<PinDecl> {
+    pins.iter()
+        .map(|(name, width)| PinDecl {
+            name: name.to_string(),
+            width: *width,
+            line: 0,
+        })
+        .collect()
+}
+
+/// チップ名からHDLファイルまたは組み込みチップを探す
+///
+/// `dirs`を先頭から順に探し、`<name>.hdl`が見つからなければ組み込みチップを使う
+pub struct Library {
+    pub dirs: Vec<PathBuf>,
+    chips: HashMap<String, Rc<Chip>>,
+}
+
+impl Library {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Library {
+            dirs,
+            chips: HashMap::new(),
+        }
+    }
+
+    /// 指定したHDLファイルを読み込む (ファイルのディレクトリを検索パスの先頭に追加する)
+    pub fn load_file(&mut self, path: &Path) -> Result<Rc<Chip>, HdlError> {
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        if !self.dirs.contains(&dir) {
+            self.dirs.insert(0, dir);
+        }
+        let def = parser::parse_file(path)?;
+        let chip = Rc::new(chip_from_def(def));
+        self.chips.insert(chip.name().to_string(), chip.clone());
+        Ok(chip)
+    }
+
+    /// チップ名を解決する
+    ///
+    /// * `name`: - チップ名
+    /// * `path`, `line`: - エラー表示用の参照元
+    pub fn resolve(&mut self, name: &str, path: &Path, line: usize) -> Result<Rc<Chip>, HdlError> {
+        if let Some(chip) = self.chips.get(name) {
+            return Ok(chip.clone());
+        }
+        let chip = match builtin::find(name) {
+            Some(builtin) if builtin.is_primitive() => Chip::Builtin(builtin),
+            found => match self.find_file(name) {
+                Some(file) => chip_from_def(parser::parse_file(&file)?),
+                None => match found {
+                    Some(builtin) => Chip::Builtin(builtin),
+                    None => {
+                        return Err(HdlError::new(
+                            path,
+                            line,
+                            format!("chip {} not found", name),
+                        ))
+                    }
+                },
+            },
+        };
+        let chip = Rc::new(chip);
+        self.chips.insert(name.to_string(), chip.clone());
+        Ok(chip)
+    }
+
+    fn find_file(&self, name: &str) -> Option<PathBuf> {
+        self.dirs
+            .iter()
+            .map(|dir| dir.join(format!("{}.hdl", name)))
+            .find(|file| file.is_file())
+    }
+}
+
+/// `BUILTIN`と書かれたHDLは組み込みチップとして扱う
+fn chip_from_def(def: ChipDef) -> Chip {
+    match def.builtin.as_deref().and_then(builtin::find) {
+        Some(builtin) => Chip::Builtin(builtin),
+        None => Chip::Hdl(def),
+    }
+}
+
+/// `dir`と同じ階層にある章ごとのディレクトリ(`01`〜`05`など)を列挙する
+pub fn chapter_dirs(dir: &Path) -> Vec<PathBuf> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let parent = match fs::canonicalize(dir)
+        .ok()
+        .and_then(|dir| dir.parent().map(Path::to_path_buf))
+    {
+        Some(parent) => parent,
+        None => return vec![],
+    };
+    let mut dirs: Vec<PathBuf> = match fs::read_dir(&parent) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.is_dir()
+                    && path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .map(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()))
+                        .unwrap_or(false)
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    dirs.sort();
+    dirs
+}
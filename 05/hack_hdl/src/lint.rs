@@ -0,0 +1,476 @@
+use crate::library::{Chip, Library};
+use crate::parser::{ChipDef, HdlError, PinRef, Signal};
+use std::collections::HashMap;
+
+/// 出力ピンごとの、組み合わせ回路として影響する入力ピン
+type Dependencies = Vec<(String, Vec<String>)>;
+
+/// 部品の入力につながれた信号
+struct Use {
+    pin: PinRef,
+    /// 部品側のピン (`And.a`)
+    target: String,
+    width: usize,
+    line: usize,
+}
+
+/// 組み合わせ回路の信号のつながり (部品の入力につながる信号 → 出力につながる信号)
+struct Edge {
+    from: String,
+    to: String,
+    part: usize,
+}
+
+/// HDLの静的チェック
+///
+/// チップを展開せず、部品のピンの宣言だけを使って1つのチップ定義を調べる
+pub struct Linter<'a> {
+    library: &'a mut Library,
+    dependencies: HashMap<String, Dependencies>,
+    visiting: Vec<String>,
+}
+
+impl<'a> Linter<'a> {
+    pub fn new(library: &'a mut Library) -> Self {
+        Linter {
+            library,
+            dependencies: HashMap::new(),
+            visiting: vec![],
+        }
+    }
+
+    /// チップ定義の問題を行番号順に返す
+    pub fn check(&mut self, def: &ChipDef) -> Vec<HdlError> {
+        let mut reports = vec![];
+        self.analyze(def, Some(&mut reports));
+        reports.sort_by_key(|report| report.line);
+        reports
+    }
+
+    /// 部品の出力が組み合わせ回路としてどの入力に依存するか
+    fn chip_dependencies(&mut self, chip: &Chip) -> Dependencies {
+        match chip {
+            Chip::Builtin(builtin) => builtin
+                .outputs
+                .iter()
+                .map(|(name, _)| {
+                    let inputs = builtin.comb_inputs.iter().map(|s| s.to_string());
+                    (name.to_string(), inputs.collect())
+                })
+                .collect(),
+            Chip::Hdl(def) => {
+                if let Some(dependencies) = self.dependencies.get(&def.name) {
+                    return dependencies.clone();
+                }
+                // 自分自身を含むチップは展開できないので、依存はないものとする
+                if self.visiting.contains(&def.name) {
+                    return vec![];
+                }
+                self.visiting.push(def.name.clone());
+                let dependencies = self.analyze(def, None);
+                self.visiting.pop();
+                self.dependencies
+                    .insert(def.name.clone(), dependencies.clone());
+                dependencies
+            }
+        }
+    }
+
+    /// チップ定義を調べて、出力ピンの依存関係を返す
+    ///
+    /// * `reports`: - 見つけた問題の追加先 (部品として調べるときは`None`)
+    fn analyze(&mut self, def: &ChipDef, mut reports: Option<&mut Vec<HdlError>>) -> Dependencies {
+        let mut report = |line: usize, message: String| {
+            if let Some(reports) = reports.as_mut() {
+                reports.push(HdlError::new(&def.path, line, message));
+            }
+        };
+        let input_width = |name: &str| def.input(name).map(|pin| pin.width);
+        let output_width = |name: &str| def.output(name).map(|pin| pin.width);
+
+        // 信号名 → ビットごとの駆動している行
+        let mut drivers: HashMap<String, Vec<Vec<usize>>> = HashMap::new();
+        for pin in def.outputs.iter() {
+            drivers.insert(pin.name.clone(), vec![vec![]; pin.width]);
+        }
+        let mut uses: Vec<Use> = vec![];
+        let mut edges: Vec<Edge> = vec![];
+        let mut unresolved = false;
+
+        for (index, part) in def.parts.iter().enumerate() {
+            let sub = match self.library.resolve(&part.chip, &def.path, part.line) {
+                Ok(sub) => sub,
+                Err(e) => {
+                    report(e.line, e.message);
+                    unresolved = true;
+                    continue;
+                }
+            };
+            let mut connected: HashMap<String, Vec<usize>> = HashMap::new();
+            let mut part_inputs: Vec<(String, String)> = vec![];
+            let mut part_outputs: Vec<(String, String)> = vec![];
+            for conn in part.connections.iter() {
+                let internal = &conn.internal;
+                let (full, is_input) = match (
+                    sub.input_width(&internal.name),
+                    sub.output_width(&internal.name),
+                ) {
+                    (Some(width), _) => (width, true),
+                    (None, Some(width)) => (width, false),
+                    (None, None) => {
+                        report(
+                            conn.line,
+                            format!("{} has no pin named {}", sub.name(), internal.name),
+                        );
+                        continue;
+                    }
+                };
+                let width = internal.width(full);
+                if internal.start() + width > full {
+                    report(
+                        conn.line,
+                        format!("{} is out of range of {}", internal, sub.name()),
+                    );
+                    continue;
+                }
+
+                if is_input {
+                    // 部品の同じ入力ビットに2回つないでいないか
+                    let bits = connected
+                        .entry(internal.name.clone())
+                        .or_insert_with(|| vec![0; full]);
+                    let bits = &mut bits[internal.start()..internal.start() + width];
+                    if let Some(&line) = bits.iter().find(|&&line| line != 0) {
+                        report(
+                            conn.line,
+                            format!(
+                                "{} of {} is connected more than once (also at line {})",
+                                internal,
+                                sub.name(),
+                                line
+                            ),
+                        );
+                    }
+                    bits.iter_mut().for_each(|line| *line = conn.line);
+                }
+
+                let external = match &conn.external {
+                    Signal::Pin(pin) => pin,
+                    Signal::Const(_) if is_input => continue,
+                    Signal::Const(value) => {
+                        report(
+                            conn.line,
+                            format!(
+                                "output {} of {} cannot be assigned to {}",
+                                internal,
+                                sub.name(),
+                                value
+                            ),
+                        );
+                        continue;
+                    }
+                };
+
+                if is_input {
+                    if output_width(&external.name).is_some() {
+                        report(
+                            conn.line,
+                            format!(
+                                "output pin {} cannot be used as an input of a part",
+                                external.name
+                            ),
+                        );
+                        continue;
+                    }
+                    uses.push(Use {
+                        pin: external.clone(),
+                        target: format!("{}.{}", sub.name(), internal),
+                        width,
+                        line: conn.line,
+                    });
+                    part_inputs.push((internal.name.clone(), external.name.clone()));
+                    continue;
+                }
+
+                if input_width(&external.name).is_some() {
+                    report(
+                        conn.line,
+                        format!("cannot assign to input pin {}", external.name),
+                    );
+                    continue;
+                }
+                let ext_width = match (output_width(&external.name), external.range) {
+                    (Some(full), _) => external.width(full),
+                    (None, Some(_)) => external.width(0),
+                    (None, None) => width,
+                };
+                if ext_width != width {
+                    report(
+                        conn.line,
+                        format!(
+                            "width mismatch: {} has {} bits but {} has {} bits",
+                            internal, width, external, ext_width
+                        ),
+                    );
+                    continue;
+                }
+                let bits = drivers.entry(external.name.clone()).or_default();
+                let end = external.start() + width;
+                if output_width(&external.name).is_some() && end > bits.len() {
+                    report(conn.line, format!("{} is out of range", external));
+                    continue;
+                }
+                if bits.len() < end {
+                    bits.resize(end, vec![]);
+                }
+                for lines in bits[external.start()..end].iter_mut() {
+                    lines.push(conn.line);
+                }
+                part_outputs.push((internal.name.clone(), external.name.clone()));
+            }
+
+            for (output, dependencies) in self.chip_dependencies(&sub) {
+                for (_, to) in part_outputs.iter().filter(|(pin, _)| *pin == output) {
+                    for (_, from) in part_inputs
+                        .iter()
+                        .filter(|(pin, _)| dependencies.contains(pin))
+                    {
+                        edges.push(Edge {
+                            from: from.clone(),
+                            to: to.clone(),
+                            part: index,
+                        });
+                    }
+                }
+            }
+        }
+
+        // 部品の入力につないだ信号の幅と、駆動されているか
+        for u in uses.iter() {
+            let pin = &u.pin;
+            let (full, undriven) = match (input_width(&pin.name), drivers.get(&pin.name)) {
+                (Some(full), _) => (full, None),
+                (None, Some(bits)) => (bits.len(), Some(bits)),
+                (None, None) => {
+                    report(u.line, format!("pin {} is not declared", pin.name));
+                    continue;
+                }
+            };
+            let width = pin.width(full);
+            if input_width(&pin.name).is_some() && pin.start() + width > full {
+                report(u.line, format!("{} is out of range", pin));
+                continue;
+            }
+            if width != u.width {
+                report(
+                    u.line,
+                    format!(
+                        "width mismatch: {} has {} bits but {} has {} bits",
+                        pin, width, u.target, u.width
+                    ),
+                );
+                continue;
+            }
+            if let Some(bits) = undriven {
+                let missing: Vec<usize> = (pin.start()..pin.start() + width)
+                    .filter(|&bit| bits.get(bit).is_none_or(|lines| lines.is_empty()))
+                    .collect();
+                if !missing.is_empty() {
+                    report(
+                        u.line,
+                        format!(
+                            "{} is used but {} is never assigned",
+                            pin,
+                            bit_ref(&pin.name, &missing)
+                        ),
+                    );
+                }
+            }
+        }
+
+        // どの部品にもつながっていない入力 (見つからない部品があるときはわからないので調べない)
+        for decl in def.inputs.iter().filter(|_| !unresolved) {
+            if !uses.iter().any(|u| u.pin.name == decl.name) {
+                report(decl.line, format!("input pin {} is never used", decl.name));
+            }
+        }
+
+        // 駆動されていない出力と、複数から駆動されている信号
+        for decl in def.outputs.iter() {
+            let missing: Vec<usize> = drivers[&decl.name]
+                .iter()
+                .enumerate()
+                .filter(|(_, lines)| lines.is_empty())
+                .map(|(bit, _)| bit)
+                .collect();
+            if missing.len() == decl.width {
+                report(
+                    decl.line,
+                    format!("output pin {} is never assigned", decl.name),
+                );
+            } else if !missing.is_empty() {
+                report(
+                    decl.line,
+                    format!(
+                        "output pin {} is never assigned",
+                        bit_ref(&decl.name, &missing)
+                    ),
+                );
+            }
+        }
+        let mut names: Vec<&String> = drivers.keys().collect();
+        names.sort();
+        for name in names {
+            let bits: Vec<usize> = drivers[name]
+                .iter()
+                .enumerate()
+                .filter(|(_, lines)| lines.len() > 1)
+                .map(|(bit, _)| bit)
+                .collect();
+            if let Some(&first) = bits.first() {
+                let lines = &drivers[name][first];
+                let others: Vec<String> = lines[..lines.len() - 1]
+                    .iter()
+                    .map(|line| line.to_string())
+                    .collect();
+                report(
+                    *lines.last().unwrap(),
+                    format!(
+                        "{} has multiple drivers (also assigned at line {})",
+                        bit_ref(name, &bits),
+                        others.join(", ")
+                    ),
+                );
+            }
+        }
+
+        for cycle in find_cycles(&edges) {
+            let line = cycle
+                .iter()
+                .map(|&edge| def.parts[edges[edge].part].line)
+                .max()
+                .unwrap();
+            let mut path = vec![edges[cycle[0]].from.clone()];
+            for &edge in cycle.iter() {
+                let part = &def.parts[edges[edge].part];
+                path.push(format!("{} (line {})", part.chip, part.line));
+                path.push(edges[edge].to.clone());
+            }
+            report(line, format!("combinational loop: {}", path.join(" -> ")));
+        }
+
+        // 出力ピンから組み合わせ回路をさかのぼって、影響する入力ピンを探す
+        def.outputs
+            .iter()
+            .map(|decl| {
+                let mut visited = vec![decl.name.clone()];
+                let mut stack = vec![decl.name.clone()];
+                while let Some(name) = stack.pop() {
+                    for edge in edges.iter().filter(|edge| edge.to == name) {
+                        if !visited.contains(&edge.from) {
+                            visited.push(edge.from.clone());
+                            stack.push(edge.from.clone());
+                        }
+                    }
+                }
+                let inputs = def
+                    .inputs
+                    .iter()
+                    .filter(|pin| visited.contains(&pin.name))
+                    .map(|pin| pin.name.clone());
+                (decl.name.clone(), inputs.collect())
+            })
+            .collect()
+    }
+}
+
+/// `a[3]`, `a[8..15]`のような表示 (連続していないときは最初の連続部分)
+fn bit_ref(name: &str, bits: &[usize]) -> String {
+    let first = bits[0];
+    let last = bits
+        .iter()
+        .zip(first..)
+        .take_while(|(&bit, expected)| bit == *expected)
+        .last()
+        .map(|(&bit, _)| bit)
+        .unwrap();
+    if first == last {
+        format!("{}[{}]", name, first)
+    } else {
+        format!("{}[{}..{}]", name, first, last)
+    }
+}
+
+/// 信号のつながりの閉路を、強連結成分ごとに1つずつ探す (辺の番号の列)
+fn find_cycles(edges: &[Edge]) -> Vec<Vec<usize>> {
+    let mut names: Vec<&str> = vec![];
+    for edge in edges.iter() {
+        for name in [edge.from.as_str(), edge.to.as_str()] {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    let node = |name: &str| names.iter().position(|n| *n == name).unwrap();
+    let mut outgoing: Vec<Vec<usize>> = vec![vec![]; names.len()];
+    for (index, edge) in edges.iter().enumerate() {
+        outgoing[node(&edge.from)].push(index);
+    }
+    let target = |edge: usize| node(&edges[edge].to);
+
+    // 到達可能性で強連結成分に分ける
+    let reach = |start: usize| {
+        let mut visited = vec![false; names.len()];
+        let mut stack = vec![start];
+        while let Some(n) = stack.pop() {
+            for &edge in outgoing[n].iter() {
+                let next = target(edge);
+                if !visited[next] {
+                    visited[next] = true;
+                    stack.push(next);
+                }
+            }
+        }
+        visited
+    };
+    let reachable: Vec<Vec<bool>> = (0..names.len()).map(reach).collect();
+    let mut done = vec![false; names.len()];
+    let mut cycles = vec![];
+    for start in 0..names.len() {
+        if done[start] || !reachable[start][start] {
+            continue;
+        }
+        for n in 0..names.len() {
+            if reachable[start][n] && reachable[n][start] {
+                done[n] = true;
+            }
+        }
+        // 幅優先探索で`start`に戻る最短の閉路を作る
+        let mut previous: Vec<Option<usize>> = vec![None; names.len()];
+        let mut queue = std::collections::VecDeque::from(vec![start]);
+        let mut last = None;
+        'search: while let Some(n) = queue.pop_front() {
+            for &edge in outgoing[n].iter() {
+                let next = target(edge);
+                if next == start {
+                    last = Some(edge);
+                    break 'search;
+                }
+                if previous[next].is_none() && reachable[next][start] {
+                    previous[next] = Some(edge);
+                    queue.push_back(next);
+                }
+            }
+        }
+        let mut cycle = vec![];
+        let mut edge = last;
+        while let Some(e) = edge {
+            cycle.push(e);
+            let from = node(&edges[e].from);
+            edge = if from == start { None } else { previous[from] };
+        }
+        cycle.reverse();
+        cycles.push(cycle);
+    }
+    cycles
+}
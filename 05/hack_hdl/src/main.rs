@@ -0,0 +1,116 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hack_hdl::{library, lint, script, stats};
+
+const USAGE: &str = "usage:
+    hack_hdl stats <CHIP.hdl> [-L <dir>]...
+    hack_hdl lint <CHIP.hdl | dir>... [-L <dir>]...
+    hack_hdl test <CHIP.tst> [-L <dir>]... [--vcd <out.vcd> [--probe <path>]...]";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        return Err(USAGE.into());
+    }
+    let command = args[1].as_str();
+
+    let mut files: Vec<String> = vec![];
+    let mut dirs: Vec<PathBuf> = vec![];
+    let mut vcd: Option<String> = None;
+    let mut probes: Vec<String> = vec![];
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().cloned().ok_or(USAGE);
+        match arg.as_str() {
+            "-L" => dirs.push(PathBuf::from(value()?)),
+            "--vcd" => vcd = Some(value()?),
+            "--probe" => probes.push(value()?),
+            _ if arg.starts_with('-') => {
+                return Err(format!("unknown option: {}\n{}", arg, USAGE).into())
+            }
+            _ => files.push(arg.to_owned()),
+        }
+    }
+    if files.is_empty() || (command != "lint" && files.len() > 1) {
+        return Err(USAGE.into());
+    }
+    let src = files[0].to_owned();
+
+    match command {
+        "stats" => {
+            if !src.ends_with(".hdl") {
+                return Err(format!("This file is not HDL: {}", src).into());
+            }
+            // 他の章のディレクトリにあるHDLも使ってNandまで展開する
+            let dir = Path::new(&src).parent().unwrap_or(Path::new("."));
+            dirs.extend(library::chapter_dirs(dir));
+            let mut library = library::Library::new(dirs);
+            let chip = library.load_file(Path::new(&src))?;
+            let stats = stats::StatsBuilder::new(&mut library).build(chip)?;
+            stats::print_report(&stats);
+        }
+        "lint" => {
+            let mut problems = 0;
+            for file in hdl_files(&files)? {
+                let dir = file.parent().unwrap_or(Path::new("."));
+                let mut search = dirs.clone();
+                search.extend(library::chapter_dirs(dir));
+                let mut library = library::Library::new(search);
+                let reports = match library.load_file(&file) {
+                    Ok(chip) => match &*chip {
+                        library::Chip::Hdl(def) => lint::Linter::new(&mut library).check(def),
+                        library::Chip::Builtin(_) => vec![],
+                    },
+                    Err(e) => vec![e],
+                };
+                for report in reports.iter() {
+                    println!("{}", report);
+                }
+                problems += reports.len();
+            }
+            if problems > 0 {
+                eprintln!("{} problem(s) found", problems);
+                std::process::exit(1);
+            }
+        }
+        "test" => {
+            if !src.ends_with(".tst") {
+                return Err(format!("This file is not a test script: {}", src).into());
+            }
+            if vcd.is_none() && !probes.is_empty() {
+                return Err("--probe needs --vcd".into());
+            }
+            let mut runner = script::ScriptRunner::new(dirs, vcd.map(|path| (path, probes)));
+            if let Err(e) = runner.run(Path::new(&src)) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            println!("End of script - Comparison ended successfully");
+        }
+        _ => return Err(format!("unknown command: {}\n{}", command, USAGE).into()),
+    }
+    Ok(())
+}
+
+/// 指定されたファイルと、ディレクトリ内のHDLファイルを列挙する
+fn hdl_files(paths: &[String]) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = vec![];
+    for path in paths.iter().map(PathBuf::from) {
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(&path)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|file| file.extension().is_some_and(|ext| ext == "hdl"))
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        } else if path.extension().is_some_and(|ext| ext == "hdl") {
+            files.push(path);
+        } else {
+            return Err(format!("This file is not HDL: {}", path.display()).into());
+        }
+    }
+    Ok(files)
+}
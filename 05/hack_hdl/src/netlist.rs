@@ -0,0 +1,398 @@
+use crate::builtin::{Builtin, State};
+use crate::library::{Chip, Library};
+use crate::parser::{HdlError, Signal};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// 1ビットの信号線の番号
+pub type Net = usize;
+
+/// 常に0の信号線
+pub const FALSE: Net = 0;
+/// 常に1の信号線
+pub const TRUE: Net = 1;
+
+/// 展開後の回路の部品 (HDLのチップはすべて展開され、NandかHDLを持たない組み込みチップだけが残る)
+#[derive(Debug, Clone)]
+pub enum Component {
+    Nand {
+        a: Net,
+        b: Net,
+        out: Net,
+    },
+    Dff {
+        input: Net,
+        out: Net,
+        state: bool,
+        next: bool,
+    },
+    Builtin {
+        builtin: &'static Builtin,
+        inputs: Vec<Vec<Net>>,
+        outputs: Vec<Vec<Net>>,
+        state: State,
+        pending: Option<(usize, u16)>,
+    },
+}
+
+/// チップの階層 (VCDの出力やピンのパス指定に使う)
+#[derive(Debug, Clone)]
+pub struct Scope {
+    /// 部品名 (同じチップが複数あるときは`And#0`, `And#1`のように番号を付ける)
+    pub name: String,
+    pub chip: String,
+    pub pins: Vec<(String, Vec<Net>)>,
+    pub children: Vec<Scope>,
+    /// 組み込みチップのときは対応する`Component`の番号
+    pub component: Option<usize>,
+}
+
+impl Scope {
+    pub fn pin(&self, name: &str) -> Option<&Vec<Net>> {
+        self.pins
+            .iter()
+            .find(|(pin, _)| pin == name)
+            .map(|(_, nets)| nets)
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Scope> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// 指定したチップ名の組み込みチップを深さ優先で探す
+    pub fn find_builtin(&self, chip: &str) -> Option<usize> {
+        if self.chip == chip && self.component.is_some() {
+            return self.component;
+        }
+        self.children
+            .iter()
+            .find_map(|child| child.find_builtin(chip))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Netlist {
+    pub nets: usize,
+    pub components: Vec<Component>,
+    pub top: Scope,
+}
+
+/// チップを展開して`Netlist`を作る
+pub fn elaborate(library: &mut Library, chip: Rc<Chip>) -> Result<Netlist, HdlError> {
+    let mut elaborator = Elaborator {
+        library,
+        parents: vec![FALSE, TRUE],
+        components: vec![],
+        stack: vec![],
+    };
+    let mut ports = vec![];
+    for pin in chip.inputs().iter().chain(chip.outputs().iter()) {
+        let nets = elaborator.new_nets(pin.width);
+        ports.push((pin.name.clone(), nets));
+    }
+    let name = chip.name().to_string();
+    let top = elaborator.instantiate(chip, name, ports)?;
+    Ok(elaborator.finish(top))
+}
+
+struct Elaborator<'a> {
+    library: &'a mut Library,
+    /// union-find (同じ信号線としてつながっているものをまとめる)
+    parents: Vec<Net>,
+    components: Vec<Component>,
+    stack: Vec<String>,
+}
+
+impl<'a> Elaborator<'a> {
+    fn new_nets(&mut self, width: usize) -> Vec<Net> {
+        let start = self.parents.len();
+        self.parents.extend(start..start + width);
+        (start..start + width).collect()
+    }
+
+    fn find(&mut self, net: Net) -> Net {
+        let mut root = net;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+        let mut net = net;
+        while self.parents[net] != root {
+            let next = self.parents[net];
+            self.parents[net] = root;
+            net = next;
+        }
+        root
+    }
+
+    /// 定数の信号線が代表になるようにつなぐ
+    fn union(&mut self, a: Net, b: Net) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        if b <= TRUE {
+            self.parents[a] = b;
+        } else {
+            self.parents[b] = a;
+        }
+    }
+
+    fn instantiate(
+        &mut self,
+        chip: Rc<Chip>,
+        name: String,
+        ports: Vec<(String, Vec<Net>)>,
+    ) -> Result<Scope, HdlError> {
+        let def = match &*chip {
+            Chip::Builtin(builtin) => {
+                let nets = |pins: &[(&str, usize)]| {
+                    pins.iter()
+                        .map(|(pin, _)| {
+                            ports
+                                .iter()
+                                .find(|(port, _)| port == pin)
+                                .map(|(_, nets)| nets.clone())
+                                .unwrap()
+                        })
+                        .collect()
+                };
+                self.components.push(Component::Builtin {
+                    builtin,
+                    inputs: nets(builtin.inputs),
+                    outputs: nets(builtin.outputs),
+                    state: builtin.initial_state(),
+                    pending: None,
+                });
+                return Ok(Scope {
+                    name,
+                    chip: builtin.name.to_string(),
+                    pins: ports,
+                    children: vec![],
+                    component: Some(self.components.len() - 1),
+                });
+            }
+            Chip::Hdl(def) => def,
+        };
+        if self.stack.contains(&def.name) {
+            return Err(HdlError::new(
+                &def.path,
+                def.line,
+                format!("chip {} contains itself", def.name),
+            ));
+        }
+        self.stack.push(def.name.clone());
+
+        let mut parts = vec![];
+        for part in def.parts.iter() {
+            parts.push(self.library.resolve(&part.chip, &def.path, part.line)?);
+        }
+
+        // 内部ピンの幅は部品の出力から決める
+        let mut signals: HashMap<String, Vec<Net>> = ports.iter().cloned().collect();
+        let mut widths: Vec<(String, usize)> = vec![];
+        for (part, sub) in def.parts.iter().zip(parts.iter()) {
+            for conn in part.connections.iter() {
+                let external = match &conn.external {
+                    Signal::Pin(pin) if !signals.contains_key(&pin.name) => pin,
+                    _ => continue,
+                };
+                let width = match sub.output_width(&conn.internal.name) {
+                    Some(width) => conn.internal.width(width),
+                    None => continue,
+                };
+                let width = external.start() + width;
+                match widths.iter_mut().find(|(name, _)| *name == external.name) {
+                    Some((_, w)) => *w = (*w).max(width),
+                    None => widths.push((external.name.clone(), width)),
+                }
+            }
+        }
+        let mut internals = vec![];
+        for (pin, width) in widths {
+            let nets = self.new_nets(width);
+            signals.insert(pin.clone(), nets.clone());
+            internals.push((pin, nets));
+        }
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for part in def.parts.iter() {
+            *counts.entry(part.chip.as_str()).or_default() += 1;
+        }
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+
+        let mut children = vec![];
+        for (part, sub) in def.parts.iter().zip(parts) {
+            let mut sub_ports = vec![];
+            for pin in sub.inputs().iter().chain(sub.outputs().iter()) {
+                let nets = self.new_nets(pin.width);
+                sub_ports.push((pin.name.clone(), nets));
+            }
+            let mut connected = vec![false; sub_ports.len()];
+            for conn in part.connections.iter() {
+                let index = match sub_ports
+                    .iter()
+                    .position(|(pin, _)| *pin == conn.internal.name)
+                {
+                    Some(index) => index,
+                    None => {
+                        return Err(HdlError::new(
+                            &def.path,
+                            conn.line,
+                            format!("{} has no pin named {}", sub.name(), conn.internal.name),
+                        ))
+                    }
+                };
+                connected[index] = true;
+                let pin_nets = sub_ports[index].1.clone();
+                let width = conn.internal.width(pin_nets.len());
+                if conn.internal.start() + width > pin_nets.len() {
+                    return Err(HdlError::new(
+                        &def.path,
+                        conn.line,
+                        format!("{} is out of range of {}", conn.internal, sub.name()),
+                    ));
+                }
+                let externals: Vec<Net> = match &conn.external {
+                    Signal::Const(value) => vec![if *value { TRUE } else { FALSE }; width],
+                    Signal::Pin(pin) => {
+                        let nets = match signals.get(&pin.name) {
+                            Some(nets) => nets,
+                            None => {
+                                return Err(HdlError::new(
+                                    &def.path,
+                                    conn.line,
+                                    format!("pin {} is never assigned", pin.name),
+                                ))
+                            }
+                        };
+                        let ext_width = pin.width(nets.len());
+                        if pin.start() + ext_width > nets.len() {
+                            return Err(HdlError::new(
+                                &def.path,
+                                conn.line,
+                                format!("{} is out of range", pin),
+                            ));
+                        }
+                        if ext_width != width {
+                            return Err(HdlError::new(
+                                &def.path,
+                                conn.line,
+                                format!(
+                                    "width mismatch: {} has {} bits but {} has {} bits",
+                                    conn.internal, width, pin, ext_width
+                                ),
+                            ));
+                        }
+                        nets[pin.start()..pin.start() + width].to_vec()
+                    }
+                };
+                for (k, external) in externals.into_iter().enumerate() {
+                    self.union(pin_nets[conn.internal.start() + k], external);
+                }
+            }
+            // つながっていない入力は0
+            for (index, (pin, nets)) in sub_ports.iter().enumerate() {
+                if !connected[index] && sub.input_width(pin).is_some() {
+                    for &net in nets.iter() {
+                        self.union(net, FALSE);
+                    }
+                }
+            }
+
+            let n = seen.entry(part.chip.as_str()).or_default();
+            let instance = if counts[part.chip.as_str()] > 1 {
+                format!("{}#{}", part.chip, n)
+            } else {
+                part.chip.clone()
+            };
+            *n += 1;
+            children.push(self.instantiate(sub, instance, sub_ports)?);
+        }
+
+        self.stack.pop();
+        let mut pins = ports;
+        pins.extend(internals);
+        Ok(Scope {
+            name,
+            chip: def.name.clone(),
+            pins,
+            children,
+            component: None,
+        })
+    }
+
+    /// union-findの代表で信号線の番号を振り直す
+    fn finish(mut self, mut top: Scope) -> Netlist {
+        let mut numbers: HashMap<Net, Net> = HashMap::new();
+        numbers.insert(FALSE, FALSE);
+        numbers.insert(TRUE, TRUE);
+        let mut renumber = |this: &mut Self, net: Net| {
+            let root = this.find(net);
+            let next = numbers.len();
+            *numbers.entry(root).or_insert(next)
+        };
+
+        let mut components = std::mem::take(&mut self.components);
+        for component in components.iter_mut() {
+            match component {
+                Component::Nand { a, b, out } => {
+                    *a = renumber(&mut self, *a);
+                    *b = renumber(&mut self, *b);
+                    *out = renumber(&mut self, *out);
+                }
+                Component::Dff { input, out, .. } => {
+                    *input = renumber(&mut self, *input);
+                    *out = renumber(&mut self, *out);
+                }
+                Component::Builtin {
+                    inputs, outputs, ..
+                } => {
+                    for net in inputs.iter_mut().chain(outputs.iter_mut()).flatten() {
+                        *net = renumber(&mut self, *net);
+                    }
+                }
+            }
+        }
+        let mut scopes = vec![&mut top];
+        while let Some(scope) = scopes.pop() {
+            for (_, nets) in scope.pins.iter_mut() {
+                for net in nets.iter_mut() {
+                    *net = renumber(&mut self, *net);
+                }
+            }
+            scopes.extend(scope.children.iter_mut());
+        }
+
+        // NandとDFFは組み込みチップとして展開されるので、ここで専用の部品に置き換える
+        for component in components.iter_mut() {
+            if let Component::Builtin {
+                builtin,
+                inputs,
+                outputs,
+                ..
+            } = component
+            {
+                if builtin.name == "Nand" {
+                    *component = Component::Nand {
+                        a: inputs[0][0],
+                        b: inputs[1][0],
+                        out: outputs[0][0],
+                    };
+                } else if builtin.name == "DFF" {
+                    *component = Component::Dff {
+                        input: inputs[0][0],
+                        out: outputs[0][0],
+                        state: false,
+                        next: false,
+                    };
+                }
+            }
+        }
+
+        Netlist {
+            nets: numbers.len(),
+            components,
+            top,
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// HDLの読み込み・解析で発生したエラー
+#[derive(Debug, Clone)]
+pub struct HdlError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub message: String,
+}
+
+impl HdlError {
+    pub fn new(path: &Path, line: usize, message: String) -> Self {
+        HdlError {
+            path: path.to_path_buf(),
+            line,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for HdlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)
+    }
+}
+
+impl std::error::Error for HdlError {}
+
+/// `IN`/`OUT`で宣言されたピン
+#[derive(Debug, Clone)]
+pub struct PinDecl {
+    pub name: String,
+    pub width: usize,
+    pub line: usize,
+}
+
+/// `name`または`name[i]`, `name[i..j]`の形のピン参照
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinRef {
+    pub name: String,
+    pub range: Option<(usize, usize)>,
+}
+
+impl fmt::Display for PinRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.range {
+            None => write!(f, "{}", self.name),
+            Some((from, to)) if from == to => write!(f, "{}[{}]", self.name, from),
+            Some((from, to)) => write!(f, "{}[{}..{}]", self.name, from, to),
+        }
+    }
+}
+
+/// 接続の右辺(親チップ側)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signal {
+    Pin(PinRef),
+    Const(bool),
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Signal::Pin(pin) => write!(f, "{}", pin),
+            Signal::Const(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// `a=b`の形の接続
+/// * `internal`: - 部品側のピン
+/// * `external`: - 親チップ側の信号
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub internal: PinRef,
+    pub external: Signal,
+    pub line: usize,
+}
+
+/// PARTSに書かれた部品
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub chip: String,
+    pub connections: Vec<Connection>,
+    pub line: usize,
+}
+
+/// 1つの`CHIP`定義
+#[derive(Debug, Clone)]
+pub struct ChipDef {
+    pub name: String,
+    pub path: PathBuf,
+    pub inputs: Vec<PinDecl>,
+    pub outputs: Vec<PinDecl>,
+    pub parts: Vec<Part>,
+    pub builtin: Option<String>,
+    pub clocked: Vec<String>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(usize),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "`{}`", ident),
+            Token::Number(n) => write!(f, "`{}`", n),
+            Token::Symbol(s) => write!(f, "`{}`", s),
+        }
+    }
+}
+
+/// HDLファイルを読み込んでチップ定義にする
+pub fn parse_file(path: &Path) -> Result<ChipDef, HdlError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| HdlError::new(path, 0, format!("cannot open file: {}", e)))?;
+    parse_str(&contents, path)
+}
+
+pub fn parse_str(contents: &str, path: &Path) -> Result<ChipDef, HdlError> {
+    let tokens = tokenize(contents, path)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        path: path.to_path_buf(),
+    };
+    parser.chip()
+}
+
+fn tokenize(contents: &str, path: &Path) -> Result<Vec<(Token, usize)>, HdlError> {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            let start = line;
+            i += 2;
+            loop {
+                if i + 1 >= chars.len() {
+                    return Err(HdlError::new(
+                        path,
+                        start,
+                        "unterminated comment".to_string(),
+                    ));
+                }
+                if chars[i] == '*' && chars[i + 1] == '/' {
+                    i += 2;
+                    break;
+                }
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((Token::Ident(chars[start..i].iter().collect()), line));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text
+                .parse()
+                .map_err(|_| HdlError::new(path, line, format!("invalid number `{}`", text)))?;
+            tokens.push((Token::Number(number), line));
+        } else if c == '.' && chars.get(i + 1) == Some(&'.') {
+            tokens.push((Token::Symbol(".."), line));
+            i += 2;
+        } else {
+            let symbol = match c {
+                '{' => "{",
+                '}' => "}",
+                '(' => "(",
+                ')' => ")",
+                '[' => "[",
+                ']' => "]",
+                ',' => ",",
+                ';' => ";",
+                ':' => ":",
+                '=' => "=",
+                _ => {
+                    return Err(HdlError::new(
+                        path,
+                        line,
+                        format!("unexpected character `{}`", c),
+                    ))
+                }
+            };
+            tokens.push((Token::Symbol(symbol), line));
+            i += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    path: PathBuf,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some((_, line)) => *line,
+            None => self.tokens.last().map(|(_, line)| *line).unwrap_or(1),
+        }
+    }
+
+    fn error(&self, message: String) -> HdlError {
+        HdlError::new(&self.path, self.line(), message)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token, HdlError> {
+        match self.tokens.get(self.pos) {
+            Some((token, _)) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(self.error(format!("expected {}, found end of file", expected))),
+        }
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(s)) if s == keyword)
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), HdlError> {
+        let line = self.line();
+        match self.next(&format!("`{}`", symbol))? {
+            Token::Symbol(s) if s == symbol => Ok(()),
+            token => Err(HdlError::new(
+                &self.path,
+                line,
+                format!("expected `{}`, found {}", symbol, token),
+            )),
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), HdlError> {
+        let line = self.line();
+        match self.next(&format!("`{}`", keyword))? {
+            Token::Ident(s) if s == keyword => Ok(()),
+            token => Err(HdlError::new(
+                &self.path,
+                line,
+                format!("expected `{}`, found {}", keyword, token),
+            )),
+        }
+    }
+
+    fn ident(&mut self, what: &str) -> Result<String, HdlError> {
+        let line = self.line();
+        match self.next(what)? {
+            Token::Ident(s) => Ok(s),
+            token => Err(HdlError::new(
+                &self.path,
+                line,
+                format!("expected {}, found {}", what, token),
+            )),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, HdlError> {
+        let line = self.line();
+        match self.next("a number")? {
+            Token::Number(n) => Ok(n),
+            token => Err(HdlError::new(
+                &self.path,
+                line,
+                format!("expected a number, found {}", token),
+            )),
+        }
+    }
+
+    fn chip(&mut self) -> Result<ChipDef, HdlError> {
+        let line = self.line();
+        self.expect_keyword("CHIP")?;
+        let name = self.ident("a chip name")?;
+        self.expect_symbol("{")?;
+
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        let mut parts = vec![];
+        let mut builtin = None;
+        let mut clocked = vec![];
+        loop {
+            if self.is_keyword("IN") {
+                self.pos += 1;
+                inputs.extend(self.pin_decls()?);
+            } else if self.is_keyword("OUT") {
+                self.pos += 1;
+                outputs.extend(self.pin_decls()?);
+            } else if self.is_keyword("PARTS") {
+                self.pos += 1;
+                self.expect_symbol(":")?;
+                while !self.is_symbol("}") && self.peek().is_some() {
+                    parts.push(self.part()?);
+                }
+            } else if self.is_keyword("BUILTIN") {
+                self.pos += 1;
+                builtin = Some(self.ident("a builtin chip name")?);
+                self.expect_symbol(";")?;
+            } else if self.is_keyword("CLOCKED") {
+                self.pos += 1;
+                loop {
+                    clocked.push(self.ident("a pin name")?);
+                    if self.is_symbol(",") {
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+                self.expect_symbol(";")?;
+            } else if self.is_symbol("}") {
+                self.pos += 1;
+                break;
+            } else {
+                let line = self.line();
+                let found = self.next("`}`")?;
+                return Err(HdlError::new(
+                    &self.path,
+                    line,
+                    format!("expected `IN`, `OUT`, `PARTS:` or `}}`, found {}", found),
+                ));
+            }
+        }
+
+        Ok(ChipDef {
+            name,
+            path: self.path.clone(),
+            inputs,
+            outputs,
+            parts,
+            builtin,
+            clocked,
+            line,
+        })
+    }
+
+    fn pin_decls(&mut self) -> Result<Vec<PinDecl>, HdlError> {
+        let mut pins = vec![];
+        loop {
+            let line = self.line();
+            let name = self.ident("a pin name")?;
+            let mut width = 1;
+            if self.is_symbol("[") {
+                self.pos += 1;
+                width = self.number()?;
+                self.expect_symbol("]")?;
+            }
+            pins.push(PinDecl { name, width, line });
+            if self.is_symbol(",") {
+                self.pos += 1;
+            } else {
+                self.expect_symbol(";")?;
+                return Ok(pins);
+            }
+        }
+    }
+
+    fn part(&mut self) -> Result<Part, HdlError> {
+        let line = self.line();
+        let chip = self.ident("a part name")?;
+        self.expect_symbol("(")?;
+        let mut connections = vec![];
+        if !self.is_symbol(")") {
+            loop {
+                let conn_line = self.line();
+                let internal = self.pin_ref()?;
+                self.expect_symbol("=")?;
+                let external = if self.is_keyword("true") {
+                    self.pos += 1;
+                    Signal::Const(true)
+                } else if self.is_keyword("false") {
+                    self.pos += 1;
+                    Signal::Const(false)
+                } else {
+                    Signal::Pin(self.pin_ref()?)
+                };
+                connections.push(Connection {
+                    internal,
+                    external,
+                    line: conn_line,
+                });
+                if self.is_symbol(",") {
+                    self.pos += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        self.expect_symbol(")")?;
+        self.expect_symbol(";")?;
+        Ok(Part {
+            chip,
+            connections,
+            line,
+        })
+    }
+
+    fn pin_ref(&mut self) -> Result<PinRef, HdlError> {
+        let name = self.ident("a pin name")?;
+        let mut range = None;
+        if self.is_symbol("[") {
+            self.pos += 1;
+            let from = self.number()?;
+            let to = if self.is_symbol("..") {
+                self.pos += 1;
+                self.number()?
+            } else {
+                from
+            };
+            self.expect_symbol("]")?;
+            if to < from {
+                return Err(self.error(format!("invalid sub bus {}[{}..{}]", name, from, to)));
+            }
+            range = Some((from, to));
+        }
+        Ok(PinRef { name, range })
+    }
+}
+
+impl PinRef {
+    /// 参照しているビット数 (範囲指定がないときは`full_width`)
+    pub fn width(&self, full_width: usize) -> usize {
+        match self.range {
+            Some((from, to)) => to - from + 1,
+            None => full_width,
+        }
+    }
+
+    /// 参照している先頭のビット
+    pub fn start(&self) -> usize {
+        self.range.map(|(from, _)| from).unwrap_or(0)
+    }
+}
+
+impl ChipDef {
+    pub fn input(&self, name: &str) -> Option<&PinDecl> {
+        self.inputs.iter().find(|pin| pin.name == name)
+    }
+
+    pub fn output(&self, name: &str) -> Option<&PinDecl> {
+        self.outputs.iter().find(|pin| pin.name == name)
+    }
+}
@@ -0,0 +1,645 @@
+use crate::library::{Chip, Library};
+use crate::netlist;
+use crate::simulator::Simulator;
+use crate::vcd::VcdWriter;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const MAX_WHILE_ITERATIONS: usize = 1_000_000;
+
+/// テストスクリプト(.tst)のコマンド
+#[derive(Debug, Clone)]
+enum Command {
+    Load(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<OutputSpec>),
+    Set(String, String),
+    Eval,
+    Output,
+    Tick,
+    Tock,
+    Echo(String),
+    /// `ROM32K load Xxx.hack`のような組み込みチップへのファイル読み込み
+    LoadMemory(String, String),
+    Repeat(Option<usize>, Vec<(Command, usize)>),
+    While(Condition, Vec<(Command, usize)>),
+    Ignored,
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    name: String,
+    op: String,
+    value: String,
+}
+
+/// `output-list`の1項目 (`name%B1.16.1`)
+#[derive(Debug, Clone)]
+struct OutputSpec {
+    name: String,
+    format: char,
+    left: usize,
+    len: usize,
+    right: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Separator,
+    Open,
+    Close,
+}
+
+fn tokenize(contents: &str) -> Vec<(Token, usize)> {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i + 1 < chars.len() && !(chars[i] == '*' && chars[i + 1] == '/') {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            tokens.push((
+                Token::Text(chars[start..i.min(chars.len())].iter().collect()),
+                line,
+            ));
+            i += 1;
+        } else if c == ',' || c == ';' {
+            tokens.push((Token::Separator, line));
+            i += 1;
+        } else if c == '{' {
+            tokens.push((Token::Open, line));
+            i += 1;
+        } else if c == '}' {
+            tokens.push((Token::Close, line));
+            i += 1;
+        } else {
+            let start = i;
+            while i < chars.len()
+                && !chars[i].is_whitespace()
+                && !matches!(chars[i], ',' | ';' | '{' | '}' | '"')
+            {
+                i += 1;
+            }
+            tokens.push((Token::Word(chars[start..i].iter().collect()), line));
+        }
+    }
+    tokens
+}
+
+struct ScriptParser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl ScriptParser {
+    fn commands(&mut self, in_block: bool) -> Result<Vec<(Command, usize)>, String> {
+        let mut commands = vec![];
+        loop {
+            match self.tokens.get(self.pos) {
+                None => {
+                    if in_block {
+                        return Err("missing `}`".to_string());
+                    }
+                    return Ok(commands);
+                }
+                Some((Token::Close, line)) => {
+                    if !in_block {
+                        return Err(format!("line {}: unexpected `}}`", line));
+                    }
+                    self.pos += 1;
+                    return Ok(commands);
+                }
+                Some((Token::Separator, _)) => {
+                    self.pos += 1;
+                }
+                Some((_, line)) => {
+                    let line = *line;
+                    let command = self
+                        .command()
+                        .map_err(|e| format!("line {}: {}", line, e))?;
+                    commands.push((command, line));
+                }
+            }
+        }
+    }
+
+    fn words(&mut self) -> Vec<String> {
+        let mut words = vec![];
+        while let Some((token, _)) = self.tokens.get(self.pos) {
+            match token {
+                Token::Word(word) | Token::Text(word) => words.push(word.clone()),
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        words
+    }
+
+    fn block(&mut self) -> Result<Vec<(Command, usize)>, String> {
+        match self.tokens.get(self.pos) {
+            Some((Token::Open, _)) => {
+                self.pos += 1;
+                self.commands(true)
+            }
+            _ => Err("expected `{`".to_string()),
+        }
+    }
+
+    fn command(&mut self) -> Result<Command, String> {
+        let words = self.words();
+        let arg = |n: usize| {
+            words
+                .get(n)
+                .cloned()
+                .ok_or_else(|| format!("missing argument for {}", words[0]))
+        };
+        let command = match words.first().map(|w| w.as_str()) {
+            Some("load") => Command::Load(arg(1)?),
+            Some("output-file") => Command::OutputFile(arg(1)?),
+            Some("compare-to") => Command::CompareTo(arg(1)?),
+            Some("output-list") => Command::OutputList(
+                words[1..]
+                    .iter()
+                    .map(|w| parse_output_spec(w))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Some("set") => Command::Set(arg(1)?, arg(2)?),
+            Some("eval") => Command::Eval,
+            Some("output") => Command::Output,
+            Some("tick") => Command::Tick,
+            Some("tock") => Command::Tock,
+            Some("echo") => Command::Echo(arg(1)?),
+            Some("clear-echo") | Some("breakpoint") | Some("clear-breakpoints") => Command::Ignored,
+            Some("repeat") => {
+                let count = match words.get(1) {
+                    Some(n) => Some(n.parse().map_err(|_| format!("invalid count {}", n))?),
+                    None => None,
+                };
+                Command::Repeat(count, self.block()?)
+            }
+            Some("while") => {
+                if words.len() != 4 {
+                    return Err("expected `while <name> <op> <value>`".to_string());
+                }
+                let condition = Condition {
+                    name: words[1].clone(),
+                    op: words[2].clone(),
+                    value: words[3].clone(),
+                };
+                Command::While(condition, self.block()?)
+            }
+            Some(chip) if words.get(1).map(|w| w.as_str()) == Some("load") => {
+                Command::LoadMemory(chip.to_string(), arg(2)?)
+            }
+            Some(other) => return Err(format!("unknown command {}", other)),
+            None => return Err("expected a command".to_string()),
+        };
+        Ok(command)
+    }
+}
+
+fn parse_output_spec(word: &str) -> Result<OutputSpec, String> {
+    let (name, format) = match word.split_once('%') {
+        Some((name, format)) => (name, Some(format)),
+        None => (word, None),
+    };
+    let format = match format {
+        Some(format) => format,
+        None => {
+            return Ok(OutputSpec {
+                name: name.to_string(),
+                format: 'B',
+                left: 1,
+                // ピンの幅は回路を読み込んだ後で決まる
+                len: 0,
+                right: 1,
+            });
+        }
+    };
+    let mut chars = format.chars();
+    let kind = chars
+        .next()
+        .ok_or_else(|| format!("invalid output format {}", word))?;
+    let numbers: Vec<usize> = chars
+        .as_str()
+        .split('.')
+        .map(|n| n.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid output format {}", word))?;
+    if numbers.len() != 3 || !"BDXS".contains(kind) {
+        return Err(format!("invalid output format {}", word));
+    }
+    Ok(OutputSpec {
+        name: name.to_string(),
+        format: kind,
+        left: numbers[0],
+        len: numbers[1],
+        right: numbers[2],
+    })
+}
+
+/// `%B0101`, `%X2000`, `%D-1`, `-1`などの値を読む
+fn parse_value(text: &str) -> Result<u16, String> {
+    let invalid = || format!("invalid value {}", text);
+    let (radix, digits) = match text.strip_prefix('%') {
+        Some(rest) => {
+            let radix = match rest.chars().next() {
+                Some('B') => 2,
+                Some('X') => 16,
+                Some('D') => 10,
+                _ => return Err(invalid()),
+            };
+            (radix, &rest[1..])
+        }
+        None => (10, text),
+    };
+    let value = i32::from_str_radix(digits, radix).map_err(|_| invalid())?;
+    Ok(value as u16)
+}
+
+/// テストスクリプトを実行する
+pub struct ScriptRunner {
+    dir: PathBuf,
+    library_dirs: Vec<PathBuf>,
+    simulator: Option<Simulator>,
+    output_list: Vec<OutputSpec>,
+    output_file: Option<PathBuf>,
+    lines: Vec<String>,
+    compare: Option<Vec<String>>,
+    vcd: Option<(String, Vec<String>)>,
+    vcd_writer: Option<VcdWriter>,
+}
+
+impl ScriptRunner {
+    /// * `library_dirs`: - スクリプトのディレクトリの次に部品を探すディレクトリ
+    /// * `vcd`: - VCDの出力先と記録するピンのパス
+    pub fn new(library_dirs: Vec<PathBuf>, vcd: Option<(String, Vec<String>)>) -> Self {
+        ScriptRunner {
+            dir: PathBuf::new(),
+            library_dirs,
+            simulator: None,
+            output_list: vec![],
+            output_file: None,
+            lines: vec![],
+            compare: None,
+            vcd,
+            vcd_writer: None,
+        }
+    }
+
+    /// スクリプトを実行する。比較に失敗したときはエラーを返す
+    pub fn run(&mut self, script_path: &Path) -> Result<(), String> {
+        let contents = fs::read_to_string(script_path)
+            .map_err(|e| format!("{}: {}", script_path.display(), e))?;
+        self.dir = script_path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut parser = ScriptParser {
+            tokens: tokenize(&contents),
+            pos: 0,
+        };
+        let commands = parser
+            .commands(false)
+            .map_err(|e| format!("{}: {}", script_path.display(), e))?;
+        let result = self
+            .load_default(script_path, &commands)
+            .and_then(|_| self.execute_all(&commands));
+        self.write_output()?;
+        result.map_err(|e| format!("{}: {}", script_path.display(), e))
+    }
+
+    fn execute_all(&mut self, commands: &[(Command, usize)]) -> Result<(), String> {
+        for (command, line) in commands.iter() {
+            self.execute(command)
+                .map_err(|e| format!("line {}: {}", line, e))?;
+        }
+        Ok(())
+    }
+
+    /// `load`や`compare-to`がないスクリプトは、ファイル名と同じチップと比較ファイルを使う
+    /// (`ALU-basic.tst`なら`ALU`と`ALU-basic.cmp`)
+    fn load_default(
+        &mut self,
+        script_path: &Path,
+        commands: &[(Command, usize)],
+    ) -> Result<(), String> {
+        let stem = script_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        if !commands
+            .iter()
+            .any(|(command, _)| matches!(command, Command::Load(_)))
+        {
+            let name = stem.split('-').next().unwrap_or(stem);
+            let mut library = Library::new(self.library_dirs.clone());
+            library.dirs.insert(0, self.dir.clone());
+            let chip = library
+                .resolve(name, script_path, 0)
+                .map_err(|e| e.to_string())?;
+            self.start(&mut library, chip)?;
+        }
+        let cmp = self.dir.join(format!("{}.cmp", stem));
+        if !commands
+            .iter()
+            .any(|(command, _)| matches!(command, Command::CompareTo(_)))
+            && cmp.exists()
+        {
+            self.execute(&Command::CompareTo(format!("{}.cmp", stem)))?;
+        }
+        Ok(())
+    }
+
+    /// チップを展開してシミュレーションを始める
+    fn start(&mut self, library: &mut Library, chip: Rc<Chip>) -> Result<(), String> {
+        let netlist = netlist::elaborate(library, chip).map_err(|e| e.to_string())?;
+        let simulator = Simulator::new(netlist)?;
+        if let Some((path, probes)) = &self.vcd {
+            self.vcd_writer = Some(VcdWriter::new(path, &simulator, probes)?);
+        }
+        self.simulator = Some(simulator);
+        self.sample()
+    }
+
+    fn simulator(&mut self) -> Result<&mut Simulator, String> {
+        self.simulator
+            .as_mut()
+            .ok_or_else(|| "no chip is loaded".to_string())
+    }
+
+    fn execute(&mut self, command: &Command) -> Result<(), String> {
+        match command {
+            Command::Load(file) => {
+                let mut library = Library::new(self.library_dirs.clone());
+                let chip = library
+                    .load_file(&self.dir.join(file))
+                    .map_err(|e| e.to_string())?;
+                self.start(&mut library, chip)?;
+            }
+            Command::OutputFile(file) => {
+                self.output_file = Some(self.dir.join(file));
+            }
+            Command::CompareTo(file) => {
+                let path = self.dir.join(file);
+                let contents =
+                    fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                self.compare = Some(contents.lines().map(|line| line.to_string()).collect());
+            }
+            Command::OutputList(specs) => {
+                let simulator = self.simulator()?;
+                let mut specs = specs.clone();
+                for spec in specs.iter_mut().filter(|spec| spec.len == 0) {
+                    spec.len = simulator.find_pin(&spec.name).map_or(16, |nets| nets.len());
+                }
+                let header: String = specs.iter().map(header_cell).collect();
+                self.output_list = specs;
+                self.emit(format!("{}|", header))?;
+            }
+            Command::Set(name, value) => {
+                let value = parse_value(value)?;
+                let simulator = self.simulator()?;
+                match parse_state_ref(name) {
+                    Some((chip, address)) => {
+                        if !simulator.write_state(&chip, address, value) {
+                            return Err(format!("no builtin chip {} to set", chip));
+                        }
+                    }
+                    None => {
+                        let nets = simulator
+                            .netlist
+                            .top
+                            .pin(name)
+                            .cloned()
+                            .ok_or_else(|| format!("no pin named {}", name))?;
+                        simulator.write(&nets, value);
+                    }
+                }
+            }
+            Command::Eval => {
+                self.simulator()?.eval();
+                self.sample()?;
+            }
+            Command::Tick => {
+                self.simulator()?.tick();
+                self.sample()?;
+            }
+            Command::Tock => {
+                self.simulator()?.tock();
+                self.sample()?;
+            }
+            Command::Output => {
+                let simulator = self.simulator.as_ref().ok_or("no chip is loaded")?;
+                let mut line = String::new();
+                for spec in self.output_list.iter() {
+                    line.push_str(&value_cell(spec, simulator)?);
+                }
+                line.push('|');
+                self.emit(line)?;
+            }
+            Command::Echo(text) => println!("{}", text),
+            Command::LoadMemory(chip, file) => {
+                let path = self.dir.join(file);
+                let contents =
+                    fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                let simulator = self.simulator()?;
+                let words = contents
+                    .lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty());
+                for (address, word) in words.enumerate() {
+                    let value = u16::from_str_radix(word, 2)
+                        .map_err(|_| format!("{}: invalid instruction {}", path.display(), word))?;
+                    if !simulator.write_state(chip, address, value) {
+                        return Err(format!("no builtin chip {} to load", chip));
+                    }
+                }
+            }
+            Command::Repeat(count, body) => match count {
+                Some(count) => {
+                    for _ in 0..*count {
+                        self.execute_all(body)?;
+                    }
+                }
+                None => loop {
+                    self.execute_all(body)?;
+                },
+            },
+            Command::While(condition, body) => {
+                // キーボード入力を待つループなどで止まらないようにする
+                let mut count = 0;
+                while self.check(condition)? {
+                    count += 1;
+                    if count > MAX_WHILE_ITERATIONS {
+                        return Err(format!(
+                            "while loop did not end after {} iterations",
+                            MAX_WHILE_ITERATIONS
+                        ));
+                    }
+                    self.execute_all(body)?;
+                }
+            }
+            Command::Ignored => {}
+        }
+        Ok(())
+    }
+
+    fn check(&self, condition: &Condition) -> Result<bool, String> {
+        let simulator = self.simulator.as_ref().ok_or("no chip is loaded")?;
+        let left = read_variable(simulator, &condition.name)? as i16;
+        let right = parse_value(&condition.value)? as i16;
+        match condition.op.as_str() {
+            "=" => Ok(left == right),
+            "<>" => Ok(left != right),
+            "<" => Ok(left < right),
+            ">" => Ok(left > right),
+            "<=" => Ok(left <= right),
+            ">=" => Ok(left >= right),
+            op => Err(format!("unknown operator {}", op)),
+        }
+    }
+
+    fn sample(&mut self) -> Result<(), String> {
+        if let (Some(writer), Some(simulator)) = (self.vcd_writer.as_mut(), self.simulator.as_ref())
+        {
+            writer.sample(simulator).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// 出力に1行追加して、比較ファイルの同じ行と比べる
+    fn emit(&mut self, line: String) -> Result<(), String> {
+        self.lines.push(line);
+        let index = self.lines.len() - 1;
+        if let Some(expected) = self.compare.as_ref().and_then(|lines| lines.get(index)) {
+            if !matches_line(&self.lines[index], expected) {
+                return Err(format!(
+                    "comparison failure at line {}\n  expected: {}\n  actual:   {}",
+                    index + 1,
+                    expected,
+                    self.lines[index]
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_output(&self) -> Result<(), String> {
+        if let Some(path) = &self.output_file {
+            let mut contents = self.lines.join("\n");
+            contents.push('\n');
+            fs::write(path, contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+/// 比較ファイルの`*`は任意の1文字に一致する
+fn matches_line(actual: &str, expected: &str) -> bool {
+    let actual: Vec<char> = actual.trim_end().chars().collect();
+    let expected: Vec<char> = expected.trim_end().chars().collect();
+    actual.len() == expected.len()
+        && actual
+            .iter()
+            .zip(expected.iter())
+            .all(|(a, e)| *e == '*' || a == e)
+}
+
+/// `RAM16K[3]`や`DRegister[]`を(チップ名, アドレス)にする
+fn parse_state_ref(name: &str) -> Option<(String, usize)> {
+    let (chip, rest) = name.split_once('[')?;
+    let index = rest.strip_suffix(']')?;
+    let address = if index.is_empty() {
+        0
+    } else {
+        index.parse().ok()?
+    };
+    Some((chip.to_string(), address))
+}
+
+fn read_variable(simulator: &Simulator, name: &str) -> Result<u16, String> {
+    if let Some(nets) = simulator.netlist.top.pin(name) {
+        return Ok(simulator.read(nets));
+    }
+    if let Some((chip, address)) = parse_state_ref(name) {
+        if let Some(nets) = simulator.netlist.top.pin(&chip) {
+            // ピンの1ビットだけを読む
+            return Ok((simulator.read(nets) >> address) & 1);
+        }
+        if let Some(value) = simulator.read_state(&chip, address) {
+            return Ok(value);
+        }
+    }
+    Err(format!("no pin or builtin chip named {}", name))
+}
+
+fn header_cell(spec: &OutputSpec) -> String {
+    let width = spec.left + spec.len + spec.right;
+    let name: String = spec.name.chars().take(width).collect();
+    let left = (width - name.len()) / 2;
+    let right = width - name.len() - left;
+    format!("|{}{}{}", " ".repeat(left), name, " ".repeat(right))
+}
+
+fn value_cell(spec: &OutputSpec, simulator: &Simulator) -> Result<String, String> {
+    let text = if spec.name == "time" {
+        format!("{:<w$}", simulator.time_string(), w = spec.len)
+    } else {
+        let value = read_variable(simulator, &spec.name)?;
+        let width = simulator
+            .netlist
+            .top
+            .pin(&spec.name)
+            .map(|nets| nets.len())
+            .unwrap_or(16);
+        match spec.format {
+            'B' => {
+                let bits = format!("{:016b}", value);
+                bits[16 - spec.len.min(16)..].to_string()
+            }
+            'X' => {
+                let digits = format!("{:04X}", value);
+                format!("{:>w$}", &digits[4 - spec.len.min(4)..], w = spec.len)
+            }
+            'D' => {
+                let number = if width == 16 {
+                    (value as i16).to_string()
+                } else {
+                    value.to_string()
+                };
+                format!("{:>w$}", number, w = spec.len)
+            }
+            _ => format!("{:<w$}", value, w = spec.len),
+        }
+    };
+    Ok(format!(
+        "|{}{}{}",
+        " ".repeat(spec.left),
+        text,
+        " ".repeat(spec.right)
+    ))
+}
@@ -0,0 +1,309 @@
+use crate::netlist::{Component, Net, Netlist, Scope, TRUE};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+/// 展開した回路をクロック単位で動かす
+///
+/// 入力が変わった部品だけを評価順に評価し直す
+pub struct Simulator {
+    pub netlist: Netlist,
+    values: Vec<bool>,
+    /// 組み合わせ回路として評価する順番
+    order: Vec<usize>,
+    /// 部品ごとの`order`での位置
+    positions: Vec<usize>,
+    /// 信号線ごとに、それを組み合わせ回路の入力とする部品の`order`での位置
+    readers: Vec<Vec<usize>>,
+    /// 評価し直す必要がある`order`での位置
+    dirty: Vec<bool>,
+    queue: BinaryHeap<Reverse<usize>>,
+    /// 経過したクロック数
+    pub time: usize,
+    /// tickの後でtockの前か
+    pub half: bool,
+}
+
+impl Simulator {
+    pub fn new(netlist: Netlist) -> Result<Self, String> {
+        let order = evaluation_order(&netlist)?;
+        let mut positions = vec![0; order.len()];
+        let mut readers: Vec<Vec<usize>> = vec![vec![]; netlist.nets];
+        for (position, &index) in order.iter().enumerate() {
+            positions[index] = position;
+            for net in comb_inputs(&netlist.components[index]) {
+                readers[net].push(position);
+            }
+        }
+        let mut values = vec![false; netlist.nets];
+        values[TRUE] = true;
+        let mut simulator = Simulator {
+            netlist,
+            values,
+            dirty: vec![true; order.len()],
+            queue: (0..order.len()).map(Reverse).collect(),
+            order,
+            positions,
+            readers,
+            time: 0,
+            half: false,
+        };
+        simulator.eval();
+        Ok(simulator)
+    }
+
+    pub fn read(&self, nets: &[Net]) -> u16 {
+        nets.iter().enumerate().fold(0, |acc, (bit, &net)| {
+            acc | ((self.values[net] as u16) << bit)
+        })
+    }
+
+    pub fn write(&mut self, nets: &[Net], value: u16) {
+        for (bit, &net) in nets.iter().enumerate() {
+            self.set(net, (value >> bit) & 1 == 1);
+        }
+    }
+
+    /// 信号線の値を変えて、それを読む部品を評価し直す対象にする
+    fn set(&mut self, net: Net, value: bool) {
+        if net <= TRUE || self.values[net] == value {
+            return;
+        }
+        self.values[net] = value;
+        for &position in self.readers[net].iter() {
+            if !self.dirty[position] {
+                self.dirty[position] = true;
+                self.queue.push(Reverse(position));
+            }
+        }
+    }
+
+    /// 部品を評価し直す対象にする (内部状態が変わったとき)
+    fn touch(&mut self, index: usize) {
+        let position = self.positions[index];
+        if !self.dirty[position] {
+            self.dirty[position] = true;
+            self.queue.push(Reverse(position));
+        }
+    }
+
+    /// 組み合わせ回路を評価する
+    pub fn eval(&mut self) {
+        let mut outputs: Vec<(Net, bool)> = vec![];
+        while let Some(Reverse(position)) = self.queue.pop() {
+            self.dirty[position] = false;
+            outputs.clear();
+            match &self.netlist.components[self.order[position]] {
+                Component::Nand { a, b, out } => {
+                    outputs.push((*out, !(self.values[*a] && self.values[*b])));
+                }
+                Component::Dff { out, state, .. } => outputs.push((*out, *state)),
+                Component::Builtin {
+                    builtin,
+                    inputs,
+                    outputs: nets,
+                    state,
+                    ..
+                } => {
+                    let inputs: Vec<u16> = inputs.iter().map(|nets| self.read(nets)).collect();
+                    let values = builtin.eval(&inputs, state);
+                    for (nets, value) in nets.iter().zip(values) {
+                        for (bit, &net) in nets.iter().enumerate() {
+                            outputs.push((net, (value >> bit) & 1 == 1));
+                        }
+                    }
+                }
+            }
+            for &(net, value) in outputs.iter() {
+                self.set(net, value);
+            }
+        }
+    }
+
+    /// クロックの前半: 組み合わせ回路を評価して、クロック付きのチップが入力を取り込む
+    pub fn tick(&mut self) {
+        self.eval();
+        let values = &self.values;
+        let read = |nets: &Vec<Net>| {
+            nets.iter()
+                .enumerate()
+                .fold(0, |acc, (bit, &net)| acc | ((values[net] as u16) << bit))
+        };
+        for component in self.netlist.components.iter_mut() {
+            match component {
+                Component::Dff { input, next, .. } => *next = values[*input],
+                Component::Builtin {
+                    builtin,
+                    inputs,
+                    state,
+                    pending,
+                    ..
+                } if builtin.clocked => {
+                    let inputs: Vec<u16> = inputs.iter().map(read).collect();
+                    *pending = builtin.clock(&inputs, state);
+                }
+                _ => {}
+            }
+        }
+        self.half = true;
+    }
+
+    /// クロックの後半: 取り込んだ値を出力に反映する
+    pub fn tock(&mut self) {
+        let mut changed = vec![];
+        let mut outputs = vec![];
+        for (index, component) in self.netlist.components.iter_mut().enumerate() {
+            match component {
+                Component::Dff {
+                    out, state, next, ..
+                } if *state != *next => {
+                    *state = *next;
+                    outputs.push((*out, *state));
+                }
+                Component::Builtin { state, pending, .. } => {
+                    if let Some((address, value)) = pending.take() {
+                        state.write(address, value);
+                        changed.push(index);
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (net, value) in outputs {
+            self.set(net, value);
+        }
+        for index in changed {
+            self.touch(index);
+        }
+        self.eval();
+        self.time += 1;
+        self.half = false;
+    }
+
+    /// `time`の表示 (`3`や`3+`)
+    pub fn time_string(&self) -> String {
+        if self.half {
+            format!("{}+", self.time)
+        } else {
+            format!("{}", self.time)
+        }
+    }
+
+    /// `CPU.ALU.zr`のようなパスでピンを探す (先頭のチップ名は省略できる)
+    pub fn find_pin(&self, path: &str) -> Option<&Vec<Net>> {
+        let (scope, pin) = match path.rsplit_once('.') {
+            Some((scope, pin)) => (self.find_scope(scope)?, pin),
+            None => (&self.netlist.top, path),
+        };
+        scope.pin(pin)
+    }
+
+    /// `CPU.ALU`のようなパスで部品を探す (先頭のチップ名は省略できる)
+    pub fn find_scope(&self, path: &str) -> Option<&Scope> {
+        let top = &self.netlist.top;
+        let mut names = path.split('.').peekable();
+        if names.peek() == Some(&top.name.as_str()) {
+            names.next();
+        }
+        let mut scope = top;
+        for name in names {
+            scope = scope.child(name)?;
+        }
+        Some(scope)
+    }
+
+    /// 組み込みチップの内部状態を読む (`RAM16K[3]`, `DRegister[]`)
+    ///
+    /// 内部状態はtickで書き換わる (出力に反映されるのはtock)
+    pub fn read_state(&self, chip: &str, address: usize) -> Option<u16> {
+        let index = self.netlist.top.find_builtin(chip)?;
+        match &self.netlist.components[index] {
+            Component::Builtin { pending, .. } if pending.map(|(a, _)| a) == Some(address) => {
+                pending.map(|(_, value)| value)
+            }
+            Component::Builtin { state, .. } => state.read(address),
+            Component::Dff { next, .. } if self.half && address == 0 => Some(*next as u16),
+            Component::Dff { state, .. } if address == 0 => Some(*state as u16),
+            _ => None,
+        }
+    }
+
+    /// 組み込みチップの内部状態を書き換える
+    pub fn write_state(&mut self, chip: &str, address: usize, value: u16) -> bool {
+        let index = match self.netlist.top.find_builtin(chip) {
+            Some(index) => index,
+            None => return false,
+        };
+        match &mut self.netlist.components[index] {
+            Component::Builtin { state, .. } => state.write(address, value),
+            Component::Dff { out, state, .. } if address == 0 => {
+                *state = value & 1 == 1;
+                let (out, state) = (*out, *state);
+                self.set(out, state);
+                return true;
+            }
+            _ => return false,
+        }
+        self.touch(index);
+        true
+    }
+}
+
+/// 組み合わせ回路の依存関係で部品を並べる
+fn evaluation_order(netlist: &Netlist) -> Result<Vec<usize>, String> {
+    let mut drivers: HashMap<Net, usize> = HashMap::new();
+    for (index, component) in netlist.components.iter().enumerate() {
+        match component {
+            Component::Nand { out, .. } | Component::Dff { out, .. } => {
+                drivers.insert(*out, index);
+            }
+            Component::Builtin { outputs, .. } => {
+                for &net in outputs.iter().flatten() {
+                    drivers.insert(net, index);
+                }
+            }
+        }
+    }
+
+    let mut edges: Vec<Vec<usize>> = vec![vec![]; netlist.components.len()];
+    let mut indegree = vec![0; netlist.components.len()];
+    for (index, component) in netlist.components.iter().enumerate() {
+        for net in comb_inputs(component) {
+            if let Some(&driver) = drivers.get(&net) {
+                edges[driver].push(index);
+                indegree[index] += 1;
+            }
+        }
+    }
+
+    let mut queue: VecDeque<usize> = (0..indegree.len()).filter(|&i| indegree[i] == 0).collect();
+    let mut order = vec![];
+    while let Some(index) = queue.pop_front() {
+        order.push(index);
+        for &next in edges[index].iter() {
+            indegree[next] -= 1;
+            if indegree[next] == 0 {
+                queue.push_back(next);
+            }
+        }
+    }
+    if order.len() != netlist.components.len() {
+        return Err(format!("combinational loop in chip {}", netlist.top.chip));
+    }
+    Ok(order)
+}
+
+/// 組み合わせ回路として出力に影響する入力の信号線
+fn comb_inputs(component: &Component) -> Vec<Net> {
+    match component {
+        Component::Nand { a, b, .. } => vec![*a, *b],
+        Component::Dff { .. } => vec![],
+        Component::Builtin {
+            builtin, inputs, ..
+        } => builtin
+            .comb_inputs
+            .iter()
+            .filter_map(|name| builtin.input_index(name))
+            .flat_map(|i| inputs[i].iter().copied())
+            .collect(),
+    }
+}
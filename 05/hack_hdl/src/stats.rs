@@ -0,0 +1,463 @@
+use crate::library::{Chip, Library};
+use crate::parser::{HdlError, Signal};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::rc::Rc;
+
+/// チップ1種類あたりの統計 (部品は再帰的に展開して数える)
+#[derive(Debug, Clone)]
+pub struct ChipStats {
+    pub name: String,
+    /// PARTSに直接書かれた部品の数
+    pub parts: usize,
+    /// 再帰的に展開したときの部品の総数
+    pub instances: u64,
+    pub nands: u64,
+    pub dffs: u64,
+    /// 組み合わせ回路の最長経路 (Nandの段数)
+    pub depth: u32,
+    /// HDLがなくNandの数が分からない組み込みチップ
+    pub opaque: BTreeSet<String>,
+    /// このチップが使っているチップ (PARTSに書かれた順)
+    pub children: Vec<String>,
+    timing: Timing,
+}
+
+/// ピン(ビット)間の遅延
+///
+/// 入力・出力はそれぞれ宣言順にビットを並べたインデックスで表す
+#[derive(Debug, Clone, Default)]
+struct Timing {
+    inputs: Vec<(String, usize)>,
+    outputs: Vec<(String, usize)>,
+    /// `comb[o][i]`: 入力ビットiから出力ビットoまでの最長の段数
+    comb: Vec<Vec<Option<u32>>>,
+    /// DFFの出力から出力ビットまでの最長の段数
+    src: Vec<Option<u32>>,
+    /// 入力ビットからDFFの入力までの最長の段数
+    sink: Vec<Option<u32>>,
+    /// DFFからDFFまでの最長の段数
+    internal: Option<u32>,
+}
+
+impl Timing {
+    fn new(inputs: Vec<(String, usize)>, outputs: Vec<(String, usize)>) -> Self {
+        let in_bits = inputs.iter().map(|(_, width)| width).sum();
+        let out_bits: usize = outputs.iter().map(|(_, width)| width).sum();
+        Timing {
+            inputs,
+            outputs,
+            comb: vec![vec![None; in_bits]; out_bits],
+            src: vec![None; out_bits],
+            sink: vec![None; in_bits],
+            internal: None,
+        }
+    }
+
+    fn offset(pins: &[(String, usize)], name: &str) -> Option<(usize, usize)> {
+        let mut offset = 0;
+        for (pin, width) in pins {
+            if pin == name {
+                return Some((offset, *width));
+            }
+            offset += width;
+        }
+        None
+    }
+
+    fn depth(&self) -> u32 {
+        self.comb
+            .iter()
+            .flatten()
+            .chain(self.src.iter())
+            .chain(self.sink.iter())
+            .chain(std::iter::once(&self.internal))
+            .filter_map(|depth| *depth)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// チップの統計を再帰的に計算する
+pub struct StatsBuilder<'a> {
+    library: &'a mut Library,
+    stats: HashMap<String, ChipStats>,
+    visiting: Vec<String>,
+}
+
+impl<'a> StatsBuilder<'a> {
+    pub fn new(library: &'a mut Library) -> Self {
+        StatsBuilder {
+            library,
+            stats: HashMap::new(),
+            visiting: vec![],
+        }
+    }
+
+    /// `chip`とその部品の統計を、`chip`から深さ優先でたどった順に返す
+    pub fn build(&mut self, chip: Rc<Chip>) -> Result<Vec<ChipStats>, HdlError> {
+        self.chip_stats(&chip)?;
+        let mut order = vec![];
+        let mut stack = vec![chip.name().to_string()];
+        while let Some(name) = stack.pop() {
+            if order.contains(&name) {
+                continue;
+            }
+            let stats = &self.stats[&name];
+            for child in stats.children.iter().rev() {
+                stack.push(child.clone());
+            }
+            order.push(name);
+        }
+        Ok(order
+            .into_iter()
+            .map(|name| self.stats[&name].clone())
+            .collect())
+    }
+
+    fn chip_stats(&mut self, chip: &Chip) -> Result<ChipStats, HdlError> {
+        if let Some(stats) = self.stats.get(chip.name()) {
+            return Ok(stats.clone());
+        }
+        let stats = match chip {
+            Chip::Builtin(builtin) => {
+                let mut timing = Timing::new(pins(&chip.inputs()), pins(&chip.outputs()));
+                let (mut nands, mut dffs) = (0, 0);
+                let mut opaque = BTreeSet::new();
+                match builtin.name {
+                    "Nand" => {
+                        nands = 1;
+                        timing.comb = vec![vec![Some(1), Some(1)]];
+                    }
+                    "DFF" => {
+                        dffs = 1;
+                        timing.src = vec![Some(0)];
+                        timing.sink = vec![Some(0)];
+                    }
+                    _ => {
+                        // HDLがないので中身は分からない。クロックと組み合わせ入力の関係だけ反映する
+                        opaque.insert(builtin.name.to_string());
+                        for name in builtin.comb_inputs {
+                            let (offset, width) = Timing::offset(&timing.inputs, name).unwrap();
+                            for row in timing.comb.iter_mut() {
+                                for depth in row[offset..offset + width].iter_mut() {
+                                    *depth = Some(0);
+                                }
+                            }
+                        }
+                        if builtin.clocked {
+                            timing.src.iter_mut().for_each(|depth| *depth = Some(0));
+                            timing.sink.iter_mut().for_each(|depth| *depth = Some(0));
+                        }
+                    }
+                }
+                ChipStats {
+                    name: builtin.name.to_string(),
+                    parts: 0,
+                    instances: 0,
+                    nands,
+                    dffs,
+                    depth: timing.depth(),
+                    opaque,
+                    children: vec![],
+                    timing,
+                }
+            }
+            Chip::Hdl(def) => {
+                if self.visiting.contains(&def.name) {
+                    return Err(HdlError::new(
+                        &def.path,
+                        def.line,
+                        format!("chip {} contains itself", def.name),
+                    ));
+                }
+                self.visiting.push(def.name.clone());
+                let stats = self.hdl_stats(chip);
+                self.visiting.pop();
+                stats?
+            }
+        };
+        self.stats.insert(stats.name.clone(), stats.clone());
+        Ok(stats)
+    }
+
+    fn resolve(&mut self, name: &str, chip: &Chip, line: usize) -> Result<Rc<Chip>, HdlError> {
+        let def = match chip {
+            Chip::Hdl(def) => def,
+            Chip::Builtin(_) => unreachable!(),
+        };
+        let part = self.library.resolve(name, &def.path, line)?;
+        // ARegister/DRegisterは常に組み込みなので、Registerの実装で代用して数える
+        if let Chip::Builtin(builtin) = &*part {
+            if builtin.name == "ARegister" || builtin.name == "DRegister" {
+                if let Ok(register) = self.library.resolve("Register", &def.path, line) {
+                    if let Chip::Hdl(_) = &*register {
+                        return Ok(register);
+                    }
+                }
+            }
+        }
+        Ok(part)
+    }
+
+    fn hdl_stats(&mut self, chip: &Chip) -> Result<ChipStats, HdlError> {
+        let def = match chip {
+            Chip::Hdl(def) => def,
+            Chip::Builtin(_) => unreachable!(),
+        };
+        let mut timing = Timing::new(pins(&def.inputs), pins(&def.outputs));
+        let in_bits = timing.sink.len();
+
+        let mut parts = vec![];
+        let mut children: Vec<String> = vec![];
+        let (mut instances, mut nands, mut dffs) = (0, 0, 0);
+        let mut opaque = BTreeSet::new();
+        let mut internal = None;
+        for part in def.parts.iter() {
+            let sub_chip = self.resolve(&part.chip, chip, part.line)?;
+            let sub = self.chip_stats(&sub_chip)?;
+            instances += 1 + sub.instances;
+            nands += sub.nands;
+            dffs += sub.dffs;
+            opaque.extend(sub.opaque.iter().cloned());
+            internal = internal.max(sub.timing.internal);
+            if !children.contains(&sub.name) {
+                children.push(sub.name.clone());
+            }
+            parts.push(sub);
+        }
+
+        // ノード: 親の入力ビット → 各部品の出力ビット の順に番号を振る
+        let mut part_offsets = vec![];
+        let mut nodes = in_bits;
+        for sub in parts.iter() {
+            part_offsets.push(nodes);
+            nodes += sub.timing.src.len();
+        }
+
+        // 信号名(ビットごと) → それを駆動するノード
+        let mut drivers: HashMap<String, Vec<Option<usize>>> = HashMap::new();
+        let mut offset = 0;
+        for (name, width) in timing.inputs.iter() {
+            drivers.insert(name.clone(), (offset..offset + width).map(Some).collect());
+            offset += width;
+        }
+        for (index, part) in def.parts.iter().enumerate() {
+            let sub = &parts[index];
+            for conn in part.connections.iter() {
+                let (pin_offset, pin_width) =
+                    match Timing::offset(&sub.timing.outputs, &conn.internal.name) {
+                        Some(found) => found,
+                        None => continue,
+                    };
+                let external = match &conn.external {
+                    Signal::Pin(pin) => pin,
+                    Signal::Const(_) => continue,
+                };
+                let width = conn.internal.width(pin_width);
+                let bits = drivers.entry(external.name.clone()).or_default();
+                for k in 0..width {
+                    let bit = external.start() + k;
+                    if bits.len() <= bit {
+                        bits.resize(bit + 1, None);
+                    }
+                    bits[bit] = Some(part_offsets[index] + pin_offset + conn.internal.start() + k);
+                }
+            }
+        }
+
+        // 各部品の入力ビット → それを駆動するノード
+        let mut part_inputs: Vec<Vec<Option<usize>>> = vec![];
+        for (index, part) in def.parts.iter().enumerate() {
+            let sub = &parts[index];
+            let mut inputs = vec![None; sub.timing.sink.len()];
+            for conn in part.connections.iter() {
+                let (pin_offset, pin_width) =
+                    match Timing::offset(&sub.timing.inputs, &conn.internal.name) {
+                        Some(found) => found,
+                        None => {
+                            if Timing::offset(&sub.timing.outputs, &conn.internal.name).is_none() {
+                                return Err(HdlError::new(
+                                    &def.path,
+                                    conn.line,
+                                    format!("{} has no pin named {}", sub.name, conn.internal.name),
+                                ));
+                            }
+                            continue;
+                        }
+                    };
+                let external = match &conn.external {
+                    Signal::Pin(pin) => pin,
+                    Signal::Const(_) => continue,
+                };
+                let width = conn.internal.width(pin_width);
+                for k in 0..width {
+                    let driver = drivers
+                        .get(&external.name)
+                        .and_then(|bits| bits.get(external.start() + k))
+                        .copied()
+                        .flatten();
+                    if let Some(input) = inputs.get_mut(pin_offset + conn.internal.start() + k) {
+                        *input = driver;
+                    }
+                }
+            }
+            part_inputs.push(inputs);
+        }
+
+        // 部品の入力ビット → 出力ビット の辺
+        let mut edges: Vec<Vec<(usize, u32)>> = vec![vec![]; nodes];
+        for (index, sub) in parts.iter().enumerate() {
+            for (o, row) in sub.timing.comb.iter().enumerate() {
+                for (i, depth) in row.iter().enumerate() {
+                    if let (Some(depth), Some(from)) = (depth, part_inputs[index][i]) {
+                        edges[from].push((part_offsets[index] + o, *depth));
+                    }
+                }
+            }
+        }
+        let order = topological_order(&edges).ok_or_else(|| {
+            HdlError::new(
+                &def.path,
+                def.line,
+                format!("combinational loop in chip {}", def.name),
+            )
+        })?;
+
+        let longest = |init: Vec<Option<u32>>| {
+            let mut dist = init;
+            for &node in order.iter() {
+                if let Some(d) = dist[node] {
+                    for &(to, w) in edges[node].iter() {
+                        dist[to] = dist[to].max(Some(d + w));
+                    }
+                }
+            }
+            dist
+        };
+        let to_sinks = |dist: &[Option<u32>]| {
+            let mut best = None;
+            for (index, sub) in parts.iter().enumerate() {
+                for (i, sink) in sub.timing.sink.iter().enumerate() {
+                    if let (Some(sink), Some(from)) = (sink, part_inputs[index][i]) {
+                        if let Some(d) = dist[from] {
+                            best = best.max(Some(d + sink));
+                        }
+                    }
+                }
+            }
+            best
+        };
+        let output_drivers: Vec<Option<usize>> = timing
+            .outputs
+            .iter()
+            .flat_map(|(name, width)| {
+                let bits = drivers.get(name);
+                (0..*width).map(move |bit| bits.and_then(|bits| bits.get(bit).copied().flatten()))
+            })
+            .collect();
+
+        for input in 0..in_bits {
+            let mut init = vec![None; nodes];
+            init[input] = Some(0);
+            let dist = longest(init);
+            for (o, driver) in output_drivers.iter().enumerate() {
+                timing.comb[o][input] = driver.and_then(|node| dist[node]);
+            }
+            timing.sink[input] = to_sinks(&dist);
+        }
+
+        let mut init = vec![None; nodes];
+        for (index, sub) in parts.iter().enumerate() {
+            for (o, src) in sub.timing.src.iter().enumerate() {
+                init[part_offsets[index] + o] = *src;
+            }
+        }
+        let dist = longest(init);
+        for (o, driver) in output_drivers.iter().enumerate() {
+            timing.src[o] = driver.and_then(|node| dist[node]);
+        }
+        timing.internal = internal.max(to_sinks(&dist));
+
+        Ok(ChipStats {
+            name: def.name.clone(),
+            parts: def.parts.len(),
+            instances,
+            nands,
+            dffs,
+            depth: timing.depth(),
+            opaque,
+            children,
+            timing,
+        })
+    }
+}
+
+fn pins(decls: &[crate::parser::PinDecl]) -> Vec<(String, usize)> {
+    decls
+        .iter()
+        .map(|pin| (pin.name.clone(), pin.width))
+        .collect()
+}
+
+/// 辺の向きに沿ったノードの順番 (閉路があるときはNone)
+fn topological_order(edges: &[Vec<(usize, u32)>]) -> Option<Vec<usize>> {
+    let mut indegree = vec![0; edges.len()];
+    for list in edges.iter() {
+        for &(to, _) in list.iter() {
+            indegree[to] += 1;
+        }
+    }
+    let mut queue: VecDeque<usize> = (0..edges.len()).filter(|&n| indegree[n] == 0).collect();
+    let mut order = vec![];
+    while let Some(node) = queue.pop_front() {
+        order.push(node);
+        for &(to, _) in edges[node].iter() {
+            indegree[to] -= 1;
+            if indegree[to] == 0 {
+                queue.push_back(to);
+            }
+        }
+    }
+    if order.len() == edges.len() {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+/// 統計を表にして表示する
+pub fn print_report(stats: &[ChipStats]) {
+    let name_width = stats.iter().map(|s| s.name.len()).max().unwrap_or(4).max(4);
+    println!(
+        "{:<w$} {:>6} {:>10} {:>10} {:>8} {:>6}",
+        "chip",
+        "parts",
+        "instances",
+        "nand",
+        "dff",
+        "depth",
+        w = name_width
+    );
+    for s in stats.iter() {
+        println!(
+            "{:<w$} {:>6} {:>10} {:>10} {:>8} {:>6}",
+            s.name,
+            s.parts,
+            s.instances,
+            s.nands,
+            s.dffs,
+            s.depth,
+            w = name_width
+        );
+    }
+    if let Some(top) = stats.first() {
+        if !top.opaque.is_empty() {
+            let names: Vec<&str> = top.opaque.iter().map(|s| s.as_str()).collect();
+            println!();
+            println!(
+                "builtin chips without HDL (not counted): {}",
+                names.join(", ")
+            );
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::netlist::{Net, Scope};
+use crate::simulator::Simulator;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// 記録するピン
+struct Probe {
+    id: String,
+    nets: Vec<Net>,
+    last: Option<u16>,
+}
+
+/// VCD(Value Change Dump)形式で信号の変化を書き出す
+///
+/// 時刻は半クロック単位 (`t`は`2t`, `t+`は`2t+1`)
+pub struct VcdWriter {
+    stream: BufWriter<File>,
+    probes: Vec<Probe>,
+    clock_id: String,
+    clock: Option<bool>,
+    last_time: Option<usize>,
+}
+
+/// 記録するピンの木 (VCDの`$scope`に対応する)
+#[derive(Default)]
+struct ScopeTree {
+    name: String,
+    vars: Vec<(String, usize, String)>,
+    children: Vec<ScopeTree>,
+}
+
+impl ScopeTree {
+    fn child(&mut self, name: &str) -> &mut ScopeTree {
+        match self.children.iter().position(|child| child.name == name) {
+            Some(index) => &mut self.children[index],
+            None => {
+                self.children.push(ScopeTree {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn write(&self, stream: &mut impl Write) -> std::io::Result<()> {
+        writeln!(stream, "$scope module {} $end", self.name)?;
+        for (name, width, id) in self.vars.iter() {
+            if *width == 1 {
+                writeln!(stream, "$var wire 1 {} {} $end", id, name)?;
+            } else {
+                writeln!(
+                    stream,
+                    "$var wire {} {} {} [{}:0] $end",
+                    width,
+                    id,
+                    name,
+                    width - 1
+                )?;
+            }
+        }
+        for child in self.children.iter() {
+            child.write(stream)?;
+        }
+        writeln!(stream, "$upscope $end")
+    }
+}
+
+fn write_header(stream: &mut impl Write, tree: &ScopeTree) -> std::io::Result<()> {
+    writeln!(stream, "$version hack_hdl $end")?;
+    writeln!(stream, "$timescale 1ns $end")?;
+    tree.write(stream)?;
+    writeln!(stream, "$enddefinitions $end")
+}
+
+/// VCDの識別子 (`!`から`~`までの文字を使った94進数)
+fn identifier(mut n: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (n % 94) as u8) as char);
+        n /= 94;
+        if n == 0 {
+            return id;
+        }
+        n -= 1;
+    }
+}
+
+impl VcdWriter {
+    /// * `paths`: - `CPU.ALU.zr`のようなピンのパス、または`CPU.ALU`のような部品のパス(すべてのピン)
+    ///   空のときは一番上のチップのピンをすべて記録する
+    pub fn new(file_path: &str, simulator: &Simulator, paths: &[String]) -> Result<Self, String> {
+        let top = &simulator.netlist.top;
+        let mut selected: Vec<(Vec<String>, String, Vec<Net>)> = vec![];
+        let add_scope = |selected: &mut Vec<_>, scope_path: Vec<String>, scope: &Scope| {
+            for (pin, nets) in scope.pins.iter() {
+                selected.push((scope_path.clone(), pin.clone(), nets.clone()));
+            }
+        };
+        if paths.is_empty() {
+            add_scope(&mut selected, vec![], top);
+        }
+        for path in paths.iter() {
+            let mut names: Vec<String> = path.split('.').map(|name| name.to_string()).collect();
+            if names.first() == Some(&top.name) {
+                names.remove(0);
+            }
+            if let Some(scope) = simulator.find_scope(path) {
+                add_scope(&mut selected, names, scope);
+            } else if let Some(nets) = simulator.find_pin(path) {
+                let pin = names.pop().unwrap();
+                selected.push((names, pin, nets.clone()));
+            } else {
+                return Err(format!("no pin or part named {}", path));
+            }
+        }
+
+        let mut tree = ScopeTree {
+            name: top.name.clone(),
+            ..Default::default()
+        };
+        let clock_id = identifier(0);
+        tree.vars.push(("clk".to_string(), 1, clock_id.clone()));
+        let mut probes = vec![];
+        for (scope_path, pin, nets) in selected {
+            let mut node = &mut tree;
+            for name in scope_path.iter() {
+                node = node.child(name);
+            }
+            if node.vars.iter().any(|(name, _, _)| *name == pin) {
+                continue;
+            }
+            let id = identifier(probes.len() + 1);
+            node.vars.push((pin, nets.len(), id.clone()));
+            probes.push(Probe {
+                id,
+                nets,
+                last: None,
+            });
+        }
+
+        let file = File::create(file_path).map_err(|e| format!("{}: {}", file_path, e))?;
+        let mut stream = BufWriter::new(file);
+        write_header(&mut stream, &tree).map_err(|e| e.to_string())?;
+        Ok(VcdWriter {
+            stream,
+            probes,
+            clock_id,
+            clock: None,
+            last_time: None,
+        })
+    }
+
+    /// 現在の値を記録する (前回から変化したものだけ書く)
+    pub fn sample(&mut self, simulator: &Simulator) -> std::io::Result<()> {
+        let time = simulator.time * 2 + simulator.half as usize;
+        let mut changes = vec![];
+        if self.clock != Some(simulator.half) {
+            self.clock = Some(simulator.half);
+            changes.push(format!("{}{}", simulator.half as u8, self.clock_id));
+        }
+        for probe in self.probes.iter_mut() {
+            let value = simulator.read(&probe.nets);
+            if probe.last == Some(value) {
+                continue;
+            }
+            probe.last = Some(value);
+            if probe.nets.len() == 1 {
+                changes.push(format!("{}{}", value, probe.id));
+            } else {
+                changes.push(format!("b{:b} {}", value, probe.id));
+            }
+        }
+        if changes.is_empty() {
+            return Ok(());
+        }
+        if self.last_time != Some(time) {
+            writeln!(self.stream, "#{}", time)?;
+            self.last_time = Some(time);
+        }
+        for change in changes {
+            writeln!(self.stream, "{}", change)?;
+        }
+        self.stream.flush()
+    }
+}
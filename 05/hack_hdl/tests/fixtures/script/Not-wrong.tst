@@ -0,0 +1,10 @@
+// 01/Not.tstと同じ入力で、比較ファイルの3行目だけがわざと間違っている
+output-list in out;
+
+set in 0,
+eval,
+output;
+
+set in 1,
+eval,
+output;
@@ -0,0 +1,93 @@
+//! 間違いを1つずつ入れたHDL (`tests/fixtures/lint/`) を調べて、見つけた問題のメッセージと行番号を確かめる
+
+use std::path::{Path, PathBuf};
+
+use hack_hdl::library::{self, Chip, Library};
+use hack_hdl::lint::Linter;
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/lint")
+        .join(format!("{}.hdl", name))
+}
+
+/// `hack_hdl lint`と同じく調べて、ただ1つの問題が`ファイル:行番号: メッセージ`になることを確かめる
+fn assert_lint(name: &str, line: usize, message: &str) {
+    let path = fixture(name);
+    let mut library = Library::new(library::chapter_dirs(path.parent().unwrap()));
+    let chip = library.load_file(&path).unwrap();
+    let Chip::Hdl(def) = &*chip else {
+        panic!("{} is builtin", name);
+    };
+    let reports = Linter::new(&mut library).check(def);
+    let reports: Vec<String> = reports.iter().map(|report| report.to_string()).collect();
+    assert_eq!(
+        reports,
+        [format!("{}:{}: {}", path.display(), line, message)],
+        "{}",
+        name
+    );
+}
+
+#[test]
+fn unknown_pin() {
+    assert_lint("UnknownPin", 7, "And has no pin named c");
+}
+
+#[test]
+fn undeclared_pin() {
+    assert_lint("UndeclaredPin", 7, "pin tmp is not declared");
+}
+
+#[test]
+fn width_mismatch() {
+    assert_lint(
+        "WidthMismatch",
+        7,
+        "width mismatch: a has 8 bits but Not16.in has 16 bits",
+    );
+}
+
+#[test]
+fn multiple_drivers() {
+    assert_lint(
+        "MultipleDrivers",
+        8,
+        "out[0] has multiple drivers (also assigned at line 7)",
+    );
+}
+
+#[test]
+fn undriven_output() {
+    assert_lint("UndrivenOutput", 4, "output pin other is never assigned");
+}
+
+#[test]
+fn unused_input() {
+    assert_lint("UnusedInput", 3, "input pin b is never used");
+}
+
+#[test]
+fn combinational_loop() {
+    assert_lint(
+        "CombinationalLoop",
+        8,
+        "combinational loop: x -> Not (line 8) -> y -> And (line 7) -> x",
+    );
+}
+
+#[test]
+fn course_chips_are_clean() {
+    for chip in ["01/DMux8Way", "02/ALU", "03/PC", "05/CPU", "05/Computer"] {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../..")
+            .join(format!("{}.hdl", chip));
+        let mut library = Library::new(library::chapter_dirs(path.parent().unwrap()));
+        let chip = library.load_file(&path).unwrap();
+        let Chip::Hdl(def) = &*chip else {
+            panic!("{} is builtin", path.display());
+        };
+        let reports = Linter::new(&mut library).check(def);
+        assert!(reports.is_empty(), "{:?}", reports);
+    }
+}
@@ -0,0 +1,69 @@
+//! テストスクリプトを実行して比較ファイルと比べ、VCDに書き出した波形を確かめる
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hack_hdl::script::ScriptRunner;
+
+/// リポジトリの一番上のディレクトリからのパス
+fn repo(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../..")
+        .join(path)
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/script")
+        .join(name)
+}
+
+#[test]
+fn course_script_passes() {
+    // `hack_hdl test ../CPU.tst`と同じく、05にない部品は組み込みチップを使う
+    let mut runner = ScriptRunner::new(vec![], None);
+    runner.run(&repo("05/CPU.tst")).unwrap();
+}
+
+#[test]
+fn wrong_compare_file_fails_with_the_line_number() {
+    let mut runner = ScriptRunner::new(vec![repo("01")], None);
+    let error = runner.run(&fixture("Not-wrong.tst")).unwrap_err();
+    assert!(error.contains("comparison failure at line 3"), "{}", error);
+    assert!(error.contains("expected: | 1 | 1 |"), "{}", error);
+    assert!(error.contains("actual:   | 1 | 0 |"), "{}", error);
+}
+
+#[test]
+fn vcd_records_header_and_value_changes() {
+    let path = std::env::temp_dir().join(format!("hack_hdl_{}_Not.vcd", std::process::id()));
+    let vcd = (path.to_string_lossy().to_string(), vec![]);
+    let mut runner = ScriptRunner::new(vec![], Some(vcd));
+    runner.run(&repo("01/Not.tst")).unwrap();
+    let contents = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let (header, changes) = lines.split_at(
+        lines
+            .iter()
+            .position(|line| *line == "$enddefinitions $end")
+            .unwrap()
+            + 1,
+    );
+    assert_eq!(
+        header,
+        [
+            "$version hack_hdl $end",
+            "$timescale 1ns $end",
+            "$scope module Not $end",
+            "$var wire 1 ! clk $end",
+            "$var wire 1 \" in $end",
+            "$var wire 1 # out $end",
+            "$upscope $end",
+            "$enddefinitions $end",
+        ]
+    );
+    // 最初にすべての値を書き、`set in 1`の後はinとoutの変化だけを書く
+    assert_eq!(changes, ["#0", "0!", "0\"", "1#", "1\"", "0#"]);
+}
@@ -0,0 +1,49 @@
+//! 教材のチップを部品まで展開して、Nandの数と組み合わせ回路の段数を確かめる
+
+use std::path::{Path, PathBuf};
+
+use hack_hdl::library::{self, Library};
+use hack_hdl::parser::HdlError;
+use hack_hdl::stats::{ChipStats, StatsBuilder};
+
+/// リポジトリの一番上のディレクトリからのパス
+fn repo(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../..").join(path)
+}
+
+/// `hack_hdl stats`と同じく章のディレクトリも探して、一番上のチップの統計を返す
+fn stats(path: &Path) -> Result<ChipStats, HdlError> {
+    let dir = path.parent().unwrap();
+    let mut library = Library::new(library::chapter_dirs(dir));
+    let chip = library.load_file(path)?;
+    let stats = StatsBuilder::new(&mut library).build(chip)?;
+    Ok(stats[0].clone())
+}
+
+#[test]
+fn mux8way16_counts() {
+    let stats = stats(&repo("01/Mux8Way16.hdl")).unwrap();
+    assert_eq!(stats.name, "Mux8Way16");
+    assert_eq!(stats.nands, 1120);
+    assert_eq!(stats.dffs, 0);
+    assert_eq!(stats.depth, 19);
+    assert!(stats.opaque.is_empty());
+}
+
+#[test]
+fn cpu_counts() {
+    // ARegister/DRegisterは03のRegisterで代用して数える
+    let stats = stats(&repo("05/CPU.hdl")).unwrap();
+    assert_eq!(stats.nands, 3493);
+    assert_eq!(stats.dffs, 48);
+    assert_eq!(stats.depth, 190);
+}
+
+#[test]
+fn undefined_part_is_an_error() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/UsesMissing.hdl");
+    let error = stats(&path).unwrap_err();
+    assert_eq!(error.path, path);
+    assert_eq!(error.line, 7);
+    assert_eq!(error.message, "chip Missing not found");
+}
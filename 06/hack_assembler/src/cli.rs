@@ -0,0 +1,80 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::{assemble, assemble_source};
+
+pub const USAGE: &str = "usage: hack_assembler [-q | -v...] [-o <FILE>] <FILE.asm | ->
+
+Writes FILE.hack next to the input. \"-\" reads the assembly from stdin and
+writes the machine code to stdout unless -o is given.
+
+options:
+  -o, --output <FILE>  write the machine code to FILE (\"-\" for stdout)
+  -q, --quiet          only print warnings and errors
+  -v, --verbose        print what the assembler does (-vv for every symbol)
+  -h, --help           print this help
+  -V, --version        print the version";
+
+/// `hack_assembler`のコマンドを実行する
+///
+/// * `args`: - プログラム名と`-q`/`-v`を除いたコマンドライン引数
+pub fn main(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut output: Option<String> = None;
+    let mut src: Option<String> = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            "-V" | "--version" => {
+                println!("hack_assembler {}", env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            "-o" | "--output" => output = Some(args.next().ok_or("-o needs a file name")?),
+            _ if src.is_none() && (arg == "-" || !arg.starts_with('-')) => src = Some(arg),
+            _ => return Err(format!("unexpected argument: {}\n{}", arg, USAGE).into()),
+        }
+    }
+    let Some(src) = src else {
+        return Err(USAGE.into());
+    };
+
+    let assembly = if src == "-" {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents)?;
+        assemble_source(&contents)?
+    } else {
+        if !src.ends_with(".asm") {
+            return Err(format!("This file is not hack assembly: {}", src).into());
+        }
+        assemble(&src)?
+    };
+
+    // 指定がなければ入力の隣に書き、標準入力からなら標準出力に書く
+    let output = output.unwrap_or_else(|| match src.as_str() {
+        "-" => "-".to_string(),
+        _ => Path::new(&src)
+            .with_extension("hack")
+            .to_string_lossy()
+            .to_string(),
+    });
+    let mut target: BufWriter<Box<dyn Write>> = match output.as_str() {
+        "-" => BufWriter::new(Box::new(io::stdout().lock())),
+        _ => BufWriter::new(Box::new(
+            fs::File::create(&output).map_err(|e| format!("{}: {}", output, e))?,
+        )),
+    };
+    for code in assembly.codes.iter() {
+        writeln!(target, "{}", code)?;
+    }
+    target.flush()?;
+    if output != "-" {
+        log::info!("wrote {}", output);
+    }
+
+    Ok(())
+}
@@ -7,6 +7,12 @@ pub struct CodeGen {
     dest: HashMap<String, String>,
 }
 
+impl Default for CodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CodeGen {
     pub fn new() -> Self {
         let comp_mnemonic = vec![
@@ -82,6 +88,7 @@ impl CodeGen {
             "AM".to_string(),
             "AD".to_string(),
             "ADM".to_string(),
+            "AMD".to_string(),
         ];
         let dest_bin = vec![
             "000".to_string(),
@@ -93,9 +100,10 @@ impl CodeGen {
             "101".to_string(),
             "110".to_string(),
             "111".to_string(),
+            "111".to_string(),
         ];
 
-        let jump_mnemonic = vec![
+        let jump_mnemonic = [
             "null".to_string(),
             "JGT".to_string(),
             "JEQ".to_string(),
@@ -105,7 +113,7 @@ impl CodeGen {
             "JLE".to_string(),
             "JMP".to_string(),
         ];
-        let jump_bin = vec![
+        let jump_bin = [
             "000".to_string(),
             "001".to_string(),
             "010".to_string(),
@@ -130,27 +138,22 @@ impl CodeGen {
             jump.insert(jump_mnemonic[i].to_owned(), jump_bin[i].to_owned());
         }
 
-        let code = CodeGen { comp, dest, jump };
-        code
+        CodeGen { comp, dest, jump }
     }
 
     pub fn gen_comp(&self, code: &String) -> String {
-        // eprintln!("{}", code);
         self.comp.get(code).unwrap().to_owned()
     }
     pub fn gen_dest(&self, code: &String) -> String {
-        // eprintln!("{}", code);
         self.dest.get(code).unwrap().to_owned()
     }
     pub fn gen_jump(&self, code: &String) -> String {
-        // eprintln!("{}", code);
         self.jump.get(code).unwrap().to_owned()
     }
-    pub fn gen_abit(&self, code: &String) -> String {
-        return match code.contains(&"M") {
+    pub fn gen_abit(&self, code: &str) -> String {
+        match code.contains("M") {
             true => "1".to_string(),
             false => "0".to_string(),
-        };
+        }
     }
 }
-
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+pub mod cli;
+pub mod code;
+pub mod logger;
+pub mod parser;
+pub mod symbol_table;
+
+/// アセンブルした結果
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    /// 機械語 (`0`と`1`の16文字)
+    pub codes: Vec<String>,
+    /// 定義済みシンボル・ラベル・変数のアドレス
+    pub symbol_table: symbol_table::SymbolTable,
+    /// ラベルとROMアドレス (出てきた順)
+    pub labels: Vec<(String, usize)>,
+}
+
+impl Assembly {
+    /// 機械語を数値にしたもの
+    pub fn words(&self) -> Vec<u16> {
+        self.codes
+            .iter()
+            .map(|code| u16::from_str_radix(code, 2).unwrap())
+            .collect()
+    }
+
+    /// ラベル以外のシンボル(定義済みシンボルと変数)とRAMアドレス
+    pub fn variables(&self) -> HashMap<String, usize> {
+        self.symbol_table
+            .symbols
+            .iter()
+            .filter(|(name, _)| !self.labels.iter().any(|(label, _)| label == *name))
+            .map(|(name, address)| (name.to_owned(), *address))
+            .collect()
+    }
+}
+
+/// A命令に書ける最大の値
+const MAX_CONSTANT: usize = 32767;
+
+/// アセンブリファイルを機械語に変換する
+pub fn assemble(src: &String) -> Result<Assembly, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(src).map_err(|e| format!("{}: {}", src, e))?;
+    assemble_source(&contents)
+}
+
+/// 読み込んだアセンブリコードを機械語に変換する
+///
+/// * `contents`: - アセンブリコード (標準入力から読んだものなど)
+pub fn assemble_source(contents: &str) -> Result<Assembly, Box<dyn std::error::Error>> {
+    let codegen = code::CodeGen::new();
+    let mut symbol_table = symbol_table::SymbolTable::new();
+    let mut labels = vec![];
+    // ラベルと定義した行 (同じラベルを2回定義すると後のアドレスで上書きされてしまう)
+    let mut label_lines: HashMap<String, usize> = HashMap::new();
+    let mut label_address = 0;
+    let mut variable_address = 16;
+
+    let mut parser = parser::Parser::from_source(contents);
+    while parser.has_more_lines() {
+        parser.advance();
+        match parser.instruction_type {
+            parser::InstructionType::L_INSTRUCTION => {
+                if let Some(line) = label_lines.insert(parser.symbol.to_owned(), parser.lineno) {
+                    return Err(format!(
+                        "line {}: label ({}) is already defined at line {}",
+                        parser.lineno, parser.symbol, line
+                    )
+                    .into());
+                }
+                log::trace!("label {} = {}", parser.symbol, label_address);
+                symbol_table.add_entry(parser.symbol.to_owned(), label_address);
+                labels.push((parser.symbol.to_owned(), label_address));
+            }
+            _ => {
+                label_address += 1;
+            }
+        }
+    }
+
+    parser.lineno = 0;
+
+    // 変数は出てきた順にアドレスを決めるので、シンボルの解決までは順に行う
+    let mut instructions = vec![];
+    while parser.has_more_lines() {
+        parser.advance();
+        let instruction = match parser.instruction_type {
+            parser::InstructionType::A_INSTRUCTION => {
+                let symbol: usize = if parser.symbol.starts_with(|c: char| c.is_ascii_digit()) {
+                    // A命令は15ビットなので、それより大きい値は機械語にできない
+                    match parser.symbol.parse::<usize>() {
+                        Ok(value) if value <= MAX_CONSTANT => value,
+                        _ => {
+                            return Err(format!(
+                                "line {}: @{} is out of range (0 to {})",
+                                parser.lineno, parser.symbol, MAX_CONSTANT
+                            )
+                            .into())
+                        }
+                    }
+                } else {
+                    // ラベルでも定義済みでもないシンボルは変数
+                    if !symbol_table.contains(&parser.symbol) {
+                        log::trace!("variable {} = {}", parser.symbol, variable_address);
+                        symbol_table.add_entry(parser.symbol.to_owned(), variable_address);
+                        variable_address += 1;
+                    }
+                    symbol_table.get_address(&parser.symbol)
+                };
+                Instruction::A(symbol)
+            }
+            parser::InstructionType::C_INSTRUCTION => Instruction::C {
+                dest: parser.dest.to_owned(),
+                comp: parser.comp.to_owned(),
+                jump: parser.jump.to_owned(),
+            },
+            parser::InstructionType::L_INSTRUCTION => continue,
+        };
+        instructions.push(instruction);
+    }
+
+    let codes = encode_parallel(&codegen, &instructions);
+    log::debug!(
+        "assembled {} instructions ({} labels, {} variables)",
+        codes.len(),
+        labels.len(),
+        variable_address - 16
+    );
+
+    Ok(Assembly {
+        codes,
+        symbol_table,
+        labels,
+    })
+}
+
+/// シンボルを解決した命令
+enum Instruction {
+    A(usize),
+    C {
+        dest: String,
+        comp: String,
+        jump: String,
+    },
+}
+
+impl Instruction {
+    fn encode(&self, codegen: &code::CodeGen) -> String {
+        match self {
+            Instruction::A(address) => format!("0{:015b}", address),
+            Instruction::C { dest, comp, jump } => {
+                let dest_code = codegen.gen_dest(dest);
+                let comp_code = codegen.gen_comp(comp);
+                let jump_code = codegen.gen_jump(jump);
+                let abit = codegen.gen_abit(comp);
+                format!("111{}{}{}{}", abit, comp_code, dest_code, jump_code)
+            }
+        }
+    }
+}
+
+/// 1つのスレッドで機械語にする最小の命令数 (小さいプログラムはスレッドを作らない)
+const MIN_CHUNK: usize = 4096;
+
+/// 命令を区切ってスレッドごとに機械語にし、元の順につなげる
+fn encode_parallel(codegen: &code::CodeGen, instructions: &[Instruction]) -> Vec<String> {
+    let jobs = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = instructions.len().div_ceil(jobs).max(MIN_CHUNK);
+    if instructions.len() <= chunk_size {
+        return instructions
+            .iter()
+            .map(|instruction| instruction.encode(codegen))
+            .collect();
+    }
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = instructions
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|instruction| instruction.encode(codegen))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
@@ -0,0 +1,62 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// `log`のメッセージを標準エラー出力に書く
+///
+/// Infoはメッセージだけ、それ以外は`warning: `のようにレベルを付けて書く
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let prefix = match record.level() {
+            Level::Error => "error: ",
+            Level::Warn => "warning: ",
+            Level::Info => "",
+            Level::Debug => "debug: ",
+            Level::Trace => "trace: ",
+        };
+        eprintln!("{}{}", prefix, record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// ログを標準エラー出力に書くようにする。2回目からは詳しさだけを変える
+pub fn init(level: LevelFilter) {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// 引数から`-q`/`--quiet`と`-v`/`--verbose`を取り除いて、ログの詳しさを決める
+///
+/// 既定はInfo、`-q`でWarn、`-v`でDebug、`-vv` (`-v -v`) でTrace
+///
+/// * `args`: - プログラム名を除いたコマンドライン引数
+pub fn verbosity<I: IntoIterator<Item = String>>(args: I) -> (LevelFilter, Vec<String>) {
+    let mut quiet = false;
+    let mut verbose = 0;
+    let mut rest = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "-q" | "--quiet" => quiet = true,
+            "-v" | "--verbose" => verbose += 1,
+            "-vv" => verbose += 2,
+            _ => rest.push(arg),
+        }
+    }
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    (level, rest)
+}
@@ -27,6 +27,13 @@ impl Parser {
         let mut f = File::open(src_filepath).expect("cannot open file");
         let mut contents = String::new();
         f.read_to_string(&mut contents).unwrap();
+        Self::from_source(&contents)
+    }
+
+    /// ファイルではなく、読み込んだアセンブリコードから作る
+    ///
+    /// * `contents`: - アセンブリコード
+    pub fn from_source(contents: &str) -> Self {
         let lineno = 0usize;
         let codes: Vec<String> = contents.split("\n").map(|x| x.to_string()).collect();
         let dest = String::new();
@@ -45,13 +52,17 @@ impl Parser {
         }
     }
 
+    /// 空行とコメントを除いて、まだ命令が残っているか
     pub fn has_more_lines(&self) -> bool {
-        self.lineno + 1 <= self.codes.len()
+        (self.lineno..self.codes.len()).any(|lineno| Self::is_code(&self.codes[lineno]))
+    }
+
+    fn is_code(line: &str) -> bool {
+        !line.trim().is_empty() && !line.trim().starts_with("//")
     }
 
     fn is_valid(&self) -> bool {
-        !self.codes[self.lineno].trim().is_empty()
-            && !self.codes[self.lineno].trim().starts_with("//")
+        Self::is_code(&self.codes[self.lineno])
     }
 
     pub fn advance(&mut self) {
@@ -79,13 +90,10 @@ impl Parser {
                 ""
             }
             .to_string();
-            // let caps = re.captures(line.as_str()).unwrap();
-            // eprintln!("{:?}", caps);
-            // self.symbol = caps.name("target").map_or("", |m| m.as_str()).to_string();
-        } else if line.starts_with("@") {
+        } else if let Some(symbol) = line.strip_prefix("@") {
             // @のときのパーサを書く
             self.instruction_type = InstructionType::A_INSTRUCTION;
-            self.symbol = line[1..].to_string();
+            self.symbol = symbol.to_string();
         } else {
             // 式
             self.instruction_type = InstructionType::C_INSTRUCTION;
@@ -102,7 +110,6 @@ impl Parser {
             }
         }
         self.lineno += 1;
-        // println!("code:\n{}", line);
     }
 
     pub fn get_symbol(&self) -> String {
@@ -124,4 +131,3 @@ impl Parser {
         self.instruction_type.to_owned()
     }
 }
-
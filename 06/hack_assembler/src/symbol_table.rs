@@ -5,6 +5,12 @@ pub struct SymbolTable {
     pub symbols: HashMap<String, usize>,
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> Self {
         let symbols_string = vec![
@@ -43,8 +49,7 @@ impl SymbolTable {
         SymbolTable { symbols }
     }
 
-    pub fn add_entry(&mut self, symbol_name: String, address: usize) -> () {
-        eprintln!("{}, {}", symbol_name, address);
+    pub fn add_entry(&mut self, symbol_name: String, address: usize) {
         self.symbols.insert(symbol_name, address);
     }
 
@@ -53,8 +58,6 @@ impl SymbolTable {
     }
 
     pub fn get_address(&mut self, symbol_name: &String) -> usize {
-        eprintln!("{}", symbol_name);
         self.symbols.get(symbol_name).unwrap().to_owned()
     }
 }
-
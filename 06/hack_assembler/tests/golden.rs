@@ -0,0 +1,136 @@
+//! `data/`のプログラムをアセンブルして、機械語を比べる
+//!
+//! `Add`, `Max`, `Rect`は`05/`にある教材の`.hack`と比べる。
+//! `Pong`には教材の`.hack`がないので、`PongL`と同じ機械語になることだけを確かめる
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use hack_assembler::{assemble, assemble_source};
+
+/// アセンブルするプログラムと、比べる`05/`の機械語の名前
+const PROGRAMS: [(&str, &str); 5] = [
+    ("Add", "Add"),
+    ("Max", "Max"),
+    ("MaxL", "Max"),
+    ("Rect", "Rect"),
+    ("RectL", "Rect"),
+];
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn source(name: &str) -> PathBuf {
+    root().join("data").join(format!("{}.asm", name))
+}
+
+/// `05/`にある教材の機械語 (CRLFの改行と先頭の空行を除いた行)
+fn golden(name: &str) -> Vec<String> {
+    let path = root().join("../../05").join(format!("{}.hack", name));
+    let contents =
+        fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    contents
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn assemble_data(name: &str) -> Vec<String> {
+    let path = source(name).to_string_lossy().to_string();
+    assemble(&path)
+        .unwrap_or_else(|e| panic!("{}: {}", path, e))
+        .codes
+}
+
+/// 機械語を1行ずつ比べる
+fn assert_codes_eq(name: &str, codes: &[String], golden: &[String]) {
+    assert_eq!(
+        golden.len(),
+        codes.len(),
+        "{}: number of instructions",
+        name
+    );
+    for (address, (code, expected)) in codes.iter().zip(golden.iter()).enumerate() {
+        assert_eq!(code, expected, "{}: instruction {}", name, address);
+    }
+}
+
+#[test]
+fn programs_match_course_binaries() {
+    for (name, expected) in PROGRAMS {
+        assert_codes_eq(name, &assemble_data(name), &golden(expected));
+    }
+}
+
+#[test]
+fn labeled_and_symbol_less_variants_match() {
+    for name in ["Max", "Rect", "Pong"] {
+        let symbolic = assemble_data(name);
+        let symbol_less = assemble_data(&format!("{}L", name));
+        assert!(
+            symbolic == symbol_less,
+            "{}.asm and {}L.asm assemble differently",
+            name,
+            name
+        );
+    }
+}
+
+#[test]
+fn source_and_file_assemble_alike() {
+    for name in PROGRAMS
+        .map(|(name, _)| name)
+        .into_iter()
+        .chain(["Pong", "PongL"])
+    {
+        let contents = fs::read_to_string(source(name)).unwrap();
+        let codes = assemble_source(&contents).unwrap().codes;
+        assert!(codes == assemble_data(name), "{}", name);
+    }
+}
+
+#[test]
+fn binary_writes_machine_code_to_stdout() {
+    let output = Command::new(env!("CARGO_BIN_EXE_hack_assembler"))
+        .args(["-q", "-o", "-"])
+        .arg(source("Max"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(
+        output.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let codes: Vec<String> = stdout.lines().map(|line| line.to_string()).collect();
+    assert_codes_eq("Max", &codes, &golden("Max"));
+}
+
+#[test]
+fn duplicate_labels_are_rejected() {
+    let error = assemble_source("(LOOP)\n@LOOP\n0;JMP\n(LOOP)\n").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "line 4: label (LOOP) is already defined at line 1"
+    );
+}
+
+#[test]
+fn constants_above_15_bits_are_rejected() {
+    assert!(assemble_source("@32767\nD=A\n").is_ok());
+    let error = assemble_source("@1\nD=A\n@70000\n").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "line 3: @70000 is out of range (0 to 32767)"
+    );
+    let error = assemble_source("@32768\n").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "line 1: @32768 is out of range (0 to 32767)"
+    );
+}
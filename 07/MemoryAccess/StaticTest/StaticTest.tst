@@ -0,0 +1,19 @@
+// This file is part of www.nand2tetris.org
+// and the book "The Elements of Computing Systems"
+// by Nisan and Schocken, MIT Press.
+// File name: projects/7/MemoryAccess/StaticTest/StaticTest.tst
+
+// Tests StaticTest.asm on the CPU emulator.
+
+load StaticTest.asm,
+output-file StaticTest.out,
+compare-to StaticTest.cmp,
+output-list RAM[256]%D1.6.1;
+
+set RAM[0] 256,  // initializes the stack pointer
+
+repeat 200 {
+  ticktock;
+}
+
+output;
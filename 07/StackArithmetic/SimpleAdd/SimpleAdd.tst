@@ -0,0 +1,19 @@
+// This file is part of www.nand2tetris.org
+// and the book "The Elements of Computing Systems"
+// by Nisan and Schocken, MIT Press.
+// File name: projects/7/StackArithmetic/SimpleAdd/SimpleAdd.tst
+
+// Tests SimpleAdd.asm on the CPU emulator.
+
+load SimpleAdd.asm,
+output-file SimpleAdd.out,
+compare-to SimpleAdd.cmp,
+output-list RAM[0]%D2.6.2 RAM[256]%D2.6.2;
+
+set RAM[0] 256,  // initializes the stack pointer
+
+repeat 60 {
+  ticktock;
+}
+
+output;
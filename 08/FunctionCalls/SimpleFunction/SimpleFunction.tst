@@ -0,0 +1,30 @@
+// This file is part of www.nand2tetris.org
+// and the book "The Elements of Computing Systems"
+// by Nisan and Schocken, MIT Press.
+// File name: projects/8/FunctionCalls/SimpleFunction/SimpleFunction.tst
+
+// Tests SimpleFunction.asm on the CPU emulator.
+
+load SimpleFunction.asm,
+output-file SimpleFunction.out,
+compare-to SimpleFunction.cmp,
+output-list RAM[0]%D1.6.1 RAM[1]%D1.6.1 RAM[2]%D1.6.1 RAM[3]%D1.6.1 RAM[4]%D1.6.1 RAM[310]%D1.6.1;
+
+set RAM[0] 317,  // SP
+set RAM[1] 317,  // LCL
+set RAM[2] 310,  // ARG
+set RAM[3] 3000,  // THIS
+set RAM[4] 4000,  // THAT
+set RAM[310] 1234,  // argument 0
+set RAM[311] 37,  // argument 1
+set RAM[312] 1000,  // return address
+set RAM[313] 305,  // caller's LCL
+set RAM[314] 300,  // caller's ARG
+set RAM[315] 3010,  // caller's THIS
+set RAM[316] 4010,  // caller's THAT
+
+repeat 300 {
+  ticktock;
+}
+
+output;
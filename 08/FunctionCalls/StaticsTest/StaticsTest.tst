@@ -0,0 +1,19 @@
+// This file is part of www.nand2tetris.org
+// and the book "The Elements of Computing Systems"
+// by Nisan and Schocken, MIT Press.
+// File name: projects/8/FunctionCalls/StaticsTest/StaticsTest.tst
+
+// StaticsTest.asm results from translating Class1.vm, Class2.vm and Sys.vm
+// into a single assembly program. The bootstrap code sets the stack pointer
+// and calls Sys.init.
+
+load StaticsTest.asm,
+output-file StaticsTest.out,
+compare-to StaticsTest.cmp,
+output-list RAM[0]%D1.6.1 RAM[261]%D1.6.1 RAM[262]%D1.6.1;
+
+repeat 2500 {
+  ticktock;
+}
+
+output;
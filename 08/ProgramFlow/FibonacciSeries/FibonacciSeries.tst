@@ -0,0 +1,23 @@
+// This file is part of www.nand2tetris.org
+// and the book "The Elements of Computing Systems"
+// by Nisan and Schocken, MIT Press.
+// File name: projects/8/ProgramFlow/FibonacciSeries/FibonacciSeries.tst
+
+// Tests FibonacciSeries.asm on the CPU emulator.
+
+load FibonacciSeries.asm,
+output-file FibonacciSeries.out,
+compare-to FibonacciSeries.cmp,
+output-list RAM[3000]%D1.6.2 RAM[3001]%D1.6.2 RAM[3002]%D1.6.2 RAM[3003]%D1.6.2 RAM[3004]%D1.6.2 RAM[3005]%D1.6.2;
+
+set RAM[0] 256,  // SP
+set RAM[1] 300,  // LCL
+set RAM[2] 400,  // ARG
+set RAM[400] 6,  // argument[0], n
+set RAM[401] 3000,  // argument[1], base address of the generated series
+
+repeat 1100 {
+  ticktock;
+}
+
+output;
@@ -0,0 +1,500 @@
+use crate::command::{function_ranges, ArithmeticOp, Segment, Span, Spanned, VmCommand, VmFile};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+
+/// ブートストラップでのSPの初期値
+const STACK_BASE: usize = 256;
+/// スタックに使える最後のアドレス (2048からはヒープ)
+const STACK_END: usize = 2047;
+/// `call`がスタックに積むリターンアドレスとLCL, ARG, THIS, THATの数
+const FRAME_SIZE: usize = 5;
+
+/// `call`コマンド1つ
+#[derive(Debug, Clone)]
+pub struct CallSite {
+    pub callee: String,
+    pub n_args: u16,
+    pub file: String,
+    pub span: Span,
+    /// `call`の直前 (引数を積んだあと) の作業用スタックの深さ
+    depth: usize,
+}
+
+/// 関数1つ
+#[derive(Debug, Clone)]
+pub struct FunctionInfo {
+    pub file: String,
+    pub span: Span,
+    pub n_locals: u16,
+    /// 本体で使っている`argument`の最大のインデックス + 1
+    pub args_used: u16,
+    pub calls: Vec<CallSite>,
+    /// 作業用スタックの最大の深さ (分岐は考えず、上から順に数えた見積もり)
+    pub max_depth: usize,
+}
+
+/// 関数のスタック使用量の見積もり
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackUsage {
+    /// 呼び出した関数も含めたワード数
+    Bounded(usize),
+    /// 再帰しているので上限がない
+    Unbounded,
+}
+
+/// VMプログラム全体の呼び出しグラフ
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    pub functions: BTreeMap<String, FunctionInfo>,
+    /// 関数の外 (ファイルの先頭) にある`call`
+    pub top_level_calls: Vec<CallSite>,
+    /// 同じ名前の関数の2つ目以降の定義 (呼び出しグラフには最初の定義を使う)
+    pub duplicates: Vec<(String, FunctionInfo)>,
+}
+
+impl Analysis {
+    pub fn new(files: &[VmFile]) -> Self {
+        let mut analysis = Analysis::default();
+        for file in files.iter() {
+            let ranges = function_ranges(&file.commands);
+            let first = ranges
+                .first()
+                .map_or(file.commands.len(), |(_, range)| range.start);
+            let (calls, _, _) = scan(&file.path, &file.commands[..first]);
+            analysis.top_level_calls.extend(calls);
+            for (name, range) in ranges {
+                let commands = &file.commands[range];
+                let VmCommand::Function(_, n_locals) = commands[0].command else {
+                    continue;
+                };
+                let (calls, args_used, max_depth) = scan(&file.path, &commands[1..]);
+                let function = FunctionInfo {
+                    file: file.path.clone(),
+                    span: commands[0].span,
+                    n_locals,
+                    args_used,
+                    calls,
+                    max_depth,
+                };
+                match analysis.functions.entry(name) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(function);
+                    }
+                    Entry::Occupied(entry) => {
+                        analysis.duplicates.push((entry.key().clone(), function));
+                    }
+                }
+            }
+        }
+        analysis
+    }
+
+    /// 呼び出されているが定義されていない関数と、呼び出している場所
+    pub fn undefined(&self) -> BTreeMap<&str, Vec<&CallSite>> {
+        let mut undefined: BTreeMap<&str, Vec<&CallSite>> = BTreeMap::new();
+        for call in self.all_calls() {
+            if !self.functions.contains_key(&call.callee) {
+                undefined.entry(&call.callee).or_default().push(call);
+            }
+        }
+        undefined
+    }
+
+    /// 2回以上定義されている関数と、最初の定義と重複した定義の場所 (`Main.vm:3`)
+    pub fn duplicate_definitions(&self) -> Vec<String> {
+        self.duplicates
+            .iter()
+            .map(|(name, duplicate)| {
+                let first = &self.functions[name];
+                format!(
+                    "{} is defined at {}:{} and {}:{}",
+                    name, first.file, first.span.line, duplicate.file, duplicate.span.line
+                )
+            })
+            .collect()
+    }
+
+    /// 使われていない関数
+    ///
+    /// `Sys.init`があればそこからたどれない関数、なければどこからも呼ばれない関数
+    pub fn unused(&self) -> Vec<&str> {
+        let used = if self.functions.contains_key("Sys.init") {
+            self.reachable_from("Sys.init")
+        } else {
+            self.all_calls().map(|call| call.callee.as_str()).collect()
+        };
+        self.functions
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !used.contains(name))
+            .collect()
+    }
+
+    /// 引数の数の食い違い
+    ///
+    /// `function`には引数の数が書かれていないので、呼び出しどうしで数が違うものと、
+    /// 本体で使っている`argument`より少ない数で呼んでいるものを報告する
+    pub fn argument_mismatches(&self) -> Vec<String> {
+        let mut by_callee: BTreeMap<&str, Vec<&CallSite>> = BTreeMap::new();
+        for call in self.all_calls() {
+            by_callee.entry(&call.callee).or_default().push(call);
+        }
+        let mut mismatches = vec![];
+        for (callee, calls) in by_callee.iter() {
+            let counts: BTreeSet<u16> = calls.iter().map(|call| call.n_args).collect();
+            if counts.len() > 1 {
+                let sites: Vec<String> = calls
+                    .iter()
+                    .map(|call| format!("{} ({})", call.location(), call.n_args))
+                    .collect();
+                mismatches.push(format!(
+                    "{} is called with different argument counts: {}",
+                    callee,
+                    sites.join(", ")
+                ));
+            }
+            let Some(function) = self.functions.get(*callee) else {
+                continue;
+            };
+            for call in calls.iter().filter(|call| call.n_args < function.args_used) {
+                mismatches.push(format!(
+                    "{}: call {} {} but {} uses argument {}",
+                    call.location(),
+                    callee,
+                    call.n_args,
+                    callee,
+                    function.args_used - 1
+                ));
+            }
+        }
+        mismatches
+    }
+
+    /// 再帰呼び出しの輪 (強連結成分のうち、2つ以上の関数を含むものか自分自身を呼ぶもの)
+    pub fn cycles(&self) -> Vec<Vec<String>> {
+        let names: Vec<&String> = self.functions.keys().collect();
+        let index_of: BTreeMap<&str, usize> = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.as_str(), index))
+            .collect();
+        let edges: Vec<Vec<usize>> = names
+            .iter()
+            .map(|name| {
+                let callees: BTreeSet<usize> = self.functions[*name]
+                    .calls
+                    .iter()
+                    .filter_map(|call| index_of.get(call.callee.as_str()).copied())
+                    .collect();
+                callees.into_iter().collect()
+            })
+            .collect();
+        let mut cycles = vec![];
+        for component in strongly_connected_components(&edges) {
+            let recursive = component.len() > 1 || edges[component[0]].contains(&component[0]);
+            if recursive {
+                let mut cycle: Vec<String> = component
+                    .iter()
+                    .map(|index| names[*index].clone())
+                    .collect();
+                cycle.sort();
+                cycles.push(cycle);
+            }
+        }
+        cycles.sort();
+        cycles
+    }
+
+    /// 関数が呼ばれてから戻るまでに使うスタックのワード数 (ローカル変数, 作業用スタック, 呼び出した関数)
+    pub fn stack_usage(&self, name: &str) -> StackUsage {
+        let mut memo: BTreeMap<String, StackUsage> = BTreeMap::new();
+        self.stack_usage_memo(name, &mut memo, &mut BTreeSet::new())
+    }
+
+    fn stack_usage_memo(
+        &self,
+        name: &str,
+        memo: &mut BTreeMap<String, StackUsage>,
+        visiting: &mut BTreeSet<String>,
+    ) -> StackUsage {
+        if let Some(usage) = memo.get(name) {
+            return *usage;
+        }
+        let Some(function) = self.functions.get(name) else {
+            // 定義されていない関数はわからないので数えない
+            return StackUsage::Bounded(0);
+        };
+        if !visiting.insert(name.to_string()) {
+            return StackUsage::Unbounded;
+        }
+        let mut usage = StackUsage::Bounded(function.n_locals as usize + function.max_depth);
+        for call in function.calls.iter() {
+            usage = match (usage, self.stack_usage_memo(&call.callee, memo, visiting)) {
+                (StackUsage::Bounded(current), StackUsage::Bounded(callee)) => {
+                    let at_call = function.n_locals as usize + call.depth + FRAME_SIZE + callee;
+                    StackUsage::Bounded(current.max(at_call))
+                }
+                _ => StackUsage::Unbounded,
+            };
+        }
+        visiting.remove(name);
+        memo.insert(name.to_string(), usage);
+        usage
+    }
+
+    /// 解析結果を表示する
+    pub fn report(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let n_calls = self.all_calls().count();
+        writeln!(
+            output,
+            "functions: {}, calls: {}",
+            self.functions.len(),
+            n_calls
+        )?;
+
+        let duplicates = self.duplicate_definitions();
+        writeln!(output)?;
+        writeln!(output, "duplicate functions: {}", duplicates.len())?;
+        for duplicate in duplicates.iter() {
+            writeln!(output, "  {}", duplicate)?;
+        }
+
+        let undefined = self.undefined();
+        writeln!(output)?;
+        writeln!(output, "undefined functions: {}", undefined.len())?;
+        for (name, calls) in undefined.iter() {
+            let sites: Vec<String> = calls.iter().map(|call| call.location()).collect();
+            writeln!(output, "  {} (called at {})", name, sites.join(", "))?;
+        }
+
+        let unused = self.unused();
+        writeln!(output)?;
+        writeln!(output, "unused functions: {}", unused.len())?;
+        for name in unused.iter() {
+            let function = &self.functions[*name];
+            writeln!(
+                output,
+                "  {} ({}:{})",
+                name, function.file, function.span.line
+            )?;
+        }
+
+        let mismatches = self.argument_mismatches();
+        writeln!(output)?;
+        writeln!(output, "argument count mismatches: {}", mismatches.len())?;
+        for mismatch in mismatches.iter() {
+            writeln!(output, "  {}", mismatch)?;
+        }
+
+        let cycles = self.cycles();
+        writeln!(output)?;
+        writeln!(output, "recursion cycles: {}", cycles.len())?;
+        for cycle in cycles.iter() {
+            writeln!(output, "  {}", cycle.join(" <-> "))?;
+        }
+
+        writeln!(output)?;
+        if self.functions.contains_key("Sys.init") {
+            match self.stack_usage("Sys.init") {
+                StackUsage::Bounded(words) => {
+                    // ブートストラップのcallの分も含める
+                    let top = STACK_BASE + FRAME_SIZE + words;
+                    writeln!(
+                        output,
+                        "max stack depth (estimate): {} words from Sys.init, SP up to {}",
+                        FRAME_SIZE + words,
+                        top
+                    )?;
+                    if top > STACK_END {
+                        writeln!(
+                            output,
+                            "  warning: stack may overflow into the heap (above {})",
+                            STACK_END
+                        )?;
+                    }
+                }
+                StackUsage::Unbounded => writeln!(
+                    output,
+                    "max stack depth (estimate): unbounded (recursion reachable from Sys.init)"
+                )?,
+            }
+        } else {
+            writeln!(output, "max stack depth (estimate): no Sys.init")?;
+        }
+        Ok(())
+    }
+
+    /// 呼び出しグラフをDOT形式で書き出す
+    ///
+    /// 定義されていない関数は破線、呼び出し回数が2回以上の辺には回数を付ける
+    pub fn write_dot(&self, path: &str) -> Result<(), String> {
+        let mut dot = String::from("digraph calls {\n    node [shape=box];\n");
+        for (name, function) in self.functions.iter() {
+            dot.push_str(&format!(
+                "    \"{}\" [tooltip=\"{}:{}\"];\n",
+                name, function.file, function.span.line
+            ));
+        }
+        for name in self.undefined().keys() {
+            dot.push_str(&format!("    \"{}\" [style=dashed];\n", name));
+        }
+        let callers = self
+            .functions
+            .iter()
+            .map(|(name, function)| (name.as_str(), &function.calls))
+            .chain(std::iter::once(("(top)", &self.top_level_calls)));
+        for (caller, calls) in callers {
+            let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+            for call in calls.iter() {
+                *counts.entry(&call.callee).or_default() += 1;
+            }
+            for (callee, count) in counts {
+                if count > 1 {
+                    dot.push_str(&format!(
+                        "    \"{}\" -> \"{}\" [label=\"{}\"];\n",
+                        caller, callee, count
+                    ));
+                } else {
+                    dot.push_str(&format!("    \"{}\" -> \"{}\";\n", caller, callee));
+                }
+            }
+        }
+        dot.push_str("}\n");
+        fs::write(path, dot).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn all_calls(&self) -> impl Iterator<Item = &CallSite> {
+        self.functions
+            .values()
+            .flat_map(|function| function.calls.iter())
+            .chain(self.top_level_calls.iter())
+    }
+
+    fn reachable_from<'a>(&'a self, root: &'a str) -> BTreeSet<&'a str> {
+        let mut reachable: BTreeSet<&str> = BTreeSet::new();
+        let mut stack: Vec<&str> = vec![root];
+        stack.extend(self.top_level_calls.iter().map(|call| call.callee.as_str()));
+        while let Some(name) = stack.pop() {
+            if reachable.insert(name) {
+                if let Some(function) = self.functions.get(name) {
+                    stack.extend(function.calls.iter().map(|call| call.callee.as_str()));
+                }
+            }
+        }
+        reachable
+    }
+}
+
+impl CallSite {
+    /// `Main.vm:12`
+    pub fn location(&self) -> String {
+        format!("{}:{}", self.file, self.span.line)
+    }
+}
+
+/// 関数の本体から、`call`, 使っている引数の数, 作業用スタックの最大の深さを取り出す
+fn scan(file: &str, commands: &[Spanned]) -> (Vec<CallSite>, u16, usize) {
+    let mut calls = vec![];
+    let mut args_used = 0;
+    let mut depth: usize = 0;
+    let mut max_depth = 0;
+    for spanned in commands.iter() {
+        match &spanned.command {
+            VmCommand::Push(segment, index) => {
+                if *segment == Segment::Argument {
+                    args_used = args_used.max(index + 1);
+                }
+                depth += 1;
+            }
+            VmCommand::Pop(segment, index) => {
+                if *segment == Segment::Argument {
+                    args_used = args_used.max(index + 1);
+                }
+                depth = depth.saturating_sub(1);
+            }
+            VmCommand::Arithmetic(ArithmeticOp::Neg | ArithmeticOp::Not) => {}
+            VmCommand::Arithmetic(_) | VmCommand::IfGoto(_) => {
+                depth = depth.saturating_sub(1);
+            }
+            VmCommand::Call(callee, n_args) => {
+                calls.push(CallSite {
+                    callee: callee.clone(),
+                    n_args: *n_args,
+                    file: file.to_string(),
+                    span: spanned.span,
+                    depth,
+                });
+                // 引数が戻り値1つに置き換わる
+                depth = depth.saturating_sub(*n_args as usize) + 1;
+            }
+            VmCommand::Return => {
+                depth = 0;
+            }
+            VmCommand::Label(_) | VmCommand::Goto(_) | VmCommand::Function(..) => {}
+        }
+        max_depth = max_depth.max(depth);
+    }
+    (calls, args_used, max_depth)
+}
+
+/// Tarjanのアルゴリズムで強連結成分を求める
+fn strongly_connected_components(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct State<'a> {
+        edges: &'a [Vec<usize>],
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    fn visit(state: &mut State, v: usize) {
+        state.index[v] = Some(state.next);
+        state.low[v] = state.next;
+        state.next += 1;
+        state.stack.push(v);
+        state.on_stack[v] = true;
+        for &w in state.edges[v].iter() {
+            match state.index[w] {
+                None => {
+                    visit(state, w);
+                    state.low[v] = state.low[v].min(state.low[w]);
+                }
+                Some(index) if state.on_stack[w] => {
+                    state.low[v] = state.low[v].min(index);
+                }
+                _ => {}
+            }
+        }
+        if Some(state.low[v]) == state.index[v] {
+            let mut component = vec![];
+            while let Some(w) = state.stack.pop() {
+                state.on_stack[w] = false;
+                component.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            state.components.push(component);
+        }
+    }
+
+    let mut state = State {
+        edges,
+        index: vec![None; edges.len()],
+        low: vec![0; edges.len()],
+        on_stack: vec![false; edges.len()],
+        stack: vec![],
+        next: 0,
+        components: vec![],
+    };
+    for v in 0..edges.len() {
+        if state.index[v].is_none() {
+            visit(&mut state, v);
+        }
+    }
+    state.components
+}
@@ -0,0 +1,96 @@
+use crate::code_writer::{CodeWriter, Fragment, SourceMapping};
+use crate::command::VmFile;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// 翻訳の仕方を変えたら上げて、古いキャッシュを使わないようにする
+const FORMAT_VERSION: u32 = 2;
+
+/// ファイルごとの翻訳結果を、VMコマンドのハッシュをキーにしてディレクトリに保存する
+///
+/// `<ハッシュ>.asm`に断片のアセンブリ、`<ハッシュ>.map`に断片の先頭からのソースマップを書く。
+/// 読めないキャッシュは無視して翻訳し直す。複数のスレッドやプロセスから使える
+pub struct Cache {
+    dir: PathBuf,
+    /// キャッシュを使ったファイルの数
+    pub hits: AtomicUsize,
+    /// 翻訳したファイルの数
+    pub misses: AtomicUsize,
+}
+
+impl Cache {
+    /// * `dir`: - キャッシュを置くディレクトリ (なければ作る)
+    pub fn new(dir: &str) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Cache {
+            dir: PathBuf::from(dir),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        })
+    }
+
+    /// キャッシュがあればそれを、なければ翻訳して保存した結果を返す
+    ///
+    /// * `program`: - 翻訳するファイル
+    /// * `checked`: - 実行時の検査のコードを出力する
+    pub fn translate(&self, program: &VmFile, checked: bool) -> std::io::Result<Fragment> {
+        let key = format!("{:016x}", hash(program, checked));
+        let asm_path = self.dir.join(format!("{}.asm", key));
+        let map_path = self.dir.join(format!("{}.map", key));
+        if let Some(fragment) = load(&asm_path, &map_path) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(fragment);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let fragment = CodeWriter::translate(program, checked);
+        let map: Vec<String> = fragment
+            .source_map
+            .iter()
+            .map(|mapping| mapping.to_line() + "\n")
+            .collect();
+        // ソースマップを後に書くので、途中で止まってもソースマップのない断片は使われない
+        write_atomic(&asm_path, &fragment.asm)?;
+        write_atomic(&map_path, &map.concat())?;
+        Ok(fragment)
+    }
+}
+
+/// 同じディレクトリの一時ファイルに書いてから名前を変えるので、
+/// 同時に書いたり途中で止まったりしても、書きかけのファイルが`path`に残らない
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let temporary = path.with_extension(format!(
+        "{}.{}.tmp",
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
+}
+
+fn load(asm_path: &PathBuf, map_path: &PathBuf) -> Option<Fragment> {
+    let asm = fs::read_to_string(asm_path).ok()?;
+    let source_map = fs::read_to_string(map_path)
+        .ok()?
+        .lines()
+        .map(SourceMapping::parse)
+        .collect::<Option<Vec<_>>>()?;
+    Some(Fragment { asm, source_map })
+}
+
+/// パス (staticの名前とソースマップに使う)、行番号つきのVMコマンド、検査の有無のFNV-1aハッシュ
+///
+/// 実行するたびに同じ値になるように、`std`のハッシュは使わない
+fn hash(program: &VmFile, checked: bool) -> u64 {
+    let mut text = format!("{}\n{}\n{}\n", FORMAT_VERSION, checked, program.path);
+    for spanned in program.commands.iter() {
+        text.push_str(&format!("{}\t{}\n", spanned.span.line, spanned.command));
+    }
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
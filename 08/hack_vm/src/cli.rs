@@ -0,0 +1,350 @@
+use crate::{analysis, cache, code_writer, command, interpreter, native, optimizer, os, parser};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, thread};
+
+pub const USAGE: &str = "usage: hack_vm [-O] [--checked] [--no-os] [--analyze] [--dot <FILE>]
+               [--cache <DIR>] [-j <N>] [--run [--native-os] [--max-steps <N>]]
+               [-q | -v] [-r] [-o <FILE>] <FILE.vm | DIRECTORY | ->...
+
+Translates into DIRECTORY/DIRECTORY.asm for a directory and FILE.asm for a
+file (named after the first input), with a .map source map next to it.
+\"-\" reads VM code from stdin and writes the assembly to stdout unless -o is
+given.
+
+options:
+  -o <FILE>       write the assembly to FILE (\"-\" for stdout, without a
+                  source map)
+  -r, --recursive also read .vm files in subdirectories
+  -O, --optimize  fold constants, inline small functions and drop functions
+                  unreachable from Sys.init
+  --checked       emit code that traps on stack overflow/underflow and
+                  this/that addresses outside RAM
+  --no-os         do not link the built-in Jack OS classes
+  --cache <DIR>   reuse per-file translations stored in DIR, keyed by a hash
+                  of the file's VM commands
+  -j, --jobs <N>  translate files in N threads (default: number of CPUs)
+  --analyze       report undefined and unused functions, argument count
+                  mismatches, recursion and stack depth instead of translating
+  --dot <FILE>    write the call graph in DOT format
+  --run           interpret the VM code instead of translating it
+  --native-os     with --run, execute the Jack OS functions natively
+  --max-steps <N> with --run, stop after N commands (default 100000000)
+  -q, --quiet     only print warnings and errors
+  -v, --verbose   also print the files read
+  -h, --help      print this help
+  -V, --version   print the version";
+
+/// 標準入力から読んだVMコードのファイル名
+const STDIN_PATH: &str = "stdin";
+
+/// `--run`で実行するVMコマンドの数の既定値
+const DEFAULT_MAX_STEPS: u64 = 100_000_000;
+
+/// `hack_vm`のコマンドを実行する
+///
+/// * `args`: - プログラム名と`-q`/`-v`を除いたコマンドライン引数
+pub fn main(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut optimize = false;
+    let mut checked = false;
+    let mut analyze = false;
+    let mut link_os = true;
+    let mut run = false;
+    let mut native_os = false;
+    let mut max_steps = DEFAULT_MAX_STEPS;
+    let mut dot_path: Option<String> = None;
+    let mut cache_dir: Option<String> = None;
+    let mut jobs = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut recursive = false;
+    let mut output_arg: Option<String> = None;
+    let mut inputs: Vec<String> = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            "-V" | "--version" => {
+                println!("hack_vm {}", env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            "-O" | "--optimize" => optimize = true,
+            "--checked" => checked = true,
+            "--no-os" => link_os = false,
+            "-r" | "--recursive" => recursive = true,
+            "-o" | "--output" => output_arg = Some(args.next().ok_or("-o needs a file name")?),
+            "--analyze" => analyze = true,
+            "--dot" => dot_path = Some(args.next().ok_or("--dot needs a file name")?),
+            "--cache" => cache_dir = Some(args.next().ok_or("--cache needs a directory")?),
+            "-j" | "--jobs" => {
+                let text = args.next().ok_or("--jobs needs a number")?;
+                jobs = text
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("invalid number of jobs: {}", text))?;
+            }
+            "--run" => run = true,
+            "--native-os" => native_os = true,
+            "--max-steps" => {
+                let steps = args.next().ok_or("--max-steps needs a number")?;
+                max_steps = steps
+                    .parse()
+                    .map_err(|_| format!("invalid number of steps: {}", steps))?;
+            }
+            _ if arg == "-" || !arg.starts_with('-') => inputs.push(arg),
+            _ => return Err(format!("unexpected argument: {}\n{}", arg, USAGE).into()),
+        }
+    }
+    let Some(first_input) = inputs.first() else {
+        return Err(USAGE.into());
+    };
+    // 出力先。`None`なら標準出力
+    let output: Option<PathBuf> = match output_arg.as_deref() {
+        Some("-") => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None if first_input == "-" => None,
+        None => Some(output_path(Path::new(first_input))?),
+    };
+    // ディレクトリは中の`.vm`ファイルを、ファイルはそのまま、指定した順に並べる
+    let mut files: Vec<String> = vec![];
+    for input in inputs.iter() {
+        if input == "-" {
+            if !files.contains(input) {
+                files.push(input.clone());
+            }
+            continue;
+        }
+        let path = Path::new(input);
+        let metadata = fs::metadata(path).map_err(|e| format!("{}: {}", input, e))?;
+        let found = if metadata.is_dir() {
+            vm_files(path, recursive)?
+        } else {
+            vec![path.to_path_buf()]
+        };
+        for file in found {
+            let file = file.to_string_lossy().to_string();
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+
+    // 書き出す前にすべてのファイルを読んで、誤りをまとめて報告する
+    let mut programs: Vec<command::VmFile> = vec![];
+    let mut errors: Vec<parser::VmError> = vec![];
+    for file_name in files.iter() {
+        // 標準入力は`stdin`という名前のファイルとして扱う (staticは`stdin.0`のようになる)
+        let (path, parsed) = if file_name == "-" {
+            let mut contents = String::new();
+            io::stdin().read_to_string(&mut contents)?;
+            let path = STDIN_PATH.to_string();
+            let parsed = parser::parse_str(&contents, Path::new(&path));
+            (path, parsed)
+        } else {
+            (file_name.clone(), parser::parse_file(Path::new(file_name)))
+        };
+        match parsed {
+            Ok(commands) => {
+                log::debug!("read {} ({} commands)", path, commands.len());
+                programs.push(command::VmFile { path, commands })
+            }
+            Err(mut file_errors) => errors.append(&mut file_errors),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(join_errors(&errors).into());
+    }
+
+    // 足りないOSのクラスを組み込みのJack OSから足す
+    if link_os {
+        match os::link(&mut programs) {
+            Ok(linked) if !linked.is_empty() => log::info!("linked OS: {}", linked.join(", ")),
+            Ok(_) => {}
+            Err(errors) => return Err(join_errors(&errors).into()),
+        }
+    }
+
+    check_file_names(&programs)?;
+
+    // 呼び出しグラフは最適化する前のプログラムで調べる
+    if analyze || dot_path.is_some() {
+        let analysis = analysis::Analysis::new(&programs);
+        if let Some(dot_path) = dot_path.as_deref() {
+            analysis.write_dot(dot_path)?;
+        }
+        if analyze {
+            analysis.report(&mut std::io::stdout())?;
+            return Ok(());
+        }
+    }
+
+    if optimize {
+        let stats = optimizer::optimize(&mut programs);
+        log::info!(
+            "optimized: {} -> {} commands ({} folded, {} simplified, {} inlined, {} functions removed)",
+            stats.commands_before,
+            stats.commands_after,
+            stats.folded,
+            stats.simplified,
+            stats.inlined,
+            stats.removed_functions.len()
+        );
+    }
+
+    if run {
+        return run_programs(&programs, native_os, max_steps);
+    }
+
+    // 最終的にはすべてのファイルを1つの.asmに書き出す
+    let stream: Box<dyn Write> = match output.as_ref() {
+        Some(path) => Box::new(BufWriter::new(
+            fs::File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let mut code_writer = code_writer::CodeWriter::with_stream(stream);
+    code_writer.checked = checked;
+    // Sys.initがあるときはSys.initから始める
+    if programs.iter().any(|program| program.defines("Sys.init")) {
+        code_writer.write_init();
+    }
+    // ファイルごとに並列に翻訳して (キャッシュがあれば使って)、ファイルの順につなげる
+    let cache = cache_dir.as_deref().map(cache::Cache::new).transpose()?;
+    let fragments =
+        code_writer::translate_parallel(&programs, jobs, |program| match cache.as_ref() {
+            Some(cache) => cache.translate(program, checked),
+            None => Ok(code_writer::CodeWriter::translate(program, checked)),
+        });
+    for fragment in fragments {
+        code_writer.write_fragment(&fragment?);
+    }
+    if let Some(cache) = cache {
+        log::info!(
+            "cache: {} reused, {} translated",
+            cache.hits.into_inner(),
+            cache.misses.into_inner()
+        );
+    }
+    code_writer.write_routines();
+    // .asmの命令とVMコマンドの対応 (標準出力に書いたときは出さない)
+    if let Some(output) = output {
+        let map = output.with_extension("map");
+        code_writer.write_source_map(&map.to_string_lossy())?;
+        log::info!("wrote {}", output.display());
+    }
+    Ok(())
+}
+
+/// ディレクトリの中の`.vm`ファイルを、`Sys.vm`を先頭にしてパスの順に並べる
+///
+/// * `dir`: - 探すディレクトリ
+/// * `recursive`: - サブディレクトリの中も探す (そうでなければサブディレクトリは飛ばす)
+fn vm_files(dir: &Path, recursive: bool) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files: Vec<PathBuf> = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = fs::read_dir(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                if recursive {
+                    dirs.push(path);
+                }
+            } else if path.extension().is_some_and(|extension| extension == "vm") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort_by_key(|path| {
+        (
+            path.file_name().is_none_or(|name| name != "Sys.vm"),
+            path.clone(),
+        )
+    });
+    Ok(files)
+}
+
+/// 複数の誤りを1行ずつ並べ、最後に数を付けた1つのメッセージにする
+fn join_errors<E: std::fmt::Display>(errors: &[E]) -> String {
+    let mut message = String::new();
+    for error in errors.iter() {
+        message.push_str(&format!("{}\n", error));
+    }
+    message.push_str(&format!("{} error(s)", errors.len()));
+    message
+}
+
+/// ファイル名 (拡張子を除く) が同じファイルがあれば誤りにする
+///
+/// staticは`Main.0`、比較やリターンアドレスのラベルは`Main$L0`のようにファイル名から付けるので、
+/// `a/Main.vm`と`b/Main.vm`を一緒に翻訳すると重なってしまう
+fn check_file_names(programs: &[command::VmFile]) -> Result<(), Box<dyn Error>> {
+    let mut seen: HashMap<&str, &str> = HashMap::new();
+    for program in programs.iter() {
+        let stem = Path::new(&program.path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        if let Some(first) = seen.insert(stem, &program.path) {
+            return Err(format!(
+                "{} and {} have the same file name {}; their statics and labels would collide",
+                first, program.path, stem
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// 出力する`.asm`のパス (ディレクトリ`Dir`なら`Dir/Dir.asm`、ファイル`Xxx.vm`なら`Xxx.asm`)
+fn output_path(input: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    if !input.is_dir() {
+        return Ok(input.with_extension("asm"));
+    }
+    // `.`のように名前のないディレクトリは絶対パスにして名前を得る
+    let mut name = match input.file_name() {
+        Some(name) => name.to_owned(),
+        None => fs::canonicalize(input)
+            .map_err(|e| format!("{}: {}", input.display(), e))?
+            .file_name()
+            .ok_or_else(|| format!("cannot name the output for {}", input.display()))?
+            .to_owned(),
+    };
+    name.push(".asm");
+    Ok(input.join(name))
+}
+
+/// VMコードを実行して、止まった位置とスタック (RAM[256]からSPの前まで) を表示する
+///
+/// * `native_os`: - OSの関数をネイティブで実行する
+/// * `max_steps`: - 実行するVMコマンドの最大数
+fn run_programs(
+    programs: &[command::VmFile],
+    native_os: bool,
+    max_steps: u64,
+) -> Result<(), Box<dyn Error>> {
+    let native = native_os.then(native::NativeOs::new);
+    let mut interpreter =
+        interpreter::Interpreter::new(programs, native).map_err(|errors| join_errors(&errors))?;
+    let stop = interpreter.run(max_steps)?;
+    match stop {
+        interpreter::Stop::Halted => println!("halted after {} commands", interpreter.steps),
+        interpreter::Stop::Limit => println!(
+            "stopped after {} commands at {}",
+            interpreter.steps,
+            interpreter.location()
+        ),
+    }
+    let sp = interpreter.ram[0] as usize;
+    let stack = interpreter.ram.get(256..sp).unwrap_or_default();
+    println!("SP: {}", sp);
+    println!(
+        "stack: {:?}",
+        stack.iter().map(|value| *value as i16).collect::<Vec<_>>()
+    );
+    Ok(())
+}
@@ -1,84 +1,494 @@
-use crate::parser::CommandType;
+use crate::command::{ArithmeticOp, Segment, VmCommand, VmFile};
 use std::fs::File;
 use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub struct CodeWriter {
-    pub stream: BufWriter<File>,
+/// スタックの先頭のアドレス
+const STACK_BASE: usize = 256;
+/// スタックに使える最後のアドレス (2048からはヒープ)
+const STACK_END: usize = 2047;
+/// RAMの最後のアドレス (キーボード)
+const RAM_END: usize = 24576;
+/// `call`がスタックに積むリターンアドレスとLCL, ARG, THIS, THATの数
+const FRAME_SIZE: usize = 5;
+
+pub struct CodeWriter<W: Write = BufWriter<File>> {
+    pub stream: W,
     pub base_name: String,
+    /// ファイルごとに0から数える (ラベルにはファイル名を付ける)
     label_number: usize,
     function_name: String,
+    /// 翻訳している`.vm`ファイルのパス
+    source_file: String,
+    /// 書き出した行数
+    asm_line: usize,
+    /// 次に書き出す命令のROMアドレス
+    rom_address: usize,
+    pub source_map: Vec<SourceMapping>,
+    /// スタックとセグメントの範囲を実行時に調べるコードを出力する
+    pub checked: bool,
+    /// 翻訳している関数のローカル変数の数
+    function_locals: u16,
+}
+
+/// 出力したアセンブリと元のVMコマンドの対応 (ソースマップの1行)
+#[derive(Debug, Clone)]
+pub struct SourceMapping {
+    /// 最初の命令のROMアドレス
+    pub rom_address: usize,
+    /// 最初の命令の`.asm`での行番号
+    pub asm_line: usize,
+    pub file: String,
+    pub line: usize,
+    /// VMコマンドを含む関数 (関数の外なら空)
+    pub function: String,
+    pub command: String,
+}
+
+impl SourceMapping {
+    /// ソースマップの1行 (`ROMアドレス, .asmの行番号, ファイル:行番号, 関数名, VMコマンド`をタブ区切り)
+    pub fn to_line(&self) -> String {
+        let function = if self.function.is_empty() {
+            "-"
+        } else {
+            self.function.as_str()
+        };
+        format!(
+            "{}\t{}\t{}:{}\t{}\t{}",
+            self.rom_address, self.asm_line, self.file, self.line, function, self.command
+        )
+    }
+
+    /// `to_line`で書いた1行を読む
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, '\t');
+        let rom_address = fields.next()?.parse().ok()?;
+        let asm_line = fields.next()?.parse().ok()?;
+        let (file, source_line) = fields.next()?.rsplit_once(':')?;
+        let function = match fields.next()? {
+            "-" => String::new(),
+            function => function.to_string(),
+        };
+        Some(SourceMapping {
+            rom_address,
+            asm_line,
+            file: file.to_string(),
+            line: source_line.parse().ok()?,
+            function,
+            command: fields.next()?.to_string(),
+        })
+    }
+}
+
+/// 1つの`.vm`ファイルを翻訳したアセンブリ
+///
+/// ROMアドレスと`.asm`の行番号は断片の先頭から数える。`CodeWriter::write_fragment`でつなげる
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    pub asm: String,
+    pub source_map: Vec<SourceMapping>,
 }
 
 impl CodeWriter {
     pub fn new(file_path: &str) -> std::io::Result<Self> {
         let file = File::create(file_path)?;
-        let stream = BufWriter::new(file);
-        let base_name = String::new();
-        return Ok(CodeWriter {
+        Ok(CodeWriter::with_stream(BufWriter::new(file)))
+    }
+}
+
+impl CodeWriter<Vec<u8>> {
+    /// 1つのファイルを、ほかのファイルとは関係なく翻訳する
+    ///
+    /// ラベルにはファイル名を付けるので、別々に翻訳した断片をつなげても重ならない
+    ///
+    /// * `program`: - 翻訳するファイル
+    /// * `checked`: - 実行時の検査のコードを出力する
+    pub fn translate(program: &VmFile, checked: bool) -> Fragment {
+        let mut code_writer = CodeWriter::with_stream(vec![]);
+        code_writer.checked = checked;
+        code_writer.set_base_name(&program.path);
+        for spanned in program.commands.iter() {
+            code_writer.set_source(spanned.span.line, &spanned.command);
+            code_writer.write_comment(&spanned.command);
+            code_writer.write_code(&spanned.command);
+        }
+        Fragment {
+            asm: String::from_utf8(code_writer.stream).unwrap(),
+            source_map: code_writer.source_map,
+        }
+    }
+}
+
+/// ファイルを`jobs`個のスレッドで1つずつ翻訳して、ファイルの順に返す
+///
+/// 各スレッドは次のファイルを取っては`translate`を呼ぶ。断片はファイルごとに独立しているので、
+/// どのスレッドが翻訳しても同じ結果になる
+///
+/// * `programs`: - 翻訳するファイル
+/// * `jobs`: - スレッドの数 (1ならスレッドを作らない)
+/// * `translate`: - 1つのファイルを翻訳する関数 (`CodeWriter::translate`かキャッシュ)
+pub fn translate_parallel<R, F>(programs: &[VmFile], jobs: usize, translate: F) -> Vec<R>
+where
+    R: Send,
+    F: Fn(&VmFile) -> R + Sync,
+{
+    if jobs <= 1 || programs.len() <= 1 {
+        return programs.iter().map(translate).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..jobs.min(programs.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(program) = programs.get(index) else {
+                            break results;
+                        };
+                        results.push((index, translate(program)));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+impl<W: Write> CodeWriter<W> {
+    pub fn with_stream(stream: W) -> Self {
+        CodeWriter {
             stream,
-            base_name,
+            base_name: String::new(),
             label_number: 0,
             function_name: String::new(),
-        });
+            source_file: String::new(),
+            asm_line: 0,
+            rom_address: 0,
+            source_map: vec![],
+            checked: false,
+            function_locals: 0,
+        }
     }
 
+    /// static変数の名前(`Xxx.i`)に使うファイル名を設定する
+    ///
+    /// * `file_path`: - 翻訳する`.vm`ファイルのパス
     pub fn set_base_name(&mut self, file_path: &str) {
-        let base_name = file_path.split('/').next().unwrap().to_string();
+        let base_name = std::path::Path::new(file_path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+            .to_string();
         self.base_name = base_name;
+        self.source_file = file_path.to_string();
+        // 前のファイルの関数の続きにしない
+        self.function_name = String::new();
+        self.function_locals = 0;
+    }
+
+    /// 別に翻訳したファイルをつなげる。ソースマップのアドレスと行番号は今の位置からにずらす
+    pub fn write_fragment(&mut self, fragment: &Fragment) {
+        let rom_address = self.rom_address;
+        let asm_line = self.asm_line;
+        self.source_map
+            .extend(fragment.source_map.iter().map(|mapping| SourceMapping {
+                rom_address: mapping.rom_address + rom_address,
+                asm_line: mapping.asm_line + asm_line,
+                ..mapping.clone()
+            }));
+        for line in fragment.asm.lines() {
+            self.count_line(line);
+        }
+        self.stream.write_all(fragment.asm.as_bytes()).unwrap();
+        self.stream.flush().unwrap();
     }
 
-    pub fn write_code(&mut self, command: CommandType, arg1: String, arg2: Option<usize>) {
+    /// これから書き出すコードがどのVMコマンドのものかを記録する
+    ///
+    /// * `line`: - `.vm`ファイルでの行番号
+    /// * `command`: - VMコマンド
+    pub fn set_source(&mut self, line: usize, command: &VmCommand) {
+        // function コマンドはその関数に含める
+        let function = match command {
+            VmCommand::Function(name, _) => name.clone(),
+            _ => self.function_name.clone(),
+        };
+        self.source_map.push(SourceMapping {
+            rom_address: self.rom_address,
+            asm_line: self.asm_line + 1,
+            file: self.source_file.clone(),
+            line,
+            function,
+            command: command.to_string(),
+        });
+    }
+
+    /// ソースマップを書き出す
+    /// 1行に`ROMアドレス, .asmの行番号, ファイル:行番号, 関数名, VMコマンド`をタブ区切りで書く
+    ///
+    /// * `file_path`: - 出力先 (`Foo.asm`なら`Foo.map`)
+    pub fn write_source_map(&self, file_path: &str) -> std::io::Result<()> {
+        let mut stream = BufWriter::new(File::create(file_path)?);
+        writeln!(stream, "# rom\tasm_line\tvm_file:line\tfunction\tcommand")?;
+        for mapping in self.source_map.iter() {
+            writeln!(stream, "{}", mapping.to_line())?;
+        }
+        stream.flush()
+    }
+
+    /// ブートストラップコード
+    /// SPを256にしてSys.initを呼び出す
+    pub fn write_init(&mut self) {
+        self.source_file = "(bootstrap)".to_string();
+        let call = VmCommand::Call("Sys.init".to_string(), 0);
+        self.set_source(0, &call);
+        self.write_simple_comment("start bootstrap");
+        self.write_multiple(vec!["@256", "D=A", "@SP", "M=D"]);
+        self.write_call("Sys.init", 0);
+        self.write_simple_comment("end bootstrap");
+    }
+
+    pub fn write_code(&mut self, command: &VmCommand) {
+        if self.checked {
+            self.write_checks(command);
+        }
         match command {
-            CommandType::C_POP => {
-                self.write_pop(arg1, arg2);
+            VmCommand::Push(segment, index) => {
+                self.write_push(Operand::Segment(*segment, *index));
             }
-            CommandType::C_PUSH => {
-                self.write_push(arg1, arg2);
+            VmCommand::Pop(segment, index) => {
+                self.write_pop(Operand::Segment(*segment, *index));
             }
-            CommandType::C_ARITHETIC => {
-                self.write_arithmetic(command, arg1);
+            VmCommand::Arithmetic(op) => {
+                self.write_arithmetic(*op);
             }
-            CommandType::C_LABEL => {
-                self.write_label(arg1);
+            VmCommand::Label(label) => {
+                self.write_label(label);
             }
-            CommandType::C_IF => {
-                self.write_if_goto(arg1);
+            VmCommand::IfGoto(label) => {
+                self.write_if_goto(label);
             }
-            CommandType::C_GOTO => {
-                self.write_goto(arg1);
+            VmCommand::Goto(label) => {
+                self.write_goto(label);
             }
-            CommandType::C_FUNCTION => {
-                self.write_function(arg1, arg2);
+            VmCommand::Function(name, n_vars) => {
+                self.write_function(name, *n_vars);
             }
-            CommandType::C_RETURN => {
+            VmCommand::Return => {
                 self.write_return();
             }
-            CommandType::C_CALL => {
-                self.write_call(arg1, arg2);
-            }
-            _ => {
-                unreachable!();
+            VmCommand::Call(name, n_args) => {
+                self.write_call(name, *n_args);
             }
         }
     }
 
-    pub fn write_arithmetic(&mut self, _command: CommandType, arg1: String) {
-        match &*arg1 {
-            "add" => self.write_add(),
-            "sub" => self.write_sub(),
-            "neg" => self.write_neg(),
-            "and" => self.write_and(),
-            "or" => self.write_or(),
-            "not" => self.write_not(),
-            "eq" => self.write_eq(),
-            "lt" => self.write_lt(),
-            "gt" => self.write_gt(),
-            "label" => self.write_label(arg1),
-            "if-goto" => self.write_if_goto(arg1),
-            "goto" => self.write_goto(arg1),
-            _ => {
-                unimplemented!();
+    /// VMコマンドを実行する前に、スタックとセグメントの範囲を調べる
+    fn write_checks(&mut self, command: &VmCommand) {
+        match command {
+            VmCommand::Push(segment, index) => {
+                self.write_segment_check(*segment, *index);
+                self.write_overflow_check(1);
             }
+            VmCommand::Pop(segment, index) => {
+                self.write_segment_check(*segment, *index);
+                self.write_underflow_check(1);
+            }
+            VmCommand::Arithmetic(ArithmeticOp::Neg | ArithmeticOp::Not) => {
+                self.write_underflow_check(1)
+            }
+            VmCommand::Arithmetic(_) => self.write_underflow_check(2),
+            VmCommand::IfGoto(_) | VmCommand::Return => self.write_underflow_check(1),
+            VmCommand::Function(_, n_vars) if *n_vars > 0 => {
+                self.write_overflow_check(*n_vars as usize)
+            }
+            VmCommand::Call(_, n_args) => {
+                self.write_underflow_check(*n_args as usize);
+                self.write_overflow_check(FRAME_SIZE);
+            }
+            _ => {}
+        }
+    }
+
+    /// `n`個pushしてもSPが`STACK_END + 1`を超えないこと
+    fn write_overflow_check(&mut self, n: usize) {
+        let limit = format!("@{}", STACK_END + 1 - n + 1);
+        self.write_check(
+            vec!["@SP", "D=M", limit.as_str(), "D=D-A"],
+            "D;JLT",
+            Trap::StackOverflow,
+        );
+    }
+
+    /// `n`個popできるだけ作業用スタックに値があること
+    ///
+    /// 関数の中ではLCLとローカル変数の上から、関数の外ではスタックの先頭から数える
+    fn write_underflow_check(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        if self.function_name.is_empty() {
+            let limit = format!("@{}", STACK_BASE + n);
+            self.write_check(
+                vec!["@SP", "D=M", limit.as_str(), "D=D-A"],
+                "D;JGE",
+                Trap::StackUnderflow,
+            );
+        } else {
+            let limit = format!("@{}", self.function_locals as usize + n);
+            self.write_check(
+                vec!["@SP", "D=M", "@LCL", "D=D-M", limit.as_str(), "D=D-A"],
+                "D;JGE",
+                Trap::StackUnderflow,
+            );
+        }
+    }
+
+    /// `this`と`that`が指すアドレスがRAMの範囲 (キーボードまで) にあること
+    ///
+    /// 引き算は符号付きで比べるので、ベースが負 (32768以上) のときとインデックスを足して
+    /// 溢れたときは、範囲と比べる前にトラップに飛ぶ。
+    /// `pointer`と`temp`のインデックスはパースするときに調べてある
+    fn write_segment_check(&mut self, segment: Segment, index: u16) {
+        let base = match segment {
+            Segment::This => "@THIS",
+            Segment::That => "@THAT",
+            _ => return,
+        };
+        let index = format!("@{}", index);
+        let limit = format!("@{}", RAM_END + 1);
+        self.write_guarded_check(
+            vec![
+                (vec![base, "D=M"], "D;JLT"),
+                (vec![index.as_str(), "D=D+A"], "D;JLT"),
+            ],
+            vec![limit.as_str(), "D=D-A"],
+            "D;JLT",
+            Trap::SegmentOutOfRange,
+        );
+    }
+
+    /// `compute`でDに値を計算して、`jump`が成り立たなければトラップに飛ぶ
+    ///
+    /// トラップには検査した場所のROMアドレスをDに入れて飛ぶので、エミュレータはソースマップから
+    /// どのVMコマンドで止まったかがわかる
+    fn write_check(&mut self, compute: Vec<&str>, jump: &str, trap: Trap) {
+        self.write_guarded_check(vec![], compute, jump, trap);
+    }
+
+    /// `write_check`の前に、`guards`の`(計算, 条件)`を順に行い、条件が成り立てばトラップに飛ぶ
+    ///
+    /// `guards`の計算はDを引き継ぐので、途中の値を調べながら1つの値を計算できる
+    fn write_guarded_check(
+        &mut self,
+        guards: Vec<(Vec<&str>, &str)>,
+        compute: Vec<&str>,
+        jump: &str,
+        trap: Trap,
+    ) {
+        self.write_simple_comment("start check");
+        let check_label = format!("__VM_CHECK_{}", self.get_label_name());
+        let ok_label = format!("{}_OK", check_label);
+        let fail_label = format!("{}_FAIL", check_label);
+        self.write(format!("({})", check_label).as_str());
+        let guarded = !guards.is_empty();
+        for (guard, fail) in guards {
+            self.write_multiple(guard);
+            self.write_multiple(vec![format!("@{}", fail_label).as_str(), fail]);
+        }
+        self.write_multiple(compute);
+        self.write_multiple(vec![format!("@{}", ok_label).as_str(), jump]);
+        if guarded {
+            self.write(format!("({})", fail_label).as_str());
+        }
+        self.write_multiple(vec![
+            format!("@{}", check_label).as_str(),
+            "D=A",
+            format!("@{}", trap.label()).as_str(),
+            "0;JMP",
+        ]);
+        self.write(format!("({})", ok_label).as_str());
+        self.write_simple_comment("end check");
+    }
+
+    /// プログラムの最後に置く共通のルーチン
+    ///
+    /// プログラムの終わりから続けて実行しないように、先に無限ループを置く
+    pub fn write_routines(&mut self) {
+        self.set_routine_source("end");
+        self.write_multiple(vec!["(__VM_END)", "@__VM_END", "0;JMP"]);
+        self.set_routine_source("call");
+        self.write_call_routine();
+        self.set_routine_source("return");
+        self.write_return_routine();
+        if self.checked {
+            self.set_routine_source("trap");
+            self.write_trap_routine();
+        }
+    }
+
+    /// エミュレータが最後のVMコマンドの続きと思わないように、ルーチンもソースマップに載せる
+    ///
+    /// * `name`: - ルーチンの名前 (ソースマップでは`(name):0`になる)
+    fn set_routine_source(&mut self, name: &str) {
+        self.source_map.push(SourceMapping {
+            rom_address: self.rom_address,
+            asm_line: self.asm_line + 1,
+            file: format!("({})", name),
+            line: 0,
+            function: String::new(),
+            command: name.to_string(),
+        });
+    }
+
+    /// 検査に失敗したときに飛ぶトラップ
+    ///
+    /// `__VM_TRAP`にエラーコード、`__VM_TRAP_PC`に検査した場所のROMアドレスを書いて止まる
+    fn write_trap_routine(&mut self) {
+        self.write_simple_comment("start trap");
+        for trap in [
+            Trap::StackOverflow,
+            Trap::StackUnderflow,
+            Trap::SegmentOutOfRange,
+        ] {
+            self.write_multiple(vec![
+                format!("({})", trap.label()).as_str(),
+                "@__VM_TRAP_PC",
+                "M=D",
+                format!("@{}", trap as usize).as_str(),
+                "D=A",
+                "@__VM_TRAP_SET",
+                "0;JMP",
+            ]);
+        }
+        self.write_multiple(vec![
+            "(__VM_TRAP_SET)",
+            "@__VM_TRAP",
+            "M=D",
+            "(__VM_TRAP_HALT)",
+            "@__VM_TRAP_HALT",
+            "0;JMP",
+        ]);
+        self.write_simple_comment("end trap");
+    }
+
+    pub fn write_arithmetic(&mut self, op: ArithmeticOp) {
+        match op {
+            ArithmeticOp::Add => self.write_binary("add", "M=D+M"),
+            ArithmeticOp::Sub => self.write_binary("sub", "M=M-D"),
+            ArithmeticOp::And => self.write_binary("and", "M=D&M"),
+            ArithmeticOp::Or => self.write_binary("or", "M=D|M"),
+            ArithmeticOp::Neg => self.write_unary("neg", "M=-M"),
+            ArithmeticOp::Not => self.write_unary("not", "M=!M"),
+            ArithmeticOp::Eq => self.write_compare("eq", "D;JEQ"),
+            ArithmeticOp::Lt => self.write_signed_compare("lt", "D;JLT"),
+            ArithmeticOp::Gt => self.write_signed_compare("gt", "D;JGT"),
         }
     }
 
@@ -87,34 +497,96 @@ impl CodeWriter {
     ///
     /// * `function_name`: - 関数の名前
     /// * `n_vars`: - ローカル変数の数
-    fn write_function(&mut self, function_name: String, n_vars: Option<usize>) {
+    fn write_function(&mut self, function_name: &str, n_vars: u16) {
         self.write_simple_comment("start function");
-        self.write(format!("({}.{})", self.base_name, function_name).as_str());
-        for i in 0..n_vars.unwrap_or(0) {
-            self.write_push("constant".to_string(), Some(0));
-            self.write_pop("local".to_string(), Some(i));
+        self.write(format!("({})", function_name).as_str());
+        self.function_name = function_name.to_string();
+        self.function_locals = n_vars;
+        // LCLはSPと同じ場所を指しているので、SPから0を書いて進めればローカル変数の領域になる
+        if n_vars > 0 {
+            self.write_multiple(vec!["@SP", "A=M", "M=0"]);
+            for _ in 1..n_vars {
+                self.write_multiple(vec!["A=A+1", "M=0"]);
+            }
+            self.write_multiple(vec!["D=A+1", "@SP", "M=D"]);
         }
         self.write_simple_comment("end function");
     }
 
+    /// 共通の`__VM_RETURN`に飛ぶ
     fn write_return(&mut self) {
         self.write_simple_comment("start return");
+        self.write_multiple(vec!["@__VM_RETURN", "0;JMP"]);
+        self.write_simple_comment("end return");
+    }
+
+    /// 関数から戻る共通のルーチン
+    fn write_return_routine(&mut self) {
+        self.write_simple_comment("start return routine");
+        self.write("(__VM_RETURN)");
         // ARG, SP, THAT, THIS, ARG, LCLを復元
 
+        // frame = LCL (R13)
+        self.write_multiple(vec!["@LCL", "D=M", "@R13", "M=D"]);
+
+        // リターンアドレス = *(frame - 5) (R14)
+        // 引数が0個のときは戻り値で上書きされるので先に取っておく
+        self.write_multiple(vec!["@5", "A=D-A", "D=M", "@R14", "M=D"]);
+
         // ARG
-        self.write_pop("argument".to_string(), None);
+        self.write_multiple(vec!["@SP", "AM=M-1", "D=M", "@ARG", "A=M", "M=D"]);
+
+        // SP = ARG + 1
+        self.write_multiple(vec!["@ARG", "D=M+1", "@SP", "M=D"]);
+
+        // THAT, THIS, ARG, LCL = *(frame - 1), ..., *(frame - 4)
+        for symbol in ["THAT", "THIS", "ARG", "LCL"] {
+            self.write_multiple(vec![
+                "@R13",
+                "AM=M-1",
+                "D=M",
+                format!("@{}", symbol).as_str(),
+                "M=D",
+            ]);
+        }
 
         // return アドレスに移動
+        self.write_multiple(vec!["@R14", "A=M", "0;JMP"]);
 
-        self.write_simple_comment("end return");
+        self.write_simple_comment("end return routine");
     }
 
     ///  関数呼び出し
+    /// 呼び出す関数のアドレスをR13, 引数とフレームの数をR14, リターンアドレスをDに入れて
+    /// 共通の`__VM_CALL`に飛ぶ
     ///
     /// * `function_name`: - 関数の名前
     /// * `n_args`: - stackに積まれている引数の数
-    fn write_call(&mut self, function_name: String, n_args: Option<usize>) {
+    fn write_call(&mut self, function_name: &str, n_args: u16) {
         self.write_simple_comment("start call function");
+        let return_label = self.get_new_return_label();
+        // 関数名とリターンアドレスは関数内のラベルと違って関数名を付けない
+        self.write_multiple(vec![
+            format!("@{}", function_name).as_str(),
+            "D=A",
+            "@R13",
+            "M=D",
+            format!("@{}", n_args as usize + FRAME_SIZE).as_str(),
+            "D=A",
+            "@R14",
+            "M=D",
+            format!("@{}", return_label).as_str(),
+            "D=A",
+            "@__VM_CALL",
+            "0;JMP",
+        ]);
+        self.write(format!("({})", return_label).as_str());
+        self.write_simple_comment("end call function");
+    }
+
+    /// 関数を呼び出す共通のルーチン
+    fn write_call_routine(&mut self) {
+        self.write_simple_comment("start call routine");
         /* こんな感じでスタックに積む
          * push return return address
          * push LCL
@@ -123,345 +595,278 @@ impl CodeWriter {
          * push that
          * arg = sp - 5 - nargs
          * lcl = sp
-         * goto f
-         * (return address)*/
+         * goto f */
+        self.write("(__VM_CALL)");
 
         // リターンアドレスのpush
-        let return_label = self.get_new_return_label();
-        self.write_multiple(vec![format!("@{}", return_label).as_str(), "D=A"]);
-        self.write_push_from_d();
-
-        // localのpush
-        self.load_d_from_args("LCL".to_string(), None);
-        self.write_push_from_d();
-
-        // argumentのpush
-        self.load_d_from_args("ARG".to_string(), None);
-        self.write_push_from_d();
-
-        // THISのpush
-        self.load_d_from_args("THIS".to_string(), None);
         self.write_push_from_d();
 
-        // THATのpush
-        self.load_d_from_args("THAT".to_string(), None);
-        self.write_push_from_d();
+        // LCL, ARG, THIS, THATのpush
+        for address in [
+            VmAddress::LCL,
+            VmAddress::ARG,
+            VmAddress::THIS,
+            VmAddress::THAT,
+        ] {
+            self.load_d_from(Operand::Register(address));
+            self.write_push_from_d();
+        }
 
         // ARGの設定
-        self.write_multiple(vec![
-            "@SP",
-            "D=A",
-            "@5",
-            "D=D-A",
-            format!("@{}", n_args.unwrap()).as_str(),
-            "D=D-A",
-            "@ARG",
-            "M=D",
-        ]);
+        self.write_multiple(vec!["@SP", "D=M", "@R14", "D=D-M", "@ARG", "M=D"]);
 
         // LCLの設定
-        self.write_multiple(vec!["@SP", "D=A", "@LCL", "M=D"]);
+        self.write_multiple(vec!["@SP", "D=M", "@LCL", "M=D"]);
 
         // goto function
-        let function_label = format!("{}.{}", self.base_name, function_name);
-        self.write_goto(function_label);
+        self.write_multiple(vec!["@R13", "A=M", "0;JMP"]);
 
-        self.write_label(return_label);
-
-        self.write_simple_comment("end call function");
+        self.write_simple_comment("end call routine");
     }
 
-    fn write_label(&mut self, label: String) {
+    fn write_label(&mut self, label: &str) {
         self.write_simple_comment("start label");
+        let label = self.scoped_label(label);
         self.write(format!("({})", label).as_str());
-        self.write_simple_comment("start label");
+        self.write_simple_comment("end label");
     }
 
-    fn write_if_goto(&mut self, label: String) {
+    fn write_if_goto(&mut self, label: &str) {
         self.write_simple_comment("start if-goto");
-        self.load_symbol("@SP".to_string());
         // 事実上のpop
-        self.load_m("M-1");
-        self.load_a("M");
-        self.load_d("M");
+        self.write_multiple(vec!["@SP", "AM=M-1", "D=M"]);
+        let label = self.scoped_label(label);
         self.write(format!("@{}", label).as_str());
-        self.write("D;JGT");
+        // 0以外ならジャンプする
+        self.write("D;JNE");
         self.write_simple_comment("end if-goto");
     }
 
-    fn write_goto(&mut self, label: String) {
+    fn write_goto(&mut self, label: &str) {
         self.write_simple_comment("start goto");
+        let label = self.scoped_label(label);
         self.write(format!("@{}", label).as_str());
         self.write("0;JMP");
         self.write_simple_comment("end goto");
     }
 
-    pub fn write_comment(&mut self, command: CommandType, arg1: String, arg2: Option<usize>) {
-        let comment = format!(
-            "// {}, {}, {}",
-            command.to_string(),
-            arg1,
-            arg2.unwrap_or(0)
-        );
+    pub fn write_comment(&mut self, command: &VmCommand) {
+        let comment = format!("// {}", command);
+        self.count_line(&comment);
         self.stream.write_all(comment.as_bytes()).unwrap();
         self.write_line_break();
     }
 
     pub fn write_simple_comment(&mut self, comment: &str) {
         let comment = format!("//{}", comment);
+        self.count_line(&comment);
         self.stream.write_all(comment.as_bytes()).unwrap();
         self.write_line_break();
     }
 
+    /// 関数内のラベルは`関数名$ラベル`にする
+    fn scoped_label(&self, label: &str) -> String {
+        if self.function_name.is_empty() {
+            label.to_string()
+        } else {
+            format!("{}${}", self.function_name, label)
+        }
+    }
+
     // write_callのときのreturn labelを取得する
     fn get_new_return_label(&mut self) -> String {
         let return_label = format!("__RETURN__{}", self.get_label_name());
-        return return_label;
+        return_label
     }
 
-    ///
+    /// 重複しないラベル名(`Main$L0`, `Main$L1`, ...、ブートストラップでは`L0`)を取得する
     fn get_label_name(&mut self) -> String {
-        let label_name = format!("L{}", self.label_number);
+        let label_name = if self.base_name.is_empty() {
+            format!("L{}", self.label_number)
+        } else {
+            format!("{}$L{}", self.base_name, self.label_number)
+        };
         self.label_number += 1;
         label_name
     }
 
+    /// 行番号と、命令ならROMアドレスを進める
+    fn count_line(&mut self, line: &str) {
+        self.asm_line += 1;
+        if !line.starts_with("//") && !line.starts_with('(') {
+            self.rom_address += 1;
+        }
+    }
+
     /// 改行を追加するだけ
     fn write_line_break(&mut self) {
         self.stream.write_all(b"\n").unwrap();
         self.stream.flush().unwrap();
     }
 
-    /// スタックの先頭の値をpopして、segment[index](arg1[arg2])に格納する
-    ///
-    /// * `arg1`: segment
-    /// * `arg2`: index
-    fn write_pop(&mut self, arg1: String, arg2: Option<usize>) {
+    /// スタックの先頭の値をpopして、operandに格納する
+    fn write_pop(&mut self, operand: Operand) {
         self.write_simple_comment("start pop");
-        // ここでMに格納先アドレスが入れる
-        self.load_m_from_args(arg1.clone(), arg2.clone());
-        self.load_d("A");
-        self.load_symbol("@15".to_string());
-        self.load_m("D");
-
-        // スタックのデータ取り出し
-        self.load_symbol("@SP".to_string());
-        self.load_m("M-1"); // M = M - 1
-        self.load_a("M"); // A = M
-        self.load_d("M"); // D = *SP
-
-        self.load_symbol("@15".to_string());
-        self.load_a("M");
-        self.load_m("D");
-
+        match operand {
+            Operand::Segment(
+                segment @ (Segment::Local | Segment::Argument | Segment::This | Segment::That),
+                index,
+            ) => {
+                let base = format!("@{}", VmAddress::base_of(segment).as_usize());
+                if index == 0 {
+                    self.write_multiple(vec!["@SP", "AM=M-1", "D=M", base.as_str(), "A=M", "M=D"]);
+                } else {
+                    // 格納先のアドレスをR13に取っておく
+                    self.write_multiple(vec![
+                        base.as_str(),
+                        "D=M",
+                        format!("@{}", index).as_str(),
+                        "D=D+A",
+                        "@R13",
+                        "M=D",
+                        "@SP",
+                        "AM=M-1",
+                        "D=M",
+                        "@R13",
+                        "A=M",
+                        "M=D",
+                    ]);
+                }
+            }
+            _ => {
+                self.write_multiple(vec!["@SP", "AM=M-1", "D=M"]);
+                self.load_m_from(operand);
+                self.load_m("D");
+            }
+        }
         self.write_simple_comment("end pop");
     }
 
-    /// segment[index](arg1[arg2])の値をスタックにpushする
-    ///
-    /// * `arg1`: segment
-    /// * `arg2`: index
-    fn write_push(&mut self, arg1: String, arg2: Option<usize>) {
+    /// operandの値をスタックにpushする
+    fn write_push(&mut self, operand: Operand) {
         self.write_simple_comment("start push");
-        self.load_d_from_args(arg1.clone(), arg2.clone());
-        // self.load_symbol("@15".to_string());
-        // self.load_m("D");
-
-        self.load_symbol("@SP".to_string());
-        self.load_a("M");
-        self.load_m("D");
-        self.load_symbol("@SP".to_string());
-        self.load_m("M+1");
+        self.load_d_from(operand);
+        self.write_push_from_d();
         self.write_simple_comment("end push");
     }
 
-    // stackから2つ持ってきて足す
-    fn write_add(&mut self) {
-        self.write_simple_comment("start add");
-        self.write_pop("R13".to_string(), None);
-        self.write_pop("R14".to_string(), None);
-        self.load_d_from_args("R13".to_string(), None);
-        self.load_m_from_args("R14".to_string(), None);
-        self.load_m("D+M");
-        self.write_push("R14".to_string(), None);
-        self.write_simple_comment("end add");
-    }
-
-    /// stackから2つ持ってきて引く
-    fn write_sub(&mut self) {
-        self.write_simple_comment("start sub");
-        self.write_pop("R13".to_string(), None);
-        self.write_pop("R14".to_string(), None);
-        self.load_d_from_args("R14".to_string(), None);
-        self.load_m_from_args("R13".to_string(), None);
-        self.load_m("D-M");
-        self.write_push("R13".to_string(), None);
-        self.write_simple_comment("end sub");
-    }
-
-    /// stackから1つ持ってきて-1倍する
-    fn write_neg(&mut self) {
-        self.write_simple_comment("start neg");
-        self.write_pop("R13".to_string(), None);
-        self.load_m_from_args("R13".to_string(), None);
-        self.load_m("-M");
-        self.write_push("R13".to_string(), None);
-        self.write_simple_comment("end neg");
-    }
-
-    /// stackから2つ持ってきてandする
-    fn write_and(&mut self) {
-        self.write_simple_comment("start and");
-        self.write_pop("R13".to_string(), None);
-        self.write_pop("R14".to_string(), None);
-        self.load_d_from_args("R14".to_string(), None);
-        self.load_m_from_args("R13".to_string(), None);
-        self.load_d("D&M");
-        self.load_m("D");
-        self.write_push("R13".to_string(), None);
-        self.write_simple_comment("end and");
-    }
-
-    /// stackから2つ持ってきてorを取る
-    fn write_or(&mut self) {
-        self.write_simple_comment("start or");
-        self.write_pop("R13".to_string(), None);
-        self.write_pop("R14".to_string(), None);
-        self.load_d_from_args("R14".to_string(), None);
-        self.load_m_from_args("R13".to_string(), None);
-        self.load_d("D|M");
-        self.load_m("D");
-        self.write_push("R13".to_string(), None);
-        self.write_simple_comment("end or");
-    }
-
-    /// スタックの先頭の値を取り出して、その値を反転してpushする
-    fn write_not(&mut self) {
-        self.write_simple_comment("start not");
-        self.write_pop("R13".to_string(), None);
-        self.load_m_from_args("R13".to_string(), None);
-        self.load_m("!M");
-        self.write_push("R13".to_string(), None);
-        self.write_simple_comment("end not");
-    }
-
-    /// stackから2つ持ってきて等しいかどうかを判定する
-    /// 等しい場合は-1を、そうでない場合は0をpushする
-    fn write_eq(&mut self) {
-        self.write_simple_comment("start eq");
-        self.write_pop("R13".to_string(), None);
-        self.write_pop("R14".to_string(), None);
-        let label_true = self.get_label_name();
-        let label_false = self.get_label_name();
+    /// stackから2つ持ってきて計算し、結果をpushする
+    /// yをDに取り出して、xのあった場所をMにしてから`compute`を実行する
+    ///
+    /// * `name`: - コメントに使う名前
+    /// * `compute`: - 結果をMに書く命令 (`M=D+M`など)
+    fn write_binary(&mut self, name: &str, compute: &str) {
+        self.write_simple_comment(format!("start {}", name).as_str());
+        self.write_multiple(vec!["@SP", "AM=M-1", "D=M", "A=A-1", compute]);
+        self.write_simple_comment(format!("end {}", name).as_str());
+    }
+
+    /// スタックの先頭の値をその場で書き換える
+    ///
+    /// * `name`: - コメントに使う名前
+    /// * `compute`: - 結果をMに書く命令 (`M=-M`など)
+    fn write_unary(&mut self, name: &str, compute: &str) {
+        self.write_simple_comment(format!("start {}", name).as_str());
+        self.write_multiple(vec!["@SP", "A=M-1", compute]);
+        self.write_simple_comment(format!("end {}", name).as_str());
+    }
+
+    /// stackから2つ持ってきてx - yを`jump`で比べる
+    /// 成り立てば-1を、そうでなければ0をpushする
+    ///
+    /// * `name`: - コメントに使う名前
+    /// * `jump`: - x - yについてのジャンプ命令 (`D;JEQ`など)
+    fn write_compare(&mut self, name: &str, jump: &str) {
+        self.write_simple_comment(format!("start {}", name).as_str());
         let next_label = self.get_label_name();
-        self.load_d_from_args("R13".to_string(), None);
-        self.load_m_from_args("R14".to_string(), None);
-        self.load_d("D-M");
-        self.write_multiple(vec![
-            format!("@{}", label_true).as_str(),
-            "D;JEQ",
-            format!("@{}", label_false).as_str(),
-            "0;JMP",
-        ]);
+        // 先に真を書いておき、成り立たなければ0で上書きする
         self.write_multiple(vec![
-            format!("({})", label_true).as_str(),
-            "D=-1",
-            format!("@{}", next_label).as_str(),
-            "0;JMP",
-        ]);
-        self.write_multiple(vec![
-            format!("({})", label_false).as_str(),
-            "D=0",
+            "@SP",
+            "AM=M-1",
+            "D=M",
+            "A=A-1",
+            "D=M-D",
+            "M=-1",
             format!("@{}", next_label).as_str(),
-            "0;JMP",
+            jump,
+            "@SP",
+            "A=M-1",
+            "M=0",
+            format!("({})", next_label).as_str(),
         ]);
-        self.write(format!("({})", next_label).as_str());
-        self.write("@13");
-        self.load_m("D");
-        self.write_push("R13".to_string(), None);
-        self.write_simple_comment("end eq")
-    }
-
-    /// stackから2つ持ってきて、その大小を比較する (x < y)
-    /// x < y の場合は-1を、そうでない場合は0をpushする
-    fn write_lt(&mut self) {
-        self.write_simple_comment("start lt");
-        self.write_pop("R13".to_string(), None);
-        self.write_pop("R14".to_string(), None);
-        let label_true = self.get_label_name();
-        let label_false = self.get_label_name();
+        self.write_simple_comment(format!("end {}", name).as_str());
+    }
+
+    /// stackから2つ持ってきて、符号付きの大小を`jump`で比べる
+    /// 成り立てば-1を、そうでなければ0をpushする
+    ///
+    /// 符号が違うときにx - yを計算すると溢れる (32767 - (-2)が負になる) ので、
+    /// 符号が違えば符号だけで決めた値 (xが負なら-1、そうでなければ1) を比べる
+    ///
+    /// * `name`: - コメントに使う名前
+    /// * `jump`: - x - yについてのジャンプ命令 (`D;JLT`など)
+    fn write_signed_compare(&mut self, name: &str, jump: &str) {
+        self.write_simple_comment(format!("start {}", name).as_str());
+        let y_negative = self.get_label_name();
+        let same_sign = self.get_label_name();
+        let result = self.get_label_name();
         let next_label = self.get_label_name();
-        self.load_d_from_args("R14".to_string(), None);
-        self.load_m_from_args("R13".to_string(), None);
-        self.load_d("D-M");
         self.write_multiple(vec![
-            format!("@{}", label_true).as_str(),
+            "@SP",
+            "AM=M-1",
+            "D=M",
+            format!("@{}", y_negative).as_str(),
             "D;JLT",
-            format!("@{}", label_false).as_str(),
-            "0;JMP",
-        ]);
-        self.write_multiple(vec![
-            format!("({})", label_true).as_str(),
+            // y >= 0
+            "@SP",
+            "A=M-1",
+            "D=M",
+            format!("@{}", same_sign).as_str(),
+            "D;JGE",
             "D=-1",
-            format!("@{}", next_label).as_str(),
-            "0;JMP",
-        ]);
-        self.write_multiple(vec![
-            format!("({})", label_false).as_str(),
-            "D=0",
-            format!("@{}", next_label).as_str(),
+            format!("@{}", result).as_str(),
             "0;JMP",
-        ]);
-        self.write(format!("({})", next_label).as_str());
-        self.write("@13");
-        self.load_m("D");
-        self.write_push("R13".to_string(), None);
-        self.write_simple_comment("end lt")
-    }
-
-    /// stackから2つ持ってきて、その大小を比較する (x > y)
-    /// x > y の場合は-1を、そうでない場合は0をpushする
-    fn write_gt(&mut self) {
-        self.write_simple_comment("start gt");
-        self.write_pop("R13".to_string(), None);
-        self.write_pop("R14".to_string(), None);
-        let label_true = self.get_label_name();
-        let label_false = self.get_label_name();
-        let next_label = self.get_label_name();
-        self.load_d_from_args("R14".to_string(), None);
-        self.load_m_from_args("R13".to_string(), None);
-        self.load_d("D-M");
-        self.write_multiple(vec![
-            format!("@{}", label_true).as_str(),
-            "D;JGT",
-            format!("@{}", label_false).as_str(),
-            "0;JMP",
-        ]);
-        self.write_multiple(vec![
-            format!("({})", label_true).as_str(),
-            "D=-1",
-            format!("@{}", next_label).as_str(),
+            format!("({})", y_negative).as_str(),
+            "@SP",
+            "A=M-1",
+            "D=M",
+            format!("@{}", same_sign).as_str(),
+            "D;JLT",
+            "D=1",
+            format!("@{}", result).as_str(),
             "0;JMP",
-        ]);
-        self.write_multiple(vec![
-            format!("({})", label_false).as_str(),
-            "D=0",
+            // 同じ符号ならx - yは溢れない
+            format!("({})", same_sign).as_str(),
+            "@SP",
+            "A=M",
+            "D=M",
+            "A=A-1",
+            "D=M-D",
+            // 先に真を書いておき、成り立たなければ0で上書きする
+            format!("({})", result).as_str(),
+            "@SP",
+            "A=M-1",
+            "M=-1",
             format!("@{}", next_label).as_str(),
-            "0;JMP",
+            jump,
+            "@SP",
+            "A=M-1",
+            "M=0",
+            format!("({})", next_label).as_str(),
         ]);
-        self.write(format!("({})", next_label).as_str());
-        self.write("@13");
-        self.load_m("D");
-        self.write_push("R13".to_string(), None);
-        self.write_simple_comment("end gt")
+        self.write_simple_comment(format!("end {}", name).as_str());
     }
 
     fn write(&mut self, command: &str) {
+        self.count_line(command);
         self.stream.write_all(command.as_bytes()).unwrap();
         self.write_line_break();
     }
 
     fn write_multiple(&mut self, commands: Vec<&str>) {
+        for command in commands.iter() {
+            self.count_line(command);
+        }
         let command = commands.join("\n");
         self.stream.write_all(command.as_bytes()).unwrap();
         self.write_line_break();
@@ -489,112 +894,77 @@ impl CodeWriter {
         self.write(arg.as_str());
     }
 
-    /// Dレジスタに指定したアドレスのデータをロードする
-    ///
-    /// * `arg1`: segment
-    /// * `arg2`: index
-    fn load_d_from_args(&mut self, arg1: String, arg2: Option<usize>) {
-        match &*arg1 {
-            "local" | "argument" | "this" | "that" => match &*arg1 {
-                /* 動的にメモリの位置が変更される */
-                "argument" => self
-                    .load_d_from_dynamic_index_value(VmAddress::ARG.as_usize(), arg2.unwrap_or(0)),
-                "local" => self
-                    .load_d_from_dynamic_index_value(VmAddress::LCL.as_usize(), arg2.unwrap_or(0)),
-                "this" => self
-                    .load_d_from_dynamic_index_value(VmAddress::THIS.as_usize(), arg2.unwrap_or(0)),
-                "that" => self
-                    .load_d_from_dynamic_index_value(VmAddress::THAT.as_usize(), arg2.unwrap_or(0)),
-                _ => {
-                    unreachable!();
-                }
-            },
-            "static" => {
-                self.load_d_from_static_index_value(VmAddress::STATIC.as_usize(), arg2.unwrap_or(0))
-            }
-            "constant" => {
-                self.write_multiple(vec![format!("@{}", arg2.unwrap_or(0)).as_str(), "D=A"]);
+    /// Dレジスタにoperandの値をロードする
+    fn load_d_from(&mut self, operand: Operand) {
+        match operand {
+            Operand::Segment(Segment::Constant, index) => {
+                self.write_multiple(vec![format!("@{}", index).as_str(), "D=A"]);
             }
-            "pointer" => match arg2.unwrap_or(0) {
-                0 => self.load_d_from_static_index_value(VmAddress::THIS.as_usize(), 0),
-                1 => self.load_d_from_static_index_value(VmAddress::THAT.as_usize(), 0),
-                _ => unreachable!(),
-            },
-            "temp" => {
-                self.load_d_from_static_index_value(VmAddress::TEMP.as_usize(), arg2.unwrap_or(0))
-            }
-            "R13" => self.load_d_from_static_index_value(VmAddress::R13.as_usize(), 0),
-            "R14" => self.load_d_from_static_index_value(VmAddress::R14.as_usize(), 0),
-            "R15" => self.load_d_from_static_index_value(VmAddress::R15.as_usize(), 0),
             _ => {
-                unreachable!();
+                self.load_m_from(operand);
+                self.load_d("M");
             }
-        };
+        }
     }
 
-    /// Mレジスタに指定したアドレスのデータをロードする
-    ///
-    /// * `arg1`: segment
-    /// * `arg2`: index
-    fn load_m_from_args(&mut self, arg1: String, arg2: Option<usize>) {
-        match &*arg1 {
-            "local" | "argument" | "this" | "that" => match &*arg1 {
-                /* 動的にメモリの位置が変更される */
-                "argument" => self
-                    .load_m_from_dynamic_index_value(VmAddress::ARG.as_usize(), arg2.unwrap_or(0)),
-                "local" => self
-                    .load_m_from_dynamic_index_value(VmAddress::LCL.as_usize(), arg2.unwrap_or(0)),
-                "this" => self
-                    .load_m_from_dynamic_index_value(VmAddress::THIS.as_usize(), arg2.unwrap_or(0)),
-                "that" => self
-                    .load_m_from_dynamic_index_value(VmAddress::THAT.as_usize(), arg2.unwrap_or(0)),
-                _ => {
-                    unreachable!();
-                }
-            },
-            "static" => {
-                self.load_m_from_static_index_value(VmAddress::STATIC.as_usize(), arg2.unwrap_or(0))
+    /// Mレジスタがoperandを指すようにする
+    fn load_m_from(&mut self, operand: Operand) {
+        match operand {
+            /* 動的にメモリの位置が変更される */
+            Operand::Segment(Segment::Argument, index) => {
+                self.load_m_from_dynamic_index_value(VmAddress::ARG.as_usize(), index as usize)
             }
-            "pointer" => match arg2.unwrap_or(0) {
-                0 => self.load_m_from_static_index_value(VmAddress::THIS.as_usize(), 0),
-                1 => self.load_m_from_static_index_value(VmAddress::THAT.as_usize(), 0),
-                _ => unreachable!(),
-            },
-            "temp" => {
-                self.load_m_from_static_index_value(VmAddress::TEMP.as_usize(), arg2.unwrap_or(0))
+            Operand::Segment(Segment::Local, index) => {
+                self.load_m_from_dynamic_index_value(VmAddress::LCL.as_usize(), index as usize)
             }
-            "R13" => self.load_m_from_static_index_value(VmAddress::R13.as_usize(), 0),
-            "R14" => self.load_m_from_static_index_value(VmAddress::R14.as_usize(), 0),
-            "R15" => self.load_m_from_static_index_value(VmAddress::R15.as_usize(), 0),
-
-            _ => {
-                unreachable!();
+            Operand::Segment(Segment::This, index) => {
+                self.load_m_from_dynamic_index_value(VmAddress::THIS.as_usize(), index as usize)
+            }
+            Operand::Segment(Segment::That, index) => {
+                self.load_m_from_dynamic_index_value(VmAddress::THAT.as_usize(), index as usize)
+            }
+            Operand::Segment(Segment::Static, index) => {
+                self.load_m_from_static_symbol(index as usize)
+            }
+            Operand::Segment(Segment::Pointer, index) => {
+                self.load_m_from_static_index_value(VmAddress::THIS.as_usize(), index as usize)
+            }
+            Operand::Segment(Segment::Temp, index) => {
+                self.load_m_from_static_index_value(VmAddress::TEMP.as_usize(), index as usize)
+            }
+            Operand::Segment(Segment::Constant, _) => {
+                unreachable!("constant segment has no address")
+            }
+            // 関数呼び出しで保存するポインタの値そのものや作業用のレジスタ
+            Operand::Register(address) => {
+                self.load_m_from_static_index_value(address.as_usize(), 0)
             }
         };
     }
 
-    /// Dレジスタに指定したアドレスのデータをロードする
-    fn load_d_from_dynamic_index_value(&mut self, base_address: usize, offset: usize) {
-        self.load_m_from_dynamic_index_value(base_address, offset);
-        self.load_d("M");
-    }
-
     /// Mレジスタに指定したアドレスのデータをロードする
+    /// オフセットが0か1のときはDを使わない
     fn load_m_from_dynamic_index_value(&mut self, base_address: usize, offset: usize) {
         self.write_simple_comment("start load_m_from_dynamic_index_value");
         let base_symbol = format!("@{}", base_address);
-        let offset_symbol = format!("@{}", offset);
         self.load_symbol(base_symbol);
-        self.load_d("M");
-        self.load_symbol(offset_symbol);
-        self.load_a("D+A");
+        match offset {
+            0 => self.load_a("M"),
+            1 => self.load_a("M+1"),
+            _ => {
+                self.load_d("M");
+                self.load_symbol(format!("@{}", offset));
+                self.load_a("D+A");
+            }
+        }
         self.write_simple_comment("end load_m_from_dynamic_index_value");
     }
 
-    /// Dレジスタに指定したアドレスのデータをロードする
-    fn load_d_from_static_index_value(&mut self, base_address: usize, offset: usize) {
-        self.load_m_from_static_index_value(base_address, offset);
-        self.load_d("M");
+    /// Mレジスタにstatic変数(`Xxx.i`)をロードする
+    /// アドレスはアセンブラが16番地から割り当てる
+    fn load_m_from_static_symbol(&mut self, index: usize) {
+        let symbol = format!("@{}.{}", self.base_name, index);
+        self.load_symbol(symbol);
     }
 
     /// Mレジスタに指定したアドレスのデータをロードする
@@ -604,22 +974,54 @@ impl CodeWriter {
     }
 }
 
+/// `--checked`で検査に失敗したときに`__VM_TRAP`に書くエラーコード
+#[derive(Copy, Clone)]
+enum Trap {
+    StackOverflow = 1,
+    StackUnderflow = 2,
+    SegmentOutOfRange = 3,
+}
+
+impl Trap {
+    fn label(&self) -> &'static str {
+        match self {
+            Trap::StackOverflow => "__VM_TRAP_OVERFLOW",
+            Trap::StackUnderflow => "__VM_TRAP_UNDERFLOW",
+            Trap::SegmentOutOfRange => "__VM_TRAP_SEGMENT",
+        }
+    }
+}
+
+/// push/popの対象
 #[derive(Copy, Clone)]
+enum Operand {
+    Segment(Segment, u16),
+    Register(VmAddress),
+}
+
+#[derive(Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 enum VmAddress {
     LCL = 1,
     ARG = 2,
     THIS = 3,
     THAT = 4,
     TEMP = 5,
-    R13 = 13,
-    R14 = 14,
-    R15 = 15,
-    STATIC = 16,
 }
 
 impl VmAddress {
+    /// `local`, `argument`, `this`, `that`のベースアドレスを持つレジスタ
+    fn base_of(segment: Segment) -> Self {
+        match segment {
+            Segment::Local => VmAddress::LCL,
+            Segment::Argument => VmAddress::ARG,
+            Segment::This => VmAddress::THIS,
+            Segment::That => VmAddress::THAT,
+            _ => unreachable!("{} segment has no base address", segment),
+        }
+    }
+
     fn as_usize(&self) -> usize {
         *self as usize
     }
 }
-
@@ -0,0 +1,193 @@
+use std::fmt;
+
+/// VMのメモリセグメント
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Local,
+    Argument,
+    This,
+    That,
+    Constant,
+    Static,
+    Pointer,
+    Temp,
+}
+
+impl Segment {
+    pub const ALL: [Segment; 8] = [
+        Segment::Local,
+        Segment::Argument,
+        Segment::This,
+        Segment::That,
+        Segment::Constant,
+        Segment::Static,
+        Segment::Pointer,
+        Segment::Temp,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Segment::ALL
+            .into_iter()
+            .find(|segment| segment.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Segment::Local => "local",
+            Segment::Argument => "argument",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Constant => "constant",
+            Segment::Static => "static",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+        }
+    }
+
+    /// インデックスの最大値
+    pub fn max_index(&self) -> u16 {
+        match self {
+            Segment::Pointer => 1,
+            Segment::Temp => 7,
+            _ => 32767,
+        }
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// 算術・論理コマンド
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+}
+
+impl ArithmeticOp {
+    pub const ALL: [ArithmeticOp; 9] = [
+        ArithmeticOp::Add,
+        ArithmeticOp::Sub,
+        ArithmeticOp::Neg,
+        ArithmeticOp::Eq,
+        ArithmeticOp::Gt,
+        ArithmeticOp::Lt,
+        ArithmeticOp::And,
+        ArithmeticOp::Or,
+        ArithmeticOp::Not,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        ArithmeticOp::ALL.into_iter().find(|op| op.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "add",
+            ArithmeticOp::Sub => "sub",
+            ArithmeticOp::Neg => "neg",
+            ArithmeticOp::Eq => "eq",
+            ArithmeticOp::Gt => "gt",
+            ArithmeticOp::Lt => "lt",
+            ArithmeticOp::And => "and",
+            ArithmeticOp::Or => "or",
+            ArithmeticOp::Not => "not",
+        }
+    }
+}
+
+impl fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// VMコマンド
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VmCommand {
+    Push(Segment, u16),
+    /// `pop constant`はパース時にはじくので、セグメントが`Constant`になることはない
+    Pop(Segment, u16),
+    Arithmetic(ArithmeticOp),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    /// 関数名とローカル変数の数
+    Function(String, u16),
+    /// 関数名と引数の数
+    Call(String, u16),
+    Return,
+}
+
+/// `.vm`ファイルに書かれているのと同じ形で表示する
+impl fmt::Display for VmCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmCommand::Push(segment, index) => write!(f, "push {} {}", segment, index),
+            VmCommand::Pop(segment, index) => write!(f, "pop {} {}", segment, index),
+            VmCommand::Arithmetic(op) => write!(f, "{}", op),
+            VmCommand::Label(label) => write!(f, "label {}", label),
+            VmCommand::Goto(label) => write!(f, "goto {}", label),
+            VmCommand::IfGoto(label) => write!(f, "if-goto {}", label),
+            VmCommand::Function(name, n_locals) => write!(f, "function {} {}", name, n_locals),
+            VmCommand::Call(name, n_args) => write!(f, "call {} {}", name, n_args),
+            VmCommand::Return => write!(f, "return"),
+        }
+    }
+}
+
+/// `.vm`ファイルの中での位置
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    /// 行番号 (1から数える)
+    pub line: usize,
+    /// コマンドの始まりの列 (1から数える)
+    pub column: usize,
+}
+
+/// 位置つきのVMコマンド
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned {
+    pub command: VmCommand,
+    pub span: Span,
+}
+
+/// 1つの`.vm`ファイルのVMコマンド
+#[derive(Debug, Clone)]
+pub struct VmFile {
+    pub path: String,
+    pub commands: Vec<Spanned>,
+}
+
+impl VmFile {
+    /// 関数`name`を定義しているか
+    pub fn defines(&self, name: &str) -> bool {
+        self.commands.iter().any(|spanned| {
+            matches!(&spanned.command, VmCommand::Function(function, _) if function == name)
+        })
+    }
+}
+
+/// 関数名と、`function`から次の`function`の前までの範囲
+pub fn function_ranges(commands: &[Spanned]) -> Vec<(String, std::ops::Range<usize>)> {
+    let mut ranges: Vec<(String, std::ops::Range<usize>)> = vec![];
+    for (index, spanned) in commands.iter().enumerate() {
+        if let VmCommand::Function(name, _) = &spanned.command {
+            if let Some(last) = ranges.last_mut() {
+                last.1.end = index;
+            }
+            ranges.push((name.clone(), index..commands.len()));
+        }
+    }
+    ranges
+}
@@ -0,0 +1,391 @@
+use crate::command::{ArithmeticOp, Segment, Spanned, VmCommand, VmFile};
+use crate::native::{Memory, NativeOs, Outcome};
+use crate::parser::VmError;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// RAMの大きさ
+const RAM_SIZE: usize = 32768;
+/// staticの先頭アドレス (アセンブラが変数を置き始めるアドレス)
+const STATIC_BASE: u16 = 16;
+/// `Sys.init`から戻ったことを表すリターンアドレス
+///
+/// リターンアドレスはRAMに16ビットで積むので、VMコマンドはこれより少なくなければならない
+const HALT_ADDRESS: u16 = u16::MAX;
+
+/// 実行を止めた理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// プログラムの終わり・`Sys.init`からの戻り・自分自身への`goto`
+    Halted,
+    /// 最大のステップ数まで実行した
+    Limit,
+}
+
+/// 1つのVMコマンドと、そのファイル
+struct Instruction<'a> {
+    file: usize,
+    spanned: &'a Spanned,
+    /// 含まれる関数
+    function: &'a str,
+}
+
+/// VMコマンドをHackと同じメモリ配置 (SP, LCL, ARG, THIS, THATは0〜4番地、tempは5〜12番地) で直接実行する
+///
+/// staticはアセンブラと同じく、出てきた順に16番地から置く。
+/// `native`があれば、OSの関数はVMコードの代わりにRustで実行する
+pub struct Interpreter<'a> {
+    programs: &'a [VmFile],
+    instructions: Vec<Instruction<'a>>,
+    /// 関数名と`function`コマンドの位置
+    functions: HashMap<&'a str, usize>,
+    /// `関数名$ラベル`と`label`コマンドの位置
+    labels: HashMap<String, usize>,
+    /// ファイルごとのstaticのアドレス
+    statics: Vec<HashMap<u16, u16>>,
+    pub ram: Vec<u16>,
+    pub pc: usize,
+    /// 実行したVMコマンドの数 (ネイティブで実行した関数は1つと数える)
+    pub steps: u64,
+    pub native: Option<NativeOs>,
+    /// `(os)/`の外で`Memory`の関数を定義している (ヒープを使う関数はネイティブで実行しない)
+    user_memory: bool,
+}
+
+impl<'a> Interpreter<'a> {
+    /// 呼び出し先やラベルが見つからなければ誤りを返す
+    ///
+    /// `Sys.init`があれば、ブートストラップと同じくSPを256にして`Sys.init`を呼ぶ。
+    /// なければSPだけを256にして先頭から実行する
+    pub fn new(programs: &'a [VmFile], native: Option<NativeOs>) -> Result<Self, Vec<VmError>> {
+        let mut instructions: Vec<Instruction> = vec![];
+        let mut functions: HashMap<&str, usize> = HashMap::new();
+        let mut labels: HashMap<String, usize> = HashMap::new();
+        let mut statics: Vec<HashMap<u16, u16>> = vec![];
+        let mut static_addresses: HashMap<(String, u16), u16> = HashMap::new();
+        for (file, program) in programs.iter().enumerate() {
+            let stem = Path::new(&program.path)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default()
+                .to_string();
+            let mut file_statics = HashMap::new();
+            let mut function = "";
+            for spanned in program.commands.iter() {
+                match &spanned.command {
+                    VmCommand::Function(name, _) => {
+                        function = name;
+                        functions.insert(name, instructions.len());
+                    }
+                    VmCommand::Label(label) => {
+                        labels.insert(scoped(function, label), instructions.len());
+                    }
+                    VmCommand::Push(Segment::Static, index)
+                    | VmCommand::Pop(Segment::Static, index) => {
+                        let next = STATIC_BASE + static_addresses.len() as u16;
+                        let address = *static_addresses
+                            .entry((stem.clone(), *index))
+                            .or_insert(next);
+                        file_statics.insert(*index, address);
+                    }
+                    _ => {}
+                }
+                instructions.push(Instruction {
+                    file,
+                    spanned,
+                    function,
+                });
+            }
+            statics.push(file_statics);
+        }
+
+        // リターンアドレスが16ビットに収まらないプログラムは実行できない
+        if instructions.len() >= HALT_ADDRESS as usize {
+            let instruction = &instructions[HALT_ADDRESS as usize - 1];
+            let path = Path::new(&programs[instruction.file].path);
+            return Err(vec![VmError::new(
+                path,
+                instruction.spanned.span.line,
+                format!(
+                    "too many commands to interpret: {} (at most {})",
+                    instructions.len(),
+                    HALT_ADDRESS - 1
+                ),
+            )]);
+        }
+
+        let user_memory = functions.iter().any(|(name, &entry)| {
+            name.starts_with("Memory.")
+                && !programs[instructions[entry].file].path.starts_with("(os)")
+        });
+        let mut errors = vec![];
+        for instruction in instructions.iter() {
+            let message = match &instruction.spanned.command {
+                VmCommand::Call(name, _)
+                    if !functions.contains_key(name.as_str())
+                        && (native.is_none()
+                            || !NativeOs::is_builtin(name)
+                            || (user_memory && NativeOs::uses_heap(name))) =>
+                {
+                    format!("undefined function: {}", name)
+                }
+                VmCommand::Goto(label) | VmCommand::IfGoto(label)
+                    if !labels.contains_key(&scoped(instruction.function, label)) =>
+                {
+                    format!("undefined label: {}", label)
+                }
+                _ => continue,
+            };
+            let path = Path::new(&programs[instruction.file].path);
+            errors.push(VmError::new(path, instruction.spanned.span.line, message));
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut interpreter = Interpreter {
+            programs,
+            instructions,
+            functions,
+            labels,
+            statics,
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            steps: 0,
+            native,
+            user_memory,
+        };
+        interpreter.ram[0] = 256;
+        if let Some(&entry) = interpreter.functions.get("Sys.init") {
+            interpreter.call(entry, 0, HALT_ADDRESS);
+        }
+        Ok(interpreter)
+    }
+
+    /// 止まるまで(最大`max_steps`コマンド)実行する
+    pub fn run(&mut self, max_steps: u64) -> Result<Stop, VmError> {
+        for _ in 0..max_steps {
+            if self.step()? == Some(Stop::Halted) {
+                return Ok(Stop::Halted);
+            }
+        }
+        Ok(Stop::Limit)
+    }
+
+    /// 1つのVMコマンドを実行する。止まったら`Some(Stop::Halted)`
+    pub fn step(&mut self) -> Result<Option<Stop>, VmError> {
+        let Some(instruction) = self.instructions.get(self.pc) else {
+            return Ok(Some(Stop::Halted));
+        };
+        let command: &'a VmCommand = &instruction.spanned.command;
+        let function: &'a str = instruction.function;
+        self.steps += 1;
+        self.pc += 1;
+        match command {
+            VmCommand::Push(segment, index) => {
+                let value = match segment {
+                    Segment::Constant => *index,
+                    _ => {
+                        let address = self.address(*segment, *index);
+                        self.ram.read(address)
+                    }
+                };
+                self.push(value);
+            }
+            VmCommand::Pop(segment, index) => {
+                let value = self.pop();
+                let address = self.address(*segment, *index);
+                self.ram.write(address, value);
+            }
+            VmCommand::Arithmetic(op) => self.arithmetic(*op),
+            VmCommand::Label(_) => {}
+            VmCommand::Goto(label) => {
+                let target = self.labels[&scoped(function, label)];
+                // `label HALT; goto HALT`のように自分自身に飛び続けるなら止める
+                if target < self.pc
+                    && self.instructions[target..self.pc - 1]
+                        .iter()
+                        .all(|instruction| {
+                            matches!(instruction.spanned.command, VmCommand::Label(_))
+                        })
+                {
+                    self.pc -= 1;
+                    return Ok(Some(Stop::Halted));
+                }
+                self.pc = target;
+            }
+            VmCommand::IfGoto(label) => {
+                if self.pop() != 0 {
+                    self.pc = self.labels[&scoped(function, label)];
+                }
+            }
+            VmCommand::Function(_, n_locals) => {
+                for _ in 0..*n_locals {
+                    self.push(0);
+                }
+            }
+            VmCommand::Call(name, n_args) => {
+                // 読み込むときにコマンドの数を調べているので16ビットに収まる
+                let return_address = self.pc as u16;
+                if self.native.is_some() && NativeOs::is_builtin(name) && self.is_replaceable(name)
+                {
+                    let sp = self.ram[0];
+                    let base = sp.wrapping_sub(*n_args);
+                    let args: Vec<u16> = (0..*n_args)
+                        .map(|i| self.ram.read(base.wrapping_add(i)))
+                        .collect();
+                    let outcome = self
+                        .native
+                        .as_mut()
+                        .and_then(|native| native.call(name, &args, &mut self.ram));
+                    self.ram[0] = base;
+                    match outcome {
+                        Some(Outcome::Return(value)) => self.push(value),
+                        Some(Outcome::Error(code)) => {
+                            self.push(code);
+                            let Some(&entry) = self.functions.get("Sys.error") else {
+                                return Err(self.error(format!(
+                                    "{}: Sys.error is not defined (error code {})",
+                                    name, code
+                                )));
+                            };
+                            self.call(entry, 1, return_address);
+                        }
+                        None => unreachable!("{} is a builtin", name),
+                    }
+                } else {
+                    let entry = self.functions[name.as_str()];
+                    self.call(entry, *n_args, return_address);
+                }
+            }
+            VmCommand::Return => {
+                let frame = self.ram[1];
+                let return_address = self.ram.read(frame.wrapping_sub(5));
+                let value = self.pop();
+                let arg = self.ram[2];
+                self.ram.write(arg, value);
+                self.ram[0] = arg.wrapping_add(1);
+                for (register, offset) in [(4, 1), (3, 2), (2, 3), (1, 4)] {
+                    self.ram[register] = self.ram.read(frame.wrapping_sub(offset));
+                }
+                if return_address == HALT_ADDRESS {
+                    return Ok(Some(Stop::Halted));
+                }
+                self.pc = return_address as usize;
+            }
+        }
+        Ok(None)
+    }
+
+    /// 今実行しているVMコマンドの位置と関数
+    pub fn location(&self) -> String {
+        match self.instructions.get(self.pc) {
+            Some(instruction) => format!(
+                "{}:{} ({})",
+                self.programs[instruction.file].path,
+                instruction.spanned.span.line,
+                instruction.function
+            ),
+            None => "(end)".to_string(),
+        }
+    }
+
+    /// 引数をスタックに積んだ後で、`call`と同じフレームを作って関数の先頭に飛ぶ
+    fn call(&mut self, entry: usize, n_args: u16, return_address: u16) {
+        let sp = self.ram[0];
+        self.push(return_address);
+        for register in 1..=4 {
+            self.push(self.ram[register]);
+        }
+        self.ram[2] = sp.wrapping_sub(n_args);
+        self.ram[1] = self.ram[0];
+        self.pc = entry;
+    }
+
+    /// 組み込みのOS (`(os)/`) の関数か、どこにも定義されていない関数ならネイティブで実行できる
+    ///
+    /// `Memory`を自分で書いたときは、ネイティブのヒープと混ざらないように
+    /// `Array.new`や`String.new`などヒープを使う関数もVMのまま実行する
+    fn is_replaceable(&self, name: &str) -> bool {
+        if self.user_memory && NativeOs::uses_heap(name) {
+            return false;
+        }
+        match self.functions.get(name) {
+            Some(&entry) => {
+                let file = self.instructions[entry].file;
+                self.programs[file].path.starts_with("(os)")
+            }
+            None => true,
+        }
+    }
+
+    fn address(&self, segment: Segment, index: u16) -> u16 {
+        match segment {
+            Segment::Local => self.ram[1].wrapping_add(index),
+            Segment::Argument => self.ram[2].wrapping_add(index),
+            Segment::This => self.ram[3].wrapping_add(index),
+            Segment::That => self.ram[4].wrapping_add(index),
+            Segment::Pointer => 3 + index,
+            Segment::Temp => 5 + index,
+            Segment::Static => {
+                let file = self.instructions[self.pc - 1].file;
+                self.statics[file][&index]
+            }
+            Segment::Constant => unreachable!("pop constant is rejected by the parser"),
+        }
+    }
+
+    fn arithmetic(&mut self, op: ArithmeticOp) {
+        let y = self.pop();
+        let value = match op {
+            ArithmeticOp::Neg => y.wrapping_neg(),
+            ArithmeticOp::Not => !y,
+            _ => {
+                let x = self.pop();
+                let truth = |condition: bool| if condition { u16::MAX } else { 0 };
+                match op {
+                    ArithmeticOp::Add => x.wrapping_add(y),
+                    ArithmeticOp::Sub => x.wrapping_sub(y),
+                    ArithmeticOp::And => x & y,
+                    ArithmeticOp::Or => x | y,
+                    ArithmeticOp::Eq => truth(x == y),
+                    ArithmeticOp::Gt => truth((x as i16) > (y as i16)),
+                    ArithmeticOp::Lt => truth((x as i16) < (y as i16)),
+                    ArithmeticOp::Neg | ArithmeticOp::Not => unreachable!(),
+                }
+            }
+        };
+        self.push(value);
+    }
+
+    fn push(&mut self, value: u16) {
+        let sp = self.ram[0];
+        self.ram.write(sp, value);
+        self.ram[0] = sp.wrapping_add(1);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let sp = self.ram[0].wrapping_sub(1);
+        self.ram[0] = sp;
+        self.ram.read(sp)
+    }
+
+    /// 直前に実行したVMコマンドの位置の誤り
+    fn error(&self, message: String) -> VmError {
+        let instruction = &self.instructions[self.pc - 1];
+        VmError::new(
+            Path::new(&self.programs[instruction.file].path),
+            instruction.spanned.span.line,
+            message,
+        )
+    }
+}
+
+/// 関数内のラベルは`関数名$ラベル`にする (`CodeWriter`と同じ)
+fn scoped(function: &str, label: &str) -> String {
+    if function.is_empty() {
+        label.to_string()
+    } else {
+        format!("{}${}", function, label)
+    }
+}
@@ -0,0 +1,10 @@
+pub mod analysis;
+pub mod cache;
+pub mod cli;
+pub mod code_writer;
+pub mod command;
+pub mod interpreter;
+pub mod native;
+pub mod optimizer;
+pub mod os;
+pub mod parser;
@@ -0,0 +1,665 @@
+/// スクリーンの先頭アドレス
+const SCREEN: u16 = 16384;
+/// キーボードのアドレス
+const KBD: u16 = 24576;
+/// ヒープの先頭アドレスと、最初の空きブロックの大きさ (16383まで)
+const HEAP_BASE: u16 = 2048;
+const HEAP_SIZE: u16 = 14334;
+/// 1行の文字数と行数
+const COLUMNS: u16 = 64;
+const ROWS: u16 = 23;
+
+/// ネイティブで実行するOSの関数
+///
+/// `Sys`と、キーが押されるのを待つ`Keyboard.readChar`などは時間の進み方が変わるのでVMのまま実行する
+pub const BUILTINS: [&str; 39] = [
+    "Array.new",
+    "Array.dispose",
+    "Keyboard.init",
+    "Keyboard.keyPressed",
+    "Math.init",
+    "Math.abs",
+    "Math.multiply",
+    "Math.divide",
+    "Math.min",
+    "Math.max",
+    "Math.sqrt",
+    "Memory.init",
+    "Memory.peek",
+    "Memory.poke",
+    "Memory.alloc",
+    "Memory.deAlloc",
+    "Output.init",
+    "Output.moveCursor",
+    "Output.printChar",
+    "Output.printString",
+    "Output.printInt",
+    "Output.println",
+    "Output.backSpace",
+    "Screen.init",
+    "Screen.clearScreen",
+    "Screen.setColor",
+    "Screen.drawPixel",
+    "Screen.drawLine",
+    "Screen.drawRectangle",
+    "Screen.drawCircle",
+    "String.new",
+    "String.dispose",
+    "String.length",
+    "String.charAt",
+    "String.setCharAt",
+    "String.appendChar",
+    "String.eraseLastChar",
+    "String.intValue",
+    "String.setInt",
+];
+
+/// ネイティブのヒープ (`NativeOs`の空きブロックのリスト) を使う関数
+///
+/// `Memory`を自分で書いたプログラムでは、VMの`Memory`とヒープを共有できないのでネイティブで実行しない
+const HEAP_FUNCTIONS: [&str; 7] = [
+    "Memory.init",
+    "Memory.alloc",
+    "Memory.deAlloc",
+    "Array.new",
+    "Array.dispose",
+    "String.new",
+    "String.dispose",
+];
+
+/// `String.newLine`, `String.backSpace`, `String.doubleQuote`のような定数の関数
+const CONSTANTS: [(&str, u16); 3] = [
+    ("String.newLine", 128),
+    ("String.backSpace", 129),
+    ("String.doubleQuote", 34),
+];
+
+/// 32から126までの文字のビットマップ (`os/Output.vm`の`Output.initMap`と同じ)
+///
+/// 上から1〜9行目を3行ずつ5ビットに詰めている
+const FONT: [[u16; 3]; 95] = [
+    [0, 0, 0],             // ' '
+    [4228, 132, 4],        // '!'
+    [10570, 0, 0],         // '"'
+    [32074, 11242, 10],    // '#'
+    [6084, 16014, 4],      // '$'
+    [8803, 25668, 24],     // '%'
+    [5414, 9890, 22],      // '&'
+    [2180, 0, 0],          // "'"
+    [2184, 4162, 8],       // '('
+    [8322, 4360, 2],       // ')'
+    [21632, 4782, 0],      // '*'
+    [4224, 4255, 0],       // '+'
+    [0, 6144, 68],         // ','
+    [0, 31, 0],            // '-'
+    [0, 6144, 6],          // '.'
+    [8704, 1092, 0],       // '/'
+    [26158, 18037, 14],    // '0'
+    [4292, 4228, 14],      // '1'
+    [16942, 2184, 31],     // '2'
+    [4383, 17928, 14],     // '3'
+    [10632, 9193, 8],      // '4'
+    [15423, 17936, 14],    // '5'
+    [1100, 17967, 14],     // '6'
+    [8735, 2116, 2],       // '7'
+    [17966, 17966, 14],    // '8'
+    [17966, 8734, 6],      // '9'
+    [6336, 6336, 0],       // ':'
+    [6336, 4288, 2],       // ';'
+    [2184, 4161, 8],       // '<'
+    [31744, 992, 0],       // '='
+    [8322, 4368, 2],       // '>'
+    [16942, 136, 4],       // '?'
+    [16942, 22198, 14],    // '@'
+    [17966, 17983, 17],    // 'A'
+    [17967, 17967, 15],    // 'B'
+    [1582, 17441, 14],     // 'C'
+    [17703, 9777, 7],      // 'D'
+    [1087, 1071, 31],      // 'E'
+    [1087, 1071, 1],       // 'F'
+    [1582, 17981, 30],     // 'G'
+    [17969, 17983, 17],    // 'H'
+    [4238, 4228, 14],      // 'I'
+    [8476, 9480, 6],       // 'J'
+    [5425, 9379, 17],      // 'K'
+    [1057, 1057, 31],      // 'L'
+    [22385, 17973, 17],    // 'M'
+    [20017, 18229, 17],    // 'N'
+    [17966, 17969, 14],    // 'O'
+    [17967, 1071, 1],      // 'P'
+    [17966, 9905, 22],     // 'Q'
+    [17967, 9391, 17],     // 'R'
+    [1086, 16910, 15],     // 'S'
+    [4255, 4228, 4],       // 'T'
+    [17969, 17969, 14],    // 'U'
+    [17969, 10801, 4],     // 'V'
+    [17969, 22197, 10],    // 'W'
+    [10801, 17732, 17],    // 'X'
+    [17969, 4234, 4],      // 'Y'
+    [8735, 1092, 31],      // 'Z'
+    [2126, 2114, 14],      // '['
+    [2080, 16644, 0],      // '\\'
+    [8462, 8456, 14],      // ']'
+    [17732, 0, 0],         // '^'
+    [0, 0, 31],            // '_'
+    [8322, 0, 0],          // '`'
+    [14336, 18384, 30],    // 'a'
+    [13345, 17971, 15],    // 'b'
+    [14336, 17441, 14],    // 'c'
+    [23056, 17977, 30],    // 'd'
+    [14336, 2033, 14],     // 'e'
+    [2636, 2119, 2],       // 'f'
+    [30720, 31281, 14896], // 'g'
+    [13345, 17971, 17],    // 'h'
+    [6148, 4228, 14],      // 'i'
+    [12296, 8456, 6440],   // 'j'
+    [9249, 5221, 9],       // 'k'
+    [4230, 4228, 14],      // 'l'
+    [11264, 18101, 17],    // 'm'
+    [13312, 17971, 17],    // 'n'
+    [14336, 17969, 14],    // 'o'
+    [15360, 15921, 1057],  // 'p'
+    [30720, 31281, 16912], // 'q'
+    [13312, 1075, 1],      // 'r'
+    [30720, 16833, 15],    // 's'
+    [7234, 18498, 12],     // 't'
+    [17408, 26161, 22],    // 'u'
+    [17408, 10801, 4],     // 'v'
+    [17408, 22193, 10],    // 'w'
+    [17408, 10378, 17],    // 'x'
+    [17408, 31281, 14896], // 'y'
+    [31744, 2184, 31],     // 'z'
+    [4232, 4226, 8],       // '{'
+    [4228, 4228, 4],       // '|'
+    [4226, 4232, 2],       // '}'
+    [2048, 277, 0],        // '~'
+];
+
+/// 表示できない文字 (黒い四角)
+const UNDEFINED_CHAR: [u16; 3] = [32767, 32767, 32767];
+
+/// ネイティブのOSが読み書きするRAM
+pub trait Memory {
+    fn read(&self, address: u16) -> u16;
+    fn write(&mut self, address: u16, value: u16);
+}
+
+/// Hackと同じく、RAMの外は0を読み、キーボードより後ろには書き込まない
+impl Memory for Vec<u16> {
+    fn read(&self, address: u16) -> u16 {
+        self.get(address as usize).copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, address: u16, value: u16) {
+        if address < KBD {
+            if let Some(word) = self.get_mut(address as usize) {
+                *word = value;
+            }
+        }
+    }
+}
+
+/// ネイティブで実行した結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// 戻り値 (値を返さない関数は0)
+    Return(u16),
+    /// 代わりに`Sys.error`をこのエラーコードで呼ぶ
+    Error(u16),
+}
+
+/// 組み込みのJack OS (`os/`) と同じ動きをするOSの関数を、Rustで実行する
+///
+/// VMインタプリタとHackエミュレータで共有する。VMのOSのstatic変数にあたる状態はここに持ち、
+/// ヒープ・文字列・スクリーンはVMのOSと同じ形でRAMに置く
+#[derive(Debug, Clone)]
+pub struct NativeOs {
+    /// 空きブロックのリストの先頭 (`Memory`)
+    free_list: u16,
+    /// 描く色 (`Screen`, trueが黒)
+    color: bool,
+    /// カーソルの行と列 (`Output`)
+    row: u16,
+    column: u16,
+}
+
+impl Default for NativeOs {
+    fn default() -> Self {
+        NativeOs {
+            free_list: HEAP_BASE,
+            color: true,
+            row: 0,
+            column: 0,
+        }
+    }
+}
+
+impl NativeOs {
+    pub fn new() -> Self {
+        NativeOs::default()
+    }
+
+    /// ネイティブで実行できる関数か
+    pub fn is_builtin(name: &str) -> bool {
+        BUILTINS.contains(&name) || CONSTANTS.iter().any(|(constant, _)| *constant == name)
+    }
+
+    /// ネイティブのヒープを使う関数か
+    pub fn uses_heap(name: &str) -> bool {
+        HEAP_FUNCTIONS.contains(&name)
+    }
+
+    /// OSの関数を実行する。ネイティブで実行できない関数なら`None`
+    ///
+    /// * `name`: - 関数名 (`Math.multiply`)
+    /// * `args`: - 引数 (メソッドは最初がthis)
+    /// * `memory`: - RAM
+    pub fn call<M: Memory + ?Sized>(
+        &mut self,
+        name: &str,
+        args: &[u16],
+        memory: &mut M,
+    ) -> Option<Outcome> {
+        let arg = |index: usize| args.get(index).copied().unwrap_or(0);
+        let signed = |index: usize| arg(index) as i16;
+        let result = match name {
+            "Math.init" | "Keyboard.init" => Ok(0),
+            "Math.abs" => Ok(signed(0).wrapping_abs() as u16),
+            "Math.multiply" => Ok(signed(0).wrapping_mul(signed(1)) as u16),
+            "Math.divide" => divide(signed(0), signed(1)),
+            "Math.min" => Ok(signed(0).min(signed(1)) as u16),
+            "Math.max" => Ok(signed(0).max(signed(1)) as u16),
+            "Math.sqrt" => sqrt(signed(0)),
+            "Memory.init" => {
+                self.free_list = HEAP_BASE;
+                memory.write(HEAP_BASE, 0);
+                memory.write(HEAP_BASE + 1, HEAP_SIZE);
+                Ok(0)
+            }
+            "Memory.peek" => Ok(memory.read(arg(0))),
+            "Memory.poke" => {
+                memory.write(arg(0), arg(1));
+                Ok(0)
+            }
+            "Memory.alloc" => self.alloc(signed(0), memory),
+            "Memory.deAlloc" | "Array.dispose" | "String.dispose" => {
+                self.de_alloc(arg(0), memory);
+                Ok(0)
+            }
+            "Array.new" if signed(0) < 1 => Err(2),
+            "Array.new" => self.alloc(signed(0), memory),
+            "Keyboard.keyPressed" => Ok(memory.read(KBD)),
+            "String.new" => self.string_new(signed(0), memory),
+            "String.length" => Ok(memory.read(arg(0).wrapping_add(1))),
+            "String.charAt" => {
+                char_index(arg(0), signed(1), memory, 15).map(|address| memory.read(address))
+            }
+            "String.setCharAt" => char_index(arg(0), signed(1), memory, 16).map(|address| {
+                memory.write(address, arg(2));
+                0
+            }),
+            "String.appendChar" => append_char(arg(0), arg(1), memory).map(|_| arg(0)),
+            "String.eraseLastChar" => {
+                let length = memory.read(arg(0).wrapping_add(1));
+                if length == 0 {
+                    Err(18)
+                } else {
+                    memory.write(arg(0).wrapping_add(1), length - 1);
+                    Ok(0)
+                }
+            }
+            "String.intValue" => Ok(int_value(arg(0), memory)),
+            "String.setInt" => {
+                memory.write(arg(0).wrapping_add(1), 0);
+                signed(1)
+                    .to_string()
+                    .chars()
+                    .try_for_each(|c| append_char(arg(0), c as u16, memory))
+                    .map(|_| 0)
+            }
+            "Output.init" => {
+                self.row = 0;
+                self.column = 0;
+                Ok(0)
+            }
+            "Output.moveCursor" => self.move_cursor(signed(0), signed(1), memory),
+            "Output.printChar" => {
+                self.print_char(arg(0), memory);
+                Ok(0)
+            }
+            "Output.printString" => {
+                let length = memory.read(arg(0).wrapping_add(1));
+                for index in 0..length {
+                    let c = memory.read(arg(0).wrapping_add(2).wrapping_add(index));
+                    self.print_char(c, memory);
+                }
+                Ok(0)
+            }
+            "Output.printInt" => {
+                for c in signed(0).to_string().chars() {
+                    self.print_char(c as u16, memory);
+                }
+                Ok(0)
+            }
+            "Output.println" => {
+                self.println();
+                Ok(0)
+            }
+            "Output.backSpace" => {
+                self.back_space(memory);
+                Ok(0)
+            }
+            "Screen.init" => {
+                self.color = true;
+                Ok(0)
+            }
+            "Screen.clearScreen" => {
+                for address in SCREEN..KBD {
+                    memory.write(address, 0);
+                }
+                Ok(0)
+            }
+            "Screen.setColor" => {
+                self.color = arg(0) != 0;
+                Ok(0)
+            }
+            "Screen.drawPixel" => self.draw_pixel(signed(0), signed(1), memory).map(|_| 0),
+            "Screen.drawLine" => self
+                .draw_line(signed(0), signed(1), signed(2), signed(3), memory)
+                .map(|_| 0),
+            "Screen.drawRectangle" => self
+                .draw_rectangle(signed(0), signed(1), signed(2), signed(3), memory)
+                .map(|_| 0),
+            "Screen.drawCircle" => self
+                .draw_circle(signed(0), signed(1), signed(2), memory)
+                .map(|_| 0),
+            _ => {
+                let (_, value) = CONSTANTS.iter().find(|(constant, _)| *constant == name)?;
+                Ok(*value)
+            }
+        };
+        Some(match result {
+            Ok(value) => Outcome::Return(value),
+            Err(code) => Outcome::Error(code),
+        })
+    }
+
+    /// 最初に見つかった十分大きい空きブロックの後ろから切り出す (`Memory.alloc`と同じ)
+    fn alloc<M: Memory + ?Sized>(&mut self, size: i16, memory: &mut M) -> Result<u16, u16> {
+        if size < 1 {
+            return Err(5);
+        }
+        let size = size as u16;
+        let mut block = self.free_list;
+        while block != 0 {
+            // 空きブロックのリストが壊れていてアドレスが溢れるときも、ヒープが足りないことにする
+            let free = memory.read(block.checked_add(1).ok_or(6u16)?);
+            // 大きさ >= size + 2 (切り出すブロックのヘッダの分) なら使える
+            if free as i16 > size as i16 + 1 {
+                let rest = free - size - 2;
+                let allocated = block
+                    .checked_add(2)
+                    .and_then(|header| header.checked_add(rest))
+                    .filter(|allocated| allocated.checked_add(2 + size).is_some())
+                    .ok_or(6u16)?;
+                memory.write(block + 1, rest);
+                memory.write(allocated, 0);
+                memory.write(allocated + 1, size);
+                return Ok(allocated + 2);
+            }
+            block = memory.read(block);
+        }
+        Err(6)
+    }
+
+    /// 解放したブロックを空きブロックのリストの先頭につなぐ (`Memory.deAlloc`と同じ)
+    fn de_alloc<M: Memory + ?Sized>(&mut self, address: u16, memory: &mut M) {
+        let block = address.wrapping_sub(2);
+        memory.write(block, self.free_list);
+        self.free_list = block;
+    }
+
+    /// `this 0`が最大の長さ、`this 1`が長さ、その後ろが文字
+    fn string_new<M: Memory + ?Sized>(&mut self, max: i16, memory: &mut M) -> Result<u16, u16> {
+        if max < 0 {
+            return Err(14);
+        }
+        let string = self.alloc(max.wrapping_add(2), memory)?;
+        memory.write(string, max as u16);
+        memory.write(string + 1, 0);
+        Ok(string)
+    }
+
+    fn move_cursor<M: Memory + ?Sized>(
+        &mut self,
+        row: i16,
+        column: i16,
+        memory: &mut M,
+    ) -> Result<u16, u16> {
+        if !(0..ROWS as i16).contains(&row) || !(0..COLUMNS as i16).contains(&column) {
+            return Err(20);
+        }
+        self.row = row as u16;
+        self.column = column as u16;
+        self.draw_char(b' ' as u16, memory);
+        Ok(0)
+    }
+
+    /// 改行 (128) とバックスペース (129) も扱う
+    fn print_char<M: Memory + ?Sized>(&mut self, c: u16, memory: &mut M) {
+        match c {
+            128 => self.println(),
+            129 => self.back_space(memory),
+            _ => {
+                self.draw_char(c, memory);
+                self.column += 1;
+                if self.column == COLUMNS {
+                    self.println();
+                }
+            }
+        }
+    }
+
+    /// 最後の行の次は先頭の行に戻る
+    fn println(&mut self) {
+        self.column = 0;
+        self.row = (self.row + 1) % ROWS;
+    }
+
+    /// 1文字戻って、その文字を消す
+    fn back_space<M: Memory + ?Sized>(&mut self, memory: &mut M) {
+        if self.column > 0 {
+            self.column -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.column = COLUMNS - 1;
+        } else {
+            return;
+        }
+        self.draw_char(b' ' as u16, memory);
+    }
+
+    /// カーソルの位置に文字を描く。奇数列の文字はワードの上位8ビットに入れる
+    fn draw_char<M: Memory + ?Sized>(&mut self, c: u16, memory: &mut M) {
+        let packed = match c {
+            32..=126 => FONT[c as usize - 32],
+            _ => UNDEFINED_CHAR,
+        };
+        let mut address = SCREEN + self.row * 352 + self.column / 2;
+        for row in 0..11 {
+            // 0行目と10行目は空ける
+            let bits = match row {
+                1..=9 => {
+                    let word = packed[(row - 1) / 3];
+                    ((word >> (5 * ((row - 1) % 3))) & 31) << 1
+                }
+                _ => 0,
+            };
+            let word = memory.read(address);
+            let word = if self.column & 1 == 0 {
+                (word & 0xff00) | bits
+            } else {
+                (word & 0x00ff) | (bits << 8)
+            };
+            memory.write(address, word);
+            address += 32;
+        }
+    }
+
+    fn draw_pixel<M: Memory + ?Sized>(
+        &mut self,
+        x: i16,
+        y: i16,
+        memory: &mut M,
+    ) -> Result<(), u16> {
+        if !(0..512).contains(&x) || !(0..256).contains(&y) {
+            return Err(7);
+        }
+        let address = SCREEN + y as u16 * 32 + x as u16 / 16;
+        let bit = 1 << (x & 15);
+        let word = memory.read(address);
+        memory.write(address, if self.color { word | bit } else { word & !bit });
+        Ok(())
+    }
+
+    /// `Screen.drawLine`と同じ順にピクセルを描く
+    ///
+    /// 左から右へ進み、diff = a * dy - b * dx の符号で横と縦のどちらに進むかを決める
+    fn draw_line<M: Memory + ?Sized>(
+        &mut self,
+        x1: i16,
+        y1: i16,
+        x2: i16,
+        y2: i16,
+        memory: &mut M,
+    ) -> Result<(), u16> {
+        let ((x1, y1), (x2, y2)) = if x1 > x2 {
+            ((x2, y2), (x1, y1))
+        } else {
+            ((x1, y1), (x2, y2))
+        };
+        let dx = x2.wrapping_sub(x1);
+        let (dy, step) = match y2.wrapping_sub(y1) {
+            dy if dy < 0 => (dy.wrapping_neg(), -1),
+            dy => (dy, 1),
+        };
+        let (mut a, mut b, mut diff, mut y) = (0i16, 0i16, 0i16, y1);
+        loop {
+            self.draw_pixel(x1.wrapping_add(a), y, memory)?;
+            if a == dx && b == dy {
+                return Ok(());
+            }
+            if a != dx && (b == dy || diff < 0) {
+                a = a.wrapping_add(1);
+                diff = diff.wrapping_add(dy);
+            } else {
+                b = b.wrapping_add(1);
+                y = y.wrapping_add(step);
+                diff = diff.wrapping_sub(dx);
+            }
+        }
+    }
+
+    /// 上から1行ずつ横線を引く
+    fn draw_rectangle<M: Memory + ?Sized>(
+        &mut self,
+        x1: i16,
+        y1: i16,
+        x2: i16,
+        y2: i16,
+        memory: &mut M,
+    ) -> Result<(), u16> {
+        for y in y1..=y2 {
+            self.draw_line(x1, y, x2, y, memory)?;
+        }
+        Ok(())
+    }
+
+    /// 中心からdyの行に、幅 2 * sqrt(r^2 - dy^2) の横線を引く
+    fn draw_circle<M: Memory + ?Sized>(
+        &mut self,
+        x: i16,
+        y: i16,
+        r: i16,
+        memory: &mut M,
+    ) -> Result<(), u16> {
+        if !(0..=181).contains(&r) {
+            return Err(13);
+        }
+        for dy in -r..=r {
+            let half = sqrt(r * r - dy * dy)? as i16;
+            self.draw_line(
+                x.wrapping_sub(half),
+                y.wrapping_add(dy),
+                x.wrapping_add(half),
+                y.wrapping_add(dy),
+                memory,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// 0に向かって切り捨てる
+fn divide(x: i16, y: i16) -> Result<u16, u16> {
+    if y == 0 {
+        return Err(3);
+    }
+    Ok(x.wrapping_div(y) as u16)
+}
+
+/// 2乗してもxを超えない最大の整数
+fn sqrt(x: i16) -> Result<u16, u16> {
+    if x < 0 {
+        return Err(4);
+    }
+    let mut result: i32 = 0;
+    while (result + 1) * (result + 1) <= x as i32 {
+        result += 1;
+    }
+    Ok(result as u16)
+}
+
+/// 文字列の`index`番目の文字のアドレス
+fn char_index<M: Memory + ?Sized>(
+    string: u16,
+    index: i16,
+    memory: &M,
+    error: u16,
+) -> Result<u16, u16> {
+    let length = memory.read(string.wrapping_add(1)) as i16;
+    if index < 0 || index >= length {
+        return Err(error);
+    }
+    Ok(string.wrapping_add(2).wrapping_add(index as u16))
+}
+
+fn append_char<M: Memory + ?Sized>(string: u16, c: u16, memory: &mut M) -> Result<(), u16> {
+    let max = memory.read(string) as i16;
+    let length = memory.read(string.wrapping_add(1)) as i16;
+    if length >= max {
+        return Err(17);
+    }
+    memory.write(string.wrapping_add(2).wrapping_add(length as u16), c);
+    memory.write(string.wrapping_add(1), length as u16 + 1);
+    Ok(())
+}
+
+/// 先頭の'-'と、続く数字だけを読む (`String.intValue`と同じ)
+fn int_value<M: Memory + ?Sized>(string: u16, memory: &M) -> u16 {
+    let length = memory.read(string.wrapping_add(1));
+    let char_at = |index: u16| memory.read(string.wrapping_add(2).wrapping_add(index));
+    let negative = length > 0 && char_at(0) == b'-' as u16;
+    let mut value: i16 = 0;
+    for index in (negative as u16)..length {
+        match char_at(index).wrapping_sub(b'0' as u16) {
+            digit @ 0..=9 => value = value.wrapping_mul(10).wrapping_add(digit as i16),
+            _ => break,
+        }
+    }
+    if negative {
+        value.wrapping_neg() as u16
+    } else {
+        value as u16
+    }
+}
@@ -0,0 +1,316 @@
+use crate::command::{function_ranges, ArithmeticOp, Segment, Span, Spanned, VmCommand, VmFile};
+use std::collections::{HashMap, HashSet};
+
+/// 最適化で変わった量
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeStats {
+    pub commands_before: usize,
+    pub commands_after: usize,
+    /// 畳み込んだ定数式の数
+    pub folded: usize,
+    /// 簡単にした`if-goto`と`not; not`の数
+    pub simplified: usize,
+    /// 展開した関数呼び出しの数
+    pub inlined: usize,
+    /// 消した関数
+    pub removed_functions: Vec<String>,
+}
+
+/// すべての`.vm`ファイルをまとめて最適化する
+///
+/// 1. 小さな関数の呼び出しを展開する
+/// 2. 定数式の畳み込みと`if-goto`の簡略化
+/// 3. `Sys.init`から`call`でたどれない関数を消す (`Sys.init`があるときだけ)
+pub fn optimize(files: &mut [VmFile]) -> OptimizeStats {
+    let mut stats = OptimizeStats {
+        commands_before: count_commands(files),
+        ..Default::default()
+    };
+    let inlinable = find_inlinable(files);
+    for file in files.iter_mut() {
+        file.commands = inline_calls(&file.commands, &inlinable, &mut stats);
+        file.commands = peephole(&file.commands, &mut stats);
+    }
+    stats.removed_functions = remove_unreachable(files);
+    stats.commands_after = count_commands(files);
+    stats
+}
+
+fn count_commands(files: &[VmFile]) -> usize {
+    files.iter().map(|file| file.commands.len()).sum()
+}
+
+/// 展開できる関数の本体
+struct Inlinable {
+    n_args: u16,
+    /// 引数をpushする部分と`return`を除いた本体
+    body: Vec<VmCommand>,
+}
+
+/// ローカル変数がなく、`push argument 0`, `push argument 1`, ...で引数を順に積んでから
+/// 定数と算術コマンドだけで1つの値を計算して返す関数を探す
+///
+/// 引数はすでに呼び出し元のスタックに同じ順で積まれているので、呼び出しを残りの本体に置き換えられる
+fn find_inlinable(files: &[VmFile]) -> HashMap<String, Inlinable> {
+    let mut inlinable = HashMap::new();
+    for file in files.iter() {
+        for (name, range) in function_ranges(&file.commands) {
+            let commands: Vec<&VmCommand> = file.commands[range]
+                .iter()
+                .map(|spanned| &spanned.command)
+                .collect();
+            if !matches!(commands[0], VmCommand::Function(_, 0))
+                || commands.last() != Some(&&VmCommand::Return)
+            {
+                continue;
+            }
+            let body = &commands[1..commands.len() - 1];
+            let n_args = body
+                .iter()
+                .enumerate()
+                .take_while(|(i, command)| {
+                    **command == &VmCommand::Push(Segment::Argument, *i as u16)
+                })
+                .count();
+            let rest = &body[n_args..];
+            // スタックの深さが途中で引数より下がらず、最後に値が1つだけ残ること
+            let mut depth = n_args as isize;
+            let mut valid = true;
+            for command in rest.iter() {
+                depth += match command {
+                    VmCommand::Push(Segment::Constant, _) => 1,
+                    VmCommand::Arithmetic(ArithmeticOp::Neg | ArithmeticOp::Not) => 0,
+                    VmCommand::Arithmetic(_) => -1,
+                    _ => {
+                        valid = false;
+                        break;
+                    }
+                };
+                if depth < 1 {
+                    valid = false;
+                    break;
+                }
+            }
+            if valid && depth == 1 {
+                inlinable.insert(
+                    name,
+                    Inlinable {
+                        n_args: n_args as u16,
+                        body: rest.iter().map(|command| (*command).clone()).collect(),
+                    },
+                );
+            }
+        }
+    }
+    inlinable
+}
+
+fn inline_calls(
+    commands: &[Spanned],
+    inlinable: &HashMap<String, Inlinable>,
+    stats: &mut OptimizeStats,
+) -> Vec<Spanned> {
+    let mut output = vec![];
+    for spanned in commands.iter() {
+        match &spanned.command {
+            VmCommand::Call(name, n_args) => match inlinable.get(name) {
+                Some(function) if function.n_args == *n_args => {
+                    stats.inlined += 1;
+                    // 展開したコマンドは呼び出した行のものにする
+                    output.extend(function.body.iter().map(|command| Spanned {
+                        command: command.clone(),
+                        span: spanned.span,
+                    }));
+                }
+                _ => output.push(spanned.clone()),
+            },
+            _ => output.push(spanned.clone()),
+        }
+    }
+    output
+}
+
+/// 直前のコマンドを見ながら書き換える
+///
+/// * `push constant a; push constant b; add`のような定数式を1つの定数にする
+/// * `not; not`を消す
+/// * 定数の`if-goto`を`goto`にするか消す
+/// * `eq; not; if-goto`を`sub; if-goto`にする (差が0でなければジャンプする)
+///
+/// ラベルやcallをまたぐとコマンドが隣り合わないので、ジャンプ先をまたいで書き換えることはない
+fn peephole(commands: &[Spanned], stats: &mut OptimizeStats) -> Vec<Spanned> {
+    let mut output: Vec<Spanned> = vec![];
+    for spanned in commands.iter() {
+        match &spanned.command {
+            VmCommand::Arithmetic(op @ (ArithmeticOp::Neg | ArithmeticOp::Not)) => {
+                if let Some((x, len)) = tail_constant(&output, output.len()) {
+                    let encoded = encode(apply_unary(*op, x));
+                    if encoded.len() < len + 1 {
+                        let span = output[output.len() - len].span;
+                        output.truncate(output.len() - len);
+                        push_all(&mut output, encoded, span);
+                        stats.folded += 1;
+                        continue;
+                    }
+                }
+                if output.last().map(|last| &last.command) == Some(&spanned.command) {
+                    output.pop();
+                    stats.simplified += 1;
+                    continue;
+                }
+                output.push(spanned.clone());
+            }
+            VmCommand::Arithmetic(op) => {
+                let folded = tail_constant(&output, output.len()).and_then(|(y, y_len)| {
+                    tail_constant(&output, output.len() - y_len)
+                        .map(|(x, x_len)| (apply_binary(*op, x, y), x_len + y_len))
+                });
+                match folded {
+                    Some((value, len)) => {
+                        let span = output[output.len() - len].span;
+                        output.truncate(output.len() - len);
+                        push_all(&mut output, encode(value), span);
+                        stats.folded += 1;
+                    }
+                    None => output.push(spanned.clone()),
+                }
+            }
+            VmCommand::IfGoto(label) => {
+                if let Some((value, len)) = tail_constant(&output, output.len()) {
+                    output.truncate(output.len() - len);
+                    if value != 0 {
+                        output.push(Spanned {
+                            command: VmCommand::Goto(label.clone()),
+                            span: spanned.span,
+                        });
+                    }
+                    stats.simplified += 1;
+                    continue;
+                }
+                let n = output.len();
+                if n >= 2
+                    && output[n - 2].command == VmCommand::Arithmetic(ArithmeticOp::Eq)
+                    && output[n - 1].command == VmCommand::Arithmetic(ArithmeticOp::Not)
+                {
+                    output.pop();
+                    output[n - 2].command = VmCommand::Arithmetic(ArithmeticOp::Sub);
+                    stats.simplified += 1;
+                }
+                output.push(spanned.clone());
+            }
+            _ => output.push(spanned.clone()),
+        }
+    }
+    output
+}
+
+/// `output[..end]`の末尾にある定数 (`push constant a`, `push constant a; neg`,
+/// `push constant a; not`) の値とコマンドの数
+fn tail_constant(output: &[Spanned], end: usize) -> Option<(i16, usize)> {
+    let constant = |index: usize| match output[index].command {
+        VmCommand::Push(Segment::Constant, value) => Some(value as i16),
+        _ => None,
+    };
+    if end == 0 {
+        return None;
+    }
+    if let Some(value) = constant(end - 1) {
+        return Some((value, 1));
+    }
+    if end < 2 {
+        return None;
+    }
+    match (&output[end - 1].command, constant(end - 2)) {
+        (VmCommand::Arithmetic(ArithmeticOp::Neg), Some(value)) => Some((value.wrapping_neg(), 2)),
+        (VmCommand::Arithmetic(ArithmeticOp::Not), Some(value)) => Some((!value, 2)),
+        _ => None,
+    }
+}
+
+/// 値を積むいちばん短いコマンド列
+fn encode(value: i16) -> Vec<VmCommand> {
+    let push = |value: i16| VmCommand::Push(Segment::Constant, value as u16);
+    match value {
+        0.. => vec![push(value)],
+        -1 => vec![push(0), VmCommand::Arithmetic(ArithmeticOp::Not)],
+        i16::MIN => vec![push(i16::MAX), VmCommand::Arithmetic(ArithmeticOp::Not)],
+        _ => vec![push(-value), VmCommand::Arithmetic(ArithmeticOp::Neg)],
+    }
+}
+
+fn push_all(output: &mut Vec<Spanned>, commands: Vec<VmCommand>, span: Span) {
+    output.extend(
+        commands
+            .into_iter()
+            .map(|command| Spanned { command, span }),
+    );
+}
+
+fn apply_unary(op: ArithmeticOp, x: i16) -> i16 {
+    match op {
+        ArithmeticOp::Neg => x.wrapping_neg(),
+        _ => !x,
+    }
+}
+
+/// 16ビットの2の補数で計算する (真は-1, 偽は0)
+fn apply_binary(op: ArithmeticOp, x: i16, y: i16) -> i16 {
+    let boolean = |value: bool| if value { -1 } else { 0 };
+    match op {
+        ArithmeticOp::Add => x.wrapping_add(y),
+        ArithmeticOp::Sub => x.wrapping_sub(y),
+        ArithmeticOp::And => x & y,
+        ArithmeticOp::Or => x | y,
+        ArithmeticOp::Eq => boolean(x == y),
+        ArithmeticOp::Gt => boolean(x > y),
+        ArithmeticOp::Lt => boolean(x < y),
+        ArithmeticOp::Neg | ArithmeticOp::Not => unreachable!(),
+    }
+}
+
+/// `Sys.init`と関数の外のコマンドから`call`でたどれない関数を消して、消した関数名を返す
+fn remove_unreachable(files: &mut [VmFile]) -> Vec<String> {
+    let mut calls: HashMap<String, Vec<String>> = HashMap::new();
+    let mut roots: Vec<String> = vec![];
+    for file in files.iter() {
+        let ranges = function_ranges(&file.commands);
+        let first = ranges.first().map_or(file.commands.len(), |(_, r)| r.start);
+        roots.extend(called(&file.commands[..first]));
+        for (name, range) in ranges {
+            calls.insert(name, called(&file.commands[range]));
+        }
+    }
+    if !calls.contains_key("Sys.init") {
+        return vec![];
+    }
+    roots.push("Sys.init".to_string());
+
+    let mut reachable: HashSet<String> = HashSet::new();
+    while let Some(name) = roots.pop() {
+        if reachable.insert(name.clone()) {
+            roots.extend(calls.get(&name).into_iter().flatten().cloned());
+        }
+    }
+
+    let mut removed = vec![];
+    for file in files.iter_mut() {
+        for (name, range) in function_ranges(&file.commands).into_iter().rev() {
+            if !reachable.contains(&name) {
+                file.commands.drain(range);
+                removed.push(name);
+            }
+        }
+    }
+    removed.sort();
+    removed
+}
+
+fn called(commands: &[Spanned]) -> Vec<String> {
+    commands
+        .iter()
+        .filter_map(|spanned| match &spanned.command {
+            VmCommand::Call(name, _) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
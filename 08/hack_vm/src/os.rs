@@ -0,0 +1,67 @@
+use crate::command::{VmCommand, VmFile};
+use crate::parser::{self, VmError};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// 組み込みのJack OS (`os/`の`.vm`ファイル)
+const CLASSES: [(&str, &str); 8] = [
+    ("Array", include_str!("../os/Array.vm")),
+    ("Keyboard", include_str!("../os/Keyboard.vm")),
+    ("Math", include_str!("../os/Math.vm")),
+    ("Memory", include_str!("../os/Memory.vm")),
+    ("Output", include_str!("../os/Output.vm")),
+    ("Screen", include_str!("../os/Screen.vm")),
+    ("String", include_str!("../os/String.vm")),
+    ("Sys", include_str!("../os/Sys.vm")),
+];
+
+/// 誤りの表示やソースマップに使うOSのクラスのパス
+fn path(class: &str) -> String {
+    format!("(os)/{}.vm", class)
+}
+
+/// 呼ばれているのに定義されていない関数を含むOSのクラスを`programs`に足して、足したクラスを返す
+///
+/// `Main.main`があって`Sys.init`がないときは、`Sys.init`から始められるように`Sys`も足す。
+/// OSのクラスどうしの呼び出しもたどる。
+/// 同じ名前のクラスの関数が1つでも定義されていれば、そのクラスは足さない
+pub fn link(programs: &mut Vec<VmFile>) -> Result<Vec<&'static str>, Vec<VmError>> {
+    let mut linked = vec![];
+    loop {
+        let mut defined: HashSet<&str> = HashSet::new();
+        let mut called: Vec<&str> = vec![];
+        for program in programs.iter() {
+            for spanned in program.commands.iter() {
+                match &spanned.command {
+                    VmCommand::Function(name, _) => {
+                        defined.insert(name);
+                    }
+                    VmCommand::Call(name, _) => called.push(name),
+                    _ => {}
+                }
+            }
+        }
+        if defined.contains("Main.main") && !defined.contains("Sys.init") {
+            called.push("Sys.init");
+        }
+        let defined_classes: HashSet<&str> = defined.iter().map(|name| class_of(name)).collect();
+        let missing = called
+            .iter()
+            .filter(|name| !defined.contains(*name))
+            .map(|name| class_of(name))
+            .filter(|class| !defined_classes.contains(class))
+            .find_map(|class| CLASSES.iter().find(|(name, _)| *name == class));
+        let Some(&(class, contents)) = missing else {
+            return Ok(linked);
+        };
+        let path = path(class);
+        let commands = parser::parse_str(contents, Path::new(&path))?;
+        programs.push(VmFile { path, commands });
+        linked.push(class);
+    }
+}
+
+/// `Class.function`のクラス名
+fn class_of(name: &str) -> &str {
+    name.split('.').next().unwrap_or_default()
+}
@@ -1,180 +1,181 @@
+use crate::command::{ArithmeticOp, Segment, Span, Spanned, VmCommand};
 use std::fmt;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Seek};
+use std::fs;
+use std::path::{Path, PathBuf};
 
-pub struct Parser {
-    pub stream: BufReader<File>,
-    command_type: CommandType,
-    arg1: String,
-    arg2: Option<usize>,
+/// 定数やインデックスに使える最大値 (15ビット)
+const MAX_INDEX: u16 = 32767;
+
+/// VMコードの誤り
+#[derive(Debug, Clone)]
+pub struct VmError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub message: String,
 }
 
-impl Parser {
-    pub fn new(file_path: &str) -> std::io::Result<Self> {
-        let file = File::open(file_path)?;
-        let reader = BufReader::new(file);
+impl VmError {
+    pub fn new(path: &Path, line: usize, message: String) -> Self {
+        VmError {
+            path: path.to_path_buf(),
+            line,
+            message,
+        }
+    }
+}
 
-        Ok(Parser {
-            stream: reader,
-            command_type: CommandType::C_INIT,
-            arg1: String::new(),
-            arg2: None,
-        })
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.path.display(), self.line, self.message)
     }
+}
 
-    pub fn next_line(&mut self) -> String {
-        let mut line = String::new();
-        self.stream.read_line(&mut line).unwrap();
-        line = line.trim().to_string();
-        while line.is_empty() || line.starts_with("//") {
-            line.clear();
-            let res = self.stream.read_line(&mut line).unwrap();
-            if res == 0 {
-                break;
-            }
-            line = line.trim().to_string();
-        }
-        if !line.is_empty() {
-            self.parse_code(line.as_str());
-        }
+impl std::error::Error for VmError {}
 
-        return line.clone();
-    }
+/// `.vm`ファイルを読んでVMコマンドの列にする
+pub fn parse_file(path: &Path) -> Result<Vec<Spanned>, Vec<VmError>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| vec![VmError::new(path, 0, format!("cannot read file: {}", e))])?;
+    parse_str(&contents, path)
+}
 
-    fn parse_code(&mut self, command: &str) {
-        let split_command: Vec<&str> = command
-            .split(" ")
-            .map(|x| x.trim())
-            .filter(|&x| !x.is_empty())
-            .collect();
-        eprintln!("{:?}", split_command);
-        if split_command.is_empty() {
-            eprintln!("HERE");
-            self.command_type = CommandType::C_INIT;
-            self.arg1 = String::new();
-            self.arg2 = None;
-            return;
+/// VMコードをVMコマンドの列にする
+///
+/// 誤りのある行は読み飛ばして最後まで読み、すべての誤りをまとめて返す
+///
+/// * `contents`: - VMコード
+/// * `path`: - 誤りの表示に使うファイルのパス
+pub fn parse_str(contents: &str, path: &Path) -> Result<Vec<Spanned>, Vec<VmError>> {
+    let mut commands = vec![];
+    let mut errors = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        // 行末のコメントを取り除く
+        let code = line.split("//").next().unwrap_or_default();
+        let trimmed = code.trim_start();
+        if trimmed.trim_end().is_empty() {
+            continue;
         }
-        match split_command[0] {
-            "push" => {
-                self.command_type = CommandType::C_PUSH;
-                self.arg1 = split_command[1].to_string();
-                self.arg2 = Some(split_command[2].parse::<usize>().unwrap());
-            }
-            "pop" => {
-                self.command_type = CommandType::C_POP;
-                self.arg1 = split_command[1].to_string();
-                self.arg2 = Some(split_command[2].parse::<usize>().unwrap());
-            }
-            "add" | "sub" | "neg" | "and" | "or" | "not" | "eq" | "gt" | "lt" => {
-                self.command_type = CommandType::C_ARITHETIC;
-                self.arg1 = split_command[0].to_string();
-                self.arg2 = None;
-            }
-            "label" => {
-                self.command_type = CommandType::C_LABEL;
-                self.arg1 = split_command[1].to_string();
-                self.arg2 = None;
-            }
-            "if-goto" => {
-                self.command_type = CommandType::C_IF;
-                self.arg1 = split_command[1].to_string();
-                self.arg2 = None;
-            }
-            "goto" => {
-                self.command_type = CommandType::C_GOTO;
-                self.arg1 = split_command[1].to_string();
-                self.arg2 = None;
-            }
-            "function" => {
-                self.command_type = CommandType::C_FUNCTION;
-                self.arg1 = split_command[1].to_string();
-                self.arg2 = Some(split_command[2].parse::<usize>().unwrap());
-            }
-            "return" => {
-                self.command_type = CommandType::C_RETURN;
-                self.arg1 = String::new();
-                self.arg2 = None;
-            }
-            "call" => {
-                self.command_type = CommandType::C_CALL;
-                self.arg1 = split_command[1].to_string();
-                self.arg2 = Some(split_command[2].parse::<usize>().unwrap());
-            }
-
-            "" => {
-                self.command_type = CommandType::C_INIT;
-                self.arg1 = String::new();
-                self.arg2 = None;
-            }
-            _ => {
-                eprintln!("{:?}", split_command);
-                unreachable!()
-            }
+        let span = Span {
+            line: index + 1,
+            column: code.len() - trimmed.len() + 1,
+        };
+        match parse_command(trimmed.trim_end()) {
+            Ok(command) => commands.push(Spanned { command, span }),
+            Err(message) => errors.push(VmError::new(path, span.line, message)),
         }
     }
-    pub fn command_type(&self) -> CommandType {
-        self.command_type.clone()
-    }
-    pub fn arg1(&self) -> String {
-        self.arg1.clone()
-    }
-    pub fn arg2(&self) -> Option<usize> {
-        self.arg2.clone()
+    if errors.is_empty() {
+        Ok(commands)
+    } else {
+        Err(errors)
     }
+}
 
-    pub fn has_more_lines(&mut self) -> std::io::Result<bool> {
-        let current_pos = self.stream.stream_position().unwrap();
-        let mut tmp = String::new();
-        match self.stream.read_line(&mut tmp) {
-            Ok(0) => {
-                return Ok(false);
+/// 1つのVMコマンド (コメントは取り除いてあること)
+pub fn parse_command(code: &str) -> Result<VmCommand, String> {
+    let words: Vec<&str> = code.split_whitespace().collect();
+    let expect_args = |count: usize, names: &str| {
+        let found = words.len() - 1;
+        if found == count {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} expects {} argument(s) ({}), found {}",
+                words[0], count, names, found
+            ))
+        }
+    };
+    if let Some(op) = ArithmeticOp::from_name(words[0]) {
+        expect_args(0, "none")?;
+        return Ok(VmCommand::Arithmetic(op));
+    }
+    let command = match words[0] {
+        "push" | "pop" => {
+            expect_args(2, "segment and index")?;
+            let segment = parse_segment(words[1])?;
+            let index = parse_number(words[2], "an index")?;
+            if index > segment.max_index() {
+                return Err(format!(
+                    "expected {} index between 0 and {}, found {}",
+                    segment,
+                    segment.max_index(),
+                    index
+                ));
             }
-            Ok(_) => {
-                self.stream
-                    .seek(std::io::SeekFrom::Start(current_pos))
-                    .unwrap();
-                return Ok(true);
+            if words[0] == "push" {
+                VmCommand::Push(segment, index)
+            } else if segment == Segment::Constant {
+                return Err("cannot pop to constant segment".to_string());
+            } else {
+                VmCommand::Pop(segment, index)
             }
-            Err(e) => Err(e),
         }
-    }
-
-    /// 次の入力コマンドを読み込んで、現在のコマンドとする
-    pub fn advance(&mut self) {
-        // self.current_position.unwrap() += 1;
-    }
+        "label" | "goto" | "if-goto" => {
+            expect_args(1, "label")?;
+            let label = parse_symbol(words[1], "label")?;
+            match words[0] {
+                "label" => VmCommand::Label(label),
+                "goto" => VmCommand::Goto(label),
+                _ => VmCommand::IfGoto(label),
+            }
+        }
+        "function" => {
+            expect_args(2, "name and number of locals")?;
+            let name = parse_symbol(words[1], "function name")?;
+            VmCommand::Function(name, parse_number(words[2], "a number of locals")?)
+        }
+        "call" => {
+            expect_args(2, "name and number of arguments")?;
+            let name = parse_symbol(words[1], "function name")?;
+            VmCommand::Call(name, parse_number(words[2], "a number of arguments")?)
+        }
+        "return" => {
+            expect_args(0, "none")?;
+            VmCommand::Return
+        }
+        other => {
+            return Err(format!(
+                "expected a VM command (push, pop, add, sub, neg, eq, gt, lt, and, or, not, \
+                 label, goto, if-goto, function, call, return), found `{}`",
+                other
+            ))
+        }
+    };
+    Ok(command)
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
-#[allow(non_camel_case_types)]
-pub enum CommandType {
-    C_INIT, // 初期値
-    C_ARITHETIC,
-    C_PUSH,
-    C_POP,
-    C_LABEL,
-    C_GOTO,
-    C_IF,
-    C_FUNCTION,
-    C_RETURN,
-    C_CALL,
+fn parse_segment(word: &str) -> Result<Segment, String> {
+    Segment::from_name(word).ok_or_else(|| {
+        let names: Vec<&str> = Segment::ALL.iter().map(|segment| segment.name()).collect();
+        format!(
+            "expected a segment ({}), found `{}`",
+            names.join(", "),
+            word
+        )
+    })
 }
 
-impl fmt::Display for CommandType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            CommandType::C_INIT => write!(f, "C_INIT"),
-            CommandType::C_ARITHETIC => write!(f, "C_ARITHETIC"),
-            CommandType::C_PUSH => write!(f, "C_PUSH"),
-            CommandType::C_POP => write!(f, "C_POP"),
-            CommandType::C_LABEL => write!(f, "C_LABEL"),
-            CommandType::C_GOTO => write!(f, "C_GOTO"),
-            CommandType::C_IF => write!(f, "C_IF"),
-            CommandType::C_FUNCTION => write!(f, "C_FUNCTION"),
-            CommandType::C_RETURN => write!(f, "C_RETURN"),
-            CommandType::C_CALL => write!(f, "C_CALL"),
-        }
+/// 0から32767までの数
+fn parse_number(word: &str, what: &str) -> Result<u16, String> {
+    match word.parse::<u32>() {
+        Ok(number) if number <= MAX_INDEX as u32 => Ok(number as u16),
+        Ok(number) => Err(format!(
+            "expected {} between 0 and {}, found {}",
+            what, MAX_INDEX, number
+        )),
+        Err(_) => Err(format!("expected {}, found `{}`", what, word)),
     }
 }
 
+/// ラベルや関数名に使える文字 (英数字, `_`, `.`, `:`, `$`で、数字から始まらない)
+fn parse_symbol(word: &str, what: &str) -> Result<String, String> {
+    let valid = !word.starts_with(|c: char| c.is_ascii_digit())
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.:$".contains(c));
+    if valid {
+        Ok(word.to_string())
+    } else {
+        Err(format!("expected a {}, found `{}`", what, word))
+    }
+}
@@ -0,0 +1,131 @@
+//! インタプリタが扱えるプログラムの大きさと、ネイティブで実行するOSの関数のヒープを確かめる
+
+use std::path::Path;
+
+use hack_vm::command::VmFile;
+use hack_vm::interpreter::{Interpreter, Stop};
+use hack_vm::native::NativeOs;
+use hack_vm::{os, parser};
+
+/// インタプリタで実行するVMコマンドの最大数
+const MAX_STEPS: u64 = 1_000_000;
+
+fn parse(path: &str, source: &str) -> VmFile {
+    VmFile {
+        path: path.to_string(),
+        commands: parser::parse_str(source, Path::new(path))
+            .unwrap_or_else(|errors| panic!("{:?}", errors)),
+    }
+}
+
+/// OSを足して、ネイティブのOSで止まるまで実行したRAM
+fn run_native(mut files: Vec<VmFile>) -> Vec<u16> {
+    os::link(&mut files).unwrap();
+    let mut interpreter =
+        Interpreter::new(&files, Some(NativeOs::new())).unwrap_or_else(|e| panic!("{:?}", e));
+    assert_eq!(interpreter.run(MAX_STEPS).unwrap(), Stop::Halted);
+    interpreter.ram
+}
+
+/// エラーコードを`static 0`に書いて止まる`Sys.error`
+const SYS_ERROR: &str = "function Sys.error 0
+push argument 0
+pop static 0
+label HALT
+goto HALT
+";
+
+#[test]
+fn programs_too_large_for_16_bit_return_addresses_are_rejected() {
+    // リターンアドレスはRAMに16ビットで積むので、65535個目のコマンドからは戻れない
+    let source = "push constant 0\n".repeat(u16::MAX as usize - 1);
+    let files = [parse("Main.vm", &source)];
+    assert!(Interpreter::new(&files, None).is_ok());
+
+    let source = source + "push constant 0\n";
+    let files = [parse("Main.vm", &source)];
+    let Err(errors) = Interpreter::new(&files, None) else {
+        panic!("65535 commands were accepted");
+    };
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].line, u16::MAX as usize);
+    assert!(errors[0].message.contains("too many commands"), "{}", errors[0]);
+}
+
+#[test]
+fn corrupted_free_list_raises_sys_error() {
+    // 最初の空きブロックを小さくして、次のブロックを溢れるアドレス (65535) にする
+    let sys = format!(
+        "function Sys.init 0
+call Memory.init 0
+pop temp 0
+push constant 2049
+push constant 0
+call Memory.poke 2
+pop temp 0
+push constant 2048
+push constant 0
+not
+call Memory.poke 2
+pop temp 0
+push constant 5
+call Array.new 1
+pop temp 0
+label END
+goto END
+{}",
+        SYS_ERROR
+    );
+    let ram = run_native(vec![parse("Sys.vm", &sys)]);
+    // ヒープが足りないときと同じエラーコード
+    assert_eq!(ram[16], 6);
+}
+
+#[test]
+fn heap_functions_run_as_vm_code_with_a_user_memory_class() {
+    // 自分で書いたMemory.allocが呼ばれるので、Array.newは5000を返す
+    let sys = format!(
+        "function Sys.init 0
+push constant 3
+call Array.new 1
+pop temp 1
+push constant 4
+call String.new 1
+pop temp 2
+label END
+goto END
+{}",
+        SYS_ERROR
+    );
+    let memory = "function Memory.alloc 0
+push constant 5000
+return
+function Memory.deAlloc 0
+push constant 0
+return
+";
+    let ram = run_native(vec![parse("Sys.vm", &sys), parse("Memory.vm", memory)]);
+    assert_eq!(ram[6], 5000);
+    assert_eq!(ram[7], 5000);
+    // String.newはVMのまま最大の長さを書く
+    assert_eq!(ram[5000], 4);
+}
+
+#[test]
+fn heap_functions_run_natively_with_the_bundled_memory_class() {
+    let sys = format!(
+        "function Sys.init 0
+call Memory.init 0
+pop temp 0
+push constant 3
+call Array.new 1
+pop temp 1
+label END
+goto END
+{}",
+        SYS_ERROR
+    );
+    let ram = run_native(vec![parse("Sys.vm", &sys)]);
+    // ネイティブのヒープの最後から切り出す
+    assert!((2048..16384).contains(&ram[6]), "{}", ram[6]);
+}
@@ -0,0 +1,279 @@
+//! `optimizer::optimize`の書き換えを1つずつ確かめ、最適化したプログラムがインタプリタで同じ結果になることを調べる
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hack_vm::command::VmFile;
+use hack_vm::interpreter::{Interpreter, Stop};
+use hack_vm::optimizer::{self, OptimizeStats};
+use hack_vm::parser;
+
+/// インタプリタで実行するVMコマンドの最大数
+const MAX_STEPS: u64 = 1_000_000;
+
+fn parse(path: &str, source: &str) -> VmFile {
+    VmFile {
+        path: path.to_string(),
+        commands: parser::parse_str(source, Path::new(path))
+            .unwrap_or_else(|errors| panic!("{:?}", errors)),
+    }
+}
+
+/// 1つのファイルを最適化して、コマンドを`.vm`の形で返す
+fn optimize(source: &str) -> (Vec<String>, OptimizeStats) {
+    let mut files = vec![parse("Main.vm", source)];
+    let stats = optimizer::optimize(&mut files);
+    let commands = files[0]
+        .commands
+        .iter()
+        .map(|spanned| spanned.command.to_string())
+        .collect();
+    (commands, stats)
+}
+
+/// 止まるまで実行して、SPより下のRAM (レジスタ・static・スタック) を返す
+fn interpret(files: &[VmFile]) -> Vec<u16> {
+    let mut interpreter = Interpreter::new(files, None).unwrap_or_else(|e| panic!("{:?}", e));
+    let stop = interpreter.run(MAX_STEPS).unwrap();
+    assert!(matches!(stop, Stop::Halted), "did not halt");
+    let sp = interpreter.ram[0] as usize;
+    interpreter.ram[..sp].to_vec()
+}
+
+#[test]
+fn folds_constant_expressions() {
+    let (commands, stats) =
+        optimize("push constant 2\npush constant 3\nadd\npush constant 4\nsub\nneg\n");
+    // 2 + 3 - 4は1になり、-1は`push constant 0; not`でも短くならないので`neg`は残す
+    assert_eq!(commands, ["push constant 1", "neg"]);
+    assert_eq!(stats.folded, 2);
+}
+
+#[test]
+fn folds_with_16_bit_wraparound() {
+    // 32767 + 1は-32768になり、`push constant 32767; not`で積む
+    let (commands, _) = optimize("push constant 32767\npush constant 1\nadd\n");
+    assert_eq!(commands, ["push constant 32767", "not"]);
+    // 0 - 32767 - 2は32767に戻る
+    let (commands, _) =
+        optimize("push constant 0\npush constant 32767\nsub\npush constant 2\nsub\n");
+    assert_eq!(commands, ["push constant 32767"]);
+    // 比較は符号付きで、真は-1
+    let (commands, _) = optimize("push constant 32767\nneg\npush constant 1\nlt\n");
+    assert_eq!(commands, ["push constant 0", "not"]);
+}
+
+#[test]
+fn keeps_expressions_with_non_constants() {
+    let source = "push local 0\npush constant 1\nadd\n";
+    let (commands, stats) = optimize(&format!("function Main.f 1\n{}return\n", source));
+    assert_eq!(
+        commands,
+        [
+            "function Main.f 1",
+            "push local 0",
+            "push constant 1",
+            "add",
+            "return"
+        ]
+    );
+    assert_eq!(stats.folded, 0);
+}
+
+#[test]
+fn removes_double_not() {
+    let (commands, stats) = optimize("push local 0\nnot\nnot\npop local 1\n");
+    assert_eq!(commands, ["push local 0", "pop local 1"]);
+    assert_eq!(stats.simplified, 1);
+    // negも2回で元に戻る
+    let (commands, _) = optimize("push local 0\nneg\nneg\npop local 1\n");
+    assert_eq!(commands, ["push local 0", "pop local 1"]);
+}
+
+#[test]
+fn rewrites_eq_not_if_goto_to_sub_if_goto() {
+    let (commands, stats) =
+        optimize("push local 0\npush local 1\neq\nnot\nif-goto DIFFERENT\nlabel DIFFERENT\n");
+    assert_eq!(
+        commands,
+        [
+            "push local 0",
+            "push local 1",
+            "sub",
+            "if-goto DIFFERENT",
+            "label DIFFERENT"
+        ]
+    );
+    assert_eq!(stats.simplified, 1);
+}
+
+#[test]
+fn simplifies_constant_if_goto() {
+    let (commands, _) =
+        optimize("push constant 1\nif-goto A\npush constant 0\nif-goto B\nlabel A\nlabel B\n");
+    assert_eq!(commands, ["goto A", "label A", "label B"]);
+}
+
+#[test]
+fn inlines_small_functions() {
+    let source = "function Sys.init 0
+push local 0
+push constant 3
+call Main.twice_plus 2
+pop static 0
+label END
+goto END
+function Main.twice_plus 0
+push argument 0
+push argument 1
+add
+push constant 2
+add
+return
+";
+    let (commands, stats) = optimize(source);
+    assert_eq!(stats.inlined, 1);
+    // 呼び出しが本体に置き換わり、呼ばれなくなった関数は消える
+    assert_eq!(
+        commands[..5],
+        [
+            "function Sys.init 0",
+            "push local 0",
+            "push constant 3",
+            "add",
+            "push constant 2"
+        ]
+    );
+    assert!(!commands.iter().any(|command| command.starts_with("call")));
+    assert_eq!(stats.removed_functions, ["Main.twice_plus"]);
+}
+
+#[test]
+fn does_not_inline_functions_with_locals_or_calls() {
+    let source = "function Sys.init 0
+push constant 1
+call Main.local 1
+push constant 1
+call Main.calls 1
+label END
+goto END
+function Main.local 1
+push argument 0
+return
+function Main.calls 0
+push argument 0
+call Main.local 1
+return
+";
+    let (_, stats) = optimize(source);
+    assert_eq!(stats.inlined, 0);
+    assert!(stats.removed_functions.is_empty());
+}
+
+#[test]
+fn removes_functions_unreachable_from_sys_init() {
+    let main = "function Main.used 1
+push constant 1
+return
+function Main.unused 0
+call Main.also_unused 0
+return
+function Main.also_unused 0
+call Main.unused 0
+return
+";
+    let sys = "function Sys.init 0\ncall Main.used 0\nlabel END\ngoto END\n";
+    let mut files = vec![parse("Sys.vm", sys), parse("Main.vm", main)];
+    let stats = optimizer::optimize(&mut files);
+    assert_eq!(stats.removed_functions, ["Main.also_unused", "Main.unused"]);
+    assert!(files[1].defines("Main.used"));
+    assert!(!files[1].defines("Main.unused"));
+}
+
+#[test]
+fn keeps_functions_without_sys_init() {
+    // Sys.initがなければどこから呼ばれるかわからないので消さない
+    let (commands, stats) = optimize("function Main.f 0\npush constant 1\nreturn\n");
+    assert!(stats.removed_functions.is_empty());
+    assert_eq!(commands[0], "function Main.f 0");
+}
+
+/// `08/FunctionCalls`のディレクトリの`.vm`ファイル
+fn sample(name: &str) -> Vec<VmFile> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../FunctionCalls")
+        .join(name);
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "vm"))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            let source = fs::read_to_string(path).unwrap();
+            parse(&path.to_string_lossy(), &source)
+        })
+        .collect()
+}
+
+#[test]
+fn optimized_samples_match_the_interpreter() {
+    for name in ["FibonacciElement", "StaticsTest", "NestedCall"] {
+        let files = sample(name);
+        let mut optimized = files.clone();
+        optimizer::optimize(&mut optimized);
+        assert_eq!(interpret(&optimized), interpret(&files), "{}", name);
+    }
+}
+
+#[test]
+fn optimized_rewrites_match_the_interpreter() {
+    // すべての書き換えが起きるプログラム
+    let source = "function Sys.init 2
+push constant 32767
+push constant 2
+add
+pop local 0
+push local 0
+not
+not
+pop static 0
+push local 0
+push constant 1
+call Main.add 2
+pop static 1
+push local 0
+push local 1
+eq
+not
+if-goto DIFFERENT
+push constant 1
+pop static 2
+label DIFFERENT
+push constant 1
+if-goto END
+push constant 2
+pop static 2
+label END
+goto END
+function Main.add 0
+push argument 0
+push argument 1
+add
+return
+function Main.unused 0
+push constant 0
+return
+";
+    let files = vec![parse("Sys.vm", source)];
+    let mut optimized = files.clone();
+    let stats = optimizer::optimize(&mut optimized);
+    assert!(stats.folded > 0 && stats.simplified >= 3 && stats.inlined == 1);
+    assert_eq!(stats.removed_functions, ["Main.add", "Main.unused"]);
+    let ram = interpret(&optimized);
+    assert_eq!(ram, interpret(&files));
+    // static 0は32767 + 2 = -32767
+    assert_eq!(ram[16] as i16, -32767);
+}